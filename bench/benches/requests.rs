@@ -0,0 +1,72 @@
+//! Requests per second through the connection handler, over in-memory connections
+//!
+//! Commands are not spawned, so these measure the daemon's own cost per request
+//! (parsing, locking, logging, and writing the response) as the number of
+//! connections sending at once grows.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use sock_trigger_cmd::test_harness::{response_len, CommandRunner, KeyConfig, RunningCommand, TestServer};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::runtime::Runtime;
+
+use std::os::unix::process::ExitStatusExt;
+use std::process::{ExitStatus, Output};
+use std::sync::Arc;
+
+/// Requests sent per iteration, split evenly between the connections
+const REQUESTS: usize = 1024;
+
+const CONNECTIONS: &[usize] = &[1, 8, 64];
+
+/// Succeeds at once without spawning anything
+#[derive(Debug)]
+struct NoopRunner;
+impl CommandRunner for NoopRunner {
+    fn start(&self, _key_config: &KeyConfig, _kill_on_drop: bool) -> std::io::Result<RunningCommand> {
+        let output = Output {status: ExitStatus::from_raw(0), stdout: Vec::new(), stderr: Vec::new()};
+        Ok(RunningCommand {pid: None, output: Box::pin(async move {Ok(output.into())})})
+    }
+}
+
+/// Sends the requests over that many connections at once, each waiting for a
+/// response before sending its next request
+async fn send_requests(server: &TestServer, connections: usize) {
+    let clients: Vec<_> = (0..connections).map(|_| {
+        let mut stream = server.connect();
+        tokio::spawn(async move {
+            let mut response = [0u8; 5];
+            for _ in 0..REQUESTS / connections {
+                stream.write_all(b"noop\0").await.unwrap();
+                stream.read_exact(&mut response[..1]).await.unwrap();
+                let len = response_len(response[0]).expect("Unknown response");
+                stream.read_exact(&mut response[1..len]).await.unwrap();
+                assert_eq!(response[0], b'C', "The key did not succeed");
+            }
+        })
+    }).collect();
+    for client in clients {
+        client.await.unwrap();
+    }
+}
+
+fn requests(c: &mut Criterion) {
+    let config_path = std::env::temp_dir().join(format!("sock_trigger_cmd_bench_{}.json", std::process::id()));
+    std::fs::write(&config_path, r#"{"noop": "true"}"#).unwrap();
+    let runtime = Runtime::new().unwrap();
+    let _guard = runtime.enter();
+    let server = TestServer::with_runner(&config_path, Arc::new(NoopRunner)).unwrap();
+    let mut group = c.benchmark_group("requests");
+    group.throughput(Throughput::Elements(REQUESTS as u64));
+    for &connections in CONNECTIONS {
+        group.bench_with_input(BenchmarkId::from_parameter(connections), &connections, |b, &connections| {
+            b.iter(|| runtime.block_on(send_requests(&server, connections)));
+        });
+    }
+    group.finish();
+    let _ = std::fs::remove_file(config_path);
+}
+
+criterion_group!(benches, requests);
+criterion_main!(benches);
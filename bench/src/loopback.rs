@@ -0,0 +1,114 @@
+//! Sends a key to a running daemon over its socket from several connections at
+//! once, and prints the requests per second and latencies for each number of
+//! connections
+//!
+//! Point it at a daemon with a no-op key, such as `{"noop": "true"}`, to see
+//! the cost of the whole path from the socket to the spawned command and back.
+
+use argh::FromArgs;
+
+use serde_json::Value;
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+#[derive(FromArgs)]
+#[argh(description = "Measure requests per second and latency against a running daemon")]
+#[argh(example = "loopback --socket /tmp/bench.sock --connections 1 --connections 16")]
+struct Args {
+    #[argh(option)]
+    #[argh(description = "location of the daemon's socket")]
+    socket: PathBuf,
+    #[argh(option, default = "String::from(\"noop\")")]
+    #[argh(description = "key to send (default: noop)")]
+    key: String,
+    #[argh(option)]
+    #[argh(description = "number of connections sending at once; may be repeated (default: 1, 8, and 64)")]
+    connections: Vec<usize>,
+    #[argh(option, default = "10000")]
+    #[argh(description = "requests to send for each number of connections, split between them (default: 10000)")]
+    requests: usize
+}
+
+/// Sends the key over a connection of its own, returning the latency of each
+/// request and how many of them did not succeed
+fn run_connection(socket: &Path, key: &str, requests: usize) -> Result<(Vec<Duration>, usize), String> {
+    let stream = UnixStream::connect(socket).map_err(|e| format!("Could not connect to {}: {}", socket.display(), e))?;
+    let mut stream = BufReader::new(stream);
+    let mut line = String::new();
+    // JSON responses can be read the same way whatever the daemon's response profile
+    let mut exchange = |stream: &mut BufReader<UnixStream>, message: &[u8]| -> Result<Value, String> {
+        stream.get_mut().write_all(message).map_err(|e| format!("Could not send the request: {}", e))?;
+        line.clear();
+        match stream.read_line(&mut line) {
+            Ok(0) => Err("The daemon closed the connection".to_owned()),
+            Ok(_) => serde_json::from_str(&line).map_err(|e| format!("The daemon sent invalid JSON: {}", e)),
+            Err(e) => Err(format!("Could not read the response: {}", e))
+        }
+    };
+    if exchange(&mut stream, b"\x01JSON\0")?["status"] != "ack" {
+        return Err("The daemon did not switch to JSON responses".to_owned());
+    }
+    let message = format!("{}\0", key).into_bytes();
+    let mut latencies = Vec::with_capacity(requests);
+    let mut failures = 0;
+    for _ in 0..requests {
+        let start = Instant::now();
+        let response = exchange(&mut stream, &message)?;
+        latencies.push(start.elapsed());
+        if response["status"] != "succeeded" {
+            failures += 1;
+        }
+    }
+    Ok((latencies, failures))
+}
+
+/// The latency that the given fraction of the sorted latencies are at or under
+fn percentile(sorted: &[Duration], fraction: f64) -> Duration {
+    let index = ((sorted.len() as f64 * fraction).ceil() as usize).clamp(1, sorted.len()) - 1;
+    sorted[index]
+}
+
+fn run(args: &Args, connections: usize) -> Result<(), String> {
+    if connections == 0 || args.requests < connections {
+        return Err(format!("Need at least one request for each of {} connections", connections));
+    }
+    let start = Instant::now();
+    let results: Vec<_> = std::thread::scope(|scope| {
+        let threads: Vec<_> = (0..connections)
+            .map(|_| scope.spawn(|| run_connection(&args.socket, &args.key, args.requests / connections)))
+            .collect();
+        threads.into_iter().map(|thread| thread.join().expect("Connection thread panicked")).collect()
+    });
+    let elapsed = start.elapsed();
+    let mut latencies = Vec::new();
+    let mut failures = 0;
+    for result in results {
+        let (connection_latencies, connection_failures) = result?;
+        latencies.extend(connection_latencies);
+        failures += connection_failures;
+    }
+    latencies.sort_unstable();
+    println!("{:>4} connections: {:>9.0} requests/s, p50 {:>8.1?}, p99 {:>8.1?}, max {:>8.1?}",
+        connections, latencies.len() as f64 / elapsed.as_secs_f64(),
+        percentile(&latencies, 0.5), percentile(&latencies, 0.99), latencies[latencies.len()-1]);
+    if failures > 0 {
+        eprintln!("{} of {} requests did not succeed", failures, latencies.len());
+    }
+    Ok(())
+}
+
+fn main() {
+    let mut args: Args = argh::from_env();
+    if args.connections.is_empty() {
+        args.connections = vec![1, 8, 64];
+    }
+    for &connections in &args.connections {
+        if let Err(e) = run(&args, connections) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
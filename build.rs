@@ -0,0 +1,29 @@
+//! Records the git commit and build time for `--version`
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    let text = String::from_utf8(output.stdout).ok()?;
+    Some(text.trim().to_owned()).filter(|text| output.status.success() && !text.is_empty())
+}
+
+fn main() {
+    let commit = git(&["rev-parse", "--short=12", "HEAD"]).unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=SOCK_TRIGGER_CMD_GIT_COMMIT={}", commit);
+    // Reproducible builds set the time themselves
+    let build_time = std::env::var("SOURCE_DATE_EPOCH").ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs()));
+    println!("cargo:rustc-env=SOCK_TRIGGER_CMD_BUILD_TIME={}", build_time);
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    // Rebuilt when HEAD moves, either to another branch or along the current one
+    if let Some(git_dir) = git(&["rev-parse", "--git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", git_dir);
+        if let Some(head_ref) = git(&["symbolic-ref", "-q", "HEAD"]) {
+            println!("cargo:rerun-if-changed={}/{}", git_dir, head_ref);
+        }
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
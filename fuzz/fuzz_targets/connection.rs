@@ -0,0 +1,104 @@
+#![no_main]
+
+//! Feeds arbitrary bytes to the connection handler as the stream of one client
+//!
+//! Panics in the handler abort, so that they are found even though the handler
+//! runs in a task of its own. Each input also has to get a bounded response in
+//! a bounded time, and streams of plain keys have to get one well-formed
+//! response per key.
+
+use libfuzzer_sys::fuzz_target;
+
+use sock_trigger_cmd::test_harness::{response_len, CommandRunner, KeyConfig, RunningCommand, TestServer, FRAME_MARKER};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::runtime::Runtime;
+
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::{ExitStatus, Output};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+/// Keys whose responses all have lengths that `response_len` knows
+const CONFIG: &str = r#"{
+    "ok": "exit 0",
+    "fail": "exit 3",
+    "off": {"cmd": "exit 0", "enabled": false},
+    "with space": "exit 0",
+    "killed": {"cmd": "exit 0", "on_deadline": "kill"}
+}"#;
+
+/// Most bytes a response may have per byte of input, which is reached by JSON lines for empty keys
+const MAX_RESPONSE_PER_BYTE: usize = 64;
+
+/// How long a connection may take to be answered and closed
+const TIME_LIMIT: Duration = Duration::from_secs(10);
+
+/// Runs `exit <code>` without spawning anything
+#[derive(Debug)]
+struct ExitRunner;
+impl CommandRunner for ExitRunner {
+    fn start(&self, key_config: &KeyConfig, _kill_on_drop: bool) -> std::io::Result<RunningCommand> {
+        let code: i32 = key_config.cmd[1].parse().unwrap();
+        let output = Output {status: ExitStatus::from_raw(code << 8), stdout: Vec::new(), stderr: Vec::new()};
+        Ok(RunningCommand {pid: None, output: Box::pin(async move {Ok(output.into())})})
+    }
+}
+
+fn setup() -> &'static (Runtime, PathBuf) {
+    static SETUP: OnceLock<(Runtime, PathBuf)> = OnceLock::new();
+    SETUP.get_or_init(|| {
+        std::panic::set_hook(Box::new(|info| {
+            eprintln!("{}", info);
+            std::process::abort();
+        }));
+        let path = std::env::temp_dir().join(format!("sock_trigger_cmd_fuzz_{}.json", std::process::id()));
+        std::fs::write(&path, CONFIG).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        (runtime, path)
+    })
+}
+
+/// Checks that the response holds exactly one known response for each message
+fn check_plain_responses(data: &[u8], mut response: &[u8]) {
+    let mut messages = data.split(|byte| *byte == 0).count();
+    // A terminator at the very end does not start another message
+    if data.is_empty() || data.ends_with(&[0]) {
+        messages -= 1;
+    }
+    for _ in 0..messages {
+        let len = response.first().and_then(|byte| response_len(*byte))
+            .unwrap_or_else(|| panic!("Unknown response in {:?}", response));
+        assert!(response.len() >= len, "Response cut short: {:?}", response);
+        response = &response[len..];
+    }
+    assert!(response.is_empty(), "More responses than keys: {:?}", response);
+}
+
+fuzz_target!(|data: &[u8]| {
+    let (runtime, config_path) = setup();
+    runtime.block_on(async {
+        let server = TestServer::with_runner(config_path, Arc::new(ExitRunner)).unwrap();
+        let (mut reader, mut writer) = tokio::io::split(server.connect());
+        // Written while reading, since the handler stops reading once the stream's buffer is full
+        let write = async {
+            // The handler may close the connection before reading everything
+            let _ = writer.write_all(data).await;
+            let _ = writer.shutdown().await;
+        };
+        let mut response = Vec::new();
+        let exchange = async {
+            tokio::join!(write, reader.read_to_end(&mut response)).1
+        };
+        tokio::time::timeout(TIME_LIMIT, exchange).await
+            .expect("Connection was not answered in time")
+            .unwrap();
+        assert!(response.len() <= MAX_RESPONSE_PER_BYTE * (data.len() + 1),
+            "{} bytes of response to {} bytes", response.len(), data.len());
+        if !data.contains(&FRAME_MARKER) {
+            check_plain_responses(data, &response);
+        }
+        tokio::time::timeout(TIME_LIMIT, server.shutdown()).await.expect("Server did not shut down in time");
+    });
+});
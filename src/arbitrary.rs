@@ -0,0 +1,48 @@
+//! Generated inputs for property tests, from a fixed seed so that failures can be replayed
+
+/// How many inputs each property is checked against
+pub const CASES: usize = 2000;
+
+/// Characters that mean something to the code under test, which are picked
+/// much more often than they would be at random
+const SPECIAL_CHARS: &[char] = &['\0', '=', ' ', '\t', '\n', '\'', '"', '\\', '$', '#', '*', '~', '\x01', 'é', '\u{1F600}'];
+
+/// A xorshift generator, which is all the randomness these tests need
+pub struct Rng(u64);
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // The state may never be zero
+        Rng(seed | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number in `0..bound`
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+
+    pub fn char(&mut self) -> char {
+        match self.below(4) {
+            0 => SPECIAL_CHARS[self.below(SPECIAL_CHARS.len())],
+            1 => char::from_u32(self.below(0x11_0000) as u32).unwrap_or('\u{FFFD}'),
+            _ => char::from(b'!' + self.below(94) as u8)
+        }
+    }
+
+    /// A string of up to `max_len` characters, which may be empty
+    pub fn string(&mut self, max_len: usize) -> String {
+        let len = self.below(max_len + 1);
+        (0..len).map(|_| self.char()).collect()
+    }
+
+    /// A string of up to `max_len` characters, none of which are null
+    pub fn string_without_null(&mut self, max_len: usize) -> String {
+        self.string(max_len).replace('\0', "")
+    }
+}
@@ -0,0 +1,65 @@
+//! Single-line denial events meant for fail2ban-style log scanners
+//!
+//! Every event is one line of the form
+//! `denied: time=<RFC 3339 UTC> reason=<reason> uid=<uid> pid=<pid> key=<JSON string>`,
+//! where unknown peer ids are written as `-`.
+
+use log::warn;
+
+use tokio::net::unix::UCred;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static HAS_AUDIT_WRITER: AtomicBool = AtomicBool::new(false);
+
+/// The name of the flexi_logger writer for the dedicated audit log
+pub const WRITER_NAME: &str = "audit";
+
+/// Routes subsequent events to the audit writer as well as the main log
+pub fn enable_audit_writer() {
+    HAS_AUDIT_WRITER.store(true, Ordering::Release);
+}
+
+fn target() -> &'static str {
+    match HAS_AUDIT_WRITER.load(Ordering::Acquire) {
+        true => "{_Default,audit}",
+        false => "sock_trigger_cmd::audit"
+    }
+}
+
+/// Why a request was denied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DenyReason {
+    /// The key did not match any configured key
+    UnknownKey,
+    /// The request hit a rate limit
+    Throttled,
+    /// The key needs a signature, and the request's was missing, invalid, or stale
+    BadSignature,
+    /// The token was never minted, has expired, or was already used
+    BadToken,
+    /// The confirmation nonce was never given out, has expired, or was already used
+    BadNonce
+}
+impl DenyReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DenyReason::UnknownKey => "unknown_key",
+            DenyReason::Throttled => "throttled",
+            DenyReason::BadSignature => "bad_signature",
+            DenyReason::BadToken => "bad_token",
+            DenyReason::BadNonce => "bad_nonce"
+        }
+    }
+}
+
+/// Logs a denied request
+pub fn denied(reason: DenyReason, peer: Option<&UCred>, key: &[u8]) {
+    let time = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    let uid = peer.map_or_else(|| "-".to_owned(), |p| p.uid().to_string());
+    let pid = peer.and_then(|p| p.pid()).map_or_else(|| "-".to_owned(), |p| p.to_string());
+    // JSON escaping keeps control characters in the key from breaking the line
+    let key = serde_json::to_string(&String::from_utf8_lossy(key)).unwrap();
+    warn!(target: target(), "denied: time={} reason={} uid={} pid={} key={}",
+        time, reason.as_str(), uid, pid, key);
+}
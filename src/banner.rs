@@ -0,0 +1,64 @@
+//! The summary of the effective settings logged once the daemon has started
+//!
+//! Each line starts with `Startup:`, so that the block can be found in a log
+//! that someone attaches to an issue.
+
+use log::info;
+
+use nix::unistd::{Gid, Uid};
+
+use std::os::unix::fs::MetadataExt;
+
+use crate::config::{Config, RateLimit};
+use crate::CmdArgs;
+
+/// The number of async runtime worker threads the arguments ask for
+fn worker_threads(args: &CmdArgs) -> String {
+    match (args.current_thread, args.worker_threads) {
+        (true, _) => "current thread only".to_owned(),
+        (false, Some(threads)) => threads.to_string(),
+        // Tokio's default, which its own environment variable can override
+        (false, None) => match std::env::var("TOKIO_WORKER_THREADS") {
+            Ok(threads) => threads,
+            Err(_) => std::thread::available_parallelism().map_or(1, usize::from).to_string()
+        }
+    }
+}
+
+fn rate_limit(limit: Option<&RateLimit>) -> String {
+    match limit {
+        Some(limit) => format!("{}/s with bursts of {}", limit.rate, limit.burst),
+        None => "none".to_owned()
+    }
+}
+
+/// Logs the socket, keys, limits, timeouts, log sinks, runtime, and features in effect
+pub fn log(args: &CmdArgs, log_path: &str, config: &Config) {
+    match std::fs::metadata(&args.socket_location) {
+        Ok(metadata) => info!("Startup: socket {} with mode {:o}, owned by uid {} and gid {}",
+            args.socket_location.display(), metadata.mode() & 0o7777, metadata.uid(), metadata.gid()),
+        Err(e) => info!("Startup: socket {} ({})", args.socket_location.display(), e)
+    }
+    info!("Startup: running as uid {} and gid {}", Uid::effective(), Gid::effective());
+    crate::fd_limit::log_limits();
+    let disabled = config.keys.values().filter(|key_config| !key_config.enabled).count();
+    let detached = config.keys.values().filter(|key_config| key_config.detach).count();
+    let timed = config.keys.values().filter(|key_config| key_config.timeout.is_some()).count();
+    info!("Startup: {} keys, {} disabled, {} detached, {} with a timeout", config.keys.len(), disabled, detached, timed);
+    info!("Startup: rate limits {} globally and {} per peer, {} requests per connection",
+        rate_limit(config.rate_limit.global.as_ref()), rate_limit(config.rate_limit.per_peer.as_ref()),
+        config.max_requests_per_connection.map_or("unlimited".to_owned(), |max| max.to_string()));
+    info!("Startup: shutdown timeout {}ms, response write timeout {}s", config.shutdown_timeout.as_millis(),
+        crate::WRITE_TIMEOUT.as_secs());
+    info!("Startup: logging to {}", crate::logging::describe_sinks(args, log_path));
+    info!("Startup: {} async runtime worker threads", worker_threads(args));
+    let on_off = |is_on: bool| if is_on { "on" } else { "off" };
+    info!("Startup: subreaper {}, queueing during maintenance {}, trimming keys {}, response profile {}",
+        on_off(args.subreaper), on_off(config.queue_during_maintenance), on_off(config.trim_keys),
+        args.response_profile.as_deref().unwrap_or("standard"));
+    let features = crate::version::features();
+    info!("Startup: built with features: {}", match features.is_empty() {
+        true => "none".to_owned(),
+        false => features.join(", ")
+    });
+}
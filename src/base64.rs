@@ -0,0 +1,33 @@
+//! Decoding standard base64 (RFC 4648), used to write binary keys in the config
+
+fn sextet(c: u8) -> Option<u32> {
+    match c {
+        b'A'..=b'Z' => Some((c - b'A').into()),
+        b'a'..=b'z' => Some((c - b'a' + 26).into()),
+        b'0'..=b'9' => Some((c - b'0' + 52).into()),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None
+    }
+}
+
+/// Decodes base64 with the standard alphabet, where the padding may be left out
+pub fn decode(encoded: &str) -> Option<Vec<u8>> {
+    let unpadded = encoded.trim_end_matches('=');
+    let padding = encoded.len() - unpadded.len();
+    if padding > 2 || (padding > 0 && !encoded.len().is_multiple_of(4)) || unpadded.len() % 4 == 1 {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(unpadded.len() * 3 / 4);
+    for chunk in unpadded.as_bytes().chunks(4) {
+        let mut bits = 0u32;
+        for &c in chunk {
+            bits = bits << 6 | sextet(c)?;
+        }
+        // A partial chunk holds one byte for every 8 bits it covers
+        bits <<= 6 * (4 - chunk.len());
+        let bytes = bits.to_be_bytes();
+        decoded.extend_from_slice(&bytes[1..chunk.len()]);
+    }
+    Some(decoded)
+}
@@ -0,0 +1,206 @@
+//! Actions that the daemon performs itself instead of running a command
+
+use log::{error, info, log, warn, Level};
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::task::JoinSet;
+
+use crate::config::{Builtin, FileWrite, Forward, KeyConfig};
+use crate::forward;
+#[cfg(feature = "http")]
+use crate::http_client;
+use crate::protocol::Outcome;
+use crate::systemd;
+
+/// Makes the names of temporary files unique among concurrent writes
+static NEXT_TEMP_ID: AtomicU64 = AtomicU64::new(0);
+
+/// How long HTTP requests may take if the key has no timeout
+const DEFAULT_HTTP_TIME_LIMIT: Duration = Duration::from_secs(30);
+/// How long to wait on the peers of a forwarded key if it has no timeout,
+/// which is longer since they run their commands before answering
+const DEFAULT_FORWARD_TIME_LIMIT: Duration = Duration::from_secs(300);
+
+/// How long to wait for the action of a key that has no timeout, if not indefinitely
+pub fn default_time_limit(builtin: &Builtin) -> Option<Duration> {
+    match builtin {
+        Builtin::Http(_) => Some(DEFAULT_HTTP_TIME_LIMIT),
+        Builtin::Forward(_) => Some(DEFAULT_FORWARD_TIME_LIMIT),
+        Builtin::Unit(_) | Builtin::WriteFile(_) => None
+    }
+}
+
+/// The exit code reported for an HTTP status: 0 for success, otherwise its first digit
+#[cfg(feature = "http")]
+fn http_exit_code(status: u16) -> i32 {
+    match status {
+        200..=299 => 0,
+        status => (status / 100).into()
+    }
+}
+
+/// The exit code reported for the result of a systemd job
+fn job_exit_code(result: &str) -> i32 {
+    match result {
+        "done" => 0,
+        "dependency" => 2,
+        "timeout" => 3,
+        "canceled" => 4,
+        "skipped" => 5,
+        _ => 1
+    }
+}
+
+/// Replaces the file through a temporary file next to it, so that readers see either the old or the new contents
+fn write_atomically(write: &FileWrite, contents: &[u8]) -> std::io::Result<()> {
+    // The path was checked to have a file name when the config was loaded
+    let mut temp_name = OsString::from(".");
+    temp_name.push(write.path.file_name().unwrap());
+    temp_name.push(format!(".{}.{}.tmp", std::process::id(), NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed)));
+    let temp_path = write.path.with_file_name(temp_name);
+    let result = (|| {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(write.mode.bits())
+            .open(&temp_path)?;
+        // The mode given when creating the file is reduced by the umask
+        file.set_permissions(fs::Permissions::from_mode(write.mode.bits()))?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &write.path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result?;
+    // Make the rename itself durable
+    if let Some(dir) = write.path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Triggers the key on every peer at once, returning how many did not succeed
+async fn forward_to_peers(key: &str, forward: &Forward) -> usize {
+    let mut peers = JoinSet::new();
+    for socket in forward.sockets.iter().cloned() {
+        let forwarded_key = forward.key.clone();
+        peers.spawn(async move {
+            let result = forward::trigger(&socket, forwarded_key.as_ref()).await;
+            (socket, result)
+        });
+    }
+    let mut failed = 0;
+    // Dropping the set when the time limit runs out aborts the peers still being waited on
+    while let Some(joined) = peers.join_next().await {
+        let (socket, result) = joined.expect("Forwarding task panicked");
+        match result {
+            Ok(outcome) if outcome.is_success() =>
+                info!("Peer {} answered {} for key {}", socket.display(), outcome.label(), key),
+            Ok(outcome) => {
+                warn!("Peer {} answered {} for key {}", socket.display(), outcome.label(), key);
+                failed += 1;
+            },
+            Err(e) => {
+                warn!("Could not forward key {} to {}: {}", key, socket.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    failed
+}
+
+/// Checks that the key is sent a payload if and only if it takes one, and that the payload fits
+pub fn check_payload(key_config: &KeyConfig, payload: Option<&[u8]>) -> Result<(), String> {
+    let max_bytes = match key_config.builtin {
+        Some(Builtin::WriteFile(ref write)) if write.contents.is_none() => Some(write.max_bytes),
+        Some(Builtin::Http(ref request)) if request.body.is_none() => Some(request.max_bytes),
+        _ => None
+    };
+    match (max_bytes, payload) {
+        (None, Some(_)) => Err("it does not take a payload".to_owned()),
+        (Some(_), None) => Err("it needs a payload, sent in a PAYLOAD frame".to_owned()),
+        (Some(max_bytes), Some(payload)) if payload.len() > max_bytes =>
+            Err(format!("its payload of {} bytes is over its {} byte limit", payload.len(), max_bytes)),
+        _ => Ok(())
+    }
+}
+
+/// Performs the action of a key, reporting how it went as a command would
+///
+/// The payload must have passed `check_payload()`.
+pub async fn run(key: &str, builtin: &Builtin, payload: Option<&[u8]>) -> Outcome {
+    match builtin {
+        Builtin::Unit(job) => match systemd::run_unit_job(job).await {
+            Ok(result) => {
+                let exit_code = job_exit_code(&result);
+                let level = match exit_code {
+                    0 => Level::Info,
+                    _ => Level::Warn
+                };
+                log!(level, "Job to {} {} for key {} finished as {}", job.operation.as_str(), job.unit, key, result);
+                Outcome::Completed(exit_code)
+            },
+            Err(e) => {
+                error!("Could not {} {} for key {}: {}", job.operation.as_str(), job.unit, key, e);
+                Outcome::SpawnFailed
+            }
+        },
+        Builtin::WriteFile(write) => {
+            let contents = write.contents.as_ref().map(String::as_bytes).or(payload).unwrap_or_default().to_vec();
+            let len = contents.len();
+            // Left to finish if the key runs out of time, so that the temporary file is still cleaned up
+            let blocking_write = write.clone();
+            let result = tokio::task::spawn_blocking(move || write_atomically(&blocking_write, &contents)).await
+                .expect("File writing task panicked");
+            match result {
+                Ok(()) => {
+                    info!("Wrote {} bytes to {} for key {}", len, write.path.display(), key);
+                    Outcome::Completed(0)
+                },
+                Err(e) => {
+                    error!("Could not write {} for key {}: {}", write.path.display(), key, e);
+                    Outcome::SpawnFailed
+                }
+            }
+        },
+        #[cfg(feature = "http")]
+        Builtin::Http(request) => {
+            let body = request.body.as_ref().map(String::as_bytes).or(payload).unwrap_or_default();
+            // The caller enforces the time limit, so that running out of time is reported as such
+            match http_client::request(&request.method, &request.url, &request.headers, body, Duration::MAX).await {
+                Ok(response) => {
+                    let exit_code = http_exit_code(response.status);
+                    let level = match exit_code {
+                        0 => Level::Info,
+                        _ => Level::Warn
+                    };
+                    log!(level, "{} {} for key {} returned status {}", request.method, request.url, key, response.status);
+                    Outcome::Completed(exit_code)
+                },
+                Err(e) => {
+                    error!("Could not send {} {} for key {}: {}", request.method, request.url, key, e);
+                    Outcome::SpawnFailed
+                }
+            }
+        },
+        #[cfg(not(feature = "http"))]
+        Builtin::Http(_) => unreachable!("Refused when loading the config of builds without http"),
+        Builtin::Forward(forward) => {
+            let failed = forward_to_peers(key, forward).await;
+            let level = match failed {
+                0 => Level::Info,
+                _ => Level::Warn
+            };
+            log!(level, "Forwarded key {} to {} peers, of which {} did not succeed", key, forward.sockets.len(), failed);
+            Outcome::Completed(failed.min(255) as i32)
+        }
+    }
+}
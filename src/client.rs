@@ -0,0 +1,198 @@
+//! Talking to a running daemon over its socket, for the subcommands that act as clients
+
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use crate::protocol;
+
+/// How long to wait between attempts at connecting to a socket that is not there yet
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Connects to the socket, or to the default location if none is given
+pub fn connect(socket: Option<&Path>) -> Result<UnixStream, String> {
+    connect_waiting(socket, Duration::ZERO)
+}
+
+/// Connects to the socket, retrying for up to `wait` while it does not exist or
+/// nothing listens on it, such as while the daemon is still starting
+pub fn connect_waiting(socket: Option<&Path>, wait: Duration) -> Result<UnixStream, String> {
+    let socket = match socket {
+        Some(socket) => socket.to_owned(),
+        None => crate::default_socket_location()?
+    };
+    let give_up = Instant::now() + wait;
+    loop {
+        match UnixStream::connect(&socket) {
+            Ok(stream) => return Ok(stream),
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused)
+                && Instant::now() < give_up => std::thread::sleep(CONNECT_RETRY_DELAY),
+            Err(e) => return Err(format!("Could not connect to {}: {}", socket.display(), e))
+        }
+    }
+}
+
+/// Whether the name matches the pattern, where `*` matches any bytes and `?` any one byte
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, _) => name.is_empty(),
+        (Some((b'*', rest)), _) => wildcard_match(rest, name) || (!name.is_empty() && wildcard_match(pattern, &name[1..])),
+        (Some((b'?', rest)), Some((_, name_rest))) => wildcard_match(rest, name_rest),
+        (Some((byte, rest)), Some((name_byte, name_rest))) => byte == name_byte && wildcard_match(rest, name_rest),
+        (Some(_), None) => false
+    }
+}
+
+/// The sockets given, with a last component holding `*` or `?` replaced by
+/// the sockets in its directory that it matches, in order of their names
+pub fn expand_sockets(patterns: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    let mut sockets = Vec::new();
+    for pattern in patterns {
+        let name = pattern.file_name().map_or(&[][..], OsStrExt::as_bytes);
+        if !name.iter().any(|byte| matches!(byte, b'*' | b'?')) {
+            sockets.push(pattern.clone());
+            continue;
+        }
+        let dir = pattern.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let entries = std::fs::read_dir(dir).map_err(|e| format!("Could not list {}: {}", dir.display(), e))?;
+        let mut matched: Vec<PathBuf> = entries.filter_map(Result::ok)
+            // Like in shells, wildcards do not match hidden files
+            .filter(|entry| entry.file_name().as_bytes().first() != Some(&b'.'))
+            .filter(|entry| wildcard_match(name, entry.file_name().as_bytes()))
+            .map(|entry| entry.path())
+            .filter(|path| path.metadata().is_ok_and(|metadata| metadata.file_type().is_socket()))
+            .collect();
+        if matched.is_empty() {
+            return Err(format!("No socket matches {}", pattern.display()));
+        }
+        matched.sort_unstable();
+        sockets.extend(matched);
+    }
+    Ok(sockets)
+}
+
+/// Sends an extended frame with the verb and arguments
+pub fn send_frame(stream: &mut UnixStream, frame: &str) -> Result<(), String> {
+    let mut message = vec![protocol::FRAME_MARKER];
+    message.extend_from_slice(frame.as_bytes());
+    message.push(0);
+    stream.write_all(&message).map_err(|e| format!("Could not send the request: {}", e))
+}
+
+/// Reads the response to an admin frame that sends data back: an ack, a
+/// big-endian `u32` length, and the data
+pub fn read_admin_data(stream: &mut UnixStream) -> Result<Vec<u8>, String> {
+    let mut header = [0u8; 5];
+    stream.read_exact(&mut header[..1]).map_err(|e| format!("Could not read the response: {}", e))?;
+    match header[0] {
+        protocol::ACK_RESPONSE => {},
+        protocol::ADMIN_DENIED_RESPONSE => return Err("Only root and the daemon's user may do this".to_owned()),
+        protocol::INVALID_FRAME_RESPONSE => return Err("The daemon did not understand the request".to_owned()),
+        byte => return Err(format!("Unexpected response {:?}", char::from(byte)))
+    }
+    stream.read_exact(&mut header[1..]).map_err(|e| format!("Could not read the response: {}", e))?;
+    let len = u32::from_be_bytes(header[1..].try_into().unwrap());
+    let mut data = Vec::new();
+    stream.take(len.into()).read_to_end(&mut data).map_err(|e| format!("Could not read the response: {}", e))?;
+    if data.len() != len as usize {
+        return Err("The daemon closed the connection early".to_owned());
+    }
+    Ok(data)
+}
+
+/// Sends an admin frame that sends data back on a connection of its own, and parses the JSON it sends
+pub fn admin_request(socket: Option<&Path>, frame: &str) -> Result<Value, String> {
+    let mut stream = connect(socket)?;
+    send_frame(&mut stream, frame)?;
+    let data = read_admin_data(&mut stream)?;
+    serde_json::from_slice(&data).map_err(|e| format!("The daemon sent invalid JSON: {}", e))
+}
+
+/// A connection switched to JSON responses, which do not depend on the daemon's response profile
+///
+/// When the daemon closes the connection after `max_requests_per_connection`,
+/// the next request is sent again over a new connection.
+pub struct JsonConnection {
+    stream: BufReader<UnixStream>,
+    socket: Option<PathBuf>
+}
+impl JsonConnection {
+    /// Connects to the socket, waiting for up to `wait` for it to appear
+    pub fn open(socket: Option<&Path>, wait: Duration) -> Result<Self, String> {
+        let mut stream = connect_waiting(socket, wait)?;
+        send_frame(&mut stream, "JSON")?;
+        let mut connection = JsonConnection {stream: BufReader::new(stream), socket: socket.map(Path::to_owned)};
+        match connection.read_line()?["status"].as_str() {
+            Some("ack") => Ok(connection),
+            _ => Err("The daemon did not switch to JSON responses".to_owned())
+        }
+    }
+
+    fn read_line(&mut self) -> Result<Value, String> {
+        let mut line = String::new();
+        match self.stream.read_line(&mut line) {
+            Ok(0) => Err("The daemon closed the connection".to_owned()),
+            Ok(_) => match serde_json::from_str(&line) {
+                Ok(Value::Object(object)) => Ok(Value::Object(object)),
+                Ok(_) => Err("The daemon sent JSON that is not an object".to_owned()),
+                Err(e) => Err(format!("The daemon sent invalid JSON: {}", e))
+            },
+            Err(e) => Err(format!("Could not read the response: {}", e))
+        }
+    }
+
+    fn exchange(&mut self, message: &[u8]) -> Result<Value, String> {
+        // A daemon that asked for a reconnect has closed the connection without reading further
+        let response = match self.stream.get_mut().write_all(message) {
+            Ok(()) => Some(self.read_line()?).filter(|response| response["status"] != "reconnect"),
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => None,
+            Err(e) => return Err(format!("Could not send the request: {}", e))
+        };
+        if let Some(response) = response {
+            return Ok(response);
+        }
+        *self = JsonConnection::open(self.socket.as_deref(), Duration::ZERO)?;
+        self.stream.get_mut().write_all(message).map_err(|e| format!("Could not send the request: {}", e))?;
+        self.read_line()
+    }
+
+    /// Sends the key and waits for its response, or until the deadline in milliseconds if one is given
+    pub fn send_key(&mut self, key: &str, deadline: Option<u64>) -> Result<Value, String> {
+        if key.contains('\0') || key.as_bytes().first() == Some(&protocol::FRAME_MARKER) {
+            return Err("Keys may not contain null bytes or start with the byte 0x01".to_owned());
+        }
+        let mut message = Vec::new();
+        if let Some(deadline) = deadline {
+            message.push(protocol::FRAME_MARKER);
+            message.extend(format!("DEADLINE {}\0", deadline).into_bytes());
+        }
+        message.extend_from_slice(key.as_bytes());
+        message.push(0);
+        self.exchange(&message)
+    }
+
+    /// Waits for the job to finish and returns its result
+    pub fn follow_job(&mut self, job_id: u32) -> Result<Value, String> {
+        let mut message = vec![protocol::FRAME_MARKER];
+        message.extend(format!("JOB {}\0", job_id).into_bytes());
+        self.exchange(&message)
+    }
+}
+
+/// The status of a JSON response to a key, with the detail that came with it, such as `failed (code 3)`
+pub fn describe(response: &Value) -> String {
+    let mut description = response["status"].as_str().unwrap_or("unknown").to_owned();
+    for detail in ["code", "signal", "job", "pid", "approvals", "nonce"] {
+        match response[detail] {
+            Value::Null => {},
+            Value::String(ref value) => description += &format!(" ({} {})", detail, value),
+            ref value => description += &format!(" ({} {})", detail, value)
+        }
+    }
+    description
+}
@@ -0,0 +1,68 @@
+//! Files that a key's command leaves behind, sent back after its response to a `COLLECT` frame
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Sent before a file that was read in full
+const WHOLE_FILE: u8 = b'+';
+/// Sent before a file that was cut off at the key's `collect_max_bytes`, or at `MAX_TOTAL_BYTES`
+const TRUNCATED_FILE: u8 = b'~';
+/// Sent for a file that could not be read, with no contents
+const MISSING_FILE: u8 = b'-';
+
+/// The most bytes of all the files of one response together, which keeps
+/// the length of each file within its `u32` as well
+pub const MAX_TOTAL_BYTES: usize = 64*1024*1024;
+const _: () = assert!(MAX_TOTAL_BYTES <= u32::MAX as usize);
+
+/// A file read after a command exited
+#[derive(Debug)]
+pub struct CollectedFile {
+    pub path: PathBuf,
+    /// The start of the file, or `None` if it could not be read
+    pub contents: Option<Vec<u8>>,
+    pub is_truncated: bool
+}
+
+fn read_capped(path: &Path, max_bytes: usize) -> std::io::Result<(Vec<u8>, bool)> {
+    let mut contents = Vec::new();
+    std::fs::File::open(path)?.take(max_bytes as u64 + 1).read_to_end(&mut contents)?;
+    let is_truncated = contents.len() > max_bytes;
+    contents.truncate(max_bytes);
+    Ok((contents, is_truncated))
+}
+
+/// Reads up to `max_bytes` of each file, and up to `MAX_TOTAL_BYTES` of them all,
+/// logging the ones that cannot be read
+///
+/// This blocks on the reads, so it is run off the async threads.
+pub fn read(paths: &[PathBuf], max_bytes: usize) -> Vec<CollectedFile> {
+    let mut bytes_left = MAX_TOTAL_BYTES;
+    paths.iter().map(|path| match read_capped(path, max_bytes.min(bytes_left)) {
+        Ok((contents, is_truncated)) => {
+            bytes_left -= contents.len();
+            CollectedFile {path: path.clone(), contents: Some(contents), is_truncated}
+        },
+        Err(e) => {
+            log::warn!("Could not collect {}: {}", path.display(), e);
+            CollectedFile {path: path.clone(), contents: None, is_truncated: false}
+        }
+    }).collect()
+}
+
+/// The bytes sent after a key's response: a `u8` count, then a status byte,
+/// a big-endian `u32` length, and the contents of each file
+pub fn encode(files: &[CollectedFile]) -> Vec<u8> {
+    let mut encoded = vec![files.len() as u8];
+    for file in files {
+        let (status, contents) = match file.contents {
+            Some(ref contents) if file.is_truncated => (TRUNCATED_FILE, contents.as_slice()),
+            Some(ref contents) => (WHOLE_FILE, contents.as_slice()),
+            None => (MISSING_FILE, &[][..])
+        };
+        encoded.push(status);
+        encoded.extend((contents.len() as u32).to_be_bytes());
+        encoded.extend_from_slice(contents);
+    }
+    encoded
+}
@@ -0,0 +1,1678 @@
+use serde::{Deserialize, Deserializer};
+use serde::de::{self, MapAccess, SeqAccess, Visitor};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::Level;
+
+use nix::sys::stat::Mode;
+
+use crate::collect;
+use crate::http_client::HttpUrl;
+use crate::protocol::{Outcome, CONFIRM_PREFIX, FRAME_MARKER, TOKEN_PREFIX};
+use crate::precondition::Precondition;
+use crate::time_window::TimeWindow;
+use crate::webhook;
+use crate::base64;
+use crate::sha256;
+use crate::util::NonEmptyNoNullString;
+
+/// A config entry as written in the file: either a bare command or a table of options
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawKeyEntry {
+    Cmd(String),
+    Full(Box<RawKeySpec>)
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RawKeySpec {
+    #[serde(default)]
+    cmd: Option<String>,
+    #[serde(default)]
+    systemd: Option<String>,
+    #[serde(default)]
+    write_file: Option<RawFileWrite>,
+    #[serde(default)]
+    http: Option<RawHttpRequest>,
+    #[serde(default)]
+    forward: Option<RawForward>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    sha256: Option<String>,
+    #[serde(default)]
+    enabled: Option<bool>,
+    #[serde(default)]
+    detach: bool,
+    #[serde(default)]
+    stdout: Option<PathBuf>,
+    #[serde(default)]
+    stderr: Option<PathBuf>,
+    #[serde(default)]
+    rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    on_deadline: Option<DeadlinePolicy>,
+    #[serde(default)]
+    on_shutdown: Option<ShutdownPolicy>,
+    #[serde(default)]
+    pty: Option<bool>,
+    #[serde(default)]
+    log_output: Option<bool>,
+    #[serde(default)]
+    rotate: Option<Rotation>,
+    #[serde(default)]
+    env_profiles: Option<Vec<String>>,
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+    #[serde(default)]
+    cwd: Option<PathBuf>,
+    #[serde(default)]
+    max_output_bytes: Option<usize>,
+    #[serde(default)]
+    output_grace_ms: Option<u64>,
+    #[serde(default)]
+    log_level: Option<String>,
+    #[serde(default)]
+    umask: Option<String>,
+    #[serde(default)]
+    cpus: Option<Vec<usize>>,
+    #[serde(default)]
+    on_timeout: Option<String>,
+    #[serde(default)]
+    hmac_secret: Option<String>,
+    #[serde(default)]
+    hmac_window_ms: Option<u64>,
+    #[serde(default)]
+    allowed_windows: Option<Vec<String>>,
+    #[serde(default)]
+    confirm: bool,
+    #[serde(default)]
+    approvals: Option<u8>,
+    #[serde(default)]
+    approval_window_ms: Option<u64>,
+    #[serde(default)]
+    daily_budget_ms: Option<u64>,
+    #[serde(default)]
+    min_free_space: Option<RawFreeSpace>,
+    #[serde(default)]
+    preconditions: Vec<RawPrecondition>,
+    #[serde(default)]
+    collect: Vec<PathBuf>,
+    #[serde(default)]
+    collect_max_bytes: Option<usize>,
+    #[serde(default)]
+    webhook: Option<RawWebhook>
+}
+
+/// A `min_free_space` setting as written in the file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawFreeSpace {
+    path: PathBuf,
+    bytes: u64
+}
+
+/// A `webhook` setting as written in the file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawWebhook {
+    url: String,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    body: Option<serde_json::Value>,
+    #[serde(default)]
+    retries: Option<u32>,
+    #[serde(default)]
+    retry_delay_ms: Option<u64>
+}
+
+/// An entry of `preconditions` as written in the file
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum RawPrecondition {
+    FreeSpace(RawFreeSpace),
+    FileExists(PathBuf),
+    Pidfile(PathBuf),
+    NotRunning(NonEmptyNoNullString),
+    Check(String)
+}
+
+/// A `write_file` action as written in the file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawFileWrite {
+    path: PathBuf,
+    #[serde(default)]
+    contents: Option<String>,
+    #[serde(default)]
+    max_bytes: Option<usize>,
+    #[serde(default)]
+    mode: Option<String>
+}
+
+/// An `http` action as written in the file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawHttpRequest {
+    #[serde(default)]
+    method: Option<String>,
+    url: String,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    body: Option<String>,
+    #[serde(default)]
+    max_bytes: Option<usize>
+}
+
+/// A `forward` action as written in the file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawForward {
+    sockets: Vec<PathBuf>,
+    #[serde(default)]
+    key: Option<String>
+}
+
+/// Settings inherited by every key that does not set them itself
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RawDefaults {
+    #[serde(default)]
+    rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    on_deadline: Option<DeadlinePolicy>,
+    #[serde(default)]
+    on_shutdown: Option<ShutdownPolicy>,
+    #[serde(default)]
+    pty: Option<bool>,
+    #[serde(default)]
+    log_output: Option<bool>,
+    #[serde(default)]
+    rotate: Option<Rotation>,
+    #[serde(default)]
+    env_profiles: Option<Vec<String>>,
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+    #[serde(default)]
+    cwd: Option<PathBuf>,
+    #[serde(default)]
+    max_output_bytes: Option<usize>,
+    #[serde(default)]
+    output_grace_ms: Option<u64>,
+    #[serde(default)]
+    log_level: Option<String>,
+    #[serde(default)]
+    umask: Option<String>,
+    #[serde(default)]
+    cpus: Option<Vec<usize>>
+}
+
+/// When to rotate the files that command output is appended to
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    /// Size at which the file is rotated before more output is appended
+    pub max_bytes: u64,
+    /// Number of rotated files to keep as `<file>.1`, `<file>.2`, and so on
+    pub keep: u32
+}
+
+/// What happens to a command that outlives the deadline a client asked for
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadlinePolicy {
+    /// Keep the command running in the background
+    #[default]
+    Detach,
+    /// Kill the command
+    Kill
+}
+
+/// What happens to a running command when the daemon stops
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownPolicy {
+    /// Wait for the command to finish
+    #[default]
+    Wait,
+    /// Kill the command if it is still running after the drain timeout
+    Kill,
+    /// Stop waiting and leave the command running
+    Detach
+}
+
+/// How long stopping waits for commands that are killed at shutdown, unless configured
+const DEFAULT_SHUTDOWN_TIMEOUT_MS: u64 = 30_000;
+
+/// How long output may stay open after a command exits, unless configured
+const DEFAULT_OUTPUT_GRACE_MS: u64 = 5000;
+
+/// How long triggers in the queue file stay worth running, unless configured
+const DEFAULT_QUEUE_MAX_AGE_MS: u64 = 24*60*60*1000;
+
+/// The structured form of the config file, with settings alongside the keys
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawStructuredConfig {
+    keys: HashMap<NonEmptyNoNullString, RawKeyEntry>,
+    #[serde(default)]
+    rate_limit: Option<RateLimitConfig>,
+    #[serde(default)]
+    env_profiles: HashMap<String, EnvProfile>,
+    #[serde(default)]
+    defaults: Option<RawDefaults>,
+    #[serde(default)]
+    interpolate_env: bool,
+    #[serde(default)]
+    namespaces: HashMap<String, Access>,
+    #[serde(default)]
+    queue_during_maintenance: bool,
+    #[serde(default)]
+    queue_file: Option<PathBuf>,
+    #[serde(default)]
+    queue_max_age_ms: Option<u64>,
+    #[serde(default)]
+    shutdown_timeout_ms: Option<u64>,
+    #[serde(default)]
+    trim_keys: bool,
+    #[serde(default)]
+    max_requests_per_connection: Option<u32>,
+    #[serde(default)]
+    nofile_limit: Option<u64>,
+    #[serde(default)]
+    email_alert: Option<RawEmailAlert>,
+    #[serde(default)]
+    response_profiles: HashMap<String, BTreeMap<String, String>>,
+    #[serde(default)]
+    budget_reset_hour: Option<u8>,
+    #[serde(default)]
+    event_bus: Option<RawEventBus>
+}
+
+/// An `event_bus` setting as written in the file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawEventBus {
+    #[serde(default)]
+    redis: Option<String>,
+    #[serde(default)]
+    channel: Option<String>,
+    #[serde(default)]
+    mqtt: Option<String>,
+    #[serde(default)]
+    topic: Option<String>
+}
+
+/// An `email_alert` setting as written in the file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawEmailAlert {
+    #[serde(default)]
+    server: Option<String>,
+    from: String,
+    to: Vec<String>,
+    #[serde(default)]
+    after_failures: Option<u32>
+}
+
+/// The peers allowed to see the keys in a namespace
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Access {
+    /// Peer UIDs that are allowed
+    #[serde(default)]
+    pub uids: Vec<u32>,
+    /// Peer primary GIDs that are allowed
+    #[serde(default)]
+    pub gids: Vec<u32>
+}
+impl Access {
+    fn allows(&self, peer: Option<(u32, u32)>) -> bool {
+        peer.is_some_and(|(uid, gid)| self.uids.contains(&uid) || self.gids.contains(&gid))
+    }
+}
+
+/// A named set of environment variables that keys can share
+type EnvProfile = BTreeMap<String, String>;
+
+/// A token bucket rate limit
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// Requests replenished per second
+    pub rate: f64,
+    /// Number of requests that can be made in a burst
+    pub burst: u32
+}
+
+/// The rate limits that apply across keys
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitConfig {
+    /// Limit on all requests to the daemon
+    #[serde(default)]
+    pub global: Option<RateLimit>,
+    /// Limit on requests from each peer UID
+    #[serde(default)]
+    pub per_peer: Option<RateLimit>
+}
+
+/// An operation on a systemd unit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnitOperation {
+    Start,
+    Stop,
+    Restart
+}
+impl UnitOperation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UnitOperation::Start => "start",
+            UnitOperation::Stop => "stop",
+            UnitOperation::Restart => "restart"
+        }
+    }
+}
+
+/// A job for the service manager, such as restarting a unit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnitJob {
+    pub operation: UnitOperation,
+    pub unit: String
+}
+
+/// A file whose contents a key replaces
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileWrite {
+    pub path: PathBuf,
+    /// What is written on every trigger, or None if the client sends it in a `PAYLOAD` frame
+    pub contents: Option<String>,
+    /// Limit on contents sent by the client
+    pub max_bytes: usize,
+    pub mode: Mode
+}
+
+/// An HTTP request that a key sends
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: HttpUrl,
+    pub headers: Vec<(String, String)>,
+    /// What is sent on every trigger, or None if the client sends it in a `PAYLOAD` frame
+    pub body: Option<String>,
+    /// Limit on bodies sent by the client
+    pub max_bytes: usize
+}
+
+/// A trigger that a key passes on to other daemons
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Forward {
+    /// Sockets of the other daemons, such as ones forwarded from other hosts over SSH
+    pub sockets: Vec<PathBuf>,
+    /// The key triggered on the other daemons
+    pub key: NonEmptyNoNullString
+}
+
+/// Where to email operators when a key keeps failing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmailAlert {
+    /// SMTP relay that accepts the mail without authentication
+    pub host: String,
+    pub port: u16,
+    pub from: String,
+    pub to: Vec<String>,
+    /// Number of failures in a row that sends an email
+    pub after_failures: u32
+}
+
+/// What an event bus speaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventBusKind {
+    Redis,
+    Mqtt
+}
+
+/// Where an event is published for every run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventBus {
+    pub kind: EventBusKind,
+    pub host: String,
+    pub port: u16,
+    /// The Redis channel or MQTT topic
+    pub channel: String
+}
+
+/// Responses that replace the standard ones, for clients written for another daemon
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResponseProfile {
+    /// Responses by outcome label
+    pub responses: BTreeMap<String, Vec<u8>>,
+    /// The response for outcomes without one of their own, if not the standard one
+    pub other: Option<Vec<u8>>
+}
+impl ResponseProfile {
+    pub fn response(&self, outcome: Outcome) -> Vec<u8> {
+        self.responses.get(outcome.label()).or(self.other.as_ref()).cloned()
+            .unwrap_or_else(|| outcome.response())
+    }
+}
+
+/// An action that the daemon performs itself instead of running a command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Builtin {
+    /// Queue a job with systemd over D-Bus and wait for its result
+    Unit(UnitJob),
+    /// Atomically replace the contents of a file
+    WriteFile(FileWrite),
+    /// Send an HTTP request and report its status
+    Http(HttpRequest),
+    /// Trigger a key on other daemons and report how many did not succeed
+    Forward(Forward)
+}
+
+/// The resolved configuration for a single key
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyConfig {
+    /// The tokenized command, including any leading `VAR=VALUE` entries, or empty for builtins
+    pub cmd: Vec<String>,
+    /// What the daemon does itself instead of running a command
+    pub builtin: Option<Builtin>,
+    /// What the key is for, for operators reading the key list
+    pub description: Option<String>,
+    /// Labels for grouping keys, sorted and without duplicates
+    pub tags: Vec<String>,
+    /// The expected SHA-256 of the executable, if pinned
+    pub sha256: Option<[u8; 32]>,
+    /// Whether the key can be triggered, unless overridden at runtime
+    pub enabled: bool,
+    /// Limit on requests for this key
+    pub rate_limit: Option<RateLimit>,
+    /// What to do with the command if it outlives a client deadline
+    pub on_deadline: DeadlinePolicy,
+    /// What to do with the command if it is still running when the daemon stops
+    pub on_shutdown: ShutdownPolicy,
+    /// Whether the command is started in the background instead of being waited on
+    pub detach: bool,
+    /// Whether the command runs under a pseudo-terminal, with its output captured as stdout
+    pub pty: bool,
+    /// Files that the command's stdout and stderr are appended to
+    pub stdout: Option<PathBuf>,
+    pub stderr: Option<PathBuf>,
+    /// Whether captured output is also written to the daemon log
+    pub log_output: bool,
+    pub rotate: Option<Rotation>,
+    /// Time after which the command is killed
+    pub timeout: Option<Duration>,
+    /// Working directory of the command, instead of the daemon's
+    pub cwd: Option<PathBuf>,
+    /// Limit on how much of each output stream is captured
+    pub max_output_bytes: Option<usize>,
+    /// How long output may stay open after the command exits before it stops being read
+    pub output_grace: Duration,
+    /// Level at which the output of successful commands is logged
+    pub output_log_level: Level,
+    /// File mode creation mask of the command, instead of the daemon's
+    pub umask: Option<Mode>,
+    /// CPUs the command is confined to, instead of the daemon's
+    pub cpus: Option<Vec<usize>>,
+    /// The tokenized command run when the command is killed for exceeding its timeout
+    pub on_timeout: Option<Vec<String>>,
+    /// Requires requests for the key to be signed
+    pub signing: Option<KeySigning>,
+    /// Local times the key may be run in, or empty if it may always be run
+    pub allowed_windows: Vec<TimeWindow>,
+    /// Whether requests must be confirmed by echoing a nonce before the key runs
+    pub confirm: bool,
+    /// Requires requests from several distinct peers before the key runs
+    pub approvals: Option<Approvals>,
+    /// How long the key may run for in total each day
+    pub daily_budget: Option<Duration>,
+    /// Checks that must pass right before the key runs
+    pub preconditions: Vec<Precondition>,
+    /// Files sent back once the command exits, to clients that ask for them
+    pub collect: Vec<PathBuf>,
+    /// How much of each collected file is sent
+    pub collect_max_bytes: usize,
+    /// Where the result of every run is posted
+    pub webhook: Option<Webhook>
+}
+
+/// A webhook that the result of every run of a key is posted to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Webhook {
+    pub url: HttpUrl,
+    pub headers: Vec<(String, String)>,
+    /// The template of the body, or None for an object of every variable
+    pub body: Option<serde_json::Value>,
+    /// How many times a failed post is tried again
+    pub retries: u32,
+    /// How long to wait before the first retry, doubling for each one after it
+    pub retry_delay: Duration
+}
+
+/// How many distinct peers must request a key, and how soon after the first of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Approvals {
+    pub count: u8,
+    pub window: Duration
+}
+
+/// The shared secret that requests for a key must be signed with
+#[derive(Clone, PartialEq, Eq)]
+pub struct KeySigning {
+    pub secret: Vec<u8>,
+    /// How far a signed timestamp may be from the daemon's clock
+    pub window: Duration
+}
+// Written out by hand so that the secret does not end up in logs
+impl std::fmt::Debug for KeySigning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeySigning").field("window", &self.window).finish_non_exhaustive()
+    }
+}
+
+/// The resolved configuration file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub keys: HashMap<NonEmptyNoNullString, KeyConfig>,
+    pub rate_limit: RateLimitConfig,
+    /// Access rules for `/`-separated key namespaces
+    pub namespaces: BTreeMap<String, Access>,
+    /// Whether triggers received during maintenance are run once it ends
+    pub queue_during_maintenance: bool,
+    /// Where queued triggers are kept so that they survive restarts
+    pub queue_file: Option<PathBuf>,
+    /// How long ago a trigger in the `queue_file` may have been queued to still be run at startup
+    pub queue_max_age: Duration,
+    /// How long stopping waits before killing commands of keys with `on_shutdown` set to kill
+    pub shutdown_timeout: Duration,
+    /// Whether whitespace around requested keys is ignored
+    pub trim_keys: bool,
+    /// How many requests a connection may send before it is told to reconnect and closed
+    pub max_requests_per_connection: Option<u32>,
+    /// The limit on open file descriptors to raise to at startup
+    pub nofile_limit: Option<u64>,
+    pub email_alert: Option<EmailAlert>,
+    /// Alternative response vocabularies, one of which the daemon may be told to use
+    pub response_profiles: BTreeMap<String, ResponseProfile>,
+    /// The names of `base64:` keys, by the bytes they decode to
+    pub binary_keys: HashMap<Vec<u8>, NonEmptyNoNullString>,
+    /// The local hour at which daily budgets start over
+    pub budget_reset_hour: u8,
+    /// Where an event is published for every run
+    pub event_bus: Option<EventBus>
+}
+impl Config {
+    /// Whether a peer, given as its UID and GID, may see and trigger the key
+    ///
+    /// Every namespace containing the key must allow the peer, so keys outside
+    /// all namespaces are visible to everyone.
+    pub fn is_visible(&self, key: &str, peer: Option<(u32, u32)>) -> bool {
+        self.namespaces.iter()
+            .filter(|(namespace, _)| key.strip_prefix(namespace.as_str())
+                .is_some_and(|rest| rest.starts_with('/')))
+            .all(|(_, access)| access.allows(peer))
+    }
+}
+
+fn validate_rate_limit(limit: &RateLimit, name: &str) -> Result<(), String> {
+    if !(limit.rate.is_finite() && limit.rate > 0.0) || limit.burst == 0 {
+        return Err(format!("Rate limit for {} must have a positive rate and burst", name));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn validate_cpus(cpus: &[usize], key: &str) -> Result<(), String> {
+    if cpus.is_empty() {
+        return Err(format!("cpus for key {} must list at least one CPU", key));
+    }
+    let max = nix::sched::CpuSet::count();
+    match cpus.iter().find(|cpu| **cpu >= max) {
+        Some(cpu) => Err(format!("CPU {} for key {} is beyond the {} CPUs that can be set", cpu, key, max)),
+        None => Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn validate_cpus(_cpus: &[usize], key: &str) -> Result<(), String> {
+    Err(format!("Key {} sets cpus, but CPU affinity is only supported on Linux", key))
+}
+
+fn validate_env_profile(name: &str, profile: &EnvProfile) -> Result<(), String> {
+    for (var, value) in profile {
+        if var.is_empty() || var.contains('=') || var.contains('\0') || value.contains('\0') {
+            return Err(format!("Env profile {} has an invalid variable {:?}", name, var));
+        }
+    }
+    Ok(())
+}
+
+/// Replaces `${VAR}` with the daemon's value of `VAR`, and `$$` with `$`
+fn interpolate(value: &str) -> Result<String, String> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(pos) = rest.find('$') {
+        result.push_str(&rest[..pos]);
+        rest = &rest[pos+1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            result.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let end = after.find('}')
+                .ok_or_else(|| format!("Unterminated ${{ in {:?}", value))?;
+            let var = &after[..end];
+            let var_value = std::env::var(var)
+                .map_err(|_| format!("Environment variable {} used in {:?} is not set", var, value))?;
+            result.push_str(&var_value);
+            rest = &after[end+1..];
+        } else {
+            return Err(format!("$ must be followed by {{VAR}} or escaped as $$ in {:?}", value));
+        }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+fn interpolate_path(path: PathBuf) -> Result<PathBuf, String> {
+    // Paths come from JSON strings, so they are always valid UTF-8
+    interpolate(path.to_str().unwrap()).map(PathBuf::from)
+}
+
+/// Expands environment variables in the command and paths of an entry
+fn interpolate_spec(spec: &mut RawKeySpec) -> Result<(), String> {
+    spec.cmd = spec.cmd.as_deref().map(interpolate).transpose()?;
+    spec.on_timeout = spec.on_timeout.as_deref().map(interpolate).transpose()?;
+    spec.hmac_secret = spec.hmac_secret.as_deref().map(interpolate).transpose()?;
+    for path in [&mut spec.stdout, &mut spec.stderr, &mut spec.cwd] {
+        *path = path.take().map(interpolate_path).transpose()?;
+    }
+    if let Some(ref mut write) = spec.write_file {
+        write.path = interpolate_path(std::mem::take(&mut write.path))?;
+    }
+    if let Some(ref mut forward) = spec.forward {
+        for socket in &mut forward.sockets {
+            *socket = interpolate_path(std::mem::take(socket))?;
+        }
+    }
+    if let Some(ref mut free_space) = spec.min_free_space {
+        free_space.path = interpolate_path(std::mem::take(&mut free_space.path))?;
+    }
+    for precondition in &mut spec.preconditions {
+        match precondition {
+            RawPrecondition::FreeSpace(RawFreeSpace {path, ..}) | RawPrecondition::FileExists(path)
+                | RawPrecondition::Pidfile(path) => *path = interpolate_path(std::mem::take(path))?,
+            RawPrecondition::Check(check) => *check = interpolate(check)?,
+            RawPrecondition::NotRunning(_) => {}
+        }
+    }
+    for path in &mut spec.collect {
+        *path = interpolate_path(std::mem::take(path))?;
+    }
+    Ok(())
+}
+
+/// Parses a job such as `restart nginx.service`
+fn parse_unit_job(job: &str) -> Option<UnitJob> {
+    let mut words = job.split_whitespace();
+    let operation = match words.next()? {
+        "start" => UnitOperation::Start,
+        "stop" => UnitOperation::Stop,
+        "restart" => UnitOperation::Restart,
+        _ => return None
+    };
+    let unit = words.next()?.to_owned();
+    match words.next() {
+        Some(_) => None,
+        None => Some(UnitJob {operation, unit})
+    }
+}
+
+/// Keys whose names start with this are written as the base64 of the bytes a `KEY` frame sends
+pub const BINARY_KEY_PREFIX: &str = "base64:";
+
+/// Size of the largest payload a client may send, and so the largest `max_bytes`
+pub const MAX_PAYLOAD_LEN: usize = 64*1024;
+
+fn resolve_file_write(key: &NonEmptyNoNullString, write: RawFileWrite) -> Result<FileWrite, String> {
+    if !write.path.is_absolute() || write.path.file_name().is_none() {
+        return Err(format!("write_file path for key {} must be an absolute path to a file", key.as_ref()));
+    }
+    let max_bytes = match (&write.contents, write.max_bytes) {
+        (Some(_), Some(_)) => return Err(format!("write_file for key {} cannot set both contents and max_bytes", key.as_ref())),
+        (None, None) => return Err(format!("write_file for key {} needs contents, or max_bytes to take them from the client", key.as_ref())),
+        (Some(contents), None) => contents.len(),
+        (None, Some(max_bytes)) if max_bytes > MAX_PAYLOAD_LEN => return Err(format!(
+            "write_file max_bytes for key {} cannot be more than {}", key.as_ref(), MAX_PAYLOAD_LEN)),
+        (None, Some(max_bytes)) => max_bytes
+    };
+    let mode = match write.mode {
+        Some(ref mode) => u16::from_str_radix(mode, 8).ok()
+            .filter(|mode| *mode <= 0o777)
+            .and_then(|mode| Mode::from_bits(mode.into()))
+            .ok_or_else(|| format!("write_file mode for key {} must be an octal string from \"000\" to \"777\"", key.as_ref()))?,
+        None => Mode::from_bits_truncate(0o644)
+    };
+    Ok(FileWrite {path: write.path, contents: write.contents, max_bytes, mode})
+}
+
+/// Request headers that the HTTP client sets itself
+const RESERVED_HEADERS: [&str; 4] = ["host", "connection", "content-length", "transfer-encoding"];
+
+fn is_http_token(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Refuses a setting that needs a cargo feature the daemon was built without
+fn require_feature(feature: &str, is_enabled: bool, setting: &str) -> Result<(), String> {
+    match is_enabled {
+        true => Ok(()),
+        false => Err(format!("{} needs sock_trigger_cmd built with the {} feature", setting, feature))
+    }
+}
+
+fn resolve_http_request(key: &NonEmptyNoNullString, request: RawHttpRequest) -> Result<HttpRequest, String> {
+    require_feature("http", cfg!(feature = "http"), &format!("http for key {}", key.as_ref()))?;
+    let method = request.method.unwrap_or_else(|| "POST".to_owned());
+    if !is_http_token(&method) {
+        return Err(format!("http method for key {} is not a valid method", key.as_ref()));
+    }
+    let url = request.url.parse::<HttpUrl>()
+        .map_err(|e| format!("http url for key {}: {}", key.as_ref(), e))?;
+    for (name, value) in &request.headers {
+        if !is_http_token(name) || value.contains(['\r', '\n']) {
+            return Err(format!("http header {:?} for key {} is not a valid header", name, key.as_ref()));
+        }
+        if RESERVED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            return Err(format!("http header {} for key {} is set by the daemon", name, key.as_ref()));
+        }
+    }
+    let (body, max_bytes) = match (request.body, request.max_bytes) {
+        (Some(_), Some(_)) => return Err(format!("http for key {} cannot set both body and max_bytes", key.as_ref())),
+        (None, Some(max_bytes)) if max_bytes > MAX_PAYLOAD_LEN => return Err(format!(
+            "http max_bytes for key {} cannot be more than {}", key.as_ref(), MAX_PAYLOAD_LEN)),
+        (None, Some(max_bytes)) => (None, max_bytes),
+        (body, None) => {
+            let body = body.unwrap_or_default();
+            let len = body.len();
+            (Some(body), len)
+        }
+    };
+    Ok(HttpRequest {method, url, headers: request.headers.into_iter().collect(), body, max_bytes})
+}
+
+/// How many times a failed webhook post is tried again, unless configured
+const DEFAULT_WEBHOOK_RETRIES: u32 = 3;
+
+/// How long to wait before retrying a webhook post for the first time, unless configured
+const DEFAULT_WEBHOOK_RETRY_DELAY_MS: u64 = 1000;
+
+fn resolve_webhook(key: &NonEmptyNoNullString, webhook: RawWebhook) -> Result<Webhook, String> {
+    require_feature("http", cfg!(feature = "http"), &format!("webhook for key {}", key.as_ref()))?;
+    let url = webhook.url.parse::<HttpUrl>()
+        .map_err(|e| format!("webhook url for key {}: {}", key.as_ref(), e))?;
+    for (name, value) in &webhook.headers {
+        if !is_http_token(name) || value.contains(['\r', '\n']) {
+            return Err(format!("webhook header {:?} for key {} is not a valid header", name, key.as_ref()));
+        }
+        if RESERVED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            return Err(format!("webhook header {} for key {} is set by the daemon", name, key.as_ref()));
+        }
+    }
+    if let Some(ref body) = webhook.body {
+        let mut unknown = None;
+        webhook::placeholders(body, &mut |name| if !webhook::VARIABLES.contains(&name) {
+            unknown.get_or_insert_with(|| name.to_owned());
+        });
+        if let Some(name) = unknown {
+            return Err(format!("webhook body for key {} uses unknown variable {}", key.as_ref(), name));
+        }
+    }
+    Ok(Webhook {
+        url,
+        headers: webhook.headers.into_iter().collect(),
+        body: webhook.body,
+        retries: webhook.retries.unwrap_or(DEFAULT_WEBHOOK_RETRIES),
+        retry_delay: Duration::from_millis(webhook.retry_delay_ms.unwrap_or(DEFAULT_WEBHOOK_RETRY_DELAY_MS))
+    })
+}
+
+fn resolve_forward(key: &NonEmptyNoNullString, forward: RawForward) -> Result<Forward, String> {
+    if forward.sockets.is_empty() {
+        return Err(format!("forward for key {} needs at least one socket", key.as_ref()));
+    }
+    let forwarded_key = match forward.key {
+        Some(forwarded_key) => NonEmptyNoNullString::try_from(forwarded_key)
+            .map_err(|_| format!("forward key for key {} must be non-empty and without null bytes", key.as_ref()))?,
+        None => key.clone()
+    };
+    if forwarded_key.as_ref().as_bytes()[0] == FRAME_MARKER {
+        return Err(format!("forward key for key {} starts with a byte reserved for protocol frames", key.as_ref()));
+    }
+    Ok(Forward {sockets: forward.sockets, key: forwarded_key})
+}
+
+/// Failures in a row before an alert is emailed, unless configured
+const DEFAULT_ALERT_FAILURES: u32 = 3;
+
+fn is_mail_address(address: &str) -> bool {
+    address.contains('@') && !address.contains(|c: char| c.is_whitespace() || c.is_control() || "<>".contains(c))
+}
+
+/// Splits `host[:port]`, using the default port if none is given
+fn parse_server(server: &str, default_port: u16, setting: &str) -> Result<(String, u16), String> {
+    let (host, port) = match server.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>()
+            .map_err(|_| format!("{} server {} has an invalid port", setting, server))?),
+        None => (server, default_port)
+    };
+    if host.is_empty() {
+        return Err(format!("{} server {} has no host", setting, server));
+    }
+    Ok((host.to_owned(), port))
+}
+
+fn resolve_email_alert(alert: RawEmailAlert) -> Result<EmailAlert, String> {
+    require_feature("email", cfg!(feature = "email"), "email_alert")?;
+    let (host, port) = parse_server(alert.server.as_deref().unwrap_or("localhost"), 25, "email_alert")?;
+    if alert.to.is_empty() {
+        return Err("email_alert needs at least one address in to".to_owned());
+    }
+    if let Some(address) = std::iter::once(&alert.from).chain(&alert.to).find(|address| !is_mail_address(address)) {
+        return Err(format!("email_alert address {:?} is not a valid address", address));
+    }
+    let after_failures = alert.after_failures.unwrap_or(DEFAULT_ALERT_FAILURES);
+    if after_failures == 0 {
+        return Err("email_alert after_failures must be positive".to_owned());
+    }
+    Ok(EmailAlert {host, port, from: alert.from, to: alert.to, after_failures})
+}
+
+/// The channel or topic events are published to, unless configured
+const DEFAULT_EVENT_CHANNEL: &str = "sock_trigger_cmd/events";
+
+fn resolve_event_bus(bus: RawEventBus) -> Result<EventBus, String> {
+    require_feature("event-bus", cfg!(feature = "event-bus"), "event_bus")?;
+    let (kind, server, default_port, channel) = match bus {
+        RawEventBus {redis: Some(server), channel, mqtt: None, topic: None} => (EventBusKind::Redis, server, 6379, channel),
+        RawEventBus {redis: None, channel: None, mqtt: Some(server), topic} => (EventBusKind::Mqtt, server, 1883, topic),
+        _ => return Err("event_bus needs either redis with an optional channel, or mqtt with an optional topic".to_owned())
+    };
+    let (host, port) = parse_server(&server, default_port, "event_bus")?;
+    let channel = channel.unwrap_or_else(|| DEFAULT_EVENT_CHANNEL.to_owned());
+    let is_valid = match kind {
+        EventBusKind::Redis => !channel.is_empty(),
+        // Wildcards are only for subscribing
+        EventBusKind::Mqtt => !channel.is_empty() && channel.len() <= u16::MAX.into() && !channel.contains(['+', '#', '\0'])
+    };
+    if !is_valid {
+        return Err(format!("event_bus channel or topic {:?} is not valid", channel));
+    }
+    Ok(EventBus {kind, host, port, channel})
+}
+
+fn resolve_response_profile(name: &str, raw: BTreeMap<String, String>) -> Result<ResponseProfile, String> {
+    let mut profile = ResponseProfile::default();
+    for (label, response) in raw {
+        if response.is_empty() {
+            return Err(format!("Response for {} in response profile {} is empty", label, name));
+        }
+        match label.as_str() {
+            "other" => profile.other = Some(response.into_bytes()),
+            label if Outcome::LABELS.contains(&label) => {
+                profile.responses.insert(label.to_owned(), response.into_bytes());
+            },
+            label => return Err(format!("Response profile {} maps unknown outcome {}", name, label))
+        }
+    }
+    Ok(profile)
+}
+
+/// How far signed timestamps may be from the daemon's clock, unless configured
+const DEFAULT_HMAC_WINDOW_MS: u64 = 60_000;
+
+fn resolve_signing(key: &NonEmptyNoNullString, secret: Option<String>, window_ms: Option<u64>)
+        -> Result<Option<KeySigning>, String> {
+    let secret = match secret {
+        Some(secret) if secret.is_empty() => return Err(format!("hmac_secret for key {} is empty", key.as_ref())),
+        Some(secret) => secret.into_bytes(),
+        None if window_ms.is_some() => return Err(format!("Key {} sets hmac_window_ms without hmac_secret", key.as_ref())),
+        None => return Ok(None)
+    };
+    let window = Duration::from_millis(window_ms.unwrap_or(DEFAULT_HMAC_WINDOW_MS));
+    Ok(Some(KeySigning {secret, window}))
+}
+
+fn resolve_preconditions(key: &NonEmptyNoNullString, free_space: Option<RawFreeSpace>,
+        raw_preconditions: Vec<RawPrecondition>) -> Result<Vec<Precondition>, String> {
+    let absolute = |path: PathBuf, setting: &str| match path.is_absolute() {
+        true => Ok(path),
+        false => Err(format!("{} path for key {} must be absolute", setting, key.as_ref()))
+    };
+    let mut preconditions = Vec::new();
+    let free_space = free_space.map(|free_space| ("min_free_space", RawPrecondition::FreeSpace(free_space)));
+    for (setting, raw) in free_space.into_iter().chain(raw_preconditions.into_iter().map(|raw| ("precondition", raw))) {
+        let precondition = match raw {
+            RawPrecondition::FreeSpace(free_space) =>
+                Precondition::FreeSpace {path: absolute(free_space.path, setting)?, min_bytes: free_space.bytes},
+            RawPrecondition::FileExists(path) => Precondition::FileExists(absolute(path, setting)?),
+            RawPrecondition::Pidfile(path) => Precondition::Pidfile(absolute(path, setting)?),
+            RawPrecondition::NotRunning(other) => Precondition::NotRunning(other.into()),
+            RawPrecondition::Check(cmd) => {
+                let cmd = shlex::split(&cmd)
+                    .ok_or_else(|| format!("Check command for key {} could not be shlexed", key.as_ref()))?;
+                if cmd.iter().all(|s| s.contains('=')) {
+                    return Err(format!("Check command for key {} has no executable", key.as_ref()));
+                }
+                Precondition::Check(cmd)
+            }
+        };
+        preconditions.push(precondition);
+    }
+    Ok(preconditions)
+}
+
+fn resolve_windows(key: &NonEmptyNoNullString, windows: Option<Vec<String>>) -> Result<Vec<TimeWindow>, String> {
+    let windows = match windows {
+        Some(windows) if windows.is_empty() =>
+            return Err(format!("allowed_windows for key {} is empty, so it could never run", key.as_ref())),
+        Some(windows) => windows,
+        None => return Ok(Vec::new())
+    };
+    windows.iter()
+        .map(|window| TimeWindow::parse(window)
+            .map_err(|e| format!("Invalid allowed_windows entry for key {}: {}", key.as_ref(), e)))
+        .collect()
+}
+
+/// How much of each collected file is sent, unless configured
+const DEFAULT_COLLECT_MAX_BYTES: usize = 1024*1024;
+
+/// How long a key waits for the rest of its approvals, unless configured
+const DEFAULT_APPROVAL_WINDOW_MS: u64 = 300_000;
+
+fn resolve_approvals(key: &NonEmptyNoNullString, count: Option<u8>, window_ms: Option<u64>)
+        -> Result<Option<Approvals>, String> {
+    let count = match count {
+        Some(count) if count < 2 => return Err(format!("approvals for key {} must be at least 2", key.as_ref())),
+        Some(count) => count,
+        None if window_ms.is_some() =>
+            return Err(format!("Key {} sets approval_window_ms without approvals", key.as_ref())),
+        None => return Ok(None)
+    };
+    let window = Duration::from_millis(window_ms.unwrap_or(DEFAULT_APPROVAL_WINDOW_MS));
+    Ok(Some(Approvals {count, window}))
+}
+
+/// Resolves a key whose action is a builtin, refusing the settings that only apply to commands
+fn resolve_builtin(key: &NonEmptyNoNullString, builtin: Builtin, spec: RawKeySpec,
+        defaults: &RawDefaults) -> Result<KeyConfig, String> {
+    let command_settings = [
+        ("cmd", spec.cmd.is_some()),
+        ("sha256", spec.sha256.is_some()),
+        ("detach", spec.detach),
+        ("stdout", spec.stdout.is_some()),
+        ("stderr", spec.stderr.is_some()),
+        ("on_deadline", spec.on_deadline.is_some()),
+        ("on_shutdown", spec.on_shutdown.is_some()),
+        ("pty", spec.pty.is_some()),
+        ("log_output", spec.log_output.is_some()),
+        ("rotate", spec.rotate.is_some()),
+        ("env_profiles", spec.env_profiles.is_some()),
+        ("cwd", spec.cwd.is_some()),
+        ("max_output_bytes", spec.max_output_bytes.is_some()),
+        ("output_grace_ms", spec.output_grace_ms.is_some()),
+        ("log_level", spec.log_level.is_some()),
+        ("umask", spec.umask.is_some()),
+        ("cpus", spec.cpus.is_some()),
+        ("on_timeout", spec.on_timeout.is_some()),
+        ("collect", !spec.collect.is_empty()),
+        ("collect_max_bytes", spec.collect_max_bytes.is_some())
+    ];
+    if let Some((name, _)) = command_settings.iter().find(|(_, is_set)| *is_set) {
+        return Err(format!("Key {} runs no command, so it cannot set {}", key.as_ref(), name));
+    }
+    let rate_limit = spec.rate_limit.or(defaults.rate_limit);
+    if let Some(ref limit) = rate_limit {
+        validate_rate_limit(limit, &format!("key {}", key.as_ref()))?;
+    }
+    let signing = resolve_signing(key, spec.hmac_secret, spec.hmac_window_ms)?;
+    let allowed_windows = resolve_windows(key, spec.allowed_windows)?;
+    let approvals = resolve_approvals(key, spec.approvals, spec.approval_window_ms)?;
+    if spec.daily_budget_ms == Some(0) {
+        return Err(format!("daily_budget_ms for key {} is 0, so it could never run", key.as_ref()));
+    }
+    let daily_budget = spec.daily_budget_ms.map(Duration::from_millis);
+    let preconditions = resolve_preconditions(key, spec.min_free_space, spec.preconditions)?;
+    let webhook = spec.webhook.map(|webhook| resolve_webhook(key, webhook)).transpose()?;
+    let tags = resolve_tags(key, spec.tags)?;
+    Ok(KeyConfig {
+        cmd: Vec::new(),
+        builtin: Some(builtin),
+        description: spec.description,
+        tags,
+        sha256: None,
+        enabled: spec.enabled.unwrap_or(true),
+        rate_limit,
+        // Jobs belong to the service manager, so they are never killed or adopted
+        on_deadline: DeadlinePolicy::Kill,
+        on_shutdown: ShutdownPolicy::Wait,
+        detach: false,
+        pty: false,
+        stdout: None,
+        stderr: None,
+        log_output: false,
+        rotate: None,
+        timeout: spec.timeout_ms.or(defaults.timeout_ms).map(Duration::from_millis),
+        cwd: None,
+        max_output_bytes: None,
+        output_grace: Duration::from_millis(DEFAULT_OUTPUT_GRACE_MS),
+        output_log_level: Level::Debug,
+        umask: None,
+        cpus: None,
+        on_timeout: None,
+        signing,
+        allowed_windows,
+        confirm: spec.confirm,
+        approvals,
+        daily_budget,
+        preconditions,
+        collect: Vec::new(),
+        collect_max_bytes: DEFAULT_COLLECT_MAX_BYTES,
+        webhook
+    })
+}
+
+/// Sorts the tags of a key, checking that they can be joined with commas for metrics labels
+fn resolve_tags(key: &NonEmptyNoNullString, mut tags: Vec<String>) -> Result<Vec<String>, String> {
+    if let Some(tag) = tags.iter().find(|tag| tag.is_empty() || tag.contains(|c: char| c == ',' || c.is_whitespace())) {
+        return Err(format!("Tag {:?} of key {} must be non-empty and without commas or whitespace", tag, key.as_ref()));
+    }
+    tags.sort_unstable();
+    tags.dedup();
+    Ok(tags)
+}
+
+fn resolve_entry(key: &NonEmptyNoNullString, entry: RawKeyEntry, defaults: &RawDefaults,
+        env_profiles: &HashMap<String, EnvProfile>) -> Result<KeyConfig, String> {
+    if key.as_ref().as_bytes()[0] == FRAME_MARKER {
+        return Err(format!("Key {:?} starts with a byte reserved for protocol frames", key.as_ref()));
+    }
+    let mut spec = match entry {
+        RawKeyEntry::Cmd(cmd) => RawKeySpec {cmd: Some(cmd), ..RawKeySpec::default()},
+        RawKeyEntry::Full(spec) => *spec
+    };
+    let builtin_count = [spec.systemd.is_some(), spec.write_file.is_some(), spec.http.is_some(), spec.forward.is_some()]
+        .into_iter().filter(|is_set| *is_set).count();
+    if builtin_count > 1 {
+        return Err(format!("Key {} can only set one of systemd, write_file, http, and forward", key.as_ref()));
+    }
+    let builtin = if let Some(ref job) = spec.systemd {
+        Some(Builtin::Unit(parse_unit_job(job)
+            .ok_or_else(|| format!("systemd for key {} must be \"start\", \"stop\", or \"restart\" followed by a unit name", key.as_ref()))?))
+    } else if let Some(write) = spec.write_file.take() {
+        Some(Builtin::WriteFile(resolve_file_write(key, write)?))
+    } else if let Some(request) = spec.http.take() {
+        Some(Builtin::Http(resolve_http_request(key, request)?))
+    } else if let Some(forward) = spec.forward.take() {
+        Some(Builtin::Forward(resolve_forward(key, forward)?))
+    } else {
+        None
+    };
+    if let Some(ref builtin) = builtin {
+        return resolve_builtin(key, builtin.clone(), spec, defaults);
+    }
+    let spec_cmd = spec.cmd.as_deref()
+        .ok_or_else(|| format!("Key {} needs a cmd, systemd, write_file, http, or forward", key.as_ref()))?;
+    let inline_cmd = match shlex::split(spec_cmd) {
+        Some(vec) => vec,
+        None => return Err(format!("Command {} could not be shlexed", spec_cmd))
+    };
+    if inline_cmd.iter().all(|s| s.contains('=')) {
+        return Err(format!("Command for key {} has no executable", key.as_ref()));
+    }
+    // Profile variables become leading VAR=VALUE entries, before the inline ones so that those win
+    let mut cmd = Vec::new();
+    let profile_names = spec.env_profiles.as_ref().or(defaults.env_profiles.as_ref());
+    for name in profile_names.into_iter().flatten() {
+        let profile = env_profiles.get(name)
+            .ok_or_else(|| format!("Key {} uses unknown env profile {}", key.as_ref(), name))?;
+        cmd.extend(profile.iter().map(|(var, value)| format!("{}={}", var, value)));
+    }
+    cmd.extend(inline_cmd);
+    let sha256 = spec.sha256
+        .map(|hex| sha256::from_hex(&hex)
+            .ok_or_else(|| format!("sha256 for key {} is not 64 hex digits", key.as_ref())))
+        .transpose()?;
+    let rate_limit = spec.rate_limit.or(defaults.rate_limit);
+    if let Some(ref limit) = rate_limit {
+        validate_rate_limit(limit, &format!("key {}", key.as_ref()))?;
+    }
+    let rotate = spec.rotate.or(defaults.rotate);
+    if rotate.is_some_and(|rotation| rotation.max_bytes == 0) {
+        return Err(format!("Output rotation for key {} must have a positive max_bytes", key.as_ref()));
+    }
+    let pty = spec.pty.or(defaults.pty).unwrap_or(false);
+    if spec.detach && pty {
+        return Err(format!("Key {} cannot be both detached and run under a PTY", key.as_ref()));
+    }
+    if spec.detach && spec.on_shutdown.is_some_and(|policy| policy != ShutdownPolicy::Detach) {
+        return Err(format!("Key {} is detached, so its command always outlives the daemon", key.as_ref()));
+    }
+    let on_shutdown = match spec.detach {
+        true => ShutdownPolicy::Detach,
+        false => spec.on_shutdown.or(defaults.on_shutdown).unwrap_or_default()
+    };
+    // Closing the terminal when the daemon exits would hang the command up anyway
+    if pty && on_shutdown == ShutdownPolicy::Detach {
+        return Err(format!("Key {} cannot run under a PTY and be left running at shutdown", key.as_ref()));
+    }
+    let output_log_level = match spec.log_level.as_ref().or(defaults.log_level.as_ref()) {
+        Some(level) => level.parse::<Level>()
+            .map_err(|_| format!("Key {} has unknown log level {}", key.as_ref(), level))?,
+        None => Level::Debug
+    };
+    let umask = match spec.umask.as_ref().or(defaults.umask.as_ref()) {
+        Some(umask) => Some(u16::from_str_radix(umask, 8).ok()
+            .filter(|umask| *umask <= 0o777)
+            .and_then(|umask| Mode::from_bits(umask.into()))
+            .ok_or_else(|| format!("umask for key {} must be an octal string from \"000\" to \"777\"", key.as_ref()))?),
+        None => None
+    };
+    let timeout = spec.timeout_ms.or(defaults.timeout_ms).map(Duration::from_millis);
+    let on_timeout = match spec.on_timeout {
+        Some(ref hook) => {
+            if spec.detach {
+                return Err(format!("Key {} is detached, so its command is never killed for a timeout", key.as_ref()));
+            }
+            if timeout.is_none() {
+                return Err(format!("Key {} sets on_timeout, but not timeout_ms", key.as_ref()));
+            }
+            let hook = shlex::split(hook)
+                .ok_or_else(|| format!("on_timeout for key {} could not be shlexed", key.as_ref()))?;
+            if hook.iter().all(|s| s.contains('=')) {
+                return Err(format!("on_timeout for key {} has no executable", key.as_ref()));
+            }
+            Some(hook)
+        },
+        None => None
+    };
+    let signing = resolve_signing(key, spec.hmac_secret, spec.hmac_window_ms)?;
+    let allowed_windows = resolve_windows(key, spec.allowed_windows)?;
+    let approvals = resolve_approvals(key, spec.approvals, spec.approval_window_ms)?;
+    if spec.daily_budget_ms == Some(0) {
+        return Err(format!("daily_budget_ms for key {} is 0, so it could never run", key.as_ref()));
+    }
+    let daily_budget = spec.daily_budget_ms.map(Duration::from_millis);
+    let preconditions = resolve_preconditions(key, spec.min_free_space, spec.preconditions)?;
+    let webhook = spec.webhook.map(|webhook| resolve_webhook(key, webhook)).transpose()?;
+    if spec.detach && !spec.collect.is_empty() {
+        return Err(format!("Key {} is detached, so there is nothing to collect when it is run", key.as_ref()));
+    }
+    if spec.collect.len() > u8::MAX.into() {
+        return Err(format!("Key {} collects more than {} files", key.as_ref(), u8::MAX));
+    }
+    if let Some(path) = spec.collect.iter().find(|path| !path.is_absolute()) {
+        return Err(format!("Collected file {} of key {} must be an absolute path", path.display(), key.as_ref()));
+    }
+    let collect_max_bytes = spec.collect_max_bytes.unwrap_or(DEFAULT_COLLECT_MAX_BYTES);
+    if collect_max_bytes > collect::MAX_TOTAL_BYTES {
+        return Err(format!("collect_max_bytes for key {} must be at most {}", key.as_ref(), collect::MAX_TOTAL_BYTES));
+    }
+    let tags = resolve_tags(key, spec.tags)?;
+    let cpus = spec.cpus.or_else(|| defaults.cpus.clone());
+    if let Some(ref cpus) = cpus {
+        validate_cpus(cpus, key.as_ref())?;
+    }
+    Ok(KeyConfig {
+        cmd,
+        builtin: None,
+        description: spec.description,
+        tags,
+        sha256,
+        enabled: spec.enabled.unwrap_or(true),
+        rate_limit,
+        on_deadline: spec.on_deadline.or(defaults.on_deadline).unwrap_or_default(),
+        on_shutdown,
+        detach: spec.detach,
+        pty,
+        stdout: spec.stdout,
+        stderr: spec.stderr,
+        log_output: spec.log_output.or(defaults.log_output).unwrap_or(true),
+        rotate,
+        timeout,
+        cwd: spec.cwd.or_else(|| defaults.cwd.clone()),
+        max_output_bytes: spec.max_output_bytes.or(defaults.max_output_bytes),
+        output_grace: Duration::from_millis(spec.output_grace_ms.or(defaults.output_grace_ms)
+            .unwrap_or(DEFAULT_OUTPUT_GRACE_MS)),
+        output_log_level,
+        umask,
+        cpus,
+        on_timeout,
+        signing,
+        allowed_windows,
+        confirm: spec.confirm,
+        approvals,
+        daily_budget,
+        preconditions,
+        collect: spec.collect,
+        collect_max_bytes,
+        webhook
+    })
+}
+
+/// Deserializes any JSON value, failing if an object anywhere in it repeats a key
+///
+/// serde_json keeps the last value for a repeated key, so this runs over the
+/// file before it is parsed for real.
+struct NoDuplicateKeys;
+impl<'de> Deserialize<'de> for NoDuplicateKeys {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(NoDuplicateKeysVisitor)
+    }
+}
+
+struct NoDuplicateKeysVisitor;
+impl<'de> Visitor<'de> for NoDuplicateKeysVisitor {
+    type Value = NoDuplicateKeys;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("any JSON value")
+    }
+
+    fn visit_bool<E>(self, _: bool) -> Result<Self::Value, E> { Ok(NoDuplicateKeys) }
+    fn visit_i64<E>(self, _: i64) -> Result<Self::Value, E> { Ok(NoDuplicateKeys) }
+    fn visit_u64<E>(self, _: u64) -> Result<Self::Value, E> { Ok(NoDuplicateKeys) }
+    fn visit_f64<E>(self, _: f64) -> Result<Self::Value, E> { Ok(NoDuplicateKeys) }
+    fn visit_str<E>(self, _: &str) -> Result<Self::Value, E> { Ok(NoDuplicateKeys) }
+    fn visit_unit<E>(self) -> Result<Self::Value, E> { Ok(NoDuplicateKeys) }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        while seq.next_element::<NoDuplicateKeys>()?.is_some() {}
+        Ok(NoDuplicateKeys)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut seen = HashSet::new();
+        while let Some(key) = map.next_key::<String>()? {
+            if !seen.insert(key.clone()) {
+                return Err(de::Error::custom(format!("duplicate key {:?}", key)));
+            }
+            map.next_value::<NoDuplicateKeys>()?;
+        }
+        Ok(NoDuplicateKeys)
+    }
+}
+
+/// Reads a single config file, expanding environment variables if it opts in
+fn parse_file(path: &Path) -> Result<RawStructuredConfig, String> {
+    let config_bytes = match fs::read(path) {
+        Ok(val) => val,
+        Err(e) => return Err(format!("Unable to read config {}: {}", path.display(), e))
+    };
+    let config_value = serde_json::from_slice::<serde_json::Value>(&config_bytes)
+        .map_err(|e| format!("Config file {} is not valid JSON: {}", path.display(), e))?;
+    serde_json::from_slice::<NoDuplicateKeys>(&config_bytes)
+        .map_err(|e| format!("Config file {} has a {}", path.display(), e))?;
+    let is_structured = config_value.get("keys").is_some_and(|v| v.is_object());
+    let mut raw_config = if is_structured {
+        serde_json::from_value::<RawStructuredConfig>(config_value)
+            .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?
+    } else {
+        RawStructuredConfig {
+            keys: serde_json::from_value(config_value)
+                .map_err(|e| format!("Config file {} must map strings to commands: {}", path.display(), e))?,
+            rate_limit: None,
+            env_profiles: HashMap::new(),
+            defaults: None,
+            interpolate_env: false,
+            namespaces: HashMap::new(),
+            queue_during_maintenance: false,
+            queue_file: None,
+            queue_max_age_ms: None,
+            shutdown_timeout_ms: None,
+            trim_keys: false,
+            max_requests_per_connection: None,
+            nofile_limit: None,
+            email_alert: None,
+            response_profiles: HashMap::new(),
+            budget_reset_hour: None,
+            event_bus: None
+        }
+    };
+    if raw_config.interpolate_env {
+        for entry in raw_config.keys.values_mut() {
+            match entry {
+                RawKeyEntry::Cmd(cmd) => *cmd = interpolate(cmd)?,
+                RawKeyEntry::Full(spec) => interpolate_spec(spec)?
+            }
+        }
+        if let Some(ref mut defaults) = raw_config.defaults {
+            defaults.cwd = defaults.cwd.take().map(interpolate_path).transpose()?;
+        }
+        raw_config.queue_file = raw_config.queue_file.take().map(interpolate_path).transpose()?;
+    }
+    Ok(raw_config)
+}
+
+/// Lists the files making up the config: the path itself, or the `*.json` files in it if it is a directory
+pub fn config_files(path: &Path) -> Result<Vec<PathBuf>, String> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_owned()]);
+    }
+    let mut files = Vec::new();
+    let entries = fs::read_dir(path)
+        .map_err(|e| format!("Unable to read config directory {}: {}", path.display(), e))?;
+    for entry in entries {
+        let entry = entry
+            .map_err(|e| format!("Unable to read config directory {}: {}", path.display(), e))?;
+        let file_path = entry.path();
+        if file_path.extension().is_some_and(|ext| ext == "json") && file_path.is_file() {
+            files.push(file_path);
+        }
+    }
+    // Sorted by name so that drop-in files are merged in a stable order
+    files.sort_unstable();
+    if files.is_empty() {
+        return Err(format!("Config directory {} has no .json files", path.display()));
+    }
+    Ok(files)
+}
+
+/// Reads and validates the config
+///
+/// Each file is either a flat object mapping keys to commands, or an object
+/// whose `keys` field holds that mapping next to daemon-wide settings. A
+/// directory is read as the merge of its `*.json` files, in which each key
+/// env profile, and namespace may only be defined once, and the other top-level settings
+/// may only be set by one file.
+pub fn load_config(path: &Path) -> Result<Config, String> {
+    let mut key_entries = HashMap::new();
+    let mut env_profiles = HashMap::new();
+    let mut rate_limit = None;
+    let mut defaults = None;
+    let mut namespaces = BTreeMap::new();
+    let mut queue_during_maintenance = false;
+    let mut queue_file = None;
+    let mut queue_max_age_ms = None;
+    let mut shutdown_timeout_ms = None;
+    let mut trim_keys = false;
+    let mut max_requests_per_connection = None;
+    let mut nofile_limit = None;
+    let mut email_alert = None;
+    let mut response_profiles = BTreeMap::new();
+    let mut budget_reset_hour = None;
+    let mut event_bus = None;
+    // Which file each key, profile, and setting came from, for error messages
+    let mut origins: HashMap<String, PathBuf> = HashMap::new();
+    for file in config_files(path)? {
+        let raw_config = parse_file(&file)?;
+        let mut claim = |what: String| match origins.get(&what) {
+            Some(first) => Err(format!("{} is defined in both {} and {}", what, first.display(), file.display())),
+            None => {
+                origins.insert(what, file.clone());
+                Ok(())
+            }
+        };
+        if raw_config.rate_limit.is_some() {
+            claim("rate_limit".to_owned())?;
+            rate_limit = raw_config.rate_limit;
+        }
+        if raw_config.defaults.is_some() {
+            claim("defaults".to_owned())?;
+            defaults = raw_config.defaults;
+        }
+        if raw_config.queue_during_maintenance {
+            claim("queue_during_maintenance".to_owned())?;
+            queue_during_maintenance = true;
+        }
+        if raw_config.queue_file.is_some() {
+            claim("queue_file".to_owned())?;
+            queue_file = raw_config.queue_file;
+        }
+        if raw_config.queue_max_age_ms.is_some() {
+            claim("queue_max_age_ms".to_owned())?;
+            queue_max_age_ms = raw_config.queue_max_age_ms;
+        }
+        if raw_config.shutdown_timeout_ms.is_some() {
+            claim("shutdown_timeout_ms".to_owned())?;
+            shutdown_timeout_ms = raw_config.shutdown_timeout_ms;
+        }
+        if raw_config.trim_keys {
+            claim("trim_keys".to_owned())?;
+            trim_keys = true;
+        }
+        if raw_config.max_requests_per_connection.is_some() {
+            claim("max_requests_per_connection".to_owned())?;
+            max_requests_per_connection = raw_config.max_requests_per_connection;
+        }
+        if raw_config.nofile_limit.is_some() {
+            claim("nofile_limit".to_owned())?;
+            nofile_limit = raw_config.nofile_limit;
+        }
+        if raw_config.email_alert.is_some() {
+            claim("email_alert".to_owned())?;
+            email_alert = raw_config.email_alert;
+        }
+        if raw_config.event_bus.is_some() {
+            claim("event_bus".to_owned())?;
+            event_bus = raw_config.event_bus;
+        }
+        if raw_config.budget_reset_hour.is_some() {
+            claim("budget_reset_hour".to_owned())?;
+            budget_reset_hour = raw_config.budget_reset_hour;
+        }
+        for (name, profile) in raw_config.env_profiles {
+            claim(format!("Env profile {}", name))?;
+            env_profiles.insert(name, profile);
+        }
+        for (name, profile) in raw_config.response_profiles {
+            claim(format!("Response profile {}", name))?;
+            response_profiles.insert(name.clone(), resolve_response_profile(&name, profile)?);
+        }
+        for (namespace, access) in raw_config.namespaces {
+            claim(format!("Namespace {}", namespace))?;
+            namespaces.insert(namespace, access);
+        }
+        for (key, entry) in raw_config.keys {
+            claim(format!("Key {}", key.as_ref()))?;
+            key_entries.insert(key, entry);
+        }
+    }
+    for namespace in namespaces.keys() {
+        if namespace.is_empty() || namespace.starts_with('/') || namespace.ends_with('/') {
+            return Err(format!("Namespace {:?} must not be empty or start or end with /", namespace));
+        }
+    }
+    let rate_limit = rate_limit.unwrap_or_default();
+    let defaults = defaults.unwrap_or_default();
+
+    if let Some(ref limit) = rate_limit.global {
+        validate_rate_limit(limit, "all requests")?;
+    }
+    if let Some(ref limit) = rate_limit.per_peer {
+        validate_rate_limit(limit, "each peer")?;
+    }
+    for (name, profile) in &env_profiles {
+        validate_env_profile(name, profile)?;
+    }
+    let keys = key_entries.into_iter()
+        .map(|(k, v)| resolve_entry(&k, v, &defaults, &env_profiles).map(|v| (k, v)))
+        .collect::<Result<HashMap<_,_>,_>>()?;
+
+    if keys.is_empty() {
+        return Err("Config has no entries".to_owned());
+    }
+    let mut binary_keys = HashMap::new();
+    for key in keys.keys() {
+        if let Some(encoded) = key.as_ref().strip_prefix(BINARY_KEY_PREFIX) {
+            let decoded = base64::decode(encoded).filter(|decoded| !decoded.is_empty())
+                .ok_or_else(|| format!("Key {} does not have non-empty base64 after {}", key.as_ref(), BINARY_KEY_PREFIX))?;
+            binary_keys.insert(decoded, key.clone());
+        }
+    }
+    for (prefix, purpose) in [(TOKEN_PREFIX, "presents a token"), (CONFIRM_PREFIX, "confirms a request")] {
+        if let Some(key) = keys.keys().find(|key| key.as_ref().starts_with(prefix)) {
+            return Err(format!("Key {} cannot start with {}, which {}", key.as_ref(), prefix, purpose));
+        }
+    }
+    for (key, key_config) in &keys {
+        for precondition in &key_config.preconditions {
+            if let Precondition::NotRunning(other) = precondition {
+                if !keys.contains_key(other.as_str()) {
+                    return Err(format!("Key {} has a not_running precondition on unknown key {}", key.as_ref(), other));
+                }
+            }
+        }
+    }
+    for key in keys.iter().filter(|(_, k)| k.detach).map(|(k, _)| k) {
+        for suffix in [":stop", ":status"] {
+            let companion = format!("{}{}", key.as_ref(), suffix);
+            if keys.contains_key(companion.as_str()) {
+                return Err(format!("Key {} conflicts with a companion key of detached key {}",
+                    companion, key.as_ref()));
+            }
+        }
+    }
+    let shutdown_timeout = Duration::from_millis(shutdown_timeout_ms.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_MS));
+    let email_alert = email_alert.map(resolve_email_alert).transpose()?;
+    if queue_file.is_some() && !queue_during_maintenance {
+        return Err("queue_file needs queue_during_maintenance".to_owned());
+    }
+    if queue_max_age_ms.is_some() && queue_file.is_none() {
+        return Err("queue_max_age_ms needs queue_file".to_owned());
+    }
+    if queue_file.as_ref().is_some_and(|path| !path.is_absolute()) {
+        return Err("queue_file must be an absolute path".to_owned());
+    }
+    let queue_max_age = Duration::from_millis(queue_max_age_ms.unwrap_or(DEFAULT_QUEUE_MAX_AGE_MS));
+    if max_requests_per_connection == Some(0) {
+        return Err("max_requests_per_connection must be at least 1".to_owned());
+    }
+    if nofile_limit == Some(0) {
+        return Err("nofile_limit must be at least 1".to_owned());
+    }
+    let budget_reset_hour = budget_reset_hour.unwrap_or(0);
+    if budget_reset_hour > 23 {
+        return Err(format!("budget_reset_hour must be from 0 to 23, not {}", budget_reset_hour));
+    }
+    let event_bus = event_bus.map(resolve_event_bus).transpose()?;
+    Ok(Config {keys, rate_limit, namespaces, queue_during_maintenance, queue_file, queue_max_age, shutdown_timeout, trim_keys,
+        max_requests_per_connection, nofile_limit, email_alert, response_profiles, binary_keys, budget_reset_hour, event_bus})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolates_variables_and_escapes() {
+        std::env::set_var("SOCK_TRIGGER_CMD_TEST_DIR", "/srv/app");
+        assert_eq!(interpolate("${SOCK_TRIGGER_CMD_TEST_DIR}/bin/run --all").unwrap(), "/srv/app/bin/run --all");
+        assert_eq!(interpolate("echo $$HOME ${SOCK_TRIGGER_CMD_TEST_DIR}$$").unwrap(), "echo $HOME /srv/app$");
+        assert_eq!(interpolate("no variables").unwrap(), "no variables");
+    }
+
+    #[test]
+    fn refuses_unset_variables_and_stray_dollars() {
+        let e = interpolate("${SOCK_TRIGGER_CMD_TEST_UNSET}/run").unwrap_err();
+        assert!(e.contains("SOCK_TRIGGER_CMD_TEST_UNSET"), "{}", e);
+        assert!(interpolate("cost $5").is_err());
+        assert!(interpolate("${SOCK_TRIGGER_CMD_TEST_DIR").is_err());
+    }
+
+    #[test]
+    fn refuses_json_with_a_key_given_twice() {
+        let json = br#"{"keys": {"backup": "true", "reload": {"cmd": "true", "cmd": "false"}}}"#;
+        assert!(serde_json::from_slice::<NoDuplicateKeys>(br#"{"a": [{"b": 1}, {"b": 2}], "b": null}"#).is_ok());
+        let e = serde_json::from_slice::<NoDuplicateKeys>(json).err().unwrap().to_string();
+        assert!(e.starts_with("duplicate key \"cmd\""), "{}", e);
+    }
+
+    /// An empty directory of its own for the test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sock_trigger_cmd_config_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn merges_the_json_files_of_a_directory_in_order_of_their_names() {
+        let dir = temp_dir("merge");
+        fs::write(dir.join("20-web.json"), r#"{"reload": "true"}"#).unwrap();
+        fs::write(dir.join("10-base.json"), r#"{"keys": {"backup": "true"}, "trim_keys": true}"#).unwrap();
+        fs::write(dir.join("notes.txt"), "not a config").unwrap();
+        fs::create_dir(dir.join("30-dir.json")).unwrap();
+        assert_eq!(config_files(&dir).unwrap(), [dir.join("10-base.json"), dir.join("20-web.json")]);
+        let config = load_config(&dir).unwrap();
+        assert!(config.keys.contains_key("backup") && config.keys.contains_key("reload"));
+        assert!(config.trim_keys);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_a_key_defined_in_two_files() {
+        let dir = temp_dir("duplicate");
+        fs::write(dir.join("b.json"), r#"{"backup": "false"}"#).unwrap();
+        fs::write(dir.join("a.json"), r#"{"backup": "true"}"#).unwrap();
+        let e = load_config(&dir).unwrap_err();
+        // The file read first is named first
+        let (first, second) = (dir.join("a.json"), dir.join("b.json"));
+        assert!(e.contains("backup") && e.contains(&format!("both {} and {}", first.display(), second.display())), "{}", e);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_a_directory_without_json_files() {
+        let dir = temp_dir("empty");
+        let e = config_files(&dir).unwrap_err();
+        assert!(e.contains("has no .json files"), "{}", e);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn interpolates_the_paths_and_commands_of_entries() {
+        std::env::set_var("SOCK_TRIGGER_CMD_TEST_ROOT", "/srv/app");
+        let mut spec: RawKeySpec = serde_json::from_str(r#"{
+            "write_file": {"path": "${SOCK_TRIGGER_CMD_TEST_ROOT}/flag", "contents": "1"},
+            "min_free_space": {"path": "${SOCK_TRIGGER_CMD_TEST_ROOT}/data", "bytes": 1},
+            "preconditions": [{"pidfile": "${SOCK_TRIGGER_CMD_TEST_ROOT}/pid"}, {"check": "${SOCK_TRIGGER_CMD_TEST_ROOT}/ok $$1"}],
+            "collect": ["${SOCK_TRIGGER_CMD_TEST_ROOT}/report.txt"],
+            "forward": {"sockets": ["${SOCK_TRIGGER_CMD_TEST_ROOT}/web1.sock"]}
+        }"#).unwrap();
+        interpolate_spec(&mut spec).unwrap();
+        assert_eq!(spec.write_file.unwrap().path, Path::new("/srv/app/flag"));
+        assert_eq!(spec.min_free_space.unwrap().path, Path::new("/srv/app/data"));
+        assert!(matches!(spec.preconditions[0], RawPrecondition::Pidfile(ref path) if path == Path::new("/srv/app/pid")));
+        assert!(matches!(spec.preconditions[1], RawPrecondition::Check(ref check) if check == "/srv/app/ok $1"));
+        assert_eq!(spec.collect, [Path::new("/srv/app/report.txt")]);
+        assert_eq!(spec.forward.unwrap().sockets, [Path::new("/srv/app/web1.sock")]);
+    }
+
+    #[test]
+    fn interpolates_the_queue_file() {
+        std::env::set_var("SOCK_TRIGGER_CMD_TEST_ROOT", "/srv/app");
+        let dir = temp_dir("queue");
+        fs::write(dir.join("config.json"), r#"{"keys": {"backup": "true"}, "interpolate_env": true,
+            "queue_during_maintenance": true, "queue_file": "${SOCK_TRIGGER_CMD_TEST_ROOT}/queue"}"#).unwrap();
+        let config = load_config(&dir).unwrap();
+        assert_eq!(config.queue_file.as_deref(), Some(Path::new("/srv/app/queue")));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,331 @@
+//! A minimal D-Bus client for method calls and signals with string and integer arguments
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+use nix::unistd::Uid;
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+/// Messages larger than this are refused
+const MAX_MESSAGE_LEN: usize = 1024*1024;
+
+const SYSTEM_BUS_PATH: &str = "/run/dbus/system_bus_socket";
+
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+const SIGNAL: u8 = 4;
+
+/// A message argument; only the types used here are supported
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Arg {
+    U32(u32),
+    Str(String),
+    ObjectPath(String)
+}
+impl Arg {
+    fn signature(&self) -> char {
+        match self {
+            Arg::U32(_) => 'u',
+            Arg::Str(_) => 's',
+            Arg::ObjectPath(_) => 'o'
+        }
+    }
+
+    /// The value of a string or object path argument
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Arg::Str(s) | Arg::ObjectPath(s) => Some(s),
+            Arg::U32(_) => None
+        }
+    }
+}
+
+/// A received message
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Message {
+    message_type: u8,
+    reply_serial: Option<u32>,
+    interface: Option<String>,
+    member: Option<String>,
+    error_name: Option<String>,
+    args: Vec<Arg>
+}
+
+/// Serializes values with the alignment rules of the wire format, in little endian
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>
+}
+impl Writer {
+    fn align(&mut self, alignment: usize) {
+        self.buf.resize(self.buf.len().div_ceil(alignment) * alignment, 0);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.align(4);
+        self.buf.extend(value.to_le_bytes());
+    }
+
+    fn str(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.buf.extend(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    fn signature(&mut self, value: &str) {
+        self.buf.push(value.len() as u8);
+        self.buf.extend(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    fn arg(&mut self, arg: &Arg) {
+        match arg {
+            Arg::U32(value) => self.u32(*value),
+            Arg::Str(value) | Arg::ObjectPath(value) => self.str(value)
+        }
+    }
+}
+
+/// Reads values written with the alignment rules of the wire format
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    is_big_endian: bool
+}
+impl Reader<'_> {
+    fn align(&mut self, alignment: usize) {
+        self.pos = self.pos.div_ceil(alignment) * alignment;
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&[u8], String> {
+        let bytes = self.buf.get(self.pos..self.pos+len).ok_or("Truncated D-Bus message")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        self.align(4);
+        let bytes: [u8; 4] = self.bytes(4)?.try_into().unwrap();
+        Ok(match self.is_big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn str(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        let value = String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| "Invalid string in D-Bus message")?;
+        self.bytes(1)?;
+        Ok(value)
+    }
+
+    fn signature(&mut self) -> Result<String, String> {
+        let len = self.u8()? as usize;
+        let value = String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| "Invalid signature in D-Bus message")?;
+        self.bytes(1)?;
+        Ok(value)
+    }
+
+    fn arg(&mut self, signature: char) -> Result<Arg, String> {
+        match signature {
+            'u' => self.u32().map(Arg::U32),
+            's' => self.str().map(Arg::Str),
+            'o' => self.str().map(Arg::ObjectPath),
+            other => Err(format!("Unsupported type {} in D-Bus message", other))
+        }
+    }
+}
+
+/// The path of the system bus socket, from `DBUS_SYSTEM_BUS_ADDRESS` if it is set
+fn system_bus_path() -> Result<PathBuf, String> {
+    let address = match std::env::var("DBUS_SYSTEM_BUS_ADDRESS") {
+        Ok(address) => address,
+        Err(_) => return Ok(PathBuf::from(SYSTEM_BUS_PATH))
+    };
+    // The first address that can be used is taken
+    address.split(';')
+        .find_map(|address| address.strip_prefix("unix:")?.split(',')
+            .find_map(|param| param.strip_prefix("path=")))
+        .map(PathBuf::from)
+        .ok_or_else(|| format!("DBUS_SYSTEM_BUS_ADDRESS {} has no unix:path= address", address))
+}
+
+/// A connection to a message bus
+pub struct Connection {
+    stream: BufReader<UnixStream>,
+    next_serial: u32,
+    /// Signals that arrived while waiting for a reply
+    pending_signals: VecDeque<Message>
+}
+impl Connection {
+    /// Connects and authenticates to the system bus as the daemon's UID
+    pub async fn system() -> Result<Self, String> {
+        let path = system_bus_path()?;
+        let stream = UnixStream::connect(&path).await
+            .map_err(|e| format!("Could not connect to the system bus at {}: {}", path.display(), e))?;
+        let mut connection = Connection {stream: BufReader::new(stream), next_serial: 1, pending_signals: VecDeque::new()};
+        connection.authenticate().await?;
+        connection.call("org.freedesktop.DBus", "/org/freedesktop/DBus", "org.freedesktop.DBus", "Hello", &[]).await?;
+        Ok(connection)
+    }
+
+    async fn authenticate(&mut self) -> Result<(), String> {
+        let uid_hex: String = Uid::effective().to_string().bytes().map(|b| format!("{:02x}", b)).collect();
+        self.stream.get_mut().write_all(format!("\0AUTH EXTERNAL {}\r\n", uid_hex).as_bytes()).await
+            .map_err(|e| format!("Could not authenticate to the system bus: {}", e))?;
+        let mut line = String::new();
+        (&mut self.stream).take(4096).read_line(&mut line).await
+            .map_err(|e| format!("Could not authenticate to the system bus: {}", e))?;
+        if !line.starts_with("OK ") {
+            return Err(format!("System bus refused authentication: {}", line.trim_end()));
+        }
+        self.stream.get_mut().write_all(b"BEGIN\r\n").await
+            .map_err(|e| format!("Could not authenticate to the system bus: {}", e))
+    }
+
+    async fn send_call(&mut self, destination: &str, path: &str, interface: &str, member: &str,
+            args: &[Arg]) -> Result<u32, String> {
+        let serial = self.next_serial;
+        self.next_serial += 1;
+        let signature: String = args.iter().map(Arg::signature).collect();
+        let mut body = Writer::default();
+        for arg in args {
+            body.arg(arg);
+        }
+
+        let mut message = Writer::default();
+        message.buf.extend([b'l', METHOD_CALL, 0, 1]);
+        message.u32(body.buf.len() as u32);
+        message.u32(serial);
+        let mut fields = Writer::default();
+        let mut field = |code: u8, type_signature: &str, value: &Arg| {
+            fields.align(8);
+            fields.buf.push(code);
+            fields.signature(type_signature);
+            fields.arg(value);
+        };
+        field(1, "o", &Arg::ObjectPath(path.to_owned()));
+        field(2, "s", &Arg::Str(interface.to_owned()));
+        field(3, "s", &Arg::Str(member.to_owned()));
+        field(6, "s", &Arg::Str(destination.to_owned()));
+        if !signature.is_empty() {
+            fields.align(8);
+            fields.buf.push(8);
+            fields.signature("g");
+            fields.signature(&signature);
+        }
+        // The fields start at offset 16, which keeps their alignment the same as in their own buffer
+        message.u32(fields.buf.len() as u32);
+        message.buf.extend(fields.buf);
+        message.align(8);
+        message.buf.extend(body.buf);
+        self.stream.get_mut().write_all(&message.buf).await
+            .map_err(|e| format!("Could not send {} to the system bus: {}", member, e))?;
+        Ok(serial)
+    }
+
+    /// Calls a method and waits for its reply, keeping signals for `signal()`
+    pub async fn call(&mut self, destination: &str, path: &str, interface: &str, member: &str,
+            args: &[Arg]) -> Result<Vec<Arg>, String> {
+        let serial = self.send_call(destination, path, interface, member, args).await?;
+        loop {
+            let message = self.receive().await?;
+            if message.message_type == SIGNAL {
+                self.pending_signals.push_back(message);
+                continue;
+            }
+            if message.reply_serial != Some(serial) {
+                continue;
+            }
+            return match message.message_type {
+                METHOD_RETURN => Ok(message.args),
+                ERROR => Err(format!("{} failed with {}: {}", member,
+                    message.error_name.as_deref().unwrap_or("an unknown error"),
+                    message.args.first().and_then(Arg::as_str).unwrap_or(""))),
+                _ => continue
+            };
+        }
+    }
+
+    /// Waits for the next signal with the given interface and member
+    pub async fn signal(&mut self, interface: &str, member: &str) -> Result<Vec<Arg>, String> {
+        loop {
+            let message = match self.pending_signals.pop_front() {
+                Some(message) => message,
+                None => self.receive().await?
+            };
+            if message.message_type == SIGNAL && message.interface.as_deref() == Some(interface)
+                    && message.member.as_deref() == Some(member) {
+                return Ok(message.args);
+            }
+        }
+    }
+
+    /// Reads the next message of any type
+    async fn receive(&mut self) -> Result<Message, String> {
+        let mut head = [0u8; 16];
+        self.stream.read_exact(&mut head).await
+            .map_err(|e| format!("Could not read from the system bus: {}", e))?;
+        let is_big_endian = match head[0] {
+            b'l' => false,
+            b'B' => true,
+            _ => return Err("System bus sent a message of unknown endianness".to_owned())
+        };
+        let mut reader = Reader {buf: &head, pos: 4, is_big_endian};
+        let body_len = reader.u32()? as usize;
+        reader.u32()?;
+        let fields_len = reader.u32()? as usize;
+        let total_len = (16 + fields_len).div_ceil(8) * 8 + body_len;
+        if total_len > MAX_MESSAGE_LEN {
+            return Err(format!("System bus sent a message of {} bytes", total_len));
+        }
+        let mut buf = head.to_vec();
+        buf.resize(total_len, 0);
+        self.stream.read_exact(&mut buf[16..]).await
+            .map_err(|e| format!("Could not read from the system bus: {}", e))?;
+
+        let mut message = Message {
+            message_type: head[1],
+            reply_serial: None,
+            interface: None,
+            member: None,
+            error_name: None,
+            args: Vec::new()
+        };
+        let mut signature = String::new();
+        let mut reader = Reader {buf: &buf, pos: 16, is_big_endian};
+        while reader.pos < 16 + fields_len {
+            reader.align(8);
+            let code = reader.u8()?;
+            let type_signature = reader.signature()?;
+            let value = match type_signature.as_str() {
+                "g" => Arg::Str(reader.signature()?),
+                other if other.len() == 1 => reader.arg(other.chars().next().unwrap())?,
+                other => return Err(format!("Unsupported header field type {} in D-Bus message", other))
+            };
+            match (code, value) {
+                (2, Arg::Str(value)) => message.interface = Some(value),
+                (3, Arg::Str(value)) => message.member = Some(value),
+                (4, Arg::Str(value)) => message.error_name = Some(value),
+                (5, Arg::U32(value)) => message.reply_serial = Some(value),
+                (8, Arg::Str(value)) => signature = value,
+                _ => {}
+            }
+        }
+        reader.align(8);
+        // Bodies with other types are not needed, so they are skipped rather than failing the connection
+        if signature.chars().all(|c| "uso".contains(c)) {
+            for c in signature.chars() {
+                message.args.push(reader.arg(c)?);
+            }
+        }
+        Ok(message)
+    }
+}
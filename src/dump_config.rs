@@ -0,0 +1,205 @@
+//! The `dump-config` subcommand, which prints the resolved config
+
+use argh::FromArgs;
+
+use serde_json::{json, Value};
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::config::{self, Builtin, Config, DeadlinePolicy, EventBusKind, KeyConfig, RateLimit, ShutdownPolicy, Webhook};
+use crate::precondition::Precondition;
+use crate::sha256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(FromArgs)]
+#[argh(description = "Print the config as it will be used, after merging, defaults, interpolation, and splitting commands")]
+pub struct DumpConfigArgs {
+    #[argh(switch)]
+    #[argh(description = "print the config as JSON")]
+    json: bool,
+    #[argh(positional)]
+    #[argh(description = "location for config file or directory")]
+    config_location: PathBuf
+}
+
+fn rate_limit_json(limit: Option<&RateLimit>) -> Value {
+    match limit {
+        Some(limit) => json!({"rate": limit.rate, "burst": limit.burst}),
+        None => Value::Null
+    }
+}
+
+fn precondition_json(precondition: &Precondition) -> Value {
+    match precondition {
+        Precondition::FreeSpace {path, min_bytes} => json!({"free_space": {"path": path, "bytes": min_bytes}}),
+        Precondition::FileExists(path) => json!({"file_exists": path}),
+        Precondition::Pidfile(path) => json!({"pidfile": path}),
+        Precondition::NotRunning(key) => json!({"not_running": key}),
+        Precondition::Check(cmd) => json!({"check": cmd})
+    }
+}
+
+fn key_json(key_config: &KeyConfig) -> Value {
+    json!({
+        "cmd": key_config.cmd,
+        "systemd": match key_config.builtin {
+            Some(Builtin::Unit(ref job)) => Some(format!("{} {}", job.operation.as_str(), job.unit)),
+            _ => None
+        },
+        "http": match key_config.builtin {
+            Some(Builtin::Http(ref request)) => json!({
+                "method": request.method,
+                "url": request.url.to_string(),
+                "headers": request.headers.iter().cloned().collect::<BTreeMap<_, _>>(),
+                "body": request.body,
+                "max_bytes": request.max_bytes
+            }),
+            _ => Value::Null
+        },
+        "write_file": match key_config.builtin {
+            Some(Builtin::WriteFile(ref write)) => json!({
+                "path": write.path,
+                "contents": write.contents,
+                "max_bytes": write.max_bytes,
+                "mode": format!("{:03o}", write.mode.bits())
+            }),
+            _ => Value::Null
+        },
+        "forward": match key_config.builtin {
+            Some(Builtin::Forward(ref forward)) => json!({
+                "sockets": forward.sockets,
+                "key": forward.key.as_ref()
+            }),
+            _ => Value::Null
+        },
+        "description": key_config.description,
+        "tags": key_config.tags,
+        "sha256": key_config.sha256.map(|hash| sha256::to_hex(&hash)),
+        "enabled": key_config.enabled,
+        "rate_limit": rate_limit_json(key_config.rate_limit.as_ref()),
+        "on_deadline": match key_config.on_deadline {
+            DeadlinePolicy::Detach => "detach",
+            DeadlinePolicy::Kill => "kill"
+        },
+        "on_shutdown": match key_config.on_shutdown {
+            ShutdownPolicy::Wait => "wait",
+            ShutdownPolicy::Kill => "kill",
+            ShutdownPolicy::Detach => "detach"
+        },
+        "detach": key_config.detach,
+        "pty": key_config.pty,
+        "stdout": key_config.stdout,
+        "stderr": key_config.stderr,
+        "log_output": key_config.log_output,
+        "rotate": key_config.rotate.map(|rotation| json!({"max_bytes": rotation.max_bytes, "keep": rotation.keep})),
+        "timeout_ms": key_config.timeout.map(|timeout| timeout.as_millis() as u64),
+        "cwd": key_config.cwd,
+        "max_output_bytes": key_config.max_output_bytes,
+        "output_grace_ms": key_config.output_grace.as_millis() as u64,
+        "log_level": key_config.output_log_level.as_str().to_lowercase(),
+        "umask": key_config.umask.map(|umask| format!("{:03o}", umask.bits())),
+        "cpus": key_config.cpus,
+        "on_timeout": key_config.on_timeout,
+        // The secret is left out, since the output is meant to be shared
+        "hmac_window_ms": key_config.signing.as_ref().map(|signing| signing.window.as_millis() as u64),
+        "allowed_windows": key_config.allowed_windows.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        "confirm": key_config.confirm,
+        "approvals": key_config.approvals.map(|approvals| approvals.count),
+        "approval_window_ms": key_config.approvals.map(|approvals| approvals.window.as_millis() as u64),
+        "daily_budget_ms": key_config.daily_budget.map(|budget| budget.as_millis() as u64),
+        "preconditions": key_config.preconditions.iter().map(precondition_json).collect::<Vec<_>>(),
+        "collect": key_config.collect,
+        "collect_max_bytes": key_config.collect_max_bytes,
+        "webhook": key_config.webhook.as_ref().map(webhook_json)
+    })
+}
+
+fn webhook_json(webhook: &Webhook) -> Value {
+    json!({
+        "url": webhook.url.to_string(),
+        "headers": webhook.headers.iter().cloned().collect::<BTreeMap<_, _>>(),
+        "body": webhook.body,
+        "retries": webhook.retries,
+        "retry_delay_ms": webhook.retry_delay.as_millis() as u64
+    })
+}
+
+/// The resolved config in the structured form, with every setting spelled out
+fn config_json(config: &Config) -> Value {
+    // serde_json objects are sorted by key, which keeps the output canonical
+    let keys: BTreeMap<&str, Value> = config.keys.iter()
+        .map(|(key, key_config)| (key.as_ref(), key_json(key_config)))
+        .collect();
+    json!({
+        "keys": keys,
+        "rate_limit": {
+            "global": rate_limit_json(config.rate_limit.global.as_ref()),
+            "per_peer": rate_limit_json(config.rate_limit.per_peer.as_ref())
+        },
+        "namespaces": config.namespaces.iter()
+            .map(|(namespace, access)| (namespace.clone(), json!({"uids": access.uids, "gids": access.gids})))
+            .collect::<serde_json::Map<_, _>>(),
+        "queue_during_maintenance": config.queue_during_maintenance,
+        "queue_file": config.queue_file,
+        "queue_max_age_ms": config.queue_file.as_ref().map(|_| config.queue_max_age.as_millis() as u64),
+        "shutdown_timeout_ms": config.shutdown_timeout.as_millis() as u64,
+        "trim_keys": config.trim_keys,
+        "max_requests_per_connection": config.max_requests_per_connection,
+        "nofile_limit": config.nofile_limit,
+        "budget_reset_hour": config.budget_reset_hour,
+        "event_bus": config.event_bus.as_ref().map(|bus| match bus.kind {
+            EventBusKind::Redis => json!({"redis": format!("{}:{}", bus.host, bus.port), "channel": bus.channel}),
+            EventBusKind::Mqtt => json!({"mqtt": format!("{}:{}", bus.host, bus.port), "topic": bus.channel})
+        }),
+        "email_alert": config.email_alert.as_ref().map(|alert| json!({
+            "server": format!("{}:{}", alert.host, alert.port),
+            "from": alert.from,
+            "to": alert.to,
+            "after_failures": alert.after_failures
+        })),
+        "response_profiles": config.response_profiles.iter()
+            .map(|(name, profile)| {
+                let mut responses: serde_json::Map<String, Value> = profile.responses.iter()
+                    .map(|(label, response)| (label.clone(), String::from_utf8_lossy(response).into()))
+                    .collect();
+                if let Some(ref other) = profile.other {
+                    responses.insert("other".to_owned(), String::from_utf8_lossy(other).into());
+                }
+                (name.clone(), Value::Object(responses))
+            })
+            .collect::<serde_json::Map<_, _>>()
+    })
+}
+
+/// Prints one `name: value` line per setting, with values as compact JSON
+fn print_text(config: &Value) {
+    println!("rate_limit: {}", config["rate_limit"]);
+    println!("namespaces: {}", config["namespaces"]);
+    println!("queue_during_maintenance: {}", config["queue_during_maintenance"]);
+    println!("queue_file: {}", config["queue_file"]);
+    println!("trim_keys: {}", config["trim_keys"]);
+    println!("max_requests_per_connection: {}", config["max_requests_per_connection"]);
+    println!("nofile_limit: {}", config["nofile_limit"]);
+    println!("email_alert: {}", config["email_alert"]);
+    println!("event_bus: {}", config["event_bus"]);
+    println!("response_profiles: {}", config["response_profiles"]);
+    for (key, settings) in config["keys"].as_object().unwrap() {
+        println!();
+        println!("key {}", Value::from(key.as_str()));
+        for (name, value) in settings.as_object().unwrap() {
+            println!("  {}: {}", name, value);
+        }
+    }
+}
+
+pub fn run(args: DumpConfigArgs) -> Result<(), String> {
+    let config = config::load_config(&args.config_location)?;
+    let config = config_json(&config);
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&config).unwrap());
+    } else {
+        print_text(&config);
+    }
+    Ok(())
+}
@@ -0,0 +1,126 @@
+//! Emailing operators when a key keeps failing, through a minimal SMTP client
+//!
+//! Mail is handed to a relay, such as the local MTA, without TLS or
+//! authentication.
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use std::time::Duration;
+
+use crate::config::EmailAlert;
+use crate::protocol::Outcome;
+
+/// How long handing an email to the relay may take
+const SEND_TIME_LIMIT: Duration = Duration::from_secs(30);
+
+/// Reply lines longer than this are refused
+const MAX_REPLY_LINE_LEN: u64 = 4096;
+
+/// How much of the end of the last command's stderr an alert includes
+pub const STDERR_EXCERPT_LEN: usize = 4096;
+
+struct SmtpSession {
+    stream: BufReader<TcpStream>
+}
+impl SmtpSession {
+    /// Reads a possibly multiline reply, failing unless its code is the expected one
+    async fn expect(&mut self, expected: &[u16], after: &str) -> Result<(), String> {
+        loop {
+            let mut line = String::new();
+            (&mut self.stream).take(MAX_REPLY_LINE_LEN).read_line(&mut line).await
+                .map_err(|e| format!("Could not read the reply to {}: {}", after, e))?;
+            let code = line.get(..3).and_then(|code| code.parse::<u16>().ok())
+                .ok_or_else(|| format!("Invalid reply to {}: {:?}", after, line.trim_end()))?;
+            // Every line but the last has a - after the code
+            if line.as_bytes().get(3) == Some(&b'-') {
+                continue;
+            }
+            return match expected.contains(&code) {
+                true => Ok(()),
+                false => Err(format!("Relay refused {}: {}", after, line.trim_end()))
+            };
+        }
+    }
+
+    async fn command(&mut self, command: &str, expected: &[u16]) -> Result<(), String> {
+        let verb = command.split(' ').next().unwrap();
+        self.stream.get_mut().write_all(format!("{}\r\n", command).as_bytes()).await
+            .map_err(|e| format!("Could not send {}: {}", verb, e))?;
+        self.expect(expected, verb).await
+    }
+}
+
+/// Joins the lines with CRLF, doubling leading dots so that no line ends the data early
+fn encode_data(message: &str) -> String {
+    let mut data = String::with_capacity(message.len() + 64);
+    for line in message.lines() {
+        if line.starts_with('.') {
+            data.push('.');
+        }
+        data.push_str(line);
+        data.push_str("\r\n");
+    }
+    data.push_str(".\r\n");
+    data
+}
+
+/// Replaces characters that cannot appear in a header
+fn header_value(value: &str) -> String {
+    value.chars().map(|c| match c.is_ascii() && !c.is_ascii_control() {
+        true => c,
+        false => '?'
+    }).collect()
+}
+
+/// Sends a plain text email to every recipient of the alert
+pub async fn send(alert: &EmailAlert, subject: &str, body: &str) -> Result<(), String> {
+    let hostname = nix::unistd::gethostname().ok()
+        .and_then(|hostname| hostname.into_string().ok())
+        .unwrap_or_else(|| "localhost".to_owned());
+    let message = format!("From: <{}>\nTo: {}\nSubject: {}\nDate: {}\nContent-Type: text/plain; charset=utf-8\n\n{}",
+        alert.from,
+        alert.to.iter().map(|to| format!("<{}>", to)).collect::<Vec<_>>().join(", "),
+        header_value(subject),
+        chrono::Local::now().to_rfc2822(),
+        body);
+    let exchange = async {
+        let stream = TcpStream::connect((alert.host.as_str(), alert.port)).await
+            .map_err(|e| format!("Could not connect to {}:{}: {}", alert.host, alert.port, e))?;
+        let mut session = SmtpSession {stream: BufReader::new(stream)};
+        session.expect(&[220], "connecting").await?;
+        session.command(&format!("EHLO {}", hostname), &[250]).await?;
+        session.command(&format!("MAIL FROM:<{}>", alert.from), &[250]).await?;
+        for to in &alert.to {
+            session.command(&format!("RCPT TO:<{}>", to), &[250, 251]).await?;
+        }
+        session.command("DATA", &[354]).await?;
+        session.stream.get_mut().write_all(encode_data(&message).as_bytes()).await
+            .map_err(|e| format!("Could not send the message: {}", e))?;
+        session.expect(&[250], "the message").await?;
+        // The message was accepted, so a failed goodbye does not matter
+        let _ = session.command("QUIT", &[221]).await;
+        Ok(())
+    };
+    timeout(SEND_TIME_LIMIT, exchange).await
+        .map_err(|_| format!("Sending to {}:{} timed out", alert.host, alert.port))?
+}
+
+/// The subject and body of an alert about a key that failed `count` times in a row
+pub fn failure_alert(key: &str, count: u32, outcome: Outcome, stderr: &[u8]) -> (String, String) {
+    let hostname = nix::unistd::gethostname()
+        .map(|hostname| hostname.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let subject = format!("Key {} failed {} times in a row on {}", key, count, hostname);
+    let mut body = format!("Key {} on {} failed {} times in a row, most recently as {}.\n",
+        key, hostname, count, outcome.label());
+    match stderr.is_empty() {
+        true => body += "\nThe last command wrote nothing to stderr.\n",
+        false => {
+            body += "\nEnd of the last command's stderr:\n\n";
+            body += &String::from_utf8_lossy(stderr);
+        }
+    }
+    (subject, body)
+}
@@ -0,0 +1,106 @@
+//! Publishing a completion event for every run to a Redis channel or an MQTT topic
+//!
+//! Each event is published over a connection of its own, with minimal clients
+//! for the Redis protocol (RESP) and MQTT 3.1.1 at QoS 0, without TLS or
+//! authentication.
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use std::time::Duration;
+
+use crate::config::{EventBus, EventBusKind};
+
+/// How long publishing an event may take
+const PUBLISH_TIME_LIMIT: Duration = Duration::from_secs(10);
+
+/// Writes a RESP bulk string
+fn bulk_string(message: &mut Vec<u8>, s: &[u8]) {
+    message.extend(format!("${}\r\n", s.len()).into_bytes());
+    message.extend_from_slice(s);
+    message.extend_from_slice(b"\r\n");
+}
+
+async fn publish_redis(stream: TcpStream, channel: &str, event: &[u8]) -> Result<(), String> {
+    let mut stream = BufReader::new(stream);
+    let mut message = b"*3\r\n".to_vec();
+    bulk_string(&mut message, b"PUBLISH");
+    bulk_string(&mut message, channel.as_bytes());
+    bulk_string(&mut message, event);
+    stream.get_mut().write_all(&message).await
+        .map_err(|e| format!("Could not send PUBLISH: {}", e))?;
+    let mut reply = String::new();
+    (&mut stream).take(4096).read_line(&mut reply).await
+        .map_err(|e| format!("Could not read the reply to PUBLISH: {}", e))?;
+    // The reply is the number of subscribers that got the event
+    match reply.starts_with(':') {
+        true => Ok(()),
+        false => Err(format!("Redis refused PUBLISH: {}", reply.trim_end()))
+    }
+}
+
+/// Writes an MQTT packet with its fixed header and variable length encoding
+fn mqtt_packet(packet_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![packet_type];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        match len {
+            0 => {
+                packet.push(byte);
+                break;
+            },
+            _ => packet.push(byte | 0x80)
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// Writes an MQTT UTF-8 string, prefixed with its length
+fn mqtt_string(body: &mut Vec<u8>, s: &str) {
+    body.extend((s.len() as u16).to_be_bytes());
+    body.extend_from_slice(s.as_bytes());
+}
+
+async fn publish_mqtt(mut stream: TcpStream, topic: &str, event: &[u8]) -> Result<(), String> {
+    let mut connect = Vec::new();
+    mqtt_string(&mut connect, "MQTT");
+    // Protocol level 4 is MQTT 3.1.1, with a clean session and a 60 second keep alive
+    connect.extend([4, 0x02, 0, 60]);
+    mqtt_string(&mut connect, &format!("sock_trigger_cmd-{}", std::process::id()));
+    stream.write_all(&mqtt_packet(0x10, &connect)).await
+        .map_err(|e| format!("Could not send CONNECT: {}", e))?;
+    let mut connack = [0u8; 4];
+    stream.read_exact(&mut connack).await
+        .map_err(|e| format!("Could not read CONNACK: {}", e))?;
+    match connack {
+        [0x20, 2, _, 0] => {},
+        [0x20, 2, _, code] => return Err(format!("Broker refused the connection with code {}", code)),
+        _ => return Err("Broker sent an invalid CONNACK".to_owned())
+    }
+    let mut publish = Vec::new();
+    mqtt_string(&mut publish, topic);
+    publish.extend_from_slice(event);
+    stream.write_all(&mqtt_packet(0x30, &publish)).await
+        .map_err(|e| format!("Could not send PUBLISH: {}", e))?;
+    stream.write_all(&mqtt_packet(0xe0, &[])).await
+        .map_err(|e| format!("Could not send DISCONNECT: {}", e))?;
+    Ok(())
+}
+
+/// Publishes the JSON event to the bus
+pub async fn publish(bus: &EventBus, event: &[u8]) -> Result<(), String> {
+    let exchange = async {
+        let stream = TcpStream::connect((bus.host.as_str(), bus.port)).await
+            .map_err(|e| format!("Could not connect to {}:{}: {}", bus.host, bus.port, e))?;
+        match bus.kind {
+            EventBusKind::Redis => publish_redis(stream, &bus.channel, event).await,
+            EventBusKind::Mqtt => publish_mqtt(stream, &bus.channel, event).await
+        }
+    };
+    timeout(PUBLISH_TIME_LIMIT, exchange).await
+        .map_err(|_| format!("Publishing to {}:{} timed out", bus.host, bus.port))?
+}
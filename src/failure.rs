@@ -0,0 +1,111 @@
+//! Errors that stop the daemon, and the exit codes that tell them apart
+//!
+//! The codes follow sysexits.h, so that init systems and wrapper scripts can
+//! tell a problem that needs fixing from one that may go away on a retry.
+
+use log::error;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Starts errors written to stderr, so that they stand apart from log lines and argh's messages
+pub const STDERR_PREFIX: &str = "sock_trigger_cmd: ";
+
+static IS_LOGGING: AtomicBool = AtomicBool::new(false);
+static IS_LOGGING_TO_STDOUT: AtomicBool = AtomicBool::new(false);
+
+/// Records that the logger has started, so that errors go to it instead of only to stderr
+pub fn logging_started(is_logging_to_stdout: bool) {
+    IS_LOGGING.store(true, Ordering::Relaxed);
+    IS_LOGGING_TO_STDOUT.store(is_logging_to_stdout, Ordering::Relaxed);
+}
+
+/// Reports the error that stopped the daemon or a subcommand
+///
+/// It is logged once the logger has started, and written to stderr unless the
+/// logger already showed it on stdout.
+pub fn report(failure: &Failure) {
+    // The response has already been printed
+    if let FailureKind::Response(_) = failure.kind {
+        return;
+    }
+    if IS_LOGGING.load(Ordering::Relaxed) {
+        error!("{}", failure);
+    }
+    if !IS_LOGGING_TO_STDOUT.load(Ordering::Relaxed) {
+        eprintln!("{}{}", STDERR_PREFIX, failure);
+    }
+}
+
+/// What kind of problem stopped the daemon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// The command line was invalid
+    Usage,
+    /// The config could not be loaded, or names users or groups that do not exist
+    Config,
+    /// The socket could not be bound or taken over, which may be fixed by retrying
+    Socket,
+    /// The log file, syslog, or audit log could not be opened
+    Logging,
+    /// The daemon sent a response that a client subcommand could not make sense of
+    Protocol,
+    /// The key sent by `send` did not succeed, with the exit code standing for its response
+    Response(u8),
+    /// Anything else
+    Runtime
+}
+impl FailureKind {
+    pub fn exit_code(self) -> u8 {
+        match self {
+            FailureKind::Usage => 64,
+            FailureKind::Config => 78,
+            FailureKind::Socket => 75,
+            FailureKind::Logging => 73,
+            FailureKind::Protocol => 76,
+            FailureKind::Response(code) => code,
+            FailureKind::Runtime => 1
+        }
+    }
+}
+
+/// An error that stops the daemon
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    pub kind: FailureKind,
+    pub message: String
+}
+impl Failure {
+    pub fn usage(message: String) -> Self {
+        Failure {kind: FailureKind::Usage, message}
+    }
+
+    pub fn config(message: String) -> Self {
+        Failure {kind: FailureKind::Config, message}
+    }
+
+    pub fn socket(message: String) -> Self {
+        Failure {kind: FailureKind::Socket, message}
+    }
+
+    pub fn logging(message: String) -> Self {
+        Failure {kind: FailureKind::Logging, message}
+    }
+
+    pub fn protocol(message: String) -> Self {
+        Failure {kind: FailureKind::Protocol, message}
+    }
+
+    pub fn response(code: u8, message: String) -> Self {
+        Failure {kind: FailureKind::Response(code), message}
+    }
+}
+impl From<String> for Failure {
+    fn from(message: String) -> Self {
+        Failure {kind: FailureKind::Runtime, message}
+    }
+}
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
@@ -0,0 +1,67 @@
+//! Raising and reporting the limit on open file descriptors
+//!
+//! Every connection holds a descriptor, and every running command holds its
+//! stdout and stderr pipes, so a low limit shows up as commands that fail to
+//! spawn or connections that cannot be accepted under load.
+
+use log::{info, warn};
+
+use nix::sys::resource::{getrlimit, setrlimit, Resource, RLIM_INFINITY};
+
+/// Descriptors the daemon holds for a connection that is running a command:
+/// the connection itself and the command's stdout and stderr pipes
+const FDS_PER_COMMAND: u64 = 3;
+
+/// Raises the soft limit to `wanted`, and the hard limit along with it if needed
+///
+/// Raising the hard limit needs privileges, so without them the soft limit is
+/// only raised as far as the hard limit allows, with a warning.
+pub fn raise(wanted: u64) -> Result<(), String> {
+    let (soft, hard) = getrlimit(Resource::RLIMIT_NOFILE)
+        .map_err(|e| format!("Could not get the file descriptor limit: {}", e))?;
+    if soft != RLIM_INFINITY && soft >= wanted {
+        return Ok(());
+    }
+    if hard == RLIM_INFINITY || hard >= wanted {
+        return setrlimit(Resource::RLIMIT_NOFILE, wanted, hard)
+            .map_err(|e| format!("Could not raise the file descriptor limit to {}: {}", wanted, e));
+    }
+    match setrlimit(Resource::RLIMIT_NOFILE, wanted, wanted) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            warn!("Could not raise the hard file descriptor limit from {} to {}, so raising only up to it: {}",
+                hard, wanted, e);
+            setrlimit(Resource::RLIMIT_NOFILE, hard, hard)
+                .map_err(|e| format!("Could not raise the file descriptor limit to {}: {}", hard, e))
+        }
+    }
+}
+
+/// How many descriptors are open now, not counting the one used to list them
+fn open_count() -> Option<u64> {
+    let entries = std::fs::read_dir("/dev/fd").ok()?;
+    Some((entries.count() as u64).saturating_sub(1))
+}
+
+/// Logs the limits, and roughly how many connections and commands they leave room for
+pub fn log_limits() {
+    let (soft, hard) = match getrlimit(Resource::RLIMIT_NOFILE) {
+        Ok(limits) => limits,
+        Err(e) => {
+            warn!("Could not get the file descriptor limit: {}", e);
+            return;
+        }
+    };
+    let describe = |limit: u64| match limit {
+        RLIM_INFINITY => "unlimited".to_owned(),
+        limit => limit.to_string()
+    };
+    match open_count() {
+        Some(open) if soft != RLIM_INFINITY => {
+            let room = soft.saturating_sub(open);
+            info!("Startup: file descriptor limit {} (hard limit {}) with {} open, leaving room for about {} connections, \
+                or {} connections running commands at once", soft, describe(hard), open, room, room / FDS_PER_COMMAND);
+        },
+        _ => info!("Startup: file descriptor limit {} (hard limit {})", describe(soft), describe(hard))
+    }
+}
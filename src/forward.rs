@@ -0,0 +1,28 @@
+//! Triggering keys on other daemons, as a client of their sockets
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+use std::path::Path;
+
+use crate::protocol::{self, Outcome};
+
+/// Sends the key to the daemon listening on the socket and waits for its response
+pub async fn trigger(socket: &Path, key: &str) -> Result<Outcome, String> {
+    let mut stream = UnixStream::connect(socket).await
+        .map_err(|e| format!("Could not connect: {}", e))?;
+    let mut request = key.as_bytes().to_vec();
+    request.push(0);
+    stream.write_all(&request).await
+        .map_err(|e| format!("Could not send the key: {}", e))?;
+    let mut response = vec![0u8; 1];
+    stream.read_exact(&mut response).await
+        .map_err(|e| format!("Could not read the response: {}", e))?;
+    let len = protocol::response_len(response[0])
+        .ok_or_else(|| format!("Unexpected response {:?}", char::from(response[0])))?;
+    response.resize(len, 0);
+    stream.read_exact(&mut response[1..]).await
+        .map_err(|e| format!("Could not read the response: {}", e))?;
+    // A response of the right length may still be malformed, such as a nonce that is not hex
+    Outcome::from_response(&response).ok_or_else(|| format!("Malformed response {:?}", response))
+}
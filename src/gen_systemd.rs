@@ -0,0 +1,240 @@
+//! The `gen-systemd` subcommand, which writes a service and socket unit for a daemon invocation
+
+use argh::FromArgs;
+
+use nix::sys::stat::Mode;
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use crate::config;
+use crate::logging;
+use crate::privilege;
+use crate::CmdArgs;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(FromArgs)]
+#[argh(description = "Print or write a systemd service and socket unit that run the daemon with the given arguments")]
+#[argh(example = "sock_trigger_cmd gen-systemd --out-dir /etc/systemd/system -- --user nobody /run/trigger.sock /etc/trigger.json")]
+pub struct GenSystemdArgs {
+    #[argh(option, default = "\"sock_trigger_cmd\".to_owned()")]
+    #[argh(description = "name of the units (default sock_trigger_cmd)")]
+    name: String,
+    #[argh(option)]
+    #[argh(description = "directory to write the units to instead of printing them")]
+    out_dir: Option<PathBuf>,
+    #[argh(positional, greedy)]
+    #[argh(description = "arguments to run the daemon with, after --")]
+    daemon_args: Vec<String>
+}
+
+/// Quotes a word for a unit file, escaping specifiers and variables
+fn quote(word: &str) -> String {
+    let escaped = word.replace('%', "%%").replace('$', "$$");
+    let is_plain = !escaped.is_empty() && !escaped.contains(|c: char| c.is_whitespace() || "\"'\\;".contains(c));
+    if is_plain {
+        return escaped;
+    }
+    format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn absolute(path: &Path) -> Result<PathBuf, String> {
+    std::path::absolute(path)
+        .map_err(|e| format!("Could not resolve {}: {}", path.display(), e))
+}
+
+/// Directories the daemon and its commands are expected to write to
+fn writable_dirs(args: &CmdArgs, config: &config::Config) -> Result<BTreeSet<PathBuf>, String> {
+    let mut dirs = BTreeSet::new();
+    if !args.no_file_log {
+        dirs.insert(PathBuf::from("/var/log"));
+    }
+    // Rotation creates files next to the ones written to
+    let parent = |path: &Path| absolute(path).map(|path| path.parent().unwrap_or(&path).to_owned());
+    if let Some(ref audit_log) = args.audit_log {
+        dirs.insert(parent(audit_log)?);
+    }
+    if let Some(ref journal) = args.journal {
+        dirs.insert(parent(journal)?);
+    }
+    // The queue file is replaced by renaming a file next to it
+    if let Some(ref queue_file) = config.queue_file {
+        dirs.insert(parent(queue_file)?);
+    }
+    for key_config in config.keys.values() {
+        for path in [&key_config.stdout, &key_config.stderr].into_iter().flatten() {
+            dirs.insert(parent(path)?);
+        }
+        if let Some(ref cwd) = key_config.cwd {
+            dirs.insert(absolute(cwd)?);
+        }
+    }
+    Ok(dirs)
+}
+
+fn service_unit(name: &str, exec_start: &str, writable_dirs: &BTreeSet<PathBuf>) -> String {
+    let mut unit = String::new();
+    writeln!(unit, "[Unit]").unwrap();
+    writeln!(unit, "Description=Run commands triggered over a Unix socket").unwrap();
+    writeln!(unit, "Requires={}.socket", name).unwrap();
+    writeln!(unit, "After={}.socket", name).unwrap();
+    writeln!(unit).unwrap();
+    writeln!(unit, "[Service]").unwrap();
+    writeln!(unit, "Type=notify").unwrap();
+    writeln!(unit, "ExecStart={}", exec_start).unwrap();
+    writeln!(unit, "ExecReload=/bin/kill -HUP $MAINPID").unwrap();
+    // Ctrl-C is what lets running commands finish
+    writeln!(unit, "KillSignal=SIGINT").unwrap();
+    writeln!(unit, "Restart=on-failure").unwrap();
+    writeln!(unit, "# Commands inherit these restrictions; relax them if a command needs to").unwrap();
+    writeln!(unit, "NoNewPrivileges=yes").unwrap();
+    writeln!(unit, "ProtectSystem=strict").unwrap();
+    for dir in writable_dirs {
+        writeln!(unit, "ReadWritePaths={}", quote(&dir.to_string_lossy())).unwrap();
+    }
+    writeln!(unit, "ProtectKernelTunables=yes").unwrap();
+    writeln!(unit, "ProtectKernelModules=yes").unwrap();
+    writeln!(unit, "ProtectControlGroups=yes").unwrap();
+    writeln!(unit, "RestrictSUIDSGID=yes").unwrap();
+    writeln!(unit, "RestrictRealtime=yes").unwrap();
+    writeln!(unit, "LockPersonality=yes").unwrap();
+    writeln!(unit).unwrap();
+    writeln!(unit, "[Install]").unwrap();
+    writeln!(unit, "WantedBy=multi-user.target").unwrap();
+    writeln!(unit, "Also={}.socket", name).unwrap();
+    unit
+}
+
+fn socket_unit(name: &str, socket_location: &Path, identity: Option<&privilege::TargetIdentity>,
+        dir_mode: Option<Mode>) -> String {
+    let mut unit = String::new();
+    writeln!(unit, "[Unit]").unwrap();
+    writeln!(unit, "Description=Socket for {}.service", name).unwrap();
+    writeln!(unit).unwrap();
+    writeln!(unit, "[Socket]").unwrap();
+    writeln!(unit, "ListenStream={}", quote(&socket_location.to_string_lossy())).unwrap();
+    // The same mode and owner the daemon sets on sockets it binds itself
+    writeln!(unit, "SocketMode=0660").unwrap();
+    if let Some(identity) = identity {
+        writeln!(unit, "SocketUser={}", identity.uid).unwrap();
+        writeln!(unit, "SocketGroup={}", identity.gid).unwrap();
+    }
+    // systemd creates missing parent directories itself, always owned by root
+    if let Some(mode) = dir_mode {
+        writeln!(unit, "DirectoryMode={:04o}", mode.bits()).unwrap();
+    }
+    writeln!(unit).unwrap();
+    writeln!(unit, "[Install]").unwrap();
+    writeln!(unit, "WantedBy=sockets.target").unwrap();
+    unit
+}
+
+pub fn run(args: GenSystemdArgs) -> Result<(), String> {
+    let daemon_args: Vec<&str> = args.daemon_args.iter().map(String::as_str).collect();
+    let mut cmd_args = CmdArgs::from_args(&["sock_trigger_cmd"], &daemon_args)
+        .map_err(|e| format!("Invalid daemon arguments: {}", e.output.trim_end()))?;
+    cmd_args.resolve_locations()?;
+    // The service manager does not run the daemon from the current directory
+    cmd_args.socket_location = absolute(&cmd_args.socket_location)?;
+    cmd_args.config_location = Some(absolute(cmd_args.config_location())?);
+    if let Some(ref audit_log) = cmd_args.audit_log {
+        cmd_args.audit_log = Some(absolute(audit_log)?);
+    }
+    if let Some(ref journal) = cmd_args.journal {
+        cmd_args.journal = Some(absolute(journal)?);
+    }
+    let identity = privilege::resolve_identity(cmd_args.user.as_deref(), cmd_args.group.as_deref())?;
+    let config = config::load_config(cmd_args.config_location())?;
+
+    let exe = std::env::current_exe()
+        .map_err(|e| format!("Could not find own executable: {}", e))?;
+    let mut exec_start = vec![quote(&exe.to_string_lossy())];
+    // Relative paths were replaced above, so the options are rebuilt
+    // instead of passing the arguments through
+    let mut push_option = |flag: &str, value: Option<&str>| {
+        exec_start.push(flag.to_owned());
+        exec_start.extend(value.map(quote));
+    };
+    if let Some(ref user) = cmd_args.user {
+        push_option("--user", Some(user));
+    }
+    if let Some(ref group) = cmd_args.group {
+        push_option("--group", Some(group));
+    }
+    if cmd_args.insecure_config {
+        push_option("--insecure-config", None);
+    }
+    if cmd_args.strict {
+        push_option("--strict", None);
+    }
+    if cmd_args.no_file_log {
+        push_option("--no-file-log", None);
+    }
+    if cmd_args.no_syslog {
+        push_option("--no-syslog", None);
+    }
+    if cmd_args.log_style != logging::LogStyle::Full {
+        push_option("--log-style", Some(cmd_args.log_style.as_str()));
+    }
+    if cmd_args.stdout_level != log::LevelFilter::Info {
+        push_option("--stdout-level", Some(&cmd_args.stdout_level.as_str().to_lowercase()));
+    }
+    if let Some(ref name) = cmd_args.response_profile {
+        push_option("--response-profile", Some(name));
+    }
+    if let Some(ref audit_log) = cmd_args.audit_log {
+        push_option("--audit-log", Some(&audit_log.to_string_lossy()));
+    }
+    if let Some(ref journal) = cmd_args.journal {
+        push_option("--journal", Some(&journal.to_string_lossy()));
+    }
+    if let Some(threads) = cmd_args.worker_threads {
+        push_option("--worker-threads", Some(&threads.to_string()));
+    }
+    if cmd_args.current_thread {
+        push_option("--current-thread", None);
+    }
+    if cmd_args.subreaper {
+        push_option("--subreaper", None);
+    }
+    if let Some(mode) = cmd_args.socket_dir_mode {
+        push_option("--socket-dir-mode", Some(&format!("{:o}", mode.bits())));
+    }
+    if let Some(ref owner) = cmd_args.socket_dir_owner {
+        push_option("--socket-dir-owner", Some(owner));
+    }
+    #[cfg(feature = "otlp")]
+    if let Some(ref endpoint) = cmd_args.otlp_endpoint {
+        push_option("--otlp-endpoint", Some(endpoint));
+        push_option("--otlp-interval", Some(&cmd_args.otlp_interval.to_string()));
+    }
+    // Syslog already reaches the journal, which would get every line twice otherwise
+    if cfg!(feature = "syslog") && !cmd_args.no_syslog {
+        push_option("-q", None);
+    }
+    exec_start.push(quote(&cmd_args.socket_location.to_string_lossy()));
+    exec_start.push(quote(&cmd_args.config_location().to_string_lossy()));
+
+    let service = service_unit(&args.name, &exec_start.join(" "),
+        &writable_dirs(&cmd_args, &config)?);
+    let socket = socket_unit(&args.name, &cmd_args.socket_location, identity.as_ref(), cmd_args.socket_dir_mode);
+    match args.out_dir {
+        Some(dir) => {
+            for (suffix, unit) in [("service", service), ("socket", socket)] {
+                let path = dir.join(format!("{}.{}", args.name, suffix));
+                std::fs::write(&path, unit)
+                    .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+                println!("Wrote {}", path.display());
+            }
+        },
+        None => {
+            println!("# {}.service", args.name);
+            print!("{}", service);
+            println!();
+            println!("# {}.socket", args.name);
+            print!("{}", socket);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,78 @@
+//! Handing the listening socket and job state over to a re-executed daemon
+//!
+//! On SIGQUIT the daemon waits for its connections to finish, then execs the
+//! binary at its original path with the same arguments. The listening socket
+//! is inherited as an open file descriptor, and everything else the new
+//! process needs is passed as JSON in an environment variable.
+
+use serde::{Deserialize, Serialize};
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::stat::{fstat, SFlag};
+
+use std::collections::HashMap;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+
+const ENV_VAR: &str = "SOCK_TRIGGER_CMD_HANDOVER";
+
+/// The state passed from a daemon to the process that replaces it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Handover {
+    /// The inherited listening socket
+    pub listen_fd: RawFd,
+    /// The main log file, which the new process may no longer be able to choose itself
+    pub log_path: String,
+    /// PIDs of the commands of detached keys, by key
+    pub services: HashMap<String, u32>,
+    /// PIDs of commands that outlived a client deadline, by job id
+    pub jobs: HashMap<u32, u32>,
+    pub enabled_overrides: HashMap<String, bool>,
+    pub is_in_maintenance: bool,
+    pub next_job_id: u32
+}
+
+/// Takes the handover left by the previous daemon process, if this process is its replacement
+pub fn take() -> Result<Option<Handover>, String> {
+    let handover = match std::env::var(ENV_VAR) {
+        Ok(handover) => handover,
+        Err(_) => return Ok(None)
+    };
+    // Commands must not see it
+    std::env::remove_var(ENV_VAR);
+    serde_json::from_str(&handover)
+        .map(Some)
+        .map_err(|e| format!("Invalid handover from previous daemon: {}", e))
+}
+
+/// Takes ownership of the inherited listening socket
+pub fn listener(handover: &Handover) -> Result<UnixListener, String> {
+    let fd = handover.listen_fd;
+    let is_socket = fstat(fd)
+        .map(|stat| SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFSOCK)
+        .unwrap_or(false);
+    if !is_socket {
+        return Err(format!("Handed over descriptor {} is not a socket", fd));
+    }
+    // SAFETY: the descriptor was left open by the previous daemon for this
+    // process alone, and nothing else in this process refers to it
+    #[allow(unsafe_code)]
+    let listener = unsafe { UnixListener::from_raw_fd(fd) };
+    Ok(listener)
+}
+
+/// Replaces this process with the binary at `exe`, only returning if that fails
+pub fn exec(exe: &Path, listener: &UnixListener, handover: &Handover) -> String {
+    if let Err(e) = fcntl(listener.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::empty())) {
+        return format!("Could not pass socket to new process: {}", e);
+    }
+    let handover = serde_json::to_string(handover).expect("Handover is always serializable");
+    let e = Command::new(exe)
+        .args(std::env::args_os().skip(1))
+        .env(ENV_VAR, handover)
+        .exec();
+    format!("Could not execute {}: {}", exe.display(), e)
+}
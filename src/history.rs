@@ -0,0 +1,83 @@
+//! The `history` subcommand, which prints the recent runs of a running daemon
+
+use argh::FromArgs;
+
+use serde_json::Value;
+
+use std::path::PathBuf;
+
+use crate::client;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(FromArgs)]
+#[argh(description = "Print the recent runs of keys on a running daemon")]
+#[argh(example = "sock_trigger_cmd history --key backup --since 24h")]
+pub struct HistoryArgs {
+    #[argh(option)]
+    #[argh(description = "only print runs of this key")]
+    key: Option<String>,
+    #[argh(option, from_str_fn(parse_since))]
+    #[argh(description = "only print runs started this long ago or later, in seconds or with s, m, h, or d")]
+    since: Option<u64>,
+    #[argh(switch)]
+    #[argh(description = "print the runs as JSON")]
+    json: bool,
+    #[argh(positional)]
+    #[argh(description = "location of the daemon's socket")]
+    socket_location: Option<PathBuf>
+}
+
+fn parse_since(value: &str) -> Result<u64, String> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "s")
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60*60,
+        "d" => 24*60*60,
+        _ => return Err(format!("Invalid unit {} in {}", unit, value))
+    };
+    number.parse::<u64>().ok()
+        .and_then(|number| number.checked_mul(scale))
+        .filter(|secs| *secs > 0)
+        .ok_or_else(|| format!("Invalid duration {}", value))
+}
+
+/// A line per run, lined up, for runs from a `HISTORY` or `STATUS` frame
+pub fn run_lines(runs: &[Value]) -> Vec<String> {
+    // Keys are quoted so that spaces in them stay visible
+    let quoted: Vec<String> = runs.iter().map(|run| run["key"].to_string()).collect();
+    let key_width = quoted.iter().map(|key| key.chars().count()).max().unwrap_or(0);
+    quoted.iter().zip(runs).map(|(quoted, run)| {
+        let label = run["status"].as_str().unwrap_or_default();
+        let status = match (&run["code"], &run["signal"]) {
+            (Value::Number(code), _) => format!("{} (code {})", label, code),
+            (_, Value::Number(sig)) => format!("{} (signal {})", label, sig),
+            _ => label.to_owned()
+        };
+        format!("{}  {:width$}  {}ms  {}", run["time"].as_str().unwrap_or_default(), quoted,
+            run["duration_ms"], status, width = key_width)
+    }).collect()
+}
+
+pub fn run(args: HistoryArgs) -> Result<(), String> {
+    let mut frame = format!("HISTORY {}", args.since.unwrap_or(0));
+    if let Some(ref key) = args.key {
+        frame += " ";
+        frame += key;
+    }
+    let runs = client::admin_request(args.socket_location.as_deref(), &frame)?;
+    let runs = runs.as_array().ok_or("The daemon sent an invalid history")?;
+    if args.json {
+        let mut output = serde_json::Map::new();
+        output.insert("runs".to_owned(), runs.clone().into());
+        crate::print_json_output(output, true);
+        return Ok(());
+    }
+    for line in run_lines(runs) {
+        println!("{}", line);
+    }
+    Ok(())
+}
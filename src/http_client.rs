@@ -0,0 +1,146 @@
+//! A minimal HTTP/1.1 client for plain `http://` endpoints
+//!
+//! Without the `http` feature only URLs are left, so that configs using them can still be parsed.
+
+#[cfg(feature = "http")]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(feature = "http")]
+use tokio::net::TcpStream;
+#[cfg(feature = "http")]
+use tokio::time::timeout;
+
+use std::str::FromStr;
+#[cfg(feature = "http")]
+use std::time::Duration;
+
+/// Responses larger than this are cut off
+#[cfg(feature = "http")]
+const MAX_RESPONSE_LEN: u64 = 1024*1024;
+
+/// A parsed `http://host[:port][/path]` URL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpUrl {
+    pub host: String,
+    pub port: u16,
+    pub path: String
+}
+impl FromStr for HttpUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s.strip_prefix("http://")
+            .ok_or_else(|| format!("{} is not an http:// URL", s))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/")
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            // Leave bracketed IPv6 addresses without a port alone
+            Some((host, port)) if !port.contains(']') => (host, port.parse::<u16>()
+                .map_err(|_| format!("{} has an invalid port", s))?),
+            _ => (authority, 80)
+        };
+        if host.is_empty() {
+            return Err(format!("{} has no host", s));
+        }
+        Ok(HttpUrl {
+            host: host.to_owned(),
+            port,
+            path: path.to_owned()
+        })
+    }
+}
+impl HttpUrl {
+    /// Returns a copy of the URL with its path replaced
+    #[cfg(feature = "otlp")]
+    pub fn with_path(&self, path: &str) -> HttpUrl {
+        HttpUrl {
+            path: path.to_owned(),
+            ..self.clone()
+        }
+    }
+}
+impl std::fmt::Display for HttpUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+/// A received HTTP response
+#[cfg(feature = "http")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: Vec<u8>
+}
+
+#[cfg(feature = "http")]
+fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>, String> {
+    let mut decoded = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|w| w == b"\r\n")
+            .ok_or("Truncated chunked body")?;
+        let size_str = std::str::from_utf8(&body[..line_end]).map_err(|_| "Invalid chunk size")?;
+        let size_str = size_str.split(';').next().unwrap().trim();
+        let size = usize::from_str_radix(size_str, 16).map_err(|_| "Invalid chunk size")?;
+        body = &body[line_end+2..];
+        if size == 0 {
+            return Ok(decoded);
+        }
+        if body.len() < size {
+            return Err("Truncated chunked body".to_owned());
+        }
+        decoded.extend_from_slice(&body[..size]);
+        body = body.get(size+2..).unwrap_or_default();
+    }
+}
+
+#[cfg(feature = "http")]
+fn parse_response(raw: &[u8]) -> Result<HttpResponse, String> {
+    let header_end = raw.windows(4).position(|w| w == b"\r\n\r\n")
+        .ok_or("Response has no end of headers")?;
+    let head = std::str::from_utf8(&raw[..header_end])
+        .map_err(|_| "Response headers are not valid UTF-8")?;
+    let mut lines = head.split("\r\n");
+    let status = lines.next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or("Response has an invalid status line")?;
+    let is_chunked = lines
+        .filter_map(|line| line.split_once(':'))
+        .any(|(name, value)| name.trim().eq_ignore_ascii_case("transfer-encoding")
+            && value.trim().eq_ignore_ascii_case("chunked"));
+    let body = &raw[header_end+4..];
+    let body = match is_chunked {
+        true => decode_chunked(body)?,
+        false => body.to_vec()
+    };
+    Ok(HttpResponse {status, body})
+}
+
+/// Sends a request and waits for the whole response, closing the connection afterwards
+#[cfg(feature = "http")]
+pub async fn request(method: &str, url: &HttpUrl, headers: &[(String, String)],
+        body: &[u8], time_limit: Duration) -> Result<HttpResponse, String> {
+    let exchange = async {
+        let mut stream = TcpStream::connect((url.host.as_str(), url.port)).await
+            .map_err(|e| format!("Could not connect to {}: {}", url, e))?;
+        let mut message = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+            method, url.path, url.host, body.len());
+        for (name, value) in headers {
+            message += &format!("{}: {}\r\n", name, value);
+        }
+        message += "\r\n";
+        let mut message = message.into_bytes();
+        message.extend_from_slice(body);
+        stream.write_all(&message).await
+            .map_err(|e| format!("Could not send request to {}: {}", url, e))?;
+
+        let mut raw = Vec::new();
+        stream.take(MAX_RESPONSE_LEN).read_to_end(&mut raw).await
+            .map_err(|e| format!("Could not read response from {}: {}", url, e))?;
+        parse_response(&raw)
+    };
+    timeout(time_limit, exchange).await
+        .map_err(|_| format!("Request to {} timed out", url))?
+}
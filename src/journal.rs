@@ -0,0 +1,102 @@
+//! An append-only record of commands starting and ending, for finding out which were in flight after a crash
+//!
+//! Every event is one line of the form
+//! `start: time=<RFC 3339 UTC> daemon=<pid> run=<n> pid=<pid> key=<JSON string>` or
+//! `end: time=<RFC 3339 UTC> daemon=<pid> run=<n> elapsed_ms=<ms>`, where `run`
+//! numbers the commands of one daemon process and an unknown PID is written as `-`.
+//! Events are written by a thread of their own, so that starting a command
+//! does not wait on the disk, and start events are synced to disk as soon as
+//! they are written, unlike the main log, which is buffered.
+
+use log::error;
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+enum Event {
+    /// A line, and whether to sync the file after writing it
+    Line(String, bool),
+    /// Answered once the events sent before it are written
+    Flush(Sender<()>)
+}
+
+static JOURNAL: OnceLock<Sender<Event>> = OnceLock::new();
+
+/// Appends subsequent events to the file, creating it if needed
+pub fn open(path: &Path) -> Result<(), String> {
+    let file = File::options().append(true).create(true).open(path)
+        .map_err(|e| format!("Could not open journal {}: {}", path.display(), e))?;
+    let (send, recv) = channel();
+    if JOURNAL.set(send).is_ok() {
+        std::thread::Builder::new().name("journal".to_owned()).spawn(move || write_events(file, recv))
+            .map_err(|e| format!("Could not start the journal writer: {}", e))?;
+    }
+    Ok(())
+}
+
+/// Writes the events as they arrive, syncing once for all the ones waiting at a time
+fn write_events(mut file: File, events: Receiver<Event>) {
+    while let Ok(first) = events.recv() {
+        let mut needs_sync = false;
+        let mut flushed = Vec::new();
+        for event in std::iter::once(first).chain(events.try_iter()) {
+            match event {
+                Event::Line(line, sync) => {
+                    needs_sync |= sync;
+                    if let Err(e) = file.write_all(line.as_bytes()) {
+                        error!("Could not write to the journal: {}", e);
+                    }
+                },
+                Event::Flush(done) => flushed.push(done)
+            }
+        }
+        if needs_sync {
+            if let Err(e) = file.sync_data() {
+                error!("Could not sync the journal: {}", e);
+            }
+        }
+        for done in flushed {
+            let _ = done.send(());
+        }
+    }
+}
+
+fn append(line: String, sync: bool) {
+    if let Some(journal) = JOURNAL.get() {
+        // The writer thread runs for as long as the process
+        let _ = journal.send(Event::Line(line, sync));
+    }
+}
+
+/// Blocks until the events recorded so far are written, so that they are not lost when the daemon exits
+pub fn flush() {
+    let Some(journal) = JOURNAL.get() else {
+        return;
+    };
+    let (done, written) = channel();
+    if journal.send(Event::Flush(done)).is_ok() {
+        let _ = written.recv();
+    }
+}
+
+fn now() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+/// Records that a command or action started, to be synced to the journal
+pub fn started(run: u64, key: &str, pid: Option<u32>) {
+    let pid = pid.map_or_else(|| "-".to_owned(), |pid| pid.to_string());
+    // JSON escaping keeps control characters in the key from breaking the line
+    let key = serde_json::to_string(key).unwrap();
+    append(format!("start: time={} daemon={} run={} pid={} key={}\n", now(), std::process::id(), run, pid, key), true);
+}
+
+/// Records that a command or action is no longer running
+pub fn ended(run: u64, elapsed: Duration) {
+    append(format!("end: time={} daemon={} run={} elapsed_ms={}\n",
+        now(), std::process::id(), run, elapsed.as_millis()), false);
+}
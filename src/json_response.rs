@@ -0,0 +1,109 @@
+//! The JSON lines responses that a connection switches to with a `JSON` frame
+
+use serde_json::{json, Value};
+
+use crate::protocol::{self, Outcome};
+use crate::state::HistoryEntry;
+use crate::KeyResult;
+
+/// How much of each of stdout and stderr a response includes
+const OUTPUT_EXCERPT_LEN: usize = 4096;
+
+fn line(object: Value) -> Vec<u8> {
+    let mut line = object.to_string().into_bytes();
+    line.push(b'\n');
+    line
+}
+
+/// The response to a key
+pub fn key_line(key_bytes: &[u8], result: &KeyResult) -> Vec<u8> {
+    let outcome = result.outcome;
+    let mut object = json!({
+        "key": String::from_utf8_lossy(key_bytes),
+        "status": outcome.label(),
+        "success": outcome.is_success()
+    });
+    let detail = match outcome {
+        Outcome::Completed(code) => Some(("code", i64::from(code))),
+        Outcome::Signaled(sig) => Some(("signal", i64::from(sig))),
+        Outcome::Detached(job_id) => Some(("job", i64::from(job_id))),
+        Outcome::Started(pid) | Outcome::Running(pid) | Outcome::Stopped(pid) => Some(("pid", i64::from(pid))),
+        Outcome::AwaitingApproval(count) => Some(("approvals", i64::from(count))),
+        _ => None
+    };
+    if let Some((name, value)) = detail {
+        object[name] = value.into();
+    }
+    if let Outcome::AwaitingConfirmation(nonce) = outcome {
+        object["nonce"] = format!("{:032x}", nonce).into();
+    }
+    if let Some(duration) = result.duration {
+        object["duration_ms"] = (duration.as_millis() as u64).into();
+    }
+    if let Some(ref output) = result.output {
+        let mut is_truncated = output.is_truncated();
+        for (name, data, len) in [("stdout", &output.output.stdout, output.stdout_len),
+                ("stderr", &output.output.stderr, output.stderr_len)] {
+            let excerpt = &data[..data.len().min(OUTPUT_EXCERPT_LEN)];
+            is_truncated |= excerpt.len() < data.len();
+            object[name] = String::from_utf8_lossy(excerpt).into();
+            object[format!("{}_bytes", name)] = len.into();
+        }
+        object["truncated"] = is_truncated.into();
+        object["descendants_running"] = output.descendants_running.into();
+    }
+    if let Some(ref collected) = result.collected {
+        object["files"] = collected.iter().map(|file| json!({
+            "path": file.path,
+            "contents": file.contents.as_ref().map(|contents| String::from_utf8_lossy(contents)),
+            "truncated": file.is_truncated
+        })).collect::<Vec<_>>().into();
+    }
+    line(object)
+}
+
+/// Runs from the history, as sent after the response to a `HISTORY` frame
+pub fn history_json(entries: &[HistoryEntry]) -> Value {
+    entries.iter().map(|entry| {
+        let mut object = json!({
+            "key": entry.key,
+            "time": chrono::DateTime::<chrono::Utc>::from(entry.start)
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "status": entry.outcome.label(),
+            "success": entry.outcome.is_success(),
+            "duration_ms": entry.duration.as_millis() as u64,
+            "uid": entry.uid
+        });
+        match entry.outcome {
+            Outcome::Completed(code) => object["code"] = code.into(),
+            Outcome::Signaled(sig) => object["signal"] = sig.into(),
+            _ => {}
+        }
+        object
+    }).collect::<Vec<_>>().into()
+}
+
+/// The response to an admin frame that sends data back, with the data under `field`
+pub fn data_line(field: &str, data: Value) -> Vec<u8> {
+    let mut object = json!({"status": "ack"});
+    object[field] = data;
+    line(object)
+}
+
+/// A response that is not for a key run by itself, such as for a frame or a skipped batch entry
+pub fn frame_line(response: &[u8]) -> Vec<u8> {
+    let status = match response {
+        [protocol::ACK_RESPONSE] => "ack",
+        [protocol::ADMIN_DENIED_RESPONSE] => "admin_denied",
+        [protocol::INVALID_FRAME_RESPONSE] => "invalid_frame",
+        [protocol::SKIPPED_RESPONSE] => "skipped",
+        [protocol::RECONNECT_RESPONSE] => "reconnect",
+        response => Outcome::from_response(response).map_or("unknown", |outcome| outcome.label())
+    };
+    line(json!({"status": status}))
+}
+
+/// The response to a `MINT` frame
+pub fn token_line(token: u128) -> Vec<u8> {
+    line(json!({"status": "minted", "token": format!("{:032x}", token)}))
+}
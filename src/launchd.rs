@@ -0,0 +1,54 @@
+//! Socket activation under launchd
+
+use std::os::unix::net::UnixListener;
+
+#[cfg(target_vendor = "apple")]
+mod ffi {
+    use std::os::raw::{c_char, c_int};
+
+    extern "C" {
+        /// Part of libSystem since macOS 10.10
+        pub fn launch_activate_socket(name: *const c_char, fds: *mut *mut c_int, cnt: *mut libc::size_t) -> c_int;
+    }
+}
+
+/// Takes the listening socket from the `Sockets` entry of the job's plist with the given name
+#[cfg(target_vendor = "apple")]
+pub fn take_listener(name: &str) -> Result<UnixListener, String> {
+    use std::ffi::CString;
+    use std::os::raw::c_int;
+    use std::os::unix::io::FromRawFd;
+
+    let c_name = CString::new(name)
+        .map_err(|_| format!("launchd socket name {} contains a null byte", name))?;
+    let mut fds_ptr: *mut c_int = std::ptr::null_mut();
+    let mut count: libc::size_t = 0;
+    // SAFETY: the out pointers are valid for writes, and on success launchd
+    // hands over an array of count descriptors that the caller must free
+    #[allow(unsafe_code)]
+    let fds = unsafe {
+        let err = ffi::launch_activate_socket(c_name.as_ptr(), &mut fds_ptr, &mut count);
+        if err != 0 {
+            return Err(format!("Could not get socket {} from launchd: {}",
+                name, std::io::Error::from_raw_os_error(err)));
+        }
+        let fds = std::slice::from_raw_parts(fds_ptr, count).to_vec();
+        libc::free(fds_ptr.cast());
+        fds
+    };
+    if fds.len() != 1 {
+        for fd in fds {
+            let _ = nix::unistd::close(fd);
+        }
+        return Err(format!("launchd socket {} must have exactly one listener", name));
+    }
+    // SAFETY: launchd passed the descriptor to this process alone
+    #[allow(unsafe_code)]
+    let listener = unsafe { UnixListener::from_raw_fd(fds[0]) };
+    Ok(listener)
+}
+
+#[cfg(not(target_vendor = "apple"))]
+pub fn take_listener(_name: &str) -> Result<UnixListener, String> {
+    Err("launchd socket activation is only supported on macOS".to_owned())
+}
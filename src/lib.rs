@@ -0,0 +1,1593 @@
+// Only run_cmd, handover, systemd, and launchd need unsafe, to set up the child's session
+// before exec and to take over inherited sockets
+#![deny(unsafe_code)]
+use argh::FromArgs;
+
+use std::path::{Path, PathBuf};
+
+use nix::unistd::Uid;
+use nix::sys::stat::Mode;
+
+use std::sync::Arc;
+
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, AsyncBufReadExt, BufReader};
+use tokio::net::UnixListener;
+use tokio::net::unix::UCred;
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{channel, Sender};
+
+use std::os::unix::process::ExitStatusExt;
+use std::os::unix::io::AsRawFd;
+
+use log::{debug, info, warn, error, log, Level};
+
+mod util;
+
+#[cfg(test)]
+mod arbitrary;
+
+mod run_cmd;
+
+mod privilege;
+
+mod config;
+use config::{Config, DeadlinePolicy, KeyConfig, ShutdownPolicy};
+#[cfg(feature = "email")]
+use config::EmailAlert;
+#[cfg(feature = "event-bus")]
+use config::EventBus;
+
+mod sha256;
+
+mod base64;
+
+mod signed_key;
+
+mod time_window;
+
+mod precondition;
+
+mod collect;
+
+mod webhook;
+
+#[cfg(feature = "event-bus")]
+mod event_bus;
+
+mod queue_file;
+
+mod journal;
+use collect::CollectedFile;
+
+mod preflight;
+use preflight::CommandIdentity;
+
+mod rate_limit;
+
+mod state;
+use state::{ConfigSnapshot, HistoryEntry, ServerState};
+
+mod audit;
+use audit::DenyReason;
+
+mod protocol;
+use protocol::{Outcome, Request};
+
+mod services;
+
+mod output_file;
+
+mod dump_config;
+
+mod list_keys;
+
+mod client;
+
+mod history;
+
+mod top;
+
+mod send;
+
+mod gen_systemd;
+
+mod socket_file;
+
+mod handover;
+
+mod systemd;
+
+mod dbus;
+
+mod builtin;
+
+mod http_client;
+
+mod forward;
+
+#[cfg(feature = "email")]
+mod email;
+
+mod launchd;
+
+mod subreaper;
+
+mod fd_limit;
+
+mod banner;
+
+mod version;
+
+mod status;
+
+mod runner;
+use runner::{CommandOutput, RunningCommand};
+
+mod failure;
+pub use failure::{Failure, FailureKind};
+
+mod logging;
+
+#[cfg(feature = "syslog")]
+mod syslog;
+
+mod transport;
+
+mod json_response;
+use transport::{Connection, TriggerTransport};
+
+#[cfg(feature = "test-harness")]
+pub mod test_harness;
+
+#[cfg(feature = "otlp")]
+mod metrics;
+#[cfg(feature = "otlp")]
+mod otlp;
+
+use std::ops::Deref;
+use std::time::{Duration, Instant, SystemTime};
+
+
+/// Describes how much output `max_output_bytes` discarded, for the line logged when a command finishes
+fn truncation_note(output: &CommandOutput) -> String {
+    match output.is_truncated() {
+        true => format!(", with its output truncated from {} bytes of stdout and {} bytes of stderr",
+            output.stdout_len, output.stderr_len),
+        false => String::new()
+    }
+}
+
+/// Logs how a command finished, saves its output, and returns the corresponding outcome
+async fn finish_command(key_config: &KeyConfig, command_output: &CommandOutput) -> Outcome {
+    let cmd = &key_config.cmd;
+    let output = &command_output.output;
+    let truncation_note = truncation_note(command_output);
+    if command_output.descendants_running {
+        warn!("Command {:?} exited, but processes it started kept its output open for over {}ms; stopped reading it",
+            cmd, key_config.output_grace.as_millis());
+    }
+    let (outcome, log_output_level) = match output.status.code() {
+        Some(exit_code) => {
+            let finish_level = match exit_code {
+                0 => Level::Info,
+                _ => Level::Warn
+            };
+            log!(finish_level, "Command {:?} exited with code {}{}", cmd, exit_code, truncation_note);
+            (Outcome::Completed(exit_code), match exit_code {
+                0 => key_config.output_log_level,
+                _ => Level::Warn
+            })
+        },
+        None => {
+            // Unwrap works because process was terminated by signal by this point
+            let sig = output.status.signal().unwrap();
+            warn!("Command {:?} terminated by signal {}{}", cmd, sig, truncation_note);
+            (Outcome::Signaled(sig), Level::Warn)
+        }
+    };
+    if key_config.log_output {
+        for (name, data, len) in [("stdout", &output.stdout, command_output.stdout_len),
+                ("stderr", &output.stderr, command_output.stderr_len)] {
+            match len > data.len() as u64 {
+                true => log!(log_output_level, "{} for {:?}, first {} of {} bytes:\n{}",
+                    name, cmd, data.len(), len, String::from_utf8_lossy(data)),
+                false => log!(log_output_level, "{} for {:?}:\n{}", name, cmd, String::from_utf8_lossy(data))
+            }
+        }
+    }
+    let files: Vec<_> = [(&key_config.stdout, &output.stdout), (&key_config.stderr, &output.stderr)].into_iter()
+        .filter_map(|(path, data)| Some((path.clone()?, data.clone())))
+        .collect();
+    if !files.is_empty() {
+        // Appending, and rotating the files, blocks on the disk
+        let rotation = key_config.rotate;
+        let cmd = cmd.clone();
+        tokio::task::spawn_blocking(move || for (path, data) in files {
+            if let Err(e) = output_file::append(&path, rotation.as_ref(), &data) {
+                error!("Could not write output of {:?} to {}: {}", cmd, path.display(), e);
+            }
+        }).await.expect("Output writing task panicked");
+    }
+    outcome
+}
+
+/// Runs the key's `on_timeout` hook in the background, if it has one
+fn run_timeout_hook(key: &str, key_config: &KeyConfig, pid: Option<u32>, elapsed: Duration) {
+    let hook = match key_config.on_timeout {
+        Some(ref hook) => hook.clone(),
+        None => return
+    };
+    let key_config = key_config.clone();
+    let mut env = vec![
+        ("TRIGGER_KEY", key.to_owned()),
+        ("TRIGGER_ELAPSED_MS", elapsed.as_millis().to_string())
+    ];
+    env.extend(pid.map(|pid| ("TRIGGER_PID", pid.to_string())));
+    let key = key.to_owned();
+    tokio::spawn(async move {
+        match run_cmd::run_hook(&hook, &key_config, &env, false).await {
+            Ok(output) if output.status.success() => info!("Ran on_timeout hook of key {}", key),
+            Ok(output) => warn!("on_timeout hook of key {} failed with {}:\n{}",
+                key, output.status, String::from_utf8_lossy(&output.stderr)),
+            Err(e) => error!("Could not run on_timeout hook of key {}: {}", key, e)
+        }
+    });
+}
+
+/// How waiting for a command ended
+enum Waited {
+    Exited(std::io::Result<CommandOutput>),
+    /// Killed for exceeding the key's timeout
+    TimedOut,
+    /// Killed once the shutdown timeout passed
+    KilledAtShutdown,
+    /// Left running because the daemon is stopping
+    LeftRunning
+}
+
+/// The key a message asks for, with surrounding whitespace removed if the config says so
+fn requested_key<'a>(config: &Config, key_bytes: &'a [u8]) -> &'a [u8] {
+    match config.trim_keys {
+        true => key_bytes.trim_ascii(),
+        false => key_bytes
+    }
+}
+
+/// Handles a single key read from the socket
+///
+/// If a deadline is given, the command is killed or left running in the
+/// background once it passes, depending on the key's `on_deadline` setting.
+/// Likewise for `on_shutdown` once the daemon starts stopping.
+/// Also returns the start time and duration of the command if it was spawned
+/// and did not outlive the deadline, and its output if it exited.
+async fn process_request(state: &ServerState, snapshot: &ConfigSnapshot, peer: Option<&UCred>,
+        key_bytes: &[u8], vouched: Vouched, deadline: Option<Duration>, payload: Option<&[u8]>)
+        -> (Outcome, Option<(SystemTime, Duration)>, Option<CommandOutput>) {
+    let peer_uid = peer.map(|cred| cred.uid());
+    let key_bytes = requested_key(&snapshot.config, key_bytes);
+    if key_bytes.is_empty() {
+        warn!("Received an empty key");
+        return (Outcome::EmptyKey, None, None);
+    }
+    let key_str = match std::str::from_utf8(key_bytes) {
+        Ok(s) => s,
+        Err(_) => {
+            // Wouldn't match our keys anyways
+            let (reason, outcome) = match snapshot.rate_limiter.check(peer_uid, None) {
+                Ok(()) => (DenyReason::UnknownKey, Outcome::UnknownKey),
+                Err(_) => (DenyReason::Throttled, Outcome::Throttled)
+            };
+            audit::denied(reason, peer, key_bytes);
+            return (outcome, None, None);
+        }
+    };
+    // Keys the peer may not see are treated as unknown, so that their existence is not revealed
+    let peer_ids = peer.map(|cred| (cred.uid(), cred.gid()));
+    let key_config = snapshot.config.keys.get(key_str)
+        .filter(|_| snapshot.config.is_visible(key_str, peer_ids));
+    let companion = services::parse_companion(key_str)
+        .filter(|_| key_config.is_none())
+        .filter(|(base, _)| snapshot.config.is_visible(base, peer_ids))
+        .filter(|(base, _)| snapshot.config.keys.get(*base).is_some_and(|k| k.detach)
+            || state.services.is_tracked(base));
+    let rate_limited = match vouched.is_admitted {
+        true => Ok(()),
+        false => snapshot.rate_limiter.check(peer_uid, key_config.and(Some(key_str)))
+    };
+    if let Err(limit) = rate_limited {
+        debug!("Request for key {} hit the {} rate limit", key_str, limit);
+        audit::denied(DenyReason::Throttled, peer, key_bytes);
+        return (Outcome::Throttled, None, None);
+    }
+    if let Some((base, op)) = companion {
+        info!("Received {:?} for detached key {}", op, base);
+        return (state.services.companion(base, op), None, None);
+    }
+    let key_config = match key_config {
+        Some(key_config) => key_config,
+        None => {
+            let trimmed = key_str.trim_ascii();
+            if trimmed != key_str && snapshot.config.keys.contains_key(trimmed)
+                    && snapshot.config.is_visible(trimmed, peer_ids) {
+                warn!("Key {:?} only matches {} without its surrounding whitespace; set trim_keys to accept it",
+                    key_str, trimmed);
+            }
+            audit::denied(DenyReason::UnknownKey, peer, key_bytes);
+            return (Outcome::UnknownKey, None, None);
+        }
+    };
+
+    if !state.is_enabled(key_str, key_config) {
+        info!("Refusing disabled key {}", key_str);
+        return (Outcome::Disabled, None, None);
+    }
+    if !key_config.allowed_windows.is_empty() && !time_window::is_open(&key_config.allowed_windows) {
+        info!("Refusing key {} outside of its allowed windows", key_str);
+        return (Outcome::OutsideWindow, None, None);
+    }
+    if !vouched.is_admitted && key_config.daily_budget.is_some_and(|budget|
+            !state.budgets.has_left(key_str, budget, snapshot.config.budget_reset_hour)) {
+        info!("Refusing key {}, which has used up its daily budget", key_str);
+        return (Outcome::BudgetExhausted, None, None);
+    }
+    if let Err(e) = builtin::check_payload(key_config, payload) {
+        warn!("Refusing key {}, since {}", key_str, e);
+        return (Outcome::PayloadRejected, None, None);
+    }
+    if key_config.confirm && !vouched.is_confirmed {
+        return match state.confirmations.mint(key_str, protocol::CONFIRM_WINDOW) {
+            Ok(nonce) => {
+                info!("Asking for confirmation of key {}", key_str);
+                (Outcome::AwaitingConfirmation(nonce), None, None)
+            },
+            Err(e) => {
+                error!("Could not make a nonce to confirm key {}: {}", key_str, e);
+                (Outcome::SpawnFailed, None, None)
+            }
+        };
+    }
+    if let Some(approvals) = key_config.approvals.as_ref().filter(|_| !vouched.is_admitted) {
+        // Peers that cannot be told apart cannot approve
+        let Some(uid) = peer_uid else {
+            warn!("Refusing key {}, since it needs approvals and the peer has no credentials", key_str);
+            audit::denied(DenyReason::UnknownKey, peer, key_bytes);
+            return (Outcome::UnknownKey, None, None);
+        };
+        if let Some(count) = state.approvals.approve(key_str, uid, approvals) {
+            warn!("Key {} approved by uid {}, with {} of {} approvals", key_str, uid, count, approvals.count);
+            return (Outcome::AwaitingApproval(count), None, None);
+        }
+        warn!("Key {} approved by uid {}, which completes its approvals", key_str, uid);
+    }
+    if state.defer(key_bytes, peer, vouched.is_confirmed, payload, snapshot.config.queue_during_maintenance) {
+        info!("Deferring key {} during maintenance", key_str);
+        return (Outcome::Deferred, None, None);
+    }
+    for precondition in &key_config.preconditions {
+        if let Err(e) = precondition.check(key_str, key_config, state).await {
+            warn!("Refusing key {}, since a precondition failed: {}", key_str, e);
+            return (Outcome::PreconditionFailed, None, None);
+        }
+    }
+    info!("Received matching key {}", key_str);
+    if let Some(ref action) = key_config.builtin {
+        let action_start = SystemTime::now();
+        let action_timer = Instant::now();
+        let _running = state.running.insert(key_str, None);
+        // Nothing is killed, since the action may be carried out by someone else
+        let time_limit = [key_config.timeout, deadline].into_iter().flatten().min()
+            .or_else(|| builtin::default_time_limit(action));
+        let outcome = match time_limit {
+            Some(limit) => match tokio::time::timeout(limit, builtin::run(key_str, action, payload)).await {
+                Ok(outcome) => outcome,
+                Err(_) => {
+                    warn!("Stopped waiting for key {} after {}ms", key_str, limit.as_millis());
+                    Outcome::TimedOut
+                }
+            },
+            None => builtin::run(key_str, action, payload).await
+        };
+        return (outcome, Some((action_start, action_timer.elapsed())), None);
+    }
+    let cmd = &key_config.cmd;
+    if let Some(expected) = key_config.sha256 {
+        if let Err(e) = run_cmd::verify_executable(cmd, key_config.cwd.as_deref(), expected).await {
+            error!("Refusing to run {:?}: {}", cmd, e);
+            return (Outcome::HashMismatch, None, None);
+        }
+    }
+    if key_config.detach {
+        return (state.services.start(key_str, key_config), None, None);
+    }
+    let command_start = SystemTime::now();
+    let command_timer = Instant::now();
+    let kill_on_drop = key_config.timeout.is_some()
+        || (deadline.is_some() && key_config.on_deadline == DeadlinePolicy::Kill)
+        || key_config.on_shutdown == ShutdownPolicy::Kill;
+    let RunningCommand {pid, output} = match state.runner.start(key_config, kill_on_drop) {
+        Ok(command) => command,
+        Err(e) => {
+            error!("Error starting command: {}", e);
+            return (Outcome::SpawnFailed, None, None);
+        }
+    };
+    let timeout = key_config.timeout;
+    let on_shutdown = key_config.on_shutdown;
+    let shutdown_timeout = snapshot.config.shutdown_timeout;
+    let halted = state.halted();
+    if let Some(pid) = pid {
+        subreaper::name_group(pid, key_str);
+    }
+    let running = state.running.insert(key_str, pid);
+    #[cfg(feature = "otlp")]
+    let running_guard = metrics::RunningGuard::new(key_str);
+    let wait = async move {
+        let _running = running;
+        #[cfg(feature = "otlp")]
+        let _running_guard = running_guard;
+        // Dropping the output future kills the child
+        let mut output = Box::pin(async move {
+            match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, output).await.ok(),
+                None => Some(output.await)
+            }
+        });
+        let stop_waiting = async move {
+            match on_shutdown {
+                ShutdownPolicy::Wait => std::future::pending().await,
+                ShutdownPolicy::Kill => {
+                    halted.await;
+                    tokio::time::sleep(shutdown_timeout).await;
+                },
+                ShutdownPolicy::Detach => halted.await
+            }
+        };
+        select! {
+            output = &mut output => match output {
+                Some(result) => Waited::Exited(result),
+                None => Waited::TimedOut
+            },
+            () = stop_waiting => match on_shutdown {
+                ShutdownPolicy::Detach => {
+                    // Leaked rather than dropped so that the command is not killed,
+                    // even if it would be on drop; the daemon is about to exit anyway
+                    std::mem::forget(output);
+                    Waited::LeftRunning
+                },
+                _ => Waited::KilledAtShutdown
+            }
+        }
+    };
+    let waited = match (deadline, key_config.on_deadline) {
+        (None, _) => wait.await,
+        (Some(deadline), DeadlinePolicy::Kill) => match tokio::time::timeout(deadline, wait).await {
+            Ok(waited) => waited,
+            Err(_) => {
+                warn!("Command {:?} killed after exceeding the {}ms deadline", cmd, deadline.as_millis());
+                return (Outcome::TimedOut, Some((command_start, command_timer.elapsed())), None);
+            }
+        },
+        (Some(deadline), DeadlinePolicy::Detach) => {
+            let mut wait_task = tokio::spawn(wait);
+            match tokio::time::timeout(deadline, &mut wait_task).await {
+                Ok(waited) => waited.expect("Command wait task panicked"),
+                Err(_) => {
+                    let job_id = state.next_job_id();
+                    info!("Command {:?} exceeded the {}ms deadline and continues as job {}",
+                        cmd, deadline.as_millis(), job_id);
+                    if let Some(pid) = pid {
+                        state.jobs.insert(job_id, pid);
+                    }
+                    let jobs = state.jobs.clone();
+                    // Taken before spawning so that stopping cannot miss the job
+                    let supervised = jobs.supervise();
+                    // Retained for the peer, so that its result can be waited for with a `JOB` frame
+                    let uid = peer.map(|cred| cred.uid());
+                    let result_id = state::job_result_id(job_id);
+                    state.retained.retain(uid, &result_id, key_str.as_bytes());
+                    let retained = state.retained.clone();
+                    let key = key_str.to_owned();
+                    let key_config = key_config.clone();
+                    tokio::spawn(async move {
+                        let _supervised = supervised;
+                        let (outcome, output) = match wait_task.await.expect("Command wait task panicked") {
+                            Waited::Exited(Ok(output)) => {
+                                let outcome = finish_command(&key_config, &output).await;
+                                info!("Job {} finished as {}", job_id, outcome.label());
+                                (outcome, Some(output))
+                            },
+                            Waited::Exited(Err(e)) => {
+                                error!("Error waiting for job {}: {}", job_id, e);
+                                (Outcome::SpawnFailed, None)
+                            },
+                            Waited::TimedOut => {
+                                warn!("Job {} killed after exceeding its timeout", job_id);
+                                run_timeout_hook(&key, &key_config, pid, command_timer.elapsed());
+                                (Outcome::TimedOut, None)
+                            },
+                            Waited::KilledAtShutdown => {
+                                warn!("Job {} killed after exceeding the shutdown timeout", job_id);
+                                (Outcome::TimedOut, None)
+                            },
+                            Waited::LeftRunning => {
+                                // Kept in the table so that an upgraded daemon adopts it
+                                info!("Job {} left running at shutdown", job_id);
+                                return;
+                            }
+                        };
+                        let duration = Some(command_timer.elapsed());
+                        retained.finish(uid, &result_id, Arc::new(KeyResult {outcome, duration, output, collected: None}));
+                        jobs.remove(job_id);
+                    });
+                    return (Outcome::Detached(job_id), None, None);
+                }
+            }
+        }
+    };
+    let output = match waited {
+        Waited::Exited(Ok(output)) => output,
+        Waited::Exited(Err(e)) => {
+            error!("Error waiting for command: {}", e);
+            return (Outcome::SpawnFailed, None, None);
+        },
+        Waited::TimedOut => {
+            warn!("Command {:?} killed after exceeding its timeout", cmd);
+            run_timeout_hook(key_str, key_config, pid, command_timer.elapsed());
+            return (Outcome::TimedOut, Some((command_start, command_timer.elapsed())), None);
+        },
+        Waited::KilledAtShutdown => {
+            warn!("Command {:?} killed after exceeding the {}ms shutdown timeout", cmd, shutdown_timeout.as_millis());
+            return (Outcome::TimedOut, Some((command_start, command_timer.elapsed())), None);
+        },
+        Waited::LeftRunning => {
+            let job_id = state.next_job_id();
+            info!("Command {:?} left running as job {} at shutdown", cmd, job_id);
+            if let Some(pid) = pid {
+                state.jobs.insert(job_id, pid);
+            }
+            return (Outcome::Detached(job_id), None, None);
+        }
+    };
+    let command_timing = (command_start, command_timer.elapsed());
+    #[cfg(feature = "email")]
+    if snapshot.config.email_alert.is_some() {
+        state.failure_streaks.set_stderr(key_str, &output.output.stderr);
+    }
+    (finish_command(key_config, &output).await, Some(command_timing), Some(output))
+}
+
+/// Logs how a request that ran its key went, with the details as fields for syslog
+fn log_result(config: &Config, peer: Option<&UCred>, key_bytes: &[u8], outcome: Outcome, duration: Duration) {
+    let key = String::from_utf8_lossy(requested_key(config, key_bytes));
+    let mut fields = vec![
+        ("key", key.clone().into_owned()),
+        ("outcome", outcome.label().to_owned()),
+        ("duration_ms", duration.as_millis().to_string())
+    ];
+    match outcome {
+        Outcome::Completed(code) => fields.push(("exit", code.to_string())),
+        Outcome::Signaled(sig) => fields.push(("signal", sig.to_string())),
+        _ => {}
+    }
+    if let Some(peer) = peer {
+        fields.push(("uid", peer.uid().to_string()));
+        fields.extend(peer.pid().map(|pid| ("pid", pid.to_string())));
+    }
+    let level = match outcome.is_success() {
+        true => Level::Info,
+        false => Level::Warn
+    };
+    #[cfg(feature = "syslog")]
+    syslog::log_result(level, &fields, format_args!("Key {} finished as {} after {:.3}s",
+        key, outcome.label(), duration.as_secs_f64()));
+    #[cfg(not(feature = "syslog"))]
+    log!(level, "Key {} finished as {} after {:.3}s", key, outcome.label(), duration.as_secs_f64());
+}
+
+/// Emails the operators about a key that reached the configured number of failures in a row
+#[cfg(feature = "email")]
+async fn send_failure_alert(alert: EmailAlert, key: String, outcome: Outcome, stderr: Vec<u8>) {
+    let (subject, body) = email::failure_alert(&key, alert.after_failures, outcome, &stderr);
+    match email::send(&alert, &subject, &body).await {
+        Ok(()) => info!("Emailed {} about {} failures in a row of key {}", alert.to.join(", "), alert.after_failures, key),
+        Err(e) => error!("Could not email about failures of key {}: {}", key, e)
+    }
+}
+
+#[cfg(feature = "event-bus")]
+async fn publish_event(bus: EventBus, key: String, event: Vec<u8>) {
+    if let Err(e) = event_bus::publish(&bus, &event).await {
+        warn!("Could not publish the event for key {}: {}", key, e);
+    }
+}
+
+/// How a request for a key went, with the details a JSON response gives
+#[derive(Debug)]
+struct KeyResult {
+    outcome: Outcome,
+    /// How long the command or action ran, if it was started and did not outlive the deadline
+    duration: Option<Duration>,
+    /// The output of the command, if it exited
+    output: Option<CommandOutput>,
+    /// The files the key collects, if they were asked for, or empty if the command did not exit
+    collected: Option<Vec<CollectedFile>>
+}
+
+/// What let a request through before its key is looked up
+#[derive(Debug, Clone, Copy)]
+struct Vouched {
+    /// Whether a nonce or a token stood in for the key's confirmation
+    is_confirmed: bool,
+    /// Whether the key got through its rate limits, daily budget, and approvals before
+    /// it was deferred, so that they are not charged or asked for again
+    is_admitted: bool
+}
+
+/// Runs a single key and records how it went
+///
+/// A key that comes `vouched` for, such as one deferred during maintenance, is
+/// the name it was resolved to before, and is not resolved again, since the
+/// bare name of a key that needs a signature would be refused.
+async fn run_key(state: &ServerState, peer: Option<&UCred>, key_bytes: &[u8], vouched: Option<Vouched>,
+        deadline: Option<Duration>, payload: Option<&[u8]>, collect: bool) -> KeyResult {
+    #[cfg(feature = "otlp")]
+    let request_start = (SystemTime::now(), Instant::now());
+
+    // Take a new snapshot for every request so that reloads apply to open connections
+    let snapshot = state.snapshot();
+    let key_bytes = requested_key(&snapshot.config, key_bytes);
+    // Keys run by a token were vouched for by an admin, so they need no signature or confirmation
+    let key_str = std::str::from_utf8(key_bytes).ok().filter(|_| vouched.is_none());
+    let redeemed = key_str.and_then(|key| key.strip_prefix(protocol::TOKEN_PREFIX))
+        .map(|token| (state.tokens.redeem(token), DenyReason::BadToken, "Token"));
+    let confirmed = key_str.and_then(|key| key.strip_prefix(protocol::CONFIRM_PREFIX))
+        .map(|nonce| (state.confirmations.redeem(nonce), DenyReason::BadNonce, "Nonce"));
+    let redemption = redeemed.or(confirmed);
+    let resolved = match redemption {
+        Some((Some(ref key), _, what)) => {
+            info!("{} redeemed for key {}", what, key);
+            Ok((key.as_bytes(), Vouched {is_confirmed: true, is_admitted: false}))
+        },
+        Some((None, reason, what)) => Err((reason, format!("{} is unknown, expired, or already used", what))),
+        None => match vouched {
+            Some(vouched) => Ok((key_bytes, vouched)),
+            None => signed_key::resolve(&snapshot.config, key_bytes, SystemTime::now())
+                .map(|key_bytes| (key_bytes, Vouched {is_confirmed: false, is_admitted: false}))
+                .map_err(|e| (DenyReason::BadSignature, e))
+        }
+    };
+    let (key_bytes, (outcome, command_timing, output)) = match resolved {
+        Ok((key_bytes, vouched)) => (key_bytes,
+            process_request(state, &snapshot, peer, key_bytes, vouched, deadline, payload).await),
+        Err((reason, e)) => {
+            // Answered like an unknown key, so that signatures, tokens, and nonces cannot be probed
+            warn!("Refusing request: {}", e);
+            audit::denied(reason, peer, key_bytes);
+            (key_bytes, (Outcome::UnknownKey, None, None))
+        }
+    };
+    state.record_outcome(outcome);
+    if let Some((start, duration)) = command_timing {
+        log_result(&snapshot.config, peer, key_bytes, outcome, duration);
+        state.history.record(HistoryEntry {
+            key: String::from_utf8_lossy(requested_key(&snapshot.config, key_bytes)).into_owned(),
+            start,
+            duration,
+            outcome,
+            uid: peer.map(|cred| cred.uid())
+        });
+        let key = std::str::from_utf8(key_bytes).ok()
+            .filter(|key| snapshot.config.keys.get(*key).is_some_and(|key_config| key_config.daily_budget.is_some()));
+        if let Some(key) = key {
+            state.budgets.spend(key, duration, snapshot.config.budget_reset_hour);
+        }
+        #[cfg(feature = "email")]
+        if let Some(ref alert) = snapshot.config.email_alert {
+            let key = String::from_utf8_lossy(requested_key(&snapshot.config, key_bytes)).into_owned();
+            if let Some(stderr) = state.failure_streaks.record(&key, outcome, alert.after_failures) {
+                tokio::spawn(send_failure_alert(alert.clone(), key, outcome, stderr));
+            }
+        }
+    }
+    #[cfg(feature = "otlp")]
+    {
+        let key_bytes = requested_key(&snapshot.config, key_bytes);
+        let configured_key = std::str::from_utf8(key_bytes).ok()
+            .and_then(|key| Some((key, snapshot.config.keys.get(key)?.tags.as_slice())))
+            // Keys hidden from the peer count as unknown
+            .filter(|_| outcome != Outcome::UnknownKey);
+        metrics::record_request(configured_key, peer.map(|cred| cred.uid()), outcome,
+            command_timing.map(|(_, duration)| duration));
+        otlp::record_request(&String::from_utf8_lossy(key_bytes), outcome,
+            request_start.0, request_start.1.elapsed(), command_timing);
+    }
+    let has_exited = matches!(outcome, Outcome::Completed(_) | Outcome::Signaled(_));
+    let collected = match collect {
+        true => {
+            let to_collect = std::str::from_utf8(key_bytes).ok()
+                .and_then(|key| snapshot.config.keys.get(key))
+                .filter(|key_config| has_exited && !key_config.collect.is_empty())
+                .map(|key_config| (key_config.collect.clone(), key_config.collect_max_bytes));
+            Some(match to_collect {
+                Some((paths, max_bytes)) => tokio::task::spawn_blocking(move || collect::read(&paths, max_bytes))
+                    .await.expect("File collecting task panicked"),
+                None => Vec::new()
+            })
+        },
+        false => None
+    };
+    let result = KeyResult {outcome, duration: command_timing.map(|(_, duration)| duration), output, collected};
+    // Only runs are published, not requests that were refused before anything ran
+    #[cfg(any(feature = "http", feature = "event-bus"))]
+    if let Some(key) = std::str::from_utf8(key_bytes).ok().filter(|_| command_timing.is_some()) {
+        let variables = webhook::variables(key, &result);
+        #[cfg(feature = "event-bus")]
+        if let Some(ref bus) = snapshot.config.event_bus {
+            let event = serde_json::Value::Object(variables.clone()).to_string().into_bytes();
+            tokio::spawn(publish_event(bus.clone(), key.to_owned(), event));
+        }
+        #[cfg(feature = "http")]
+        if let Some(hook) = snapshot.config.keys.get(key).and_then(|key_config| key_config.webhook.as_ref()) {
+            let body = match hook.body {
+                Some(ref template) => webhook::render(template, &variables),
+                None => serde_json::Value::Object(variables)
+            };
+            tokio::spawn(webhook::send(hook.clone(), key.to_owned(), body));
+        }
+    }
+    result
+}
+
+/// Whether the peer may send admin frames: only root and the daemon's own user can
+fn is_admin(peer: Option<&UCred>) -> bool {
+    peer.is_some_and(|cred| cred.uid() == 0 || cred.uid() == Uid::effective().as_raw())
+}
+
+fn set_maintenance(state: &ServerState, is_active: bool) {
+    state.set_maintenance(is_active);
+    warn!("Maintenance mode {}", if is_active {"started"} else {"ended"});
+}
+
+/// Queues the triggers left in the `queue_file` by the previous daemon process
+fn resume_queued(state: &ServerState) -> Result<(), String> {
+    let snapshot = state.snapshot();
+    if let Some(ref path) = snapshot.config.queue_file {
+        let triggers = queue_file::load(path, snapshot.config.queue_max_age)?;
+        if !triggers.is_empty() {
+            info!("Resuming {} triggers from {}", triggers.len(), path.display());
+        }
+        state.requeue(triggers);
+    }
+    Ok(())
+}
+
+/// Runs the triggers queued during maintenance, in the order they arrived
+async fn run_deferred(state: Arc<ServerState>, _send_token: Sender<()>) {
+    let deferred = state.take_deferred();
+    info!("Running {} triggers deferred during maintenance", deferred.len());
+    for trigger in deferred {
+        // Keys are queued under the name they resolved to, once their signatures were checked
+        let vouched = Vouched {is_confirmed: trigger.is_confirmed, is_admitted: trigger.is_admitted};
+        run_key(&state, trigger.peer.as_ref(), &trigger.key, Some(vouched), None, trigger.payload.as_deref(), false).await;
+    }
+}
+
+/// Reads one null-terminated message into the buffer, returning false at end of stream
+///
+/// On an error the buffer may end partway through a message, and the rest of
+/// it is still unread, so the connection cannot be used for further requests.
+async fn read_message(stream: &mut (impl AsyncBufRead + Unpin), buf: &mut Vec<u8>) -> std::io::Result<bool> {
+    buf.clear();
+    if stream.read_until(b'\0', buf).await? == 0 {
+        return Ok(false);
+    }
+    // The terminator is missing if the stream ended partway through a message
+    if buf.last() == Some(&b'\0') {
+        buf.pop();
+    }
+    Ok(true)
+}
+
+/// Reads the message that a frame applies to, or returns `None` if the
+/// connection ended or broke first, after logging why
+async fn read_following(stream: &mut (impl AsyncBufRead + Unpin), max_key_len: usize, frame: &str) -> Option<Vec<u8>> {
+    let mut buf = Vec::with_capacity(max_key_len+1);
+    match read_message(stream, &mut buf).await {
+        Ok(true) => Some(buf),
+        Ok(false) => {
+            warn!("Connection closed before the key following {}", frame);
+            None
+        },
+        Err(e) => {
+            error!("Could not read from socket: {}", e);
+            None
+        }
+    }
+}
+
+/// The response to a key, in the connection's format
+fn key_response(state: &ServerState, is_json: bool, key_bytes: &[u8], result: &KeyResult) -> Vec<u8> {
+    match is_json {
+        true => json_response::key_line(key_bytes, result),
+        false => {
+            let mut response = state.response(result.outcome);
+            if let Some(ref collected) = result.collected {
+                response.extend(collect::encode(collected));
+            }
+            response
+        }
+    }
+}
+
+/// The response to a retained result, once it is finished
+async fn retained_response(state: &ServerState, is_json: bool, retained: state::Retained) -> Vec<u8> {
+    let key = retained.key.clone();
+    match retained.wait().await {
+        Some(result) => key_response(state, is_json, &key, &result),
+        None => match is_json {
+            true => json_response::frame_line(&Outcome::UnknownKey.response()),
+            false => state.response(Outcome::UnknownKey)
+        }
+    }
+}
+
+/// The response to a frame, given in the standard vocabulary, in the connection's format
+fn frame_response(is_json: bool, response: Vec<u8>) -> Vec<u8> {
+    match is_json {
+        true => json_response::frame_line(&response),
+        false => response
+    }
+}
+
+/// The response to an admin frame that sends data back: "A", a big-endian
+/// `u32` length, and the JSON, or in JSON mode an ack with the JSON under `field`
+fn data_response(is_json: bool, field: &str, data: serde_json::Value) -> Vec<u8> {
+    if is_json {
+        return json_response::data_line(field, data);
+    }
+    let data = data.to_string().into_bytes();
+    let mut response = vec![protocol::ACK_RESPONSE];
+    response.extend((data.len() as u32).to_be_bytes());
+    response.extend(data);
+    response
+}
+
+/// How long a response may take to be written before the connection is given up on
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Shortest and longest pause in accepting connections after running out of file descriptors
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Whether accepting failed because the process or system has no file descriptors left
+fn is_fd_exhaustion(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE))
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(state: impl Deref<Target=ServerState>,
+        connection: Connection<S>, _send_token: Sender<()>) {
+    debug!("Establishing connection");
+    let _open = state.open_connection();
+    let max_key_len = state.snapshot().max_key_len;
+    let max_requests = state.snapshot().config.max_requests_per_connection;
+    let peer = connection.peer;
+
+    // Reads and writes go through separate halves, so that writing a response
+    // leaves any requests already buffered, such as those of a pipelining
+    // client, where the next read finds them
+    let (reader, mut writer) = tokio::io::split(connection.stream);
+    let mut reader = BufReader::new(reader);
+
+    // One buffer is reused for every request on the connection
+    let mut key_vec: Vec<u8> = Vec::with_capacity(max_key_len+1);
+    let mut is_json = false;
+    let mut requests: u32 = 0;
+    // Null byte scanning works because UTF-8 does not have nulls
+    'connection: loop {
+        match read_message(&mut reader, &mut key_vec).await {
+            Ok(false) => {
+                break;
+            },
+            Ok(true) => {},
+            Err(e) => {
+                // No interrupted errors occur here, so the stream is unusable
+                error!("Could not read from socket: {}", e);
+                break;
+            }
+        };
+        let mut response = match protocol::parse_request(&key_vec) {
+            Ok(Request::Key(key_bytes)) => {
+                let result = run_key(&state, peer.as_ref(), key_bytes, None, None, None, false).await;
+                key_response(&state, is_json, key_bytes, &result)
+            },
+            Ok(Request::Deadline(deadline)) => {
+                let Some(deadline_key) = read_following(&mut reader, max_key_len, "a deadline").await else {
+                    break 'connection;
+                };
+                let result = run_key(&state, peer.as_ref(), &deadline_key, None, Some(deadline), None, false).await;
+                key_response(&state, is_json, &deadline_key, &result)
+            },
+            Ok(Request::Collect) => {
+                let Some(collect_key) = read_following(&mut reader, max_key_len, "a COLLECT frame").await else {
+                    break 'connection;
+                };
+                let result = run_key(&state, peer.as_ref(), &collect_key, None, None, None, true).await;
+                key_response(&state, is_json, &collect_key, &result)
+            },
+            Ok(Request::Retain(id)) => {
+                let id = id.to_owned();
+                let Some(retain_key) = read_following(&mut reader, max_key_len, "a RETAIN frame").await else {
+                    break 'connection;
+                };
+                let uid = peer.map(|cred| cred.uid());
+                match state.retained.retain(uid, &id, &retain_key) {
+                    // A client retrying after losing its connection gets the first result instead of a second run
+                    Some(retained) => {
+                        debug!("Answering RETAIN {} with its retained result", id);
+                        retained_response(&state, is_json, retained).await
+                    },
+                    None => {
+                        let result = Arc::new(run_key(&state, peer.as_ref(), &retain_key, None, None, None, false).await);
+                        state.retained.finish(uid, &id, result.clone());
+                        key_response(&state, is_json, &retain_key, &result)
+                    }
+                }
+            },
+            Ok(Request::Fetch(id)) => {
+                match state.retained.fetch(peer.map(|cred| cred.uid()), id) {
+                    Some(retained) => retained_response(&state, is_json, retained).await,
+                    None => match is_json {
+                        true => json_response::frame_line(&Outcome::UnknownKey.response()),
+                        false => state.response(Outcome::UnknownKey)
+                    }
+                }
+            },
+            Ok(Request::Job(job_id)) => {
+                match state.retained.fetch(peer.map(|cred| cred.uid()), &state::job_result_id(job_id)) {
+                    Some(retained) => retained_response(&state, is_json, retained).await,
+                    None => match is_json {
+                        true => json_response::frame_line(&Outcome::UnknownKey.response()),
+                        false => state.response(Outcome::UnknownKey)
+                    }
+                }
+            },
+            Ok(Request::Acknowledge(id)) => {
+                match state.retained.acknowledge(peer.map(|cred| cred.uid()), id) {
+                    true => frame_response(is_json, vec![protocol::ACK_RESPONSE]),
+                    false => match is_json {
+                        true => json_response::frame_line(&Outcome::UnknownKey.response()),
+                        false => state.response(Outcome::UnknownKey)
+                    }
+                }
+            },
+            Ok(Request::Payload(len)) => {
+                let mut payload = vec![0; len];
+                if let Err(e) = reader.read_exact(&mut payload).await {
+                    warn!("Could not read the {} byte payload: {}", len, e);
+                    break 'connection;
+                }
+                let Some(payload_key) = read_following(&mut reader, max_key_len, "a payload").await else {
+                    break 'connection;
+                };
+                let result = run_key(&state, peer.as_ref(), &payload_key, None, None, Some(&payload), false).await;
+                key_response(&state, is_json, &payload_key, &result)
+            },
+            Ok(Request::SizedKey(len)) => {
+                let mut key_bytes = vec![0; len];
+                if let Err(e) = reader.read_exact(&mut key_bytes).await {
+                    warn!("Could not read the {} byte key: {}", len, e);
+                    break 'connection;
+                }
+                // Bytes that are not a configured base64: key are looked up like any other key
+                if let Some(name) = state.snapshot().config.binary_keys.get(&key_bytes) {
+                    key_bytes = name.as_ref().as_bytes().to_vec();
+                }
+                let result = run_key(&state, peer.as_ref(), &key_bytes, None, None, None, false).await;
+                key_response(&state, is_json, &key_bytes, &result)
+            },
+            Ok(Request::Batch {count, stop_on_failure}) => {
+                // Receive the whole batch before running any of it
+                let mut keys = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let mut batch_key = Vec::with_capacity(max_key_len+1);
+                    match read_message(&mut reader, &mut batch_key).await {
+                        Ok(true) => keys.push(batch_key),
+                        Ok(false) => {
+                            warn!("Connection closed partway through a batch");
+                            break 'connection;
+                        },
+                        Err(e) => {
+                            error!("Could not read from socket: {}", e);
+                            break 'connection;
+                        }
+                    }
+                }
+                debug!("Running batch of {} keys", count);
+                // JSON lines need no header, since each entry is a line of its own
+                let mut response = match is_json {
+                    true => Vec::new(),
+                    false => vec![b'B', count]
+                };
+                let mut failed = false;
+                for batch_key in keys {
+                    if failed {
+                        response.extend(frame_response(is_json, vec![protocol::SKIPPED_RESPONSE]));
+                        continue;
+                    }
+                    let result = run_key(&state, peer.as_ref(), &batch_key, None, None, None, false).await;
+                    failed = stop_on_failure && !result.outcome.is_success();
+                    response.extend(key_response(&state, is_json, &batch_key, &result));
+                }
+                response
+            },
+            Ok(Request::SetEnabled {key, enabled}) => {
+                if !is_admin(peer.as_ref()) {
+                    warn!("Refusing to change whether key {} is enabled for a non-admin peer", key);
+                    frame_response(is_json, vec![protocol::ADMIN_DENIED_RESPONSE])
+                } else if !state.snapshot().config.keys.contains_key(key) {
+                    match is_json {
+                        true => json_response::frame_line(&Outcome::UnknownKey.response()),
+                        false => state.response(Outcome::UnknownKey)
+                    }
+                } else {
+                    warn!("Key {} {} by admin frame", key, if enabled {"enabled"} else {"disabled"});
+                    state.set_enabled(key, enabled);
+                    frame_response(is_json, vec![protocol::ACK_RESPONSE])
+                }
+            },
+            Ok(Request::SetMaintenance(is_active)) => {
+                if is_admin(peer.as_ref()) {
+                    set_maintenance(&state, is_active);
+                    frame_response(is_json, vec![protocol::ACK_RESPONSE])
+                } else {
+                    warn!("Refusing to change maintenance mode for a non-admin peer");
+                    frame_response(is_json, vec![protocol::ADMIN_DENIED_RESPONSE])
+                }
+            },
+            Ok(Request::RotateLog) => {
+                if !is_admin(peer.as_ref()) {
+                    warn!("Refusing to rotate the log file for a non-admin peer");
+                    frame_response(is_json, vec![protocol::ADMIN_DENIED_RESPONSE])
+                } else {
+                    match logging::rotate() {
+                        Ok(()) => {
+                            info!("Rotated the log file by admin frame");
+                            frame_response(is_json, vec![protocol::ACK_RESPONSE])
+                        },
+                        Err(e) => {
+                            error!("{}", e);
+                            frame_response(is_json, Outcome::SpawnFailed.response())
+                        }
+                    }
+                }
+            },
+            Ok(Request::MintToken {key, lifetime}) => {
+                if !is_admin(peer.as_ref()) {
+                    warn!("Refusing to mint a token for key {} for a non-admin peer", key);
+                    frame_response(is_json, vec![protocol::ADMIN_DENIED_RESPONSE])
+                } else if !state.snapshot().config.keys.contains_key(key) {
+                    match is_json {
+                        true => json_response::frame_line(&Outcome::UnknownKey.response()),
+                        false => state.response(Outcome::UnknownKey)
+                    }
+                } else {
+                    match state.tokens.mint(key, lifetime) {
+                        Ok(token) => {
+                            warn!("Minted a token for key {} valid for {}s", key, lifetime.as_secs());
+                            match is_json {
+                                true => json_response::token_line(token),
+                                false => format!("{}{:032x}", protocol::MINTED_RESPONSE as char, token).into_bytes()
+                            }
+                        },
+                        Err(e) => {
+                            error!("Could not mint a token: {}", e);
+                            match is_json {
+                                true => json_response::frame_line(&Outcome::SpawnFailed.response()),
+                                false => state.response(Outcome::SpawnFailed)
+                            }
+                        }
+                    }
+                }
+            },
+            Ok(Request::History {since, key}) => {
+                if is_admin(peer.as_ref()) {
+                    let since = since.map_or(SystemTime::UNIX_EPOCH, |since| SystemTime::now() - since);
+                    let runs = state.history.query(key, since);
+                    debug!("Sending {} runs from the history", runs.len());
+                    data_response(is_json, "runs", json_response::history_json(&runs))
+                } else {
+                    warn!("Refusing to send the history for a non-admin peer");
+                    frame_response(is_json, vec![protocol::ADMIN_DENIED_RESPONSE])
+                }
+            },
+            Ok(Request::Status) => {
+                if is_admin(peer.as_ref()) {
+                    data_response(is_json, "daemon", status::json(&state))
+                } else {
+                    warn!("Refusing to send the status for a non-admin peer");
+                    frame_response(is_json, vec![protocol::ADMIN_DENIED_RESPONSE])
+                }
+            },
+            Ok(Request::Json) => {
+                debug!("Switching connection to JSON responses");
+                is_json = true;
+                frame_response(is_json, vec![protocol::ACK_RESPONSE])
+            },
+            Err(e) => {
+                warn!("Received invalid frame: {}", e);
+                frame_response(is_json, vec![protocol::INVALID_FRAME_RESPONSE])
+            }
+        };
+        requests += 1;
+        // Anything the client sent after the last request is left unread
+        let is_last = max_requests.is_some_and(|max| requests >= max);
+        if is_last {
+            debug!("Connection reached {} requests, asking the client to reconnect", requests);
+            response.extend(frame_response(is_json, vec![protocol::RECONNECT_RESPONSE]));
+        }
+        // A client that stops reading would otherwise hold the connection open forever
+        match tokio::time::timeout(WRITE_TIMEOUT, writer.write_all(&response)).await {
+            Ok(Ok(())) => {},
+            Ok(Err(e)) => {
+                error!("Could not write to socket, closing connection: {}", e);
+                break;
+            },
+            Err(_) => {
+                error!("Client did not read its response within {}s, closing connection", WRITE_TIMEOUT.as_secs());
+                break;
+            }
+        }
+
+        if is_last || state.is_halting() {
+            break;
+        }
+    }
+    debug!("Closing connection");
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(FromArgs)]
+#[argh(description = "Start server to run commands based on keys from Unix domain socket")]
+#[argh(note = "Run `sock_trigger_cmd dump-config <config>` to print the resolved config, or
+`sock_trigger_cmd gen-systemd -- <arguments>` to generate systemd units.")]
+struct CmdArgs {
+    #[argh(switch, short = 'q')]
+    #[argh(description = "do not log to stdout")]
+    no_stdout_logs: bool,
+    #[argh(switch)]
+    #[argh(description = "do not log to a file")]
+    no_file_log: bool,
+    #[argh(switch)]
+    #[argh(description = "do not log to syslog")]
+    no_syslog: bool,
+    #[argh(option, default = "logging::LogStyle::Full", from_str_fn(logging::parse_style))]
+    #[argh(description = "format of log lines on stdout: full (the default), compact, color, or json")]
+    log_style: logging::LogStyle,
+    #[argh(option, default = "log::LevelFilter::Info", from_str_fn(logging::parse_stdout_level))]
+    #[argh(description = "most detailed level of log lines on stdout: off, error, warn, info (the default), debug, or trace")]
+    stdout_level: log::LevelFilter,
+    #[argh(option)]
+    #[argh(description = "user to switch to after binding the socket")]
+    user: Option<String>,
+    #[argh(option)]
+    #[argh(description = "group to switch to after binding the socket")]
+    group: Option<String>,
+    #[argh(switch)]
+    #[argh(description = "skip ownership and permission checks on the config file")]
+    insecure_config: bool,
+    #[argh(switch)]
+    #[argh(description = "refuse to load a config with commands that cannot be started")]
+    strict: bool,
+    #[argh(option)]
+    #[argh(description = "response profile from the config to answer clients with, instead of the standard responses")]
+    response_profile: Option<String>,
+    #[argh(option)]
+    #[argh(description = "additional file to write denial events to")]
+    audit_log: Option<PathBuf>,
+    #[argh(option)]
+    #[argh(description = "file to append a synced line to whenever a command starts, and a line when it ends")]
+    journal: Option<PathBuf>,
+    #[argh(option)]
+    #[argh(description = "number of async runtime worker threads (default: number of CPUs)")]
+    worker_threads: Option<usize>,
+    #[argh(switch)]
+    #[argh(description = "run the async runtime on the main thread only")]
+    current_thread: bool,
+    #[argh(option)]
+    #[argh(description = "name of the socket in the launchd job to listen on, instead of creating one")]
+    launchd_socket: Option<String>,
+    #[argh(switch)]
+    #[argh(description = "adopt orphaned descendants of commands, reaping them and killing them with their command (Linux only)")]
+    subreaper: bool,
+    #[argh(option, from_str_fn(socket_file::parse_dir_mode))]
+    #[argh(description = "octal mode of the socket's parent directories if they have to be created (default 755)")]
+    socket_dir_mode: Option<Mode>,
+    #[argh(option)]
+    #[argh(description = "user[:group] owning the socket's parent directories if they have to be created (default: --user and --group)")]
+    socket_dir_owner: Option<String>,
+    #[cfg(feature = "otlp")]
+    #[argh(option)]
+    #[argh(description = "OTLP/HTTP collector to export traces and metrics to, such as http://localhost:4318")]
+    otlp_endpoint: Option<String>,
+    #[cfg(feature = "otlp")]
+    #[argh(option, default = "10")]
+    #[argh(description = "seconds between OTLP exports (default 10)")]
+    otlp_interval: u64,
+    #[argh(positional)]
+    #[argh(description = "location to create socket at; if it is the only location given, it is the config instead and the socket is created at the default location")]
+    socket_location: PathBuf,
+    #[argh(positional)]
+    #[argh(description = "location for config file, or a directory whose *.json files are merged (TOML is not supported)")]
+    config_location: Option<PathBuf>
+}
+impl CmdArgs {
+    /// Moves a lone location over to the config, defaulting the socket
+    fn resolve_locations(&mut self) -> Result<(), String> {
+        if self.config_location.is_none() {
+            self.config_location = Some(std::mem::replace(&mut self.socket_location, default_socket_location()?));
+        }
+        Ok(())
+    }
+
+    fn config_location(&self) -> &Path {
+        self.config_location.as_deref().expect("Locations are resolved after parsing")
+    }
+}
+
+/// Version of what subcommands print with `--json`, raised when a field changes or goes away
+const OUTPUT_SCHEMA_VERSION: u64 = 1;
+
+/// Prints the object with `schema_version` added, for subcommands given `--json`
+fn print_json_output(mut object: serde_json::Map<String, serde_json::Value>, is_pretty: bool) {
+    object.insert("schema_version".to_owned(), OUTPUT_SCHEMA_VERSION.into());
+    let object = serde_json::Value::Object(object);
+    match is_pretty {
+        true => println!("{}", serde_json::to_string_pretty(&object).unwrap()),
+        false => println!("{}", object)
+    }
+}
+
+/// Environment variable overriding where the socket is by default
+const SOCKET_ENV_VAR: &str = "SOCK_TRIGGER_CMD_SOCKET";
+
+/// The socket location used when none is given, shared by the daemon and its clients
+fn default_socket_location() -> Result<PathBuf, String> {
+    if let Some(path) = std::env::var_os(SOCKET_ENV_VAR) {
+        return Ok(PathBuf::from(path));
+    }
+    if Uid::effective().is_root() {
+        return Ok(PathBuf::from("/run/sock_trigger_cmd.sock"));
+    }
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => Ok(Path::new(&dir).join("sock_trigger_cmd.sock")),
+        None => Err(format!("No socket location given, and neither {} nor XDG_RUNTIME_DIR is set", SOCKET_ENV_VAR))
+    }
+}
+
+/// Loads the config file, checking its permissions unless told not to
+fn load_checked_config(args: &CmdArgs, daemon_uid: Uid, command_identity: &CommandIdentity) -> Result<Config, String> {
+    if !args.insecure_config {
+        privilege::check_config_permissions(args.config_location(), daemon_uid)?;
+        // A config directory is checked above, and each of its files here
+        if args.config_location().is_dir() {
+            for file in config::config_files(args.config_location())? {
+                privilege::check_config_permissions(&file, daemon_uid)?;
+            }
+        }
+    }
+    let config = config::load_config(args.config_location())?;
+    if let Some(ref name) = args.response_profile {
+        if !config.response_profiles.contains_key(name) {
+            return Err(format!("Config has no response profile {}", name));
+        }
+    }
+    let findings = preflight::check(&config, command_identity);
+    for finding in findings.errors.iter().chain(&findings.warnings) {
+        warn!("{}", finding);
+    }
+    if args.strict && !findings.errors.is_empty() {
+        return Err(format!("{} keys have commands that cannot be started", findings.errors.len()));
+    }
+    Ok(config)
+}
+
+/// Parses the arguments after the command name, which is `name_len` words long, exiting like
+/// `argh::from_env` on `--help` and with a usage error code on errors
+fn parse_args<T: FromArgs>(argv: &[String], name_len: usize) -> T {
+    let strs: Vec<&str> = argv.iter().map(String::as_str).collect();
+    match T::from_args(&strs[..name_len], &strs[name_len..]) {
+        Ok(args) => args,
+        Err(early_exit) => {
+            match early_exit.status {
+                Ok(()) => println!("{}", early_exit.output),
+                Err(()) => eprintln!("{}{}\nRun {} --help for more information.",
+                    failure::STDERR_PREFIX, early_exit.output, strs[..name_len].join(" "))
+            }
+            std::process::exit(early_exit.status.map_or(FailureKind::Usage.exit_code().into(), |()| 0));
+        }
+    }
+}
+
+/// Runs the daemon or one of its subcommands, depending on the command line, reporting any error
+pub fn cli_main() -> Result<(), Failure> {
+    // Subcommands are dispatched before argh so that the daemon keeps its positional arguments
+    let argv: Vec<String> = std::env::args().collect();
+    let result = match argv.get(1).map(String::as_str) {
+        Some("dump-config") => dump_config::run(parse_args(&argv, 2)).map_err(Failure::config),
+        Some("list-keys") => list_keys::run(parse_args(&argv, 2)).map_err(Failure::config),
+        Some("gen-systemd") => gen_systemd::run(parse_args(&argv, 2)).map_err(Failure::from),
+        Some("history") => history::run(parse_args(&argv, 2)).map_err(Failure::socket),
+        Some("top") => top::run(parse_args(&argv, 2)).map_err(Failure::socket),
+        Some("status") => top::run_once(parse_args(&argv, 2)).map_err(Failure::socket),
+        Some("send") => send::run(parse_args(&argv, 2)),
+        // argh has no --version of its own
+        Some("version" | "--version") => {
+            version::run(parse_args(&argv, 2));
+            Ok(())
+        },
+        _ => run(&argv)
+    };
+    if let Err(ref failure) = result {
+        failure::report(failure);
+    }
+    result
+}
+fn run(argv: &[String]) -> Result<(), Failure> {
+    let mut args: CmdArgs = parse_args(argv, 1);
+    args.resolve_locations().map_err(Failure::usage)?;
+
+    let handover = handover::take()?;
+    // Resolved now, since the path no longer leads to this binary once it has been replaced
+    let exe_path = std::env::current_exe()
+        .map_err(|e| format!("Could not find own executable: {}", e))?;
+
+    let log_path = match (&handover, Uid::effective().is_root()) {
+        // Only passed on to a re-executed daemon, which does not open it either
+        _ if args.no_file_log => String::new(),
+        // Privileges may have been dropped since the previous process chose it
+        (Some(handover), _) => handover.log_path.clone(),
+        (None, true) => "/var/log/sock_trigger_cmd.log".to_owned(),
+        (None, false) => std::env::var("HOME")
+            .map_err(|_| Failure::logging("HOME is not set, so there is no log file location".to_owned()))?
+            + "/sock_trigger_cmd.log"
+    };
+    let _logger_handle = logging::start(&args, &log_path)?;
+    if let Some(ref path) = args.journal {
+        journal::open(path).map_err(Failure::logging)?;
+    }
+
+    let identity = privilege::resolve_identity(args.user.as_deref(), args.group.as_deref())
+        .map_err(Failure::config)?;
+
+    let daemon_uid = identity.as_ref().map_or_else(Uid::effective, |id| id.uid);
+    let command_identity = identity.as_ref().map_or_else(CommandIdentity::current, CommandIdentity::from_target);
+    if args.insecure_config {
+        warn!("Skipping config file permission checks");
+    }
+
+    let mut rt_builder = match (args.current_thread, args.worker_threads) {
+        (true, Some(_)) => return Err(Failure::usage("--current-thread and --worker-threads cannot be combined".to_owned())),
+        (true, None) => tokio::runtime::Builder::new_current_thread(),
+        (false, Some(0)) => return Err(Failure::usage("--worker-threads must be at least 1".to_owned())),
+        (false, threads) => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            if let Some(threads) = threads {
+                builder.worker_threads(threads);
+            }
+            builder
+        }
+    };
+
+    #[cfg(feature = "otlp")]
+    let otlp_endpoint = args.otlp_endpoint.as_deref()
+        .map(str::parse::<http_client::HttpUrl>)
+        .transpose()
+        .map_err(|e| Failure::usage(format!("Invalid OTLP endpoint: {}", e)))?;
+
+    info!("Loading configuration file");
+    let config = load_checked_config(&args, daemon_uid, &command_identity).map_err(Failure::config)?;
+    // Before dropping privileges, which raising the hard limit may need
+    if let Some(limit) = config.nofile_limit {
+        if let Err(e) = fd_limit::raise(limit) {
+            warn!("{}", e);
+        }
+    }
+
+    let std_socket = if let Some(ref handover) = handover {
+        info!("Taking over from previous daemon process");
+        // Privileges were already dropped by the previous process
+        if let Some(ref identity) = identity {
+            if Uid::effective() != identity.uid {
+                return Err(format!("Handed over daemon runs as uid {} instead of {}", Uid::effective(), identity.uid).into());
+            }
+        }
+        handover::listener(handover).map_err(Failure::socket)?
+    } else {
+        let activated = match args.launchd_socket {
+            Some(ref name) => Some(launchd::take_listener(name).map_err(Failure::socket)?),
+            None => systemd::take_listener().map_err(Failure::socket)?
+        };
+        let std_socket = match activated {
+            Some(listener) => {
+                info!("Using socket passed by the service manager");
+                listener
+            },
+            None => {
+                // Bind before starting the runtime so that privileges are dropped while single-threaded
+                let owner = match args.socket_dir_owner {
+                    Some(ref owner) => {
+                        let (user, group) = match owner.split_once(':') {
+                            Some((user, group)) => (user, Some(group)),
+                            None => (owner.as_str(), None)
+                        };
+                        privilege::resolve_identity(Some(user), group).map_err(Failure::config)?
+                    },
+                    None => identity.clone()
+                };
+                let parent_dirs = socket_file::ParentDirs {
+                    mode: args.socket_dir_mode.unwrap_or(Mode::from_bits(0o755).unwrap()),
+                    owner
+                };
+                socket_file::bind(&args.socket_location, identity.as_ref(), &parent_dirs).map_err(Failure::socket)?
+            }
+        };
+        if let Some(ref identity) = identity {
+            info!("Dropping privileges to uid {} and gid {}", identity.uid, identity.gid);
+            privilege::drop_privileges(identity)?;
+        }
+        std_socket
+    };
+    std_socket.set_nonblocking(true)
+        .map_err(|e| Failure::socket(format!("Could not set socket to nonblocking: {}", e)))?;
+    if args.subreaper {
+        subreaper::enable()?;
+        info!("Adopting orphaned descendants of commands");
+    }
+
+    info!("Starting async runtime");
+    let rt = rt_builder.enable_all().build()
+        .map_err(|e| format!("Could not start async runtime: {}", e))?;
+    let upgrade = rt.block_on(async {
+        let socket = UnixListener::from_std(std_socket)
+            .map_err(|e| format!("Could not open socket: {}", e))?;
+
+        #[cfg(feature = "otlp")]
+        if let Some(endpoint) = otlp_endpoint {
+            info!("Exporting telemetry to {}", endpoint);
+            metrics::start();
+            rt.spawn(otlp::run_exporter(endpoint, Duration::from_secs(args.otlp_interval.max(1))));
+        }
+
+        info!("Starting processing loop");
+        banner::log(&args, &log_path, &config);
+        let snapshot = ConfigSnapshot::new(config);
+        debug!("Configured keys: {:?}", snapshot.sorted_keys);
+        let state_arc = Arc::new(ServerState::new(snapshot));
+        if let Some(ref name) = args.response_profile {
+            info!("Answering with response profile {}", name);
+            state_arc.select_response_profile(name.clone());
+        }
+        if let Some(ref handover) = handover {
+            info!("Adopting {} detached commands and {} jobs", handover.services.len(), handover.jobs.len());
+            state_arc.restore(handover);
+        }
+        resume_queued(&state_arc)?;
+        // Started after restoring so that adopted commands are not taken for orphans
+        if subreaper::is_enabled() {
+            tokio::spawn(subreaper::run_reaper());
+        }
+        systemd::notify("READY=1");
+        let mut sighup = signal(SignalKind::hangup())
+            .map_err(|e| format!("Could not handle SIGHUP: {}", e))?;
+        let mut sigusr2 = signal(SignalKind::user_defined2())
+            .map_err(|e| format!("Could not handle SIGUSR2: {}", e))?;
+        let mut sigquit = signal(SignalKind::quit())
+            .map_err(|e| format!("Could not handle SIGQUIT: {}", e))?;
+        let mut sigusr1 = signal(SignalKind::user_defined1())
+            .map_err(|e| format!("Could not handle SIGUSR1: {}", e))?;
+        let mut is_upgrading = false;
+        // Retrying at once after running out of file descriptors would spin, so accepting is
+        // paused instead, leaving new connections in the listen backlog until some are closed
+        let mut accept_backoff = ACCEPT_BACKOFF_MIN;
+        let mut accept_paused_until: Option<tokio::time::Instant> = None;
+        let (send, mut recv) = channel(1);
+        loop {
+            select! {
+                _ = sighup.recv() => {
+                    info!("Received SIGHUP, reloading configuration file");
+                    match load_checked_config(&args, daemon_uid, &command_identity) {
+                        Ok(config) => {
+                            let snapshot = ConfigSnapshot::new(config);
+                            info!("Loaded {} keys", snapshot.sorted_keys.len());
+                            state_arc.replace_snapshot(snapshot);
+                        },
+                        Err(e) => error!("Keeping old configuration: {}", e)
+                    }
+                },
+                _ = sigusr1.recv() => {
+                    status::log(&state_arc);
+                },
+                _ = sigusr2.recv() => {
+                    info!("Received SIGUSR2, toggling maintenance mode");
+                    set_maintenance(&state_arc, !state_arc.is_in_maintenance());
+                },
+                _ = sigquit.recv() => {
+                    if exe_path.is_file() {
+                        info!("Received SIGQUIT, re-executing {} once current tasks finish", exe_path.display());
+                        state_arc.halt();
+                        is_upgrading = true;
+                        break;
+                    }
+                    error!("Received SIGQUIT, but {} no longer exists", exe_path.display());
+                },
+                _ = state_arc.maintenance_ended.notified() => {
+                    rt.spawn(run_deferred(state_arc.clone(), send.clone()));
+                },
+                ctrl_c_res = tokio::signal::ctrl_c() => match ctrl_c_res {
+                    Ok(()) => {
+                        info!("Received Ctrl-C, finishing current tasks");
+                        systemd::notify("STOPPING=1");
+                        state_arc.halt();
+                        break;
+                    },
+                    Err(e) => {
+                        return Err(format!("Could not handle Ctrl-C: {}", e));
+                    }
+                },
+                _ = tokio::time::sleep_until(accept_paused_until.unwrap_or_else(tokio::time::Instant::now)),
+                        if accept_paused_until.is_some() => {
+                    accept_paused_until = None;
+                },
+                conn_res = socket.accept_connection(), if accept_paused_until.is_none() => {
+                    let connection = match conn_res {
+                        Ok(connection) => {
+                            accept_backoff = ACCEPT_BACKOFF_MIN;
+                            connection
+                        },
+                        Err(e) if is_fd_exhaustion(&e) => {
+                            warn!("Could not accept a connection, pausing for {}ms: {}", accept_backoff.as_millis(), e);
+                            #[cfg(feature = "otlp")]
+                            metrics::record_accept_error("fd_limit");
+                            accept_paused_until = Some(tokio::time::Instant::now() + accept_backoff);
+                            accept_backoff = (accept_backoff*2).min(ACCEPT_BACKOFF_MAX);
+                            continue;
+                        },
+                        Err(e) => {
+                            warn!("Error with receiving connection: {}", e);
+                            #[cfg(feature = "otlp")]
+                            metrics::record_accept_error("other");
+                            continue;
+                        }
+                    };
+                    let state_arc = state_arc.clone();
+                    rt.spawn(handle_connection(state_arc, connection, send.clone()));
+                }
+            };
+        }
+        drop(send);
+        let _ = recv.recv().await;
+        state_arc.jobs.drained().await;
+        // Read back by the next daemon process, including the one upgraded to
+        state_arc.flush_deferred().await;
+        let _ = tokio::task::spawn_blocking(journal::flush).await;
+
+        if !is_upgrading {
+            return Ok(None);
+        }
+        let socket = socket.into_std()
+            .map_err(|e| format!("Could not pass socket to new process: {}", e))?;
+        Ok::<_, String>(Some((socket, state_arc)))
+    })?;
+
+    if let Some((socket, state_arc)) = upgrade {
+        let handover = state_arc.handover(socket.as_raw_fd(), log_path);
+        info!("Handing over {} detached commands and {} jobs", handover.services.len(), handover.jobs.len());
+        _logger_handle.flush();
+        return Err(handover::exec(&exe_path, &socket, &handover).into());
+    }
+
+    info!("Exiting");
+    _logger_handle.shutdown();
+    Ok(())
+}
@@ -0,0 +1,69 @@
+//! The `list-keys` subcommand, which prints the configured keys with their descriptions and tags
+
+use argh::FromArgs;
+
+use serde_json::{json, Value};
+
+use std::path::PathBuf;
+
+use crate::config::{self, KeyConfig};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(FromArgs)]
+#[argh(description = "Print the configured keys with their descriptions and tags")]
+#[argh(example = "sock_trigger_cmd list-keys --tag backup /etc/trigger.json")]
+pub struct ListKeysArgs {
+    #[argh(option)]
+    #[argh(description = "only list keys with this tag; may be repeated to require several")]
+    tag: Vec<String>,
+    #[argh(switch)]
+    #[argh(description = "print the keys as JSON")]
+    json: bool,
+    #[argh(positional)]
+    #[argh(description = "location for config file or directory")]
+    config_location: PathBuf
+}
+
+fn key_json(key: &str, key_config: &KeyConfig) -> Value {
+    json!({
+        "key": key,
+        "description": key_config.description,
+        "tags": key_config.tags,
+        "enabled": key_config.enabled,
+        "detach": key_config.detach
+    })
+}
+
+pub fn run(args: ListKeysArgs) -> Result<(), String> {
+    let config = config::load_config(&args.config_location)?;
+    let mut keys: Vec<(&str, &KeyConfig)> = config.keys.iter()
+        .map(|(key, key_config)| (key.as_ref(), key_config))
+        .filter(|(_, key_config)| args.tag.iter().all(|tag| key_config.tags.contains(tag)))
+        .collect();
+    keys.sort_unstable_by_key(|(key, _)| *key);
+    if args.json {
+        let keys: Vec<Value> = keys.iter().map(|(key, key_config)| key_json(key, key_config)).collect();
+        let mut output = serde_json::Map::new();
+        output.insert("keys".to_owned(), keys.into());
+        crate::print_json_output(output, true);
+        return Ok(());
+    }
+    // Keys are quoted so that spaces in them stay visible
+    let quoted: Vec<String> = keys.iter().map(|(key, _)| Value::from(*key).to_string()).collect();
+    let key_width = quoted.iter().map(|key| key.chars().count()).max().unwrap_or(0);
+    for (quoted, (_, key_config)) in quoted.iter().zip(&keys) {
+        let mut line = format!("{:width$}", quoted, width = key_width);
+        if !key_config.tags.is_empty() {
+            line += &format!("  [{}]", key_config.tags.join(", "));
+        }
+        if !key_config.enabled {
+            line += "  (disabled)";
+        }
+        if let Some(ref description) = key_config.description {
+            line += "  ";
+            line += description;
+        }
+        println!("{}", line.trim_end());
+    }
+    Ok(())
+}
@@ -0,0 +1,226 @@
+//! Setting up the daemon log: a rotated file, syslog, the audit log, and a copy on stdout
+
+use log::{Level, LevelFilter, Record};
+#[cfg(feature = "syslog")]
+use log::warn;
+use flexi_logger::{DeferredNow, Duplicate, FileSpec, FormatFunction, Logger, LoggerHandle};
+use flexi_logger::writers::{FileLogWriter, LogWriter};
+use flexi_logger::Criterion as LogCriterion;
+use flexi_logger::Age as LogAge;
+use flexi_logger::Naming as LogRotNaming;
+use flexi_logger::Cleanup as LogCleanup;
+
+use std::io::Write;
+#[cfg(feature = "syslog")]
+use std::io::ErrorKind;
+#[cfg(feature = "syslog")]
+use std::path::Path;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::audit;
+use crate::failure::{self, Failure};
+#[cfg(feature = "syslog")]
+use crate::syslog::SyslogWriter;
+use crate::CmdArgs;
+
+#[cfg(feature = "syslog")]
+const SYSLOG_PATH: &str = "/dev/log";
+
+/// Whether syslog was found when logging started
+static IS_SYSLOG_OPEN: AtomicBool = AtomicBool::new(false);
+
+/// Kept for rotating the log file on request, if there is one
+static FILE_LOGGER: OnceLock<LoggerHandle> = OnceLock::new();
+
+/// How log lines are written to stdout; files always get the full format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogStyle {
+    /// Timestamp with date and offset, level, and source location, as in the log file
+    Full,
+    /// Time of day, level, and message
+    Compact,
+    /// Like compact, with the level colored for terminals
+    Color,
+    /// One JSON object per line
+    Json
+}
+impl LogStyle {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogStyle::Full => "full",
+            LogStyle::Compact => "compact",
+            LogStyle::Color => "color",
+            LogStyle::Json => "json"
+        }
+    }
+
+    fn format(self) -> FormatFunction {
+        match self {
+            LogStyle::Full => flexi_logger::opt_format,
+            LogStyle::Compact => compact_format,
+            LogStyle::Color => color_format,
+            LogStyle::Json => json_format
+        }
+    }
+}
+
+pub fn parse_style(value: &str) -> Result<LogStyle, String> {
+    [LogStyle::Full, LogStyle::Compact, LogStyle::Color, LogStyle::Json].into_iter()
+        .find(|style| style.as_str() == value)
+        .ok_or_else(|| format!("{} is not one of full, compact, color, or json", value))
+}
+
+fn compact_format(w: &mut dyn Write, now: &mut DeferredNow, record: &Record) -> std::io::Result<()> {
+    write!(w, "{} {:<5} {}", now.format("%H:%M:%S%.3f"), record.level(), record.args())
+}
+
+fn color_format(w: &mut dyn Write, now: &mut DeferredNow, record: &Record) -> std::io::Result<()> {
+    let color = match record.level() {
+        Level::Error => "31",
+        Level::Warn => "33",
+        Level::Info => "32",
+        Level::Debug => "36",
+        Level::Trace => "2"
+    };
+    write!(w, "\x1b[2m{}\x1b[0m \x1b[{}m{:<5}\x1b[0m {}",
+        now.format("%H:%M:%S%.3f"), color, record.level(), record.args())
+}
+
+fn json_format(w: &mut dyn Write, now: &mut DeferredNow, record: &Record) -> std::io::Result<()> {
+    let line = serde_json::json!({
+        "time": now.format_rfc3339(),
+        "level": record.level().as_str(),
+        "file": record.file(),
+        "line": record.line(),
+        "message": record.args().to_string()
+    });
+    write!(w, "{}", line)
+}
+
+/// Parses the level of the lines copied to stdout, such as `debug`
+pub fn parse_stdout_level(value: &str) -> Result<LevelFilter, String> {
+    value.parse::<LevelFilter>()
+        .map_err(|_| format!("{} is not one of off, error, warn, info, debug, or trace", value))
+}
+
+fn duplicate(level: LevelFilter) -> Duplicate {
+    match level {
+        LevelFilter::Off => Duplicate::None,
+        LevelFilter::Error => Duplicate::Error,
+        LevelFilter::Warn => Duplicate::Warn,
+        LevelFilter::Info => Duplicate::Info,
+        LevelFilter::Debug => Duplicate::Debug,
+        LevelFilter::Trace => Duplicate::Trace
+    }
+}
+
+/// Opens syslog, or returns None if there is no syslog daemon to write to
+#[cfg(feature = "syslog")]
+fn open_syslog() -> Result<Option<Box<dyn LogWriter>>, Failure> {
+    match SyslogWriter::connect(Path::new(SYSLOG_PATH)) {
+        Ok(writer) => Ok(Some(Box::new(writer))),
+        // Containers often have no syslog daemon
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => Ok(None),
+        Err(e) => Err(Failure::logging(format!("Could not open syslog for logging: {}", e)))
+    }
+}
+
+/// Builds without syslog have none to open
+#[cfg(not(feature = "syslog"))]
+fn open_syslog() -> Result<Option<Box<dyn LogWriter>>, Failure> {
+    Ok(None)
+}
+
+/// Starts logging to the targets the arguments ask for
+///
+/// A missing syslog socket is warned about rather than stopping the daemon.
+pub fn start(args: &CmdArgs, log_path: &str) -> Result<LoggerHandle, Failure> {
+    let is_logging_to_stdout = !args.no_stdout_logs && args.stdout_level != LevelFilter::Off;
+    if args.no_file_log && args.no_syslog && !is_logging_to_stdout {
+        return Err(Failure::usage("--no-file-log and --no-syslog leave nowhere to log to without stdout".to_owned()));
+    }
+    if args.no_file_log && !cfg!(feature = "syslog") && !is_logging_to_stdout {
+        return Err(Failure::usage("--no-file-log leaves nowhere to log to without stdout in builds without syslog".to_owned()));
+    }
+    let file_spec = match args.no_file_log {
+        true => None,
+        false => Some(FileSpec::try_from(log_path)
+            .map_err(|_| Failure::logging("Could not open log file for logging".to_owned()))?)
+    };
+    let syslog = match args.no_syslog {
+        true => None,
+        false => open_syslog()?
+    };
+    #[cfg(feature = "syslog")]
+    let is_syslog_missing = !args.no_syslog && syslog.is_none();
+    IS_SYSLOG_OPEN.store(syslog.is_some(), Ordering::Relaxed);
+
+    let logger = Logger::try_with_env_or_str("debug")
+        .map_err(|e| Failure::logging(format!("Could not initialize logging: {}", e)))?;
+    let mut logger = match (file_spec, syslog) {
+        (Some(file_spec), Some(syslog)) => logger.log_to_file_and_writer(file_spec, syslog),
+        (Some(file_spec), None) => logger.log_to_file(file_spec),
+        (None, Some(syslog)) => logger.log_to_writer(syslog),
+        // Only the copy on stdout is left
+        (None, None) => logger.do_not_log()
+    }
+        .o_append(true)
+        .o_rotate(Some(
+            (LogCriterion::Age(LogAge::Day),
+            LogRotNaming::Timestamps,
+            LogCleanup::KeepLogFiles(7)
+            )))
+        .format_for_files(flexi_logger::opt_format);
+    if let Some(ref audit_log) = args.audit_log {
+        let audit_writer = FileLogWriter::builder(FileSpec::try_from(audit_log)
+                .map_err(|_| Failure::logging("Could not open audit log for logging".to_owned()))?)
+            .append()
+            .format(|w, _now, record| write!(w, "{}", record.args()))
+            .try_build()
+            .map_err(|e| Failure::logging(format!("Could not open audit log for logging: {}", e)))?;
+        logger = logger.add_writer(audit::WRITER_NAME, Box::new(audit_writer));
+        audit::enable_audit_writer();
+    }
+    if is_logging_to_stdout {
+        logger = logger.duplicate_to_stdout(duplicate(args.stdout_level))
+            .format_for_stdout(args.log_style.format())
+    }
+    let handle = logger.start()
+        .map_err(|e| Failure::logging(format!("Could not initialize logging: {}", e)))?;
+    failure::logging_started(is_logging_to_stdout);
+    if !args.no_file_log {
+        let _ = FILE_LOGGER.set(handle.clone());
+    }
+    #[cfg(feature = "syslog")]
+    if is_syslog_missing {
+        warn!("{} does not exist or is not accepting messages, so logging without syslog", SYSLOG_PATH);
+    }
+    Ok(handle)
+}
+
+/// Where the daemon logs to, such as `file /var/log/sock_trigger_cmd.log, syslog, stdout at info`
+pub fn describe_sinks(args: &CmdArgs, log_path: &str) -> String {
+    let mut sinks = Vec::new();
+    if !args.no_file_log {
+        sinks.push(format!("file {}", log_path));
+    }
+    if IS_SYSLOG_OPEN.load(Ordering::Relaxed) {
+        sinks.push("syslog".to_owned());
+    }
+    if !args.no_stdout_logs && args.stdout_level != LevelFilter::Off {
+        sinks.push(format!("stdout at {}", args.stdout_level.as_str().to_lowercase()));
+    }
+    if let Some(ref audit_log) = args.audit_log {
+        sinks.push(format!("audit log {}", audit_log.display()));
+    }
+    sinks.join(", ")
+}
+
+/// Moves the current log file aside and starts a new one, as happens daily
+pub fn rotate() -> Result<(), String> {
+    FILE_LOGGER.get()
+        .ok_or_else(|| "The daemon is not logging to a file".to_owned())?
+        .trigger_rotation()
+        .map_err(|e| format!("Could not rotate the log file: {}", e))
+}
@@ -0,0 +1,184 @@
+//! In-process counters and histograms describing the requests handled so far
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::protocol::Outcome;
+
+/// Upper bounds, in seconds, of the command duration histogram buckets
+pub const DURATION_BOUNDS: [f64; 10] = [0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+/// A histogram over fixed buckets, with one extra bucket for values above the last bound
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    pub bucket_counts: [u64; DURATION_BOUNDS.len()+1],
+    pub sum: f64,
+    pub count: u64
+}
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            bucket_counts: [0; DURATION_BOUNDS.len()+1],
+            sum: 0.0,
+            count: 0
+        }
+    }
+    fn observe(&mut self, value: f64) {
+        let bucket = DURATION_BOUNDS.iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(DURATION_BOUNDS.len());
+        self.bucket_counts[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Counters for a single configured key
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyCounters {
+    /// Commands that were spawned
+    pub runs: u64,
+    /// Commands that exited unsuccessfully, could not be spawned, or failed their hash check
+    pub failures: u64,
+    /// Commands that were terminated by a signal
+    pub signals: u64,
+    /// Requests that were rate limited
+    pub throttles: u64
+}
+
+/// A copy of all metrics at one point in time
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricsSnapshot {
+    /// When collection started
+    pub start_time: SystemTime,
+    /// Number of requests by outcome label
+    pub requests: BTreeMap<&'static str, u64>,
+    /// Counters for each configured key that has received requests
+    pub keys: BTreeMap<String, KeyCounters>,
+    /// Tags of those keys as of their latest request
+    pub key_tags: BTreeMap<String, Vec<String>>,
+    /// Requests for unknown keys by peer UID
+    pub unknown_keys: BTreeMap<Option<u32>, u64>,
+    /// Number of commands currently running, by key
+    pub running: BTreeMap<String, u64>,
+    /// Wall-clock time taken by commands that were spawned
+    pub command_duration: Histogram,
+    /// Connections that could not be accepted, by reason
+    pub accept_errors: BTreeMap<&'static str, u64>
+}
+
+struct Registry {
+    start_time: Option<SystemTime>,
+    requests: BTreeMap<&'static str, u64>,
+    keys: BTreeMap<String, KeyCounters>,
+    key_tags: BTreeMap<String, Vec<String>>,
+    unknown_keys: BTreeMap<Option<u32>, u64>,
+    running: BTreeMap<String, u64>,
+    command_duration: Histogram,
+    accept_errors: BTreeMap<&'static str, u64>
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    start_time: None,
+    requests: BTreeMap::new(),
+    keys: BTreeMap::new(),
+    key_tags: BTreeMap::new(),
+    unknown_keys: BTreeMap::new(),
+    running: BTreeMap::new(),
+    command_duration: Histogram::new(),
+    accept_errors: BTreeMap::new()
+});
+
+/// Counts a command of the key as running for as long as it is alive
+#[derive(Debug)]
+pub struct RunningGuard(String);
+impl RunningGuard {
+    pub fn new(key: &str) -> Self {
+        *REGISTRY.lock().unwrap().running.entry(key.to_owned()).or_insert(0) += 1;
+        RunningGuard(key.to_owned())
+    }
+}
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        let mut registry = REGISTRY.lock().unwrap();
+        if let Some(count) = registry.running.get_mut(&self.0) {
+            *count -= 1;
+            if *count == 0 {
+                registry.running.remove(&self.0);
+            }
+        }
+    }
+}
+
+/// Marks the start of metrics collection
+pub fn start() {
+    REGISTRY.lock().unwrap().start_time = Some(SystemTime::now());
+}
+
+/// Records a finished request, along with how long its command ran for if one was spawned
+///
+/// `key` is only given for configured keys, along with their tags, so that
+/// clients cannot create arbitrarily many labels.
+pub fn record_request(key: Option<(&str, &[String])>, peer_uid: Option<u32>, outcome: Outcome,
+        command_duration: Option<Duration>) {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.start_time.get_or_insert_with(SystemTime::now);
+    *registry.requests.entry(outcome.label()).or_insert(0) += 1;
+    if let Some(duration) = command_duration {
+        registry.command_duration.observe(duration.as_secs_f64());
+    }
+    if outcome == Outcome::UnknownKey {
+        *registry.unknown_keys.entry(peer_uid).or_insert(0) += 1;
+    }
+    if let Some((key, tags)) = key {
+        // Reloads may change the tags of a key
+        if registry.key_tags.get(key).map(Vec::as_slice) != Some(tags) {
+            registry.key_tags.insert(key.to_owned(), tags.to_vec());
+        }
+        let counters = registry.keys.entry(key.to_owned()).or_default();
+        match outcome {
+            Outcome::Completed(code) => {
+                counters.runs += 1;
+                if code != 0 {
+                    counters.failures += 1;
+                }
+            },
+            Outcome::Signaled(_) => {
+                counters.runs += 1;
+                counters.signals += 1;
+            },
+            Outcome::TimedOut => {
+                counters.runs += 1;
+                counters.failures += 1;
+            },
+            Outcome::Detached(_) | Outcome::Started(_) => counters.runs += 1,
+            Outcome::SpawnFailed | Outcome::HashMismatch => counters.failures += 1,
+            Outcome::Throttled => counters.throttles += 1,
+            Outcome::UnknownKey | Outcome::Running(_) | Outcome::NotRunning | Outcome::Stopped(_)
+                | Outcome::Disabled | Outcome::Deferred | Outcome::EmptyKey | Outcome::PayloadRejected
+                | Outcome::OutsideWindow | Outcome::AwaitingConfirmation(_)
+                | Outcome::AwaitingApproval(_) | Outcome::BudgetExhausted | Outcome::PreconditionFailed => {}
+        }
+    }
+}
+
+/// Records a connection that could not be accepted, with `fd_limit` or `other` as the reason
+pub fn record_accept_error(reason: &'static str) {
+    *REGISTRY.lock().unwrap().accept_errors.entry(reason).or_insert(0) += 1;
+}
+
+/// Returns a copy of the current metrics
+pub fn snapshot() -> MetricsSnapshot {
+    let mut registry = REGISTRY.lock().unwrap();
+    MetricsSnapshot {
+        start_time: *registry.start_time.get_or_insert_with(SystemTime::now),
+        requests: registry.requests.clone(),
+        keys: registry.keys.clone(),
+        key_tags: registry.key_tags.clone(),
+        unknown_keys: registry.unknown_keys.clone(),
+        running: registry.running.clone(),
+        command_duration: registry.command_duration.clone(),
+        accept_errors: registry.accept_errors.clone()
+    }
+}
@@ -0,0 +1,275 @@
+//! Export of request traces and metrics over OTLP/HTTP using the JSON encoding
+
+use log::{debug, warn};
+
+use serde_json::{json, Value};
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::http_client::{self, HttpUrl};
+use crate::metrics::{self, KeyCounters, DURATION_BOUNDS};
+use crate::protocol::Outcome;
+use crate::subreaper;
+
+/// Spans beyond this many are dropped while the collector is unreachable
+const MAX_BUFFERED_SPANS: usize = 4096;
+
+const SCOPE_NAME: &str = "sock_trigger_cmd";
+
+static IS_ENABLED: AtomicBool = AtomicBool::new(false);
+static SPANS: Mutex<Vec<SpanRecord>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone)]
+struct SpanRecord {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    name: &'static str,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    is_error: bool
+}
+
+fn random_bytes(buf: &mut [u8]) {
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(buf))
+        .expect("Could not read from /dev/urandom");
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+fn attribute(key: &str, value: &Value) -> Value {
+    let value = match value {
+        Value::Number(n) if n.is_i64() => json!({"intValue": n.to_string()}),
+        Value::Bool(b) => json!({"boolValue": b}),
+        Value::String(s) => json!({"stringValue": s}),
+        other => json!({"stringValue": other.to_string()})
+    };
+    json!({"key": key, "value": value})
+}
+
+fn resource() -> Value {
+    let mut attributes = vec![attribute("service.name", &json!("sock_trigger_cmd"))];
+    if let Ok(hostname) = nix::unistd::gethostname() {
+        attributes.push(attribute("host.name", &json!(hostname.to_string_lossy())));
+    }
+    json!({"attributes": attributes})
+}
+
+/// Records the spans for a finished request, if export is enabled
+///
+/// The request span covers the whole request and gets a child span for the
+/// command if one was spawned.
+pub fn record_request(key: &str, outcome: Outcome, start: SystemTime, duration: Duration,
+        command: Option<(SystemTime, Duration)>) {
+    if !IS_ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+    let mut trace_id = [0u8; 16];
+    let mut request_span_id = [0u8; 8];
+    random_bytes(&mut trace_id);
+    random_bytes(&mut request_span_id);
+    let mut records = vec![SpanRecord {
+        trace_id,
+        span_id: request_span_id,
+        parent_span_id: None,
+        name: "request",
+        start,
+        end: start + duration,
+        attributes: vec![("key", json!(key)), ("outcome", json!(outcome.label()))],
+        is_error: !outcome.is_success()
+    }];
+    if let Some((command_start, command_duration)) = command {
+        let mut command_span_id = [0u8; 8];
+        random_bytes(&mut command_span_id);
+        let mut attributes = vec![("key", json!(key))];
+        match outcome {
+            Outcome::Completed(code) => attributes.push(("exit_code", json!(code))),
+            Outcome::Signaled(sig) => attributes.push(("signal", json!(sig))),
+            _ => {}
+        }
+        records.push(SpanRecord {
+            trace_id,
+            span_id: command_span_id,
+            parent_span_id: Some(request_span_id),
+            name: "command",
+            start: command_start,
+            end: command_start + command_duration,
+            attributes,
+            is_error: !outcome.is_success()
+        });
+    }
+
+    let mut spans = SPANS.lock().unwrap();
+    if spans.len() + records.len() > MAX_BUFFERED_SPANS {
+        debug!("Dropping spans because the OTLP buffer is full");
+        return;
+    }
+    spans.extend(records);
+}
+
+fn traces_body(spans: &[SpanRecord]) -> Value {
+    let spans: Vec<Value> = spans.iter().map(|span| json!({
+        "traceId": hex(&span.trace_id),
+        "spanId": hex(&span.span_id),
+        "parentSpanId": span.parent_span_id.map(|id| hex(&id)).unwrap_or_default(),
+        "name": span.name,
+        // SPAN_KIND_SERVER for requests and SPAN_KIND_INTERNAL for commands
+        "kind": if span.parent_span_id.is_none() {2} else {1},
+        "startTimeUnixNano": unix_nanos(span.start),
+        "endTimeUnixNano": unix_nanos(span.end),
+        "attributes": span.attributes.iter().map(|(k, v)| attribute(k, v)).collect::<Vec<_>>(),
+        // STATUS_CODE_OK or STATUS_CODE_ERROR
+        "status": {"code": if span.is_error {2} else {1}}
+    })).collect();
+    json!({"resourceSpans": [{
+        "resource": resource(),
+        "scopeSpans": [{"scope": {"name": SCOPE_NAME}, "spans": spans}]
+    }]})
+}
+
+fn metrics_body() -> Value {
+    let snapshot = metrics::snapshot();
+    let start = unix_nanos(snapshot.start_time);
+    let now = unix_nanos(SystemTime::now());
+    let request_points: Vec<Value> = snapshot.requests.iter().map(|(outcome, count)| json!({
+        "attributes": [attribute("outcome", &json!(outcome))],
+        "startTimeUnixNano": start,
+        "timeUnixNano": now,
+        "asInt": count.to_string()
+    })).collect();
+    let sum_metric = |name: &str, unit: &str, points: Vec<Value>| json!({
+        "name": name,
+        "unit": unit,
+        "sum": {"aggregationTemporality": 2, "isMonotonic": true, "dataPoints": points}
+    });
+    let key_points = |counter: fn(&KeyCounters) -> u64| snapshot.keys.iter()
+        .map(|(key, counters)| json!({
+            "attributes": match snapshot.key_tags.get(key).filter(|tags| !tags.is_empty()) {
+                Some(tags) => vec![attribute("key", &json!(key)), attribute("key.tags", &json!(tags.join(",")))],
+                None => vec![attribute("key", &json!(key))]
+            },
+            "startTimeUnixNano": start,
+            "timeUnixNano": now,
+            "asInt": counter(counters).to_string()
+        }))
+        .collect::<Vec<_>>();
+    let unknown_key_points: Vec<Value> = snapshot.unknown_keys.iter().map(|(uid, count)| json!({
+        "attributes": uid.map(|uid| vec![attribute("peer.uid", &json!(uid))]).unwrap_or_default(),
+        "startTimeUnixNano": start,
+        "timeUnixNano": now,
+        "asInt": count.to_string()
+    })).collect();
+    let gauge_points = |counts: BTreeMap<Option<String>, u64>| counts.into_iter()
+        .map(|(key, count)| json!({
+            "attributes": key.map(|key| vec![attribute("key", &json!(key))]).unwrap_or_default(),
+            "timeUnixNano": now,
+            "asInt": count.to_string()
+        }))
+        .collect::<Vec<_>>();
+    let mut running_orphans = BTreeMap::new();
+    for (_, _, key) in subreaper::orphans() {
+        *running_orphans.entry(key).or_insert(0) += 1;
+    }
+    let reaped_points: Vec<Value> = subreaper::reaped_orphans().into_iter().map(|(key, count)| json!({
+        "attributes": key.map(|key| vec![attribute("key", &json!(key))]).unwrap_or_default(),
+        "startTimeUnixNano": start,
+        "timeUnixNano": now,
+        "asInt": count.to_string()
+    })).collect();
+    let accept_error_points: Vec<Value> = snapshot.accept_errors.iter().map(|(reason, count)| json!({
+        "attributes": [attribute("reason", &json!(reason))],
+        "startTimeUnixNano": start,
+        "timeUnixNano": now,
+        "asInt": count.to_string()
+    })).collect();
+    let histogram = &snapshot.command_duration;
+    // AGGREGATION_TEMPORALITY_CUMULATIVE is 2
+    json!({"resourceMetrics": [{
+        "resource": resource(),
+        "scopeMetrics": [{"scope": {"name": SCOPE_NAME}, "metrics": [
+            sum_metric("sock_trigger_cmd.requests", "{request}", request_points),
+            sum_metric("sock_trigger_cmd.key.runs", "{run}", key_points(|c| c.runs)),
+            sum_metric("sock_trigger_cmd.key.failures", "{run}", key_points(|c| c.failures)),
+            sum_metric("sock_trigger_cmd.key.signals", "{run}", key_points(|c| c.signals)),
+            sum_metric("sock_trigger_cmd.key.throttles", "{request}", key_points(|c| c.throttles)),
+            sum_metric("sock_trigger_cmd.unknown_keys", "{request}", unknown_key_points),
+            sum_metric("sock_trigger_cmd.accept.errors", "{connection}", accept_error_points),
+            {
+                "name": "sock_trigger_cmd.commands.running",
+                "unit": "{command}",
+                "gauge": {"dataPoints": [{"timeUnixNano": now, "asInt": snapshot.running.values().sum::<u64>().to_string()}]}
+            },
+            {
+                "name": "sock_trigger_cmd.key.running",
+                "unit": "{command}",
+                "gauge": {"dataPoints": gauge_points(snapshot.running.iter()
+                    .map(|(key, count)| (Some(key.clone()), *count))
+                    .collect())}
+            },
+            {
+                "name": "sock_trigger_cmd.orphans.running",
+                "unit": "{process}",
+                "gauge": {"dataPoints": gauge_points(running_orphans)}
+            },
+            sum_metric("sock_trigger_cmd.orphans.reaped", "{process}", reaped_points),
+            {
+                "name": "sock_trigger_cmd.command.duration",
+                "unit": "s",
+                "histogram": {"aggregationTemporality": 2, "dataPoints": [{
+                    "startTimeUnixNano": start,
+                    "timeUnixNano": now,
+                    "count": histogram.count.to_string(),
+                    "sum": histogram.sum,
+                    "bucketCounts": histogram.bucket_counts.iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+                    "explicitBounds": DURATION_BOUNDS
+                }]}
+            }
+        ]}]
+    }]})
+}
+
+async fn post(url: &HttpUrl, body: &Value) -> Result<(), String> {
+    let headers = [("Content-Type".to_owned(), "application/json".to_owned())];
+    let response = http_client::request("POST", url, &headers,
+        body.to_string().as_bytes(), Duration::from_secs(10)).await?;
+    match response.status {
+        200..=299 => Ok(()),
+        status => Err(format!("Collector at {} returned status {}", url, status))
+    }
+}
+
+/// Starts recording spans and periodically sends them and the metrics to the collector
+///
+/// The endpoint is the collector's base URL, to which `/v1/traces` and
+/// `/v1/metrics` are appended.
+pub async fn run_exporter(endpoint: HttpUrl, interval: Duration) {
+    IS_ENABLED.store(true, Ordering::Release);
+    let base_path = endpoint.path.trim_end_matches('/').to_owned();
+    let traces_url = endpoint.with_path(&(base_path.clone() + "/v1/traces"));
+    let metrics_url = endpoint.with_path(&(base_path + "/v1/metrics"));
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let spans = std::mem::take(&mut *SPANS.lock().unwrap());
+        if !spans.is_empty() {
+            if let Err(e) = post(&traces_url, &traces_body(&spans)).await {
+                warn!("Could not export {} spans: {}", spans.len(), e);
+            }
+        }
+        if let Err(e) = post(&metrics_url, &metrics_body()).await {
+            warn!("Could not export metrics: {}", e);
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! Per-key files that command output is appended to, with size-based rotation
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::config::Rotation;
+
+fn rotated_path(path: &Path, index: u32) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", index));
+    PathBuf::from(rotated)
+}
+
+/// Shifts `path` to `path.1`, `path.1` to `path.2`, and so on, dropping the oldest
+fn rotate(path: &Path, keep: u32) -> std::io::Result<()> {
+    if keep == 0 {
+        return fs::remove_file(path);
+    }
+    for index in (1..keep).rev() {
+        match fs::rename(rotated_path(path, index), rotated_path(path, index+1)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    fs::rename(path, rotated_path(path, 1))
+}
+
+/// Opens the file for appending, rotating it first if it has grown past the limit
+pub fn open(path: &Path, rotation: Option<&Rotation>) -> std::io::Result<File> {
+    if let Some(rotation) = rotation {
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.len() >= rotation.max_bytes => rotate(path, rotation.keep)?,
+            Ok(_) => {},
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e)
+        }
+    }
+    fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o640)
+        .open(path)
+}
+
+/// Appends captured output to the file
+pub fn append(path: &Path, rotation: Option<&Rotation>, data: &[u8]) -> std::io::Result<()> {
+    open(path, rotation)?.write_all(data)
+}
@@ -0,0 +1,81 @@
+//! Checks that must pass right before a key runs
+
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::sys::statvfs::statvfs;
+use nix::unistd::Pid;
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::config::KeyConfig;
+use crate::run_cmd;
+use crate::state::ServerState;
+
+/// How long a check command may take before the precondition counts as failed
+const CHECK_TIME_LIMIT: Duration = Duration::from_secs(10);
+
+/// Something that must hold for a key to run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Precondition {
+    /// The filesystem holding the path has at least this many bytes free for unprivileged users
+    FreeSpace {path: PathBuf, min_bytes: u64},
+    /// The path exists
+    FileExists(PathBuf),
+    /// The process whose PID is in the file is running
+    Pidfile(PathBuf),
+    /// No command of the other key is running
+    NotRunning(String),
+    /// The tokenized command exits with code 0
+    Check(Vec<String>)
+}
+impl Precondition {
+    /// Checks the precondition for the key, describing why it does not hold if it does not
+    pub async fn check(&self, key: &str, key_config: &KeyConfig, state: &ServerState) -> Result<(), String> {
+        match self {
+            Precondition::FreeSpace {path, min_bytes} => {
+                let stats = statvfs(path)
+                    .map_err(|e| format!("Could not check free space on {}: {}", path.display(), e))?;
+                let free = (stats.blocks_available() as u64).saturating_mul(stats.fragment_size() as u64);
+                match free >= *min_bytes {
+                    true => Ok(()),
+                    false => Err(format!("{} has {} bytes free, less than {}", path.display(), free, min_bytes))
+                }
+            },
+            Precondition::FileExists(path) => match path.try_exists() {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(format!("{} does not exist", path.display())),
+                Err(e) => Err(format!("Could not check whether {} exists: {}", path.display(), e))
+            },
+            Precondition::Pidfile(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| format!("Could not read pidfile {}: {}", path.display(), e))?;
+                let pid = contents.trim().parse::<i32>().ok().filter(|pid| *pid > 0)
+                    .ok_or_else(|| format!("Pidfile {} does not hold a PID", path.display()))?;
+                // A process of another user that cannot be signaled is still running
+                match kill(Pid::from_raw(pid), None) {
+                    Ok(()) | Err(Errno::EPERM) => Ok(()),
+                    Err(_) => Err(format!("Process {} from {} is not running", pid, path.display()))
+                }
+            },
+            Precondition::NotRunning(other) => {
+                let is_running = state.services.is_tracked(other)
+                    || state.running.list().iter().any(|(running_key, _, _)| running_key == other);
+                match is_running {
+                    true => Err(format!("Key {} is running", other)),
+                    false => Ok(())
+                }
+            },
+            Precondition::Check(cmd) => {
+                let env = [("TRIGGER_KEY", key.to_owned())];
+                let output = tokio::time::timeout(CHECK_TIME_LIMIT, run_cmd::run_hook(cmd, key_config, &env, true)).await
+                    .map_err(|_| format!("Check {:?} took over {}s", cmd.join(" "), CHECK_TIME_LIMIT.as_secs()))?
+                    .map_err(|e| format!("Could not run check {:?}: {}", cmd.join(" "), e))?;
+                match output.status.success() {
+                    true => Ok(()),
+                    false => Err(format!("Check {:?} failed with {}", cmd.join(" "), output.status))
+                }
+            }
+        }
+    }
+}
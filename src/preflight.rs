@@ -0,0 +1,131 @@
+//! Checks that every configured command can be started, run whenever a config is loaded
+//!
+//! Besides errors, which mean that a command will fail to start, the checks
+//! warn about commands that are likely to misbehave when run by the daemon.
+
+use nix::unistd::{Gid, Uid};
+
+use std::fs::{File, Metadata};
+use std::io::Read;
+use std::path::Path;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+use crate::config::{Config, KeyConfig};
+use crate::privilege::TargetIdentity;
+use crate::run_cmd;
+
+/// The user and groups that commands run as
+#[derive(Debug, Clone)]
+pub struct CommandIdentity {
+    uid: Uid,
+    gids: Vec<Gid>
+}
+impl CommandIdentity {
+    /// The identity of the daemon process itself
+    pub fn current() -> Self {
+        #[allow(unused_mut)]
+        let mut gids = vec![Gid::effective()];
+        #[cfg(not(target_vendor = "apple"))]
+        gids.extend(nix::unistd::getgroups().unwrap_or_default());
+        CommandIdentity {uid: Uid::effective(), gids}
+    }
+
+    /// The identity the daemon will have once it drops privileges
+    pub fn from_target(identity: &TargetIdentity) -> Self {
+        CommandIdentity {uid: identity.uid, gids: identity.groups()}
+    }
+
+    fn can_execute(&self, metadata: &Metadata) -> bool {
+        let mode = metadata.permissions().mode();
+        // Root can execute anything that anyone can execute
+        let mask = if self.uid.is_root() {
+            0o111
+        } else if metadata.uid() == self.uid.as_raw() {
+            0o100
+        } else if self.gids.contains(&Gid::from_raw(metadata.gid())) {
+            0o010
+        } else {
+            0o001
+        };
+        mode & mask != 0
+    }
+}
+
+/// Problems found with the configured commands, each starting with the key
+#[derive(Debug, Clone, Default)]
+pub struct Findings {
+    /// Commands that cannot be started
+    pub errors: Vec<String>,
+    /// Commands that can be started but probably will not work as intended
+    pub warnings: Vec<String>
+}
+
+/// Returns the first bytes of the file, or None if it cannot be read
+fn file_magic(path: &Path) -> Option<Vec<u8>> {
+    let mut magic = Vec::with_capacity(4);
+    File::open(path).ok()?.take(4).read_to_end(&mut magic).ok()?;
+    Some(magic)
+}
+
+fn check_key(key_config: &KeyConfig, identity: &CommandIdentity, warnings: &mut Vec<String>) -> Result<(), String> {
+    if key_config.builtin.is_some() {
+        return Ok(());
+    }
+    let cwd = key_config.cwd.as_deref();
+    if let Some(cwd) = cwd {
+        if !cwd.is_dir() {
+            return Err(format!("working directory {} does not exist", cwd.display()));
+        }
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(ref cpus) = key_config.cpus {
+        let available = nix::sched::sched_getaffinity(nix::unistd::Pid::from_raw(0));
+        if available.is_ok_and(|available| !cpus.iter().any(|&cpu| available.is_set(cpu).unwrap_or(false))) {
+            warnings.push(format!("none of the CPUs {:?} are available to the daemon, so the command will likely fail to start", cpus));
+        }
+    }
+    let program = run_cmd::program(&key_config.cmd);
+    if program.contains('/') && !program.starts_with('/') && cwd.is_none() {
+        warnings.push(format!("{} is relative to the daemon's working directory; use an absolute path or set cwd", program));
+    }
+    let program_name = program.rsplit('/').next().unwrap_or(program);
+    // The arguments come after any leading VAR=VALUE entries and the program
+    let mut args = key_config.cmd.iter().skip_while(|arg| arg.contains('=')).skip(1);
+    if (program_name == "sudo" || program_name == "doas") && !args.any(|arg| arg == "-n") {
+        warnings.push(format!("{} may wait for a password that nobody can type; pass -n, or run the daemon as the target user", program_name));
+    }
+    let path = run_cmd::resolve_executable(&key_config.cmd, cwd)
+        .ok_or_else(|| format!("{} is not in PATH", program))?;
+    let metadata = path.metadata()
+        .map_err(|e| format!("{} cannot be accessed: {}", path.display(), e))?;
+    if !metadata.is_file() {
+        return Err(format!("{} is not a file", path.display()));
+    }
+    let magic = file_magic(&path);
+    let is_script = magic.as_deref().is_some_and(|magic| magic.starts_with(b"#!"));
+    if !identity.can_execute(&metadata) {
+        if is_script && metadata.permissions().mode() & 0o111 == 0 {
+            return Err(format!("{} is a script without execute permission; run chmod +x on it", path.display()));
+        }
+        return Err(format!("{} is not executable by uid {}", path.display(), identity.uid));
+    }
+    if magic.is_some_and(|magic| !is_script && !magic.starts_with(b"\x7fELF")) {
+        warnings.push(format!("{} has no #! line, so it cannot be executed directly; start it with one such as #!/bin/sh", path.display()));
+    }
+    Ok(())
+}
+
+/// Checks the command of every key against the identity it runs as, in key order
+pub fn check(config: &Config, identity: &CommandIdentity) -> Findings {
+    let mut keys: Vec<_> = config.keys.iter().collect();
+    keys.sort_unstable_by_key(|(key, _)| *key);
+    let mut findings = Findings::default();
+    for (key, key_config) in keys {
+        let mut warnings = Vec::new();
+        if let Err(error) = check_key(key_config, identity, &mut warnings) {
+            findings.errors.push(format!("Key {}: {}", key.as_ref(), error));
+        }
+        findings.warnings.extend(warnings.into_iter().map(|warning| format!("Key {}: {}", key.as_ref(), warning)));
+    }
+    findings
+}
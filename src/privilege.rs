@@ -0,0 +1,129 @@
+use nix::unistd::{Gid, Group, Uid, User};
+use nix::unistd::{setgid, setuid};
+
+use std::ffi::CString;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// The identity the daemon switches to once the socket has been bound
+#[derive(Debug, Clone)]
+pub struct TargetIdentity {
+    pub uid: Uid,
+    pub gid: Gid,
+    user: Option<User>
+}
+
+fn lookup_user(name: &str) -> Result<User, String> {
+    let found = match name.parse::<u32>() {
+        Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+        Err(_) => User::from_name(name)
+    };
+    found.map_err(|e| format!("Could not look up user {}: {}", name, e))?
+        .ok_or_else(|| format!("User {} does not exist", name))
+}
+
+fn lookup_group(name: &str) -> Result<Group, String> {
+    let found = match name.parse::<u32>() {
+        Ok(gid) => Group::from_gid(Gid::from_raw(gid)),
+        Err(_) => Group::from_name(name)
+    };
+    found.map_err(|e| format!("Could not look up group {}: {}", name, e))?
+        .ok_or_else(|| format!("Group {} does not exist", name))
+}
+
+/// Resolves the `--user` and `--group` arguments (names or numeric ids)
+///
+/// The group defaults to the primary group of the user, and the user defaults
+/// to the current one when only a group is given.
+pub fn resolve_identity(user: Option<&str>, group: Option<&str>) -> Result<Option<TargetIdentity>, String> {
+    let user = user.map(lookup_user).transpose()?;
+    let group = group.map(lookup_group).transpose()?;
+    Ok(match (user, group) {
+        (None, None) => None,
+        (Some(user), group) => Some(TargetIdentity {
+            uid: user.uid,
+            gid: group.map_or(user.gid, |g| g.gid),
+            user: Some(user)
+        }),
+        (None, Some(group)) => Some(TargetIdentity {
+            uid: Uid::effective(),
+            gid: group.gid,
+            user: None
+        })
+    })
+}
+
+impl TargetIdentity {
+    /// The groups the identity has once privileges are dropped, including the primary one
+    #[cfg(not(target_vendor = "apple"))]
+    pub fn groups(&self) -> Vec<Gid> {
+        let name = self.user.as_ref().and_then(|user| CString::new(user.name.as_str()).ok());
+        name.and_then(|name| nix::unistd::getgrouplist(&name, self.gid).ok())
+            .unwrap_or_else(|| vec![self.gid])
+    }
+
+    #[cfg(target_vendor = "apple")]
+    pub fn groups(&self) -> Vec<Gid> {
+        vec![self.gid]
+    }
+}
+
+#[cfg(not(target_vendor = "apple"))]
+fn set_supplementary_groups(identity: &TargetIdentity) -> Result<(), String> {
+    match identity.user {
+        Some(ref user) => {
+            let name = CString::new(user.name.as_str())
+                .map_err(|_| format!("User name {} contains a null byte", user.name))?;
+            nix::unistd::initgroups(&name, identity.gid)
+        },
+        None => nix::unistd::setgroups(&[identity.gid])
+    }.map_err(|e| format!("Could not set supplementary groups: {}", e))
+}
+
+#[cfg(target_vendor = "apple")]
+fn set_supplementary_groups(_identity: &TargetIdentity) -> Result<(), String> {
+    Err("Dropping privileges is not supported on this platform".to_owned())
+}
+
+/// Permanently switches the process to the given identity
+///
+/// This must be called before any other threads are started. The `HOME`,
+/// `USER`, and `SHELL` variables are updated to match the new user so that
+/// commands see a consistent environment.
+pub fn drop_privileges(identity: &TargetIdentity) -> Result<(), String> {
+    set_supplementary_groups(identity)?;
+    setgid(identity.gid)
+        .map_err(|e| format!("Could not switch to gid {}: {}", identity.gid, e))?;
+    setuid(identity.uid)
+        .map_err(|e| format!("Could not switch to uid {}: {}", identity.uid, e))?;
+
+    // Make sure the switch cannot be undone
+    if !identity.uid.is_root() && setuid(Uid::from_raw(0)).is_ok() {
+        return Err("Privileges were not dropped permanently".to_owned());
+    }
+    if let Some(ref user) = identity.user {
+        std::env::set_var("HOME", &user.dir);
+        std::env::set_var("USER", &user.name);
+        std::env::set_var("SHELL", &user.shell);
+    }
+    Ok(())
+}
+
+/// Checks that the config file cannot be modified by anyone but root or the daemon user
+///
+/// Config entries are arbitrary commands, so a config that others can write to
+/// would let them run commands as the daemon.
+pub fn check_config_permissions(path: &Path, daemon_uid: Uid) -> Result<(), String> {
+    let metadata = std::fs::metadata(path)
+        .map_err(|e| format!("Unable to read config: {}", e))?;
+    let owner = Uid::from_raw(metadata.uid());
+    if !owner.is_root() && owner != daemon_uid {
+        return Err(format!("Config {} is owned by uid {}, which is neither root nor the daemon user",
+            path.display(), owner));
+    }
+    if metadata.mode() & 0o022 != 0 {
+        return Err(format!("Config {} is writable by group or others (mode {:o})",
+            path.display(), metadata.mode() & 0o7777));
+    }
+    Ok(())
+}
@@ -0,0 +1,422 @@
+//! The request frames and response vocabulary of the socket protocol
+//!
+//! Every message is terminated by a null byte. A message is either a plain
+//! key, or an extended frame starting with [`FRAME_MARKER`] followed by a verb
+//! and space-separated arguments.
+
+use std::time::Duration;
+
+use crate::config::MAX_PAYLOAD_LEN;
+
+/// Version of the socket protocol, raised when a response changes meaning or a
+/// frame is removed; frames and responses may be added without raising it
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The first byte of an extended frame; keys may not start with it
+pub const FRAME_MARKER: u8 = 0x01;
+
+/// Sent with a token when a `MINT` frame has minted one
+pub const MINTED_RESPONSE: u8 = b'M';
+
+/// Requests starting with this present a token minted by a `MINT` frame
+pub const TOKEN_PREFIX: &str = "token:";
+
+/// Requests starting with this confirm a request for a key with `confirm` set
+pub const CONFIRM_PREFIX: &str = "confirm:";
+
+/// How long the nonce for confirming a request stays valid
+pub const CONFIRM_WINDOW: Duration = Duration::from_secs(30);
+
+/// Longest time a minted token can stay valid for
+pub const MAX_TOKEN_LIFETIME: Duration = Duration::from_secs(30*24*60*60);
+
+/// How long a result retained by a `RETAIN` frame is kept if it is not acknowledged
+pub const RETENTION: Duration = Duration::from_secs(60*60);
+
+/// Longest id a `RETAIN` frame may retain a result under
+pub const MAX_RETAIN_ID_LEN: usize = 64;
+
+/// Size of the largest key a `KEY` frame may send
+pub const MAX_SIZED_KEY_LEN: usize = 4096;
+
+/// Sent in place of a result for batch entries skipped after a failure
+pub const SKIPPED_RESPONSE: u8 = b'N';
+
+/// Sent when an extended frame is malformed
+pub const INVALID_FRAME_RESPONSE: u8 = b'E';
+
+/// Sent when an admin frame has been applied
+pub const ACK_RESPONSE: u8 = b'A';
+
+/// Sent when an admin frame comes from a peer that is not root or the daemon user
+pub const ADMIN_DENIED_RESPONSE: u8 = b'P';
+
+/// Sent after the last response on a connection that reached `max_requests_per_connection`
+pub const RECONNECT_RESPONSE: u8 = b'r';
+
+/// Length of the response to a single key that starts with the given byte, if any does
+pub fn response_len(first_byte: u8) -> Option<usize> {
+    match first_byte {
+        b'C' | b'S' | b'V' => Some(2),
+        b'J' | b'D' | b'K' => Some(5),
+        b'Q' => Some(33),
+        b'F' | b'H' | b'R' | b'X' | b'T' | b'O' | b'U' | b'W' | b'Z' | b'L' | b'G' | b'Y' | b'I' => Some(1),
+        _ => None
+    }
+}
+
+/// A parsed message from the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request<'a> {
+    /// Run the command for a key
+    Key(&'a [u8]),
+    /// Run the keys in the following `count` messages in order
+    Batch {count: u8, stop_on_failure: bool},
+    /// Run the key in the following message, giving up on it after the deadline
+    Deadline(Duration),
+    /// Run the key in the message after the given number of raw bytes, passing it those bytes
+    Payload(usize),
+    /// Run the key made up of the given number of raw bytes after the frame
+    SizedKey(usize),
+    /// Enable or disable a key until the daemon restarts
+    SetEnabled {key: &'a str, enabled: bool},
+    /// Enter or leave maintenance mode
+    SetMaintenance(bool),
+    /// Rotate the log file now
+    RotateLog,
+    /// Answer the rest of the connection with JSON lines
+    Json,
+    /// Mint a token that runs the key once, if presented before it expires
+    MintToken {key: &'a str, lifetime: Duration},
+    /// Send the files the next key collects after its response
+    Collect,
+    /// Keep the result of the key in the following message under the id until it is acknowledged
+    Retain(&'a str),
+    /// Send the result retained under the id, once it is finished
+    Fetch(&'a str),
+    /// Stop retaining the result under the id
+    Acknowledge(&'a str),
+    /// Send the result of the job, once it is finished
+    Job(u32),
+    /// Send the runs of the key, or of every key, from the given time ago onwards, or all of them
+    History {since: Option<Duration>, key: Option<&'a str>},
+    /// Send what the daemon is doing right now
+    Status
+}
+
+/// Parses a message with its null terminator removed
+pub fn parse_request(msg: &[u8]) -> Result<Request<'_>, String> {
+    let frame = match msg.split_first() {
+        Some((&FRAME_MARKER, frame)) => frame,
+        _ => return Ok(Request::Key(msg))
+    };
+    let frame = std::str::from_utf8(frame)
+        .map_err(|_| "Frame is not valid UTF-8".to_owned())?;
+    let (verb, args) = frame.split_once(' ').unwrap_or((frame, ""));
+    let mut words = args.split(' ');
+    match verb {
+        "BATCH" => {
+            let count = words.next()
+                .and_then(|c| c.parse::<u8>().ok())
+                .filter(|c| *c > 0)
+                .ok_or_else(|| "BATCH needs a count from 1 to 255".to_owned())?;
+            let stop_on_failure = match words.next() {
+                None => false,
+                Some("stop") => true,
+                Some(other) => return Err(format!("Unknown BATCH option {}", other))
+            };
+            if words.next().is_some() {
+                return Err("Too many arguments to BATCH".to_owned());
+            }
+            Ok(Request::Batch {count, stop_on_failure})
+        },
+        "DEADLINE" => {
+            let millis = words.next()
+                .and_then(|ms| ms.parse::<u64>().ok())
+                .ok_or_else(|| "DEADLINE needs a number of milliseconds".to_owned())?;
+            if words.next().is_some() {
+                return Err("Too many arguments to DEADLINE".to_owned());
+            }
+            Ok(Request::Deadline(Duration::from_millis(millis)))
+        },
+        "PAYLOAD" => {
+            let len = words.next()
+                .and_then(|len| len.parse::<usize>().ok())
+                .filter(|len| *len <= MAX_PAYLOAD_LEN)
+                .ok_or_else(|| format!("PAYLOAD needs a length of at most {} bytes", MAX_PAYLOAD_LEN))?;
+            if words.next().is_some() {
+                return Err("Too many arguments to PAYLOAD".to_owned());
+            }
+            Ok(Request::Payload(len))
+        },
+        "KEY" => {
+            let len = words.next()
+                .and_then(|len| len.parse::<usize>().ok())
+                .filter(|len| (1..=MAX_SIZED_KEY_LEN).contains(len))
+                .ok_or_else(|| format!("KEY needs a length from 1 to {} bytes", MAX_SIZED_KEY_LEN))?;
+            if words.next().is_some() {
+                return Err("Too many arguments to KEY".to_owned());
+            }
+            Ok(Request::SizedKey(len))
+        },
+        // The rest of the frame is the key, which may contain spaces
+        "ENABLE" | "DISABLE" if !args.is_empty() => Ok(Request::SetEnabled {key: args, enabled: verb == "ENABLE"}),
+        "ENABLE" | "DISABLE" => Err(format!("{} needs a key", verb)),
+        "MAINTENANCE" => match args {
+            "on" => Ok(Request::SetMaintenance(true)),
+            "off" => Ok(Request::SetMaintenance(false)),
+            _ => Err("MAINTENANCE needs on or off".to_owned())
+        },
+        "ROTATE-LOG" if args.is_empty() => Ok(Request::RotateLog),
+        "ROTATE-LOG" => Err("Too many arguments to ROTATE-LOG".to_owned()),
+        "MINT" => {
+            // The rest of the frame is the key, which may contain spaces
+            let (secs, key) = args.split_once(' ').unwrap_or((args, ""));
+            let lifetime = secs.parse::<u64>().ok()
+                .map(Duration::from_secs)
+                .filter(|lifetime| !lifetime.is_zero() && *lifetime <= MAX_TOKEN_LIFETIME)
+                .ok_or_else(|| format!("MINT needs a lifetime from 1 to {} seconds", MAX_TOKEN_LIFETIME.as_secs()))?;
+            if key.is_empty() {
+                return Err("MINT needs a key".to_owned());
+            }
+            Ok(Request::MintToken {key, lifetime})
+        },
+        "COLLECT" if args.is_empty() => Ok(Request::Collect),
+        "COLLECT" => Err("Too many arguments to COLLECT".to_owned()),
+        "RETAIN" | "FETCH" | "ACK" => {
+            let id = words.next()
+                .filter(|id| !id.is_empty() && id.len() <= MAX_RETAIN_ID_LEN)
+                .ok_or_else(|| format!("{} needs an id of 1 to {} bytes", verb, MAX_RETAIN_ID_LEN))?;
+            if words.next().is_some() {
+                return Err(format!("Too many arguments to {}", verb));
+            }
+            Ok(match verb {
+                "RETAIN" => Request::Retain(id),
+                "FETCH" => Request::Fetch(id),
+                _ => Request::Acknowledge(id)
+            })
+        },
+        "JOB" => {
+            let job_id = words.next()
+                .and_then(|job_id| job_id.parse::<u32>().ok())
+                .ok_or_else(|| "JOB needs a job id".to_owned())?;
+            if words.next().is_some() {
+                return Err("Too many arguments to JOB".to_owned());
+            }
+            Ok(Request::Job(job_id))
+        },
+        "HISTORY" => {
+            // The rest of the frame is the key, which may contain spaces
+            let (secs, key) = args.split_once(' ').unwrap_or((args, ""));
+            let secs = secs.parse::<u64>()
+                .map_err(|_| "HISTORY needs a number of seconds, or 0 for all runs".to_owned())?;
+            let since = (secs > 0).then(|| Duration::from_secs(secs));
+            Ok(Request::History {since, key: (!key.is_empty()).then_some(key)})
+        },
+        "STATUS" if args.is_empty() => Ok(Request::Status),
+        "STATUS" => Err("Too many arguments to STATUS".to_owned()),
+        "JSON" if args.is_empty() => Ok(Request::Json),
+        "JSON" => Err("Too many arguments to JSON".to_owned()),
+        verb => Err(format!("Unknown frame {}", verb))
+    }
+}
+
+/// The result of handling a single request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The command ran to completion with the given exit code
+    Completed(i32),
+    /// The command was terminated by the given signal
+    Signaled(i32),
+    /// The command could not be spawned
+    SpawnFailed,
+    /// The executable did not match its pinned hash
+    HashMismatch,
+    /// The request was rate limited
+    Throttled,
+    /// The key did not match any configured key
+    UnknownKey,
+    /// The command was killed for exceeding the client's deadline
+    TimedOut,
+    /// The command exceeded the client's deadline and continues as the given job
+    Detached(u32),
+    /// The command of a detached key was started with the given PID
+    Started(u32),
+    /// The command of a detached key is running with the given PID
+    Running(u32),
+    /// The command of a detached key is not running
+    NotRunning,
+    /// SIGTERM was sent to the command of a detached key with the given PID
+    Stopped(u32),
+    /// The key is disabled
+    Disabled,
+    /// The daemon is in maintenance mode
+    Deferred,
+    /// The key was empty, usually because a client sent an unset variable
+    EmptyKey,
+    /// The key was sent a payload it does not take, or none when it needs one
+    PayloadRejected,
+    /// The key may not be run at this time of day
+    OutsideWindow,
+    /// The key only runs once the request is confirmed with the given nonce
+    AwaitingConfirmation(u128),
+    /// The key only runs once more peers request it, after the given number of them have
+    AwaitingApproval(u8),
+    /// The key has used up its daily budget
+    BudgetExhausted,
+    /// A precondition of the key did not hold
+    PreconditionFailed
+}
+impl Outcome {
+    /// The bytes sent back to the client
+    pub fn response(&self) -> Vec<u8> {
+        match *self {
+            Outcome::Completed(code) => vec![b'C', (code%256) as u8],
+            Outcome::Signaled(sig) => vec![b'S', (sig%256) as u8],
+            Outcome::SpawnFailed => vec![b'F'],
+            Outcome::HashMismatch => vec![b'H'],
+            Outcome::Throttled => vec![b'R'],
+            Outcome::UnknownKey => vec![b'X'],
+            Outcome::TimedOut => vec![b'T'],
+            Outcome::Detached(job_id) => {
+                let mut response = vec![b'J'];
+                response.extend(job_id.to_be_bytes());
+                response
+            },
+            Outcome::Started(pid) | Outcome::Running(pid) => {
+                let mut response = vec![b'D'];
+                response.extend(pid.to_be_bytes());
+                response
+            },
+            Outcome::NotRunning => vec![b'O'],
+            Outcome::Disabled => vec![b'U'],
+            Outcome::Deferred => vec![b'W'],
+            Outcome::EmptyKey => vec![b'Z'],
+            Outcome::PayloadRejected => vec![b'L'],
+            Outcome::OutsideWindow => vec![b'G'],
+            Outcome::AwaitingConfirmation(nonce) => format!("Q{:032x}", nonce).into_bytes(),
+            Outcome::AwaitingApproval(count) => vec![b'V', count],
+            Outcome::BudgetExhausted => vec![b'Y'],
+            Outcome::PreconditionFailed => vec![b'I'],
+            Outcome::Stopped(pid) => {
+                let mut response = vec![b'K'];
+                response.extend(pid.to_be_bytes());
+                response
+            }
+        }
+    }
+
+    /// Parses a response to a single key, as sent by another daemon
+    ///
+    /// Started and running commands share a response, so both parse as started.
+    pub fn from_response(response: &[u8]) -> Option<Outcome> {
+        let pid = || Some(u32::from_be_bytes(response.get(1..5)?.try_into().ok()?));
+        let outcome = match *response.first()? {
+            b'C' => Outcome::Completed((*response.get(1)?).into()),
+            b'S' => Outcome::Signaled((*response.get(1)?).into()),
+            b'F' => Outcome::SpawnFailed,
+            b'H' => Outcome::HashMismatch,
+            b'R' => Outcome::Throttled,
+            b'X' => Outcome::UnknownKey,
+            b'T' => Outcome::TimedOut,
+            b'J' => Outcome::Detached(pid()?),
+            b'D' => Outcome::Started(pid()?),
+            b'O' => Outcome::NotRunning,
+            b'K' => Outcome::Stopped(pid()?),
+            b'U' => Outcome::Disabled,
+            b'W' => Outcome::Deferred,
+            b'Z' => Outcome::EmptyKey,
+            b'L' => Outcome::PayloadRejected,
+            b'G' => Outcome::OutsideWindow,
+            b'V' => Outcome::AwaitingApproval(*response.get(1)?),
+            b'Y' => Outcome::BudgetExhausted,
+            b'I' => Outcome::PreconditionFailed,
+            b'Q' => Outcome::AwaitingConfirmation(std::str::from_utf8(response.get(1..33)?).ok()
+                .and_then(|nonce| u128::from_str_radix(nonce, 16).ok())?),
+            _ => return None
+        };
+        match outcome.response().len() == response.len() {
+            true => Some(outcome),
+            false => None
+        }
+    }
+
+    /// Whether the command exited successfully or, for detached keys, the operation succeeded
+    pub fn is_success(&self) -> bool {
+        matches!(self, Outcome::Completed(0) | Outcome::Started(_) | Outcome::Running(_) | Outcome::Stopped(_))
+    }
+
+    /// Every label that `label()` returns
+    pub const LABELS: [&'static str; 22] = ["succeeded", "failed", "signaled", "spawn_failed", "hash_mismatch",
+        "throttled", "unknown_key", "timed_out", "detached", "started", "running", "not_running", "stopped",
+        "disabled", "deferred", "empty_key", "payload_rejected", "outside_window",
+        "awaiting_confirmation", "awaiting_approval", "budget_exhausted", "precondition_failed"];
+
+    /// A short name for the outcome, used in metrics and traces
+    pub fn label(&self) -> &'static str {
+        match self {
+            Outcome::Completed(0) => "succeeded",
+            Outcome::Completed(_) => "failed",
+            Outcome::Signaled(_) => "signaled",
+            Outcome::SpawnFailed => "spawn_failed",
+            Outcome::HashMismatch => "hash_mismatch",
+            Outcome::Throttled => "throttled",
+            Outcome::UnknownKey => "unknown_key",
+            Outcome::TimedOut => "timed_out",
+            Outcome::Detached(_) => "detached",
+            Outcome::Started(_) => "started",
+            Outcome::Running(_) => "running",
+            Outcome::NotRunning => "not_running",
+            Outcome::Stopped(_) => "stopped",
+            Outcome::Disabled => "disabled",
+            Outcome::Deferred => "deferred",
+            Outcome::EmptyKey => "empty_key",
+            Outcome::PayloadRejected => "payload_rejected",
+            Outcome::OutsideWindow => "outside_window",
+            Outcome::AwaitingConfirmation(_) => "awaiting_confirmation",
+            Outcome::AwaitingApproval(_) => "awaiting_approval",
+            Outcome::BudgetExhausted => "budget_exhausted",
+            Outcome::PreconditionFailed => "precondition_failed"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One of every outcome; the match stops compiling when a variant is added without one here
+    fn every_outcome() -> Vec<Outcome> {
+        let outcomes = vec![Outcome::Completed(3), Outcome::Signaled(9), Outcome::SpawnFailed, Outcome::HashMismatch,
+            Outcome::Throttled, Outcome::UnknownKey, Outcome::TimedOut, Outcome::Detached(7), Outcome::Started(42),
+            Outcome::Running(42), Outcome::NotRunning, Outcome::Stopped(42), Outcome::Disabled, Outcome::Deferred,
+            Outcome::EmptyKey, Outcome::PayloadRejected, Outcome::OutsideWindow,
+            Outcome::AwaitingConfirmation(0x0123_4567_89ab_cdef_0123_4567_89ab_cdef), Outcome::AwaitingApproval(2),
+            Outcome::BudgetExhausted, Outcome::PreconditionFailed];
+        for outcome in &outcomes {
+            match outcome {
+                Outcome::Completed(_) | Outcome::Signaled(_) | Outcome::SpawnFailed | Outcome::HashMismatch
+                    | Outcome::Throttled | Outcome::UnknownKey | Outcome::TimedOut | Outcome::Detached(_)
+                    | Outcome::Started(_) | Outcome::Running(_) | Outcome::NotRunning | Outcome::Stopped(_)
+                    | Outcome::Disabled | Outcome::Deferred | Outcome::EmptyKey | Outcome::PayloadRejected
+                    | Outcome::OutsideWindow | Outcome::AwaitingConfirmation(_) | Outcome::AwaitingApproval(_)
+                    | Outcome::BudgetExhausted | Outcome::PreconditionFailed => {}
+            }
+        }
+        outcomes
+    }
+
+    #[test]
+    fn every_response_has_its_length_and_parses_back() {
+        for outcome in every_outcome() {
+            let response = outcome.response();
+            assert_eq!(response_len(response[0]), Some(response.len()), "Wrong length for {:?}", outcome);
+            let expected = match outcome {
+                // Both share the started response
+                Outcome::Running(pid) => Outcome::Started(pid),
+                outcome => outcome
+            };
+            assert_eq!(Outcome::from_response(&response), Some(expected), "{:?} did not parse back", outcome);
+        }
+    }
+}
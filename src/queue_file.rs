@@ -0,0 +1,132 @@
+//! Keeping the triggers queued during maintenance in a file, so that a restart does not drop them
+//!
+//! The file holds a JSON object per line, and is replaced as a whole after
+//! the queue changes, by one write for all the changes made while the write
+//! before it was in progress.
+
+use serde::{Deserialize, Serialize};
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{error, warn};
+
+use crate::state::DeferredTrigger;
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    key: String,
+    #[serde(default)]
+    confirmed: bool,
+    /// Entries without it, such as ones written by hand, go through the gates when they are run
+    #[serde(default)]
+    admitted: bool,
+    #[serde(default)]
+    payload: Option<Vec<u8>>,
+    /// Milliseconds since the Unix epoch
+    queued_at: u64
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|since| since.as_millis() as u64).unwrap_or(0)
+}
+
+/// The contents of a file holding the triggers
+pub fn encode(triggers: &[DeferredTrigger]) -> Vec<u8> {
+    let mut contents = Vec::new();
+    for trigger in triggers {
+        // Only keys that are valid UTF-8 get far enough to be deferred
+        let entry = Entry {
+            key: String::from_utf8_lossy(&trigger.key).into_owned(),
+            confirmed: trigger.is_confirmed,
+            admitted: trigger.is_admitted,
+            payload: trigger.payload.clone(),
+            queued_at: unix_millis(trigger.queued_at)
+        };
+        serde_json::to_writer(&mut contents, &entry).expect("Entries are always serializable");
+        contents.push(b'\n');
+    }
+    contents
+}
+
+/// Replaces the file with the contents, writing them to a temporary file first
+/// so that the file is never left half written
+fn write(path: &Path, contents: &[u8]) -> Result<(), String> {
+    let temp_path = path.with_extension("tmp");
+    let write = || -> std::io::Result<()> {
+        let mut file = std::fs::File::create(&temp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, path)
+    };
+    write().map_err(|e| format!("Could not write queue file {}: {}", path.display(), e))
+}
+
+/// Writes the file on blocking threads, one write at a time, collapsing the
+/// saves asked for while a write waits for its turn into that write
+#[derive(Debug, Default)]
+pub struct QueueWriter {
+    /// Whether a write has been started that has not taken its snapshot yet
+    is_pending: AtomicBool,
+    /// Held for the whole of each write
+    write_lock: Mutex<()>
+}
+impl QueueWriter {
+    /// Writes the contents that `snapshot` returns once the writes before this one are done,
+    /// unless a write that has yet to take its snapshot will save the change already
+    pub fn save_later(self: &Arc<Self>, path: PathBuf, snapshot: impl FnOnce() -> Vec<u8> + Send + 'static) {
+        if self.is_pending.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let writer = Arc::clone(self);
+        tokio::task::spawn_blocking(move || {
+            let _writing = writer.write_lock.lock().unwrap();
+            writer.is_pending.store(false, Ordering::Release);
+            if let Err(e) = write(&path, &snapshot()) {
+                error!("{}", e);
+            }
+        });
+    }
+
+    /// Writes the contents right away, after any write in progress
+    pub fn save_now(&self, path: &Path, contents: &[u8]) {
+        let _writing = self.write_lock.lock().unwrap();
+        if let Err(e) = write(path, contents) {
+            error!("{}", e);
+        }
+    }
+}
+
+/// Reads the triggers in the file, leaving out those queued longer than `max_age` ago
+///
+/// A missing file holds no triggers. The peers that sent them are not known,
+/// so the triggers are run as if sent by a peer without credentials.
+pub fn load(path: &Path, max_age: Duration) -> Result<Vec<DeferredTrigger>, String> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Could not read queue file {}: {}", path.display(), e))
+    };
+    let cutoff = unix_millis(SystemTime::now()).saturating_sub(max_age.as_millis() as u64);
+    let mut triggers = Vec::new();
+    for (index, line) in contents.lines().enumerate().filter(|(_, line)| !line.is_empty()) {
+        let entry: Entry = serde_json::from_str(line)
+            .map_err(|e| format!("Invalid entry on line {} of queue file {}: {}", index + 1, path.display(), e))?;
+        if entry.queued_at < cutoff {
+            warn!("Dropping trigger of key {} queued over {}ms ago", entry.key, max_age.as_millis());
+            continue;
+        }
+        triggers.push(DeferredTrigger {
+            key: entry.key.into_bytes(),
+            peer: None,
+            is_confirmed: entry.confirmed,
+            is_admitted: entry.admitted,
+            payload: entry.payload,
+            queued_at: UNIX_EPOCH + Duration::from_millis(entry.queued_at)
+        });
+    }
+    Ok(triggers)
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::config::{Config, RateLimit};
+use crate::util::NonEmptyNoNullString;
+
+/// A token bucket that refills continuously at a fixed rate
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant
+}
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst as f64,
+            last_refill: now
+        }
+    }
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst as f64);
+        self.last_refill = now;
+    }
+    fn has_token(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= 1.0
+    }
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+}
+
+/// Which limit a throttled request ran into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Throttled {
+    Global,
+    Peer,
+    Key
+}
+impl std::fmt::Display for Throttled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Throttled::Global => "global",
+            Throttled::Peer => "per-peer",
+            Throttled::Key => "per-key"
+        })
+    }
+}
+
+/// Tracks the global, per-peer-UID, and per-key token buckets
+#[derive(Debug)]
+pub struct RateLimiter {
+    global: Option<Mutex<TokenBucket>>,
+    per_peer_limit: Option<RateLimit>,
+    per_peer: Mutex<HashMap<Option<u32>, TokenBucket>>,
+    per_key: HashMap<NonEmptyNoNullString, Mutex<TokenBucket>>
+}
+impl RateLimiter {
+    pub fn new(config: &Config) -> Self {
+        let now = Instant::now();
+        RateLimiter {
+            global: config.rate_limit.global.map(|l| Mutex::new(TokenBucket::new(l, now))),
+            per_peer_limit: config.rate_limit.per_peer,
+            per_peer: Mutex::new(HashMap::new()),
+            per_key: config.keys.iter()
+                .filter_map(|(k, v)| v.rate_limit.map(|l| (k.clone(), Mutex::new(TokenBucket::new(l, now)))))
+                .collect()
+        }
+    }
+
+    /// Takes a token from every bucket that applies to the request, or none if any is empty
+    ///
+    /// Peers whose credentials could not be determined share a single bucket.
+    pub fn check(&self, peer_uid: Option<u32>, key: Option<&str>) -> Result<(), Throttled> {
+        let now = Instant::now();
+        let mut global = self.global.as_ref().map(|m| m.lock().unwrap());
+        let mut per_peer_map = self.per_peer.lock().unwrap();
+        let mut peer = self.per_peer_limit.map(|l| per_peer_map.entry(peer_uid)
+            .or_insert_with(|| TokenBucket::new(l, now)));
+        let mut key = key.and_then(|k| self.per_key.get(k)).map(|m| m.lock().unwrap());
+
+        if let Some(ref mut bucket) = global {
+            if !bucket.has_token(now) {
+                return Err(Throttled::Global);
+            }
+        }
+        if let Some(ref mut bucket) = peer {
+            if !bucket.has_token(now) {
+                return Err(Throttled::Peer);
+            }
+        }
+        if let Some(ref mut bucket) = key {
+            if !bucket.has_token(now) {
+                return Err(Throttled::Key);
+            }
+        }
+        global.iter_mut().for_each(|b| b.take());
+        peer.iter_mut().for_each(|b| b.take());
+        key.iter_mut().for_each(|b| b.take());
+        Ok(())
+    }
+}
@@ -1,31 +1,469 @@
-use tokio::process::Command;
-use std::process::Output;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::{Child, Command};
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use std::process::Stdio;
 
 use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::Read;
+use std::process::Output;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use nix::sys::signal::{killpg, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+
+use crate::config::KeyConfig;
+use crate::sha256::{self, Sha256};
+use crate::subreaper;
 
-/// Runs the tokenized passed-in command, separating out env vars first
-pub async fn run_cmd(cmd_args: &Vec<String>) -> Result<Output, std::io::Error> {
-    let first_non_env_index = cmd_args.iter()
-        .position(|s| !s.contains('=')).unwrap_or(0);
-    let parsed_env_map = cmd_args[..first_non_env_index].iter()
+/// Returns the index of the executable, after any leading `VAR=VALUE` entries
+fn first_non_env_index(cmd_args: &[String]) -> usize {
+    cmd_args.iter()
+        .position(|s| !s.contains('=')).unwrap_or(0)
+}
+
+/// The program the command runs, as written in the config
+pub fn program(cmd_args: &[String]) -> &str {
+    &cmd_args[first_non_env_index(cmd_args)]
+}
+
+/// Builds the environment for the command from the preserved and inline variables
+fn command_env(cmd_args: &[String]) -> Vec<(OsString, OsString)> {
+    let parsed_env_map = cmd_args[..first_non_env_index(cmd_args)].iter()
         .map(|s| {
             let eq_pos = s.find('=').unwrap();
             (&s[..eq_pos], &s[eq_pos+1..])
         })
-        .map(|(s1, s2)| (OsStr::new(s1), OsString::from(s2)));
+        .map(|(s1, s2)| (OsString::from(s1), OsString::from(s2)));
     // Preserve $HOME, $PATH, $USER, $SHELL, and $TERM if they exist
     let preserved_env_map = ["HOME", "PATH", "USER", "SHELL", "TERM"].iter()
         .filter_map(|s| {
-            std::env::var_os(s).map(|env_var| (OsStr::new(s), env_var))
+            std::env::var_os(s).map(|env_var| (OsString::from(s), env_var))
         });
+    // Chain parsed second so that it can override the preserved env vars
+    preserved_env_map.chain(parsed_env_map).collect()
+}
 
-    let cmd_obj = Command::new(&cmd_args[first_non_env_index])
-        .args(&cmd_args[first_non_env_index+1..])
+/// Builds the key's tokenized command, separating out env vars first
+fn build_cmd(key_config: &KeyConfig) -> Command {
+    let cmd_args = &key_config.cmd;
+    let first_non_env_index = first_non_env_index(cmd_args);
+
+    let mut cmd_obj = Command::new(&cmd_args[first_non_env_index]);
+    cmd_obj.args(&cmd_args[first_non_env_index+1..])
         .env_clear()
-        // Chain parsed second so that it can override the preserved env vars
-        .envs(preserved_env_map.chain(parsed_env_map))
-        // Default of output() is null stdin and piped stdout
-        .output()
-        .await;
+        .envs(command_env(cmd_args))
+        .stdin(Stdio::null());
+    if let Some(ref cwd) = key_config.cwd {
+        cmd_obj.current_dir(cwd);
+    }
+    if let Some(umask) = key_config.umask {
+        // SAFETY: umask is async-signal-safe and cannot fail
+        #[allow(unsafe_code)]
+        unsafe {
+            cmd_obj.pre_exec(move || {
+                nix::sys::stat::umask(umask);
+                Ok(())
+            });
+        }
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(ref cpus) = key_config.cpus {
+        use nix::sched::{sched_setaffinity, CpuSet};
+        let mut cpu_set = CpuSet::new();
+        for &cpu in cpus {
+            // Validated when the config was loaded
+            cpu_set.set(cpu).expect("CPU index out of range");
+        }
+        // SAFETY: sched_setaffinity is a plain system call, and the set was built beforehand
+        #[allow(unsafe_code)]
+        unsafe {
+            cmd_obj.pre_exec(move || sched_setaffinity(Pid::from_raw(0), &cpu_set).map_err(std::io::Error::from));
+        }
+    }
     cmd_obj
-}
\ No newline at end of file
+}
+
+/// Spawns the command with stdout and stderr piped, ready for `wait_with_capped_output()`
+///
+/// In subreaper mode the command leads a new process group, which its
+/// descendants stay in unless they leave it themselves.
+pub fn spawn_cmd(key_config: &KeyConfig, kill_on_drop: bool) -> Result<Child, std::io::Error> {
+    let mut cmd_obj = build_cmd(key_config);
+    cmd_obj.stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(kill_on_drop);
+    if subreaper::is_enabled() {
+        // SAFETY: setpgid is async-signal-safe and the closure does not allocate
+        #[allow(unsafe_code)]
+        unsafe {
+            cmd_obj.pre_exec(|| nix::unistd::setpgid(Pid::from_raw(0), Pid::from_raw(0)).map_err(std::io::Error::from));
+        }
+    }
+    subreaper::spawn(&mut cmd_obj)
+}
+
+/// Runs a hook of the key, such as `on_timeout`, with extra variables in its environment
+///
+/// The hook runs in the key's working directory and gets the same preserved
+/// variables as commands do. With `kill_on_drop`, dropping the future kills it.
+pub async fn run_hook(hook: &[String], key_config: &KeyConfig, env: &[(&str, String)], kill_on_drop: bool)
+        -> Result<Output, std::io::Error> {
+    let first_non_env_index = first_non_env_index(hook);
+    let mut cmd_obj = Command::new(&hook[first_non_env_index]);
+    cmd_obj.args(&hook[first_non_env_index+1..])
+        .env_clear()
+        .envs(command_env(hook))
+        .envs(env.iter().map(|(var, value)| (var, value)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(kill_on_drop);
+    if let Some(ref cwd) = key_config.cwd {
+        cmd_obj.current_dir(cwd);
+    }
+    subreaper::spawn(&mut cmd_obj)?.wait_with_output().await
+}
+
+/// Spawns the command in a new session so that it can outlive the daemon
+pub fn spawn_detached(key_config: &KeyConfig, stdout: Stdio, stderr: Stdio)
+        -> Result<Child, std::io::Error> {
+    let mut cmd_obj = build_cmd(key_config);
+    cmd_obj.stdout(stdout).stderr(stderr);
+    // SAFETY: setsid is async-signal-safe and the closure does not allocate
+    #[allow(unsafe_code)]
+    unsafe {
+        cmd_obj.pre_exec(|| nix::unistd::setsid().map(|_| ()).map_err(std::io::Error::from));
+    }
+    subreaper::spawn(&mut cmd_obj)
+}
+
+/// Spawns the command with a pseudo-terminal as its controlling terminal and stdio
+///
+/// Returns the master side of the terminal, from which the command's output is read.
+pub fn spawn_pty(key_config: &KeyConfig, kill_on_drop: bool)
+        -> Result<(Child, File), std::io::Error> {
+    let pty = nix::pty::openpty(None, None)?;
+    let mut cmd_obj = build_cmd(key_config);
+    cmd_obj.stdin(pty.slave.try_clone()?)
+        .stdout(pty.slave.try_clone()?)
+        .stderr(pty.slave)
+        .kill_on_drop(kill_on_drop);
+    // SAFETY: setsid and ioctl are async-signal-safe and the closure does not allocate
+    #[allow(unsafe_code)]
+    unsafe {
+        cmd_obj.pre_exec(|| {
+            nix::unistd::setsid()?;
+            // stdin is the slave side of the terminal by the time this runs
+            if libc::ioctl(0, libc::TIOCSCTTY, 0) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    // The Command holds the slave side until it is dropped at the end of this function
+    let child = subreaper::spawn(&mut cmd_obj)?;
+    Ok((child, File::from(pty.master)))
+}
+
+/// The output of a command that exited
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandOutput {
+    pub output: Output,
+    /// How many bytes the command wrote to stdout, including any discarded past `max_output_bytes`
+    pub stdout_len: u64,
+    /// How many bytes the command wrote to stderr, including any discarded past `max_output_bytes`
+    pub stderr_len: u64,
+    /// Whether the command exited while processes it started still held its output open
+    pub descendants_running: bool
+}
+impl CommandOutput {
+    /// Whether any of the output was discarded
+    pub fn is_truncated(&self) -> bool {
+        self.stdout_len > self.output.stdout.len() as u64 || self.stderr_len > self.output.stderr.len() as u64
+    }
+}
+impl From<Output> for CommandOutput {
+    /// Output that was kept in full
+    fn from(output: Output) -> Self {
+        CommandOutput {
+            stdout_len: output.stdout.len() as u64,
+            stderr_len: output.stderr.len() as u64,
+            output,
+            descendants_running: false
+        }
+    }
+}
+
+/// Reads a stream to the end, keeping at most `max_bytes` of it in `buf` and counting its full length
+///
+/// What was read is kept in `buf` and `len` even if the read is cancelled.
+async fn read_capped(reader: Option<impl AsyncRead + Unpin>, max_bytes: Option<usize>, buf: &mut Vec<u8>,
+        len: &mut u64) -> Result<(), std::io::Error> {
+    let Some(mut reader) = reader else {
+        return Ok(());
+    };
+    let mut chunk = [0u8; 8192];
+    loop {
+        let chunk_len = reader.read(&mut chunk).await?;
+        if chunk_len == 0 {
+            return Ok(());
+        }
+        *len += chunk_len as u64;
+        // Keep draining past the cap so that the command does not block on a full pipe
+        let kept = max_bytes.map_or(chunk_len, |max_bytes| max_bytes.saturating_sub(buf.len()).min(chunk_len));
+        buf.extend_from_slice(&chunk[..kept]);
+    }
+}
+
+/// Kills a process group when dropped, unless disarmed first
+struct KillGroupOnDrop(Option<Pid>);
+impl Drop for KillGroupOnDrop {
+    fn drop(&mut self) {
+        if let Some(pgid) = self.0 {
+            let _ = killpg(pgid, Signal::SIGKILL);
+        }
+    }
+}
+
+/// Waits for a command spawned by `spawn_cmd`, keeping at most `max_bytes` of each output stream
+///
+/// With `kill_group`, cancelling the wait kills the command's whole process
+/// group rather than just the command. If the command exits but processes it
+/// left behind still hold its pipes open `grace` later, the pipes are closed
+/// and the output is returned with `descendants_running` set.
+pub async fn wait_with_capped_output(mut child: Child, max_bytes: Option<usize>, grace: Duration, kill_group: bool)
+        -> Result<CommandOutput, std::io::Error> {
+    let mut kill_guard = KillGroupOnDrop(child.id()
+        .filter(|_| kill_group)
+        .map(|pid| Pid::from_raw(pid as i32)));
+    let (mut stdout, mut stdout_len, mut stderr, mut stderr_len) = (Vec::new(), 0, Vec::new(), 0);
+    let (stdout_pipe, stderr_pipe) = (child.stdout.take(), child.stderr.take());
+    let mut reads = Box::pin(async {
+        tokio::try_join!(
+            read_capped(stdout_pipe, max_bytes, &mut stdout, &mut stdout_len),
+            read_capped(stderr_pipe, max_bytes, &mut stderr, &mut stderr_len)
+        )
+    });
+    let (status, descendants_running) = select! {
+        read = &mut reads => {
+            read?;
+            (child.wait().await?, false)
+        },
+        status = child.wait() => {
+            let status = status?;
+            match tokio::time::timeout(grace, &mut reads).await {
+                Ok(read) => {
+                    read?;
+                    (status, false)
+                },
+                Err(_) => (status, true)
+            }
+        }
+    };
+    // Dropping the reads closes the pipes
+    drop(reads);
+    kill_guard.0 = None;
+    Ok(CommandOutput {output: Output {status, stdout, stderr}, stdout_len, stderr_len, descendants_running})
+}
+
+/// Discards what is written to it, counting the bytes even if the copy ends in an error
+struct CountingSink<'a>(&'a mut u64);
+impl std::io::Write for CountingSink<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        *self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Waits for a command spawned by `spawn_pty`, collecting its terminal output as stdout
+///
+/// If the wait is cancelled, the command's whole session is killed so that
+/// none of its processes keep the terminal open.
+pub async fn wait_with_pty_output(mut child: Child, mut master: File, max_bytes: Option<usize>)
+        -> Result<CommandOutput, std::io::Error> {
+    // The command is a session leader, so its PID is also its process group ID
+    let mut kill_guard = KillGroupOnDrop(child.id().map(|pid| Pid::from_raw(pid as i32)));
+    let (stdout, stdout_len) = tokio::task::spawn_blocking(move || {
+        let mut buf = Vec::new();
+        let mut discarded = 0;
+        let result = match max_bytes {
+            Some(max_bytes) => (&mut master).take(max_bytes as u64).read_to_end(&mut buf)
+                .and_then(|_| std::io::copy(&mut master, &mut CountingSink(&mut discarded))).map(|_| ()),
+            None => master.read_to_end(&mut buf).map(|_| ())
+        };
+        let len = buf.len() as u64 + discarded;
+        match result {
+            Ok(()) => Ok((buf, len)),
+            // Reading the master fails with EIO once every slave fd is closed
+            Err(e) if e.raw_os_error() == Some(nix::errno::Errno::EIO as i32) => Ok((buf, len)),
+            Err(e) => Err(e)
+        }
+    }).await.expect("PTY reader task panicked")?;
+    let status = child.wait().await?;
+    kill_guard.0 = None;
+    Ok(CommandOutput {output: Output {status, stdout, stderr: Vec::new()}, stdout_len, stderr_len: 0,
+        descendants_running: false})
+}
+
+/// Waits for a child that was inherited from a previous daemon process
+///
+/// Such children are not known to tokio, so they are reaped directly with
+/// non-blocking `waitpid` calls whenever a child exits.
+pub fn wait_adopted(pid: u32) -> impl std::future::Future<Output = Result<WaitStatus, std::io::Error>> {
+    // Record it before returning so that the orphan reaper leaves it alone
+    subreaper::track(pid);
+    async move {
+        // Listen before the first check so that no exit is missed
+        let mut sigchld = signal(SignalKind::child())?;
+        let pid = Pid::from_raw(pid as i32);
+        loop {
+            match waitpid(pid, Some(WaitPidFlag::WNOHANG))? {
+                WaitStatus::StillAlive => {},
+                status @ (WaitStatus::Exited(_, _) | WaitStatus::Signaled(_, _, _)) => return Ok(status),
+                // Stops and continues are not reported without WUNTRACED or WCONTINUED
+                _ => {}
+            }
+            sigchld.recv().await;
+        }
+    }
+}
+
+/// Finds the file that will be executed for the command, searching `PATH` as the child would
+pub fn resolve_executable(cmd_args: &[String], cwd: Option<&Path>) -> Option<PathBuf> {
+    let program = program(cmd_args);
+    // Like execvp, only bare names are looked up in PATH
+    if program.contains('/') {
+        // Relative paths are relative to the command's working directory
+        return Some(cwd.map_or_else(|| PathBuf::from(program), |cwd| cwd.join(program)));
+    }
+    // Later entries override earlier ones
+    let (_, path_var) = command_env(cmd_args).into_iter()
+        .rev()
+        .find(|(k, _)| k == OsStr::new("PATH"))?;
+    std::env::split_paths(&path_var)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
+}
+
+/// The reasons an executable can fail its integrity check
+#[derive(Debug)]
+pub enum VerifyError {
+    /// The executable could not be found or read
+    Unreadable(std::io::Error),
+    /// The executable's hash differs from the configured one
+    Mismatch {path: PathBuf, actual: [u8; 32]}
+}
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::Unreadable(e) =>
+                write!(f, "Could not read executable: {}", e),
+            VerifyError::Mismatch {path, actual} =>
+                write!(f, "Executable {} has unexpected sha256 {}", path.display(), sha256::to_hex(actual)),
+        }
+    }
+}
+
+fn hash_file(path: &Path) -> Result<[u8; 32], std::io::Error> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64*1024];
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => hasher.update(&buf[..n])
+        }
+    }
+    Ok(hasher.finalize())
+}
+
+/// Checks that the executable for the command has the expected SHA-256
+pub async fn verify_executable(cmd_args: &[String], cwd: Option<&Path>, expected: [u8; 32])
+        -> Result<(), VerifyError> {
+    let path = resolve_executable(cmd_args, cwd)
+        .ok_or_else(|| VerifyError::Unreadable(std::io::ErrorKind::NotFound.into()))?;
+    let (path, hash_result) = tokio::task::spawn_blocking(move || {
+        let hash_result = hash_file(&path);
+        (path, hash_result)
+    }).await.expect("Hashing task panicked");
+    let actual = hash_result.map_err(VerifyError::Unreadable)?;
+    if actual != expected {
+        return Err(VerifyError::Mismatch {path, actual});
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arbitrary::{Rng, CASES};
+
+    /// A word to go before the executable, with the value holding anything but null
+    fn env_word(rng: &mut Rng) -> (String, String) {
+        let mut name = rng.string_without_null(8).replace('=', "");
+        if name.is_empty() {
+            name.push('V');
+        }
+        (name, rng.string_without_null(8))
+    }
+
+    #[test]
+    fn splitting_never_panics() {
+        let mut rng = Rng::new(0x5eed_0003);
+        for _ in 0..CASES {
+            let cmd_args = match shlex::split(&rng.string(32)) {
+                Some(cmd_args) if !cmd_args.is_empty() => cmd_args,
+                _ => continue
+            };
+            let index = first_non_env_index(&cmd_args);
+            assert!(index < cmd_args.len());
+            // Commands made only of VAR=VALUE entries are rejected by the config
+            if !cmd_args.iter().all(|s| s.contains('=')) {
+                assert!(!program(&cmd_args).contains('='), "Wrong program for {:?}", cmd_args);
+            }
+            command_env(&cmd_args);
+        }
+    }
+
+    #[test]
+    fn quoted_words_split_back_into_argv() {
+        let mut rng = Rng::new(0x5eed_0004);
+        for _ in 0..CASES {
+            let words: Vec<String> = (0..rng.below(6)).map(|_| rng.string_without_null(12)).collect();
+            let line = shlex::try_join(words.iter().map(String::as_str)).unwrap();
+            assert_eq!(shlex::split(&line).as_ref(), Some(&words), "Split {:?} differently", line);
+        }
+    }
+
+    #[test]
+    fn env_prefix_splits_at_the_first_equals_sign() {
+        let mut rng = Rng::new(0x5eed_0005);
+        for _ in 0..CASES {
+            let env: Vec<(String, String)> = (0..rng.below(4)).map(|_| env_word(&mut rng)).collect();
+            let executable = format!("/bin/{}", rng.string_without_null(8).replace('=', ""));
+            let args: Vec<String> = (0..rng.below(4)).map(|_| rng.string_without_null(8)).collect();
+            let words: Vec<String> = env.iter().map(|(name, value)| format!("{}={}", name, value))
+                .chain(std::iter::once(executable.clone()))
+                .chain(args.iter().cloned())
+                .collect();
+            let line = shlex::try_join(words.iter().map(String::as_str)).unwrap();
+            let cmd_args = shlex::split(&line).unwrap();
+            assert_eq!(program(&cmd_args), executable);
+            assert_eq!(&cmd_args[first_non_env_index(&cmd_args)+1..], &args[..]);
+            let cmd_env = command_env(&cmd_args);
+            let expected: Vec<(OsString, OsString)> = env.into_iter()
+                .map(|(name, value)| (name.into(), value.into()))
+                .collect();
+            // The preserved variables come first
+            assert_eq!(&cmd_env[cmd_env.len()-expected.len()..], &expected[..], "Wrong env for {:?}", cmd_args);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! The backends that run the commands of triggered keys
+//!
+//! Commands of detached keys are always real processes, since they are
+//! managed with signals for as long as they run.
+
+use std::future::Future;
+use std::pin::Pin;
+use crate::config::KeyConfig;
+use crate::run_cmd;
+pub use crate::run_cmd::CommandOutput;
+use crate::subreaper;
+
+/// A command that has been started
+pub struct RunningCommand {
+    /// The PID of the process, if the backend runs processes
+    pub pid: Option<u32>,
+    /// Resolves to the output once the command exits
+    ///
+    /// Dropping it before then kills the command if it was started with `kill_on_drop`.
+    pub output: Pin<Box<dyn Future<Output = std::io::Result<CommandOutput>> + Send>>
+}
+impl std::fmt::Debug for RunningCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RunningCommand").field("pid", &self.pid).finish_non_exhaustive()
+    }
+}
+
+/// Starts the commands of keys that are waited on
+pub trait CommandRunner: std::fmt::Debug + Send + Sync {
+    fn start(&self, key_config: &KeyConfig, kill_on_drop: bool) -> std::io::Result<RunningCommand>;
+}
+
+/// Runs commands as child processes
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProcessRunner;
+impl CommandRunner for ProcessRunner {
+    fn start(&self, key_config: &KeyConfig, kill_on_drop: bool) -> std::io::Result<RunningCommand> {
+        let max_output_bytes = key_config.max_output_bytes;
+        let output_grace = key_config.output_grace;
+        // Only in subreaper mode do commands get a process group of their own
+        let kill_group = kill_on_drop && subreaper::is_enabled();
+        let (child, pty_master) = if key_config.pty {
+            let (child, master) = run_cmd::spawn_pty(key_config, kill_on_drop)?;
+            (child, Some(master))
+        } else {
+            (run_cmd::spawn_cmd(key_config, kill_on_drop)?, None)
+        };
+        Ok(RunningCommand {
+            // The child has not been waited on yet, so its PID is still known
+            pid: child.id(),
+            output: Box::pin(async move {
+                match pty_master {
+                    Some(master) => run_cmd::wait_with_pty_output(child, master, max_output_bytes).await,
+                    None => run_cmd::wait_with_capped_output(child, max_output_bytes, output_grace, kill_group).await
+                }
+            })
+        })
+    }
+}
@@ -0,0 +1,242 @@
+//! The `send` subcommand, which triggers keys on a running daemon and prints their responses
+
+use argh::FromArgs;
+
+use serde_json::Value;
+
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::client::{self, JsonConnection};
+use crate::failure::{Failure, STDERR_PREFIX};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(FromArgs)]
+#[argh(description = "Trigger a key on a running daemon and print its response")]
+#[argh(example = "sock_trigger_cmd send backup")]
+#[argh(example = "sock_trigger_cmd send --interactive --socket /run/sock_trigger_cmd.sock")]
+#[argh(example = "sock_trigger_cmd send --socket '/run/tenants/*.sock' rotate-logs")]
+pub struct SendArgs {
+    #[argh(option)]
+    #[argh(description = "location of the daemon's socket, with * and ? matching several in one directory; may be repeated to send to all at once")]
+    socket: Vec<PathBuf>,
+    #[argh(switch, short = 'i')]
+    #[argh(description = "read keys from stdin, one per line, sending them over one connection")]
+    interactive: bool,
+    #[argh(switch)]
+    #[argh(description = "also print the first 4096 bytes of each of the command's stdout and stderr")]
+    output: bool,
+    #[argh(switch)]
+    #[argh(description = "print each response as a line of JSON")]
+    json: bool,
+    #[argh(switch)]
+    #[argh(description = "do not wait for the command, leaving it running as a job; needs on_deadline \"detach\"")]
+    no_wait: bool,
+    #[argh(option)]
+    #[argh(description = "milliseconds to wait for the command before it is killed or left running as a job")]
+    wait_timeout: Option<u64>,
+    #[argh(option)]
+    #[argh(description = "instead of sending a key, wait for the job with this id to finish and print its result")]
+    follow: Option<u32>,
+    #[argh(option, default = "0")]
+    #[argh(description = "seconds to keep retrying while the socket does not exist or nothing listens on it (default: 0)")]
+    wait_for_socket: u64,
+    #[argh(positional)]
+    #[argh(description = "key to send")]
+    key: Option<String>
+}
+
+/// The deadline to send keys with, in milliseconds
+fn deadline(args: &SendArgs) -> Option<u64> {
+    match args.no_wait {
+        // The command is left running as soon as it has been started
+        true => Some(0),
+        false => args.wait_timeout
+    }
+}
+
+/// The exit code standing for the response, or `None` if it is a success
+///
+/// Commands that exit pass their code through, and signals give 128 plus the
+/// signal like in shells. Other outcomes use codes from sysexits.h, or those
+/// of `timeout` and LSB init scripts where those fit better.
+fn exit_code(response: &Value) -> Option<u8> {
+    let code = match response["status"].as_str().unwrap_or_default() {
+        "succeeded" | "started" | "running" | "stopped" => return None,
+        "failed" => response["code"].as_i64().and_then(|code| u8::try_from(code).ok()).filter(|code| *code != 0)
+            .unwrap_or(1),
+        "signaled" => response["signal"].as_u64().map_or(128, |sig| 128 + sig.min(127) as u8),
+        "not_running" => 3,
+        "unknown_key" | "empty_key" => 67,
+        "spawn_failed" | "hash_mismatch" => 71,
+        "throttled" | "deferred" => 75,
+        "payload_rejected" => 65,
+        "disabled" | "outside_window" | "budget_exhausted" | "precondition_failed" | "awaiting_confirmation"
+            | "awaiting_approval" => 77,
+        "timed_out" | "detached" => 124,
+        _ => 76
+    };
+    Some(code)
+}
+
+/// Prints the response, and the command's output if asked to, naming the
+/// socket it came from if the key was sent to several
+fn print_response(response: Value, args: &SendArgs, socket: Option<&Path>) {
+    if args.json {
+        let Value::Object(mut response) = response else {
+            unreachable!("Responses are checked to be objects");
+        };
+        if !args.output {
+            for field in ["stdout", "stderr"] {
+                response.remove(field);
+            }
+        }
+        if let Some(socket) = socket {
+            response.insert("socket".to_owned(), socket.to_string_lossy().into());
+        }
+        crate::print_json_output(response, false);
+        return;
+    }
+    match socket {
+        Some(socket) => println!("{}: {}", socket.display(), client::describe(&response)),
+        None => println!("{}", client::describe(&response))
+    }
+    if !args.output {
+        return;
+    }
+    if let Some(stdout) = response["stdout"].as_str() {
+        print!("{}", stdout);
+    }
+    if let Some(stderr) = response["stderr"].as_str() {
+        eprint!("{}", stderr);
+    }
+    if response["truncated"].as_bool() == Some(true) {
+        println!("(output truncated)");
+    }
+}
+
+fn open(args: &SendArgs, socket: Option<&Path>) -> Result<JsonConnection, String> {
+    JsonConnection::open(socket, Duration::from_secs(args.wait_for_socket))
+}
+
+/// The configured keys starting with the prefix, using the `STATUS` frame
+fn complete(socket: Option<&Path>, prefix: &str) -> Result<Vec<String>, String> {
+    let status = client::admin_request(socket, "STATUS")?;
+    Ok(status["keys"].as_array().map_or(&[][..], Vec::as_slice).iter()
+        .filter_map(|key| key["key"].as_str())
+        .filter(|key| key.starts_with(prefix))
+        .map(str::to_owned)
+        .collect())
+}
+
+fn interact(args: &SendArgs, socket: Option<&Path>) -> Result<(), String> {
+    let mut connection = open(args, socket)?;
+    eprintln!("Type a key to send it, a prefix followed by ? to list the keys starting with it, \
+        or !! to send the last key again");
+    let mut last_key: Option<String> = None;
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        eprint!("> ");
+        std::io::stderr().flush().map_err(|e| format!("Could not write to stderr: {}", e))?;
+        let Some(line) = lines.next() else {
+            eprintln!();
+            return Ok(());
+        };
+        let line = line.map_err(|e| format!("Could not read stdin: {}", e))?;
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(prefix) = line.strip_suffix('?') {
+            match complete(socket, prefix) {
+                Ok(keys) if keys.is_empty() => eprintln!("No keys start with {:?}", prefix),
+                Ok(keys) => keys.iter().for_each(|key| println!("{}", key)),
+                Err(e) => eprintln!("Could not list the keys: {}", e)
+            }
+            continue;
+        }
+        let key = match line.as_str() {
+            "!!" => match last_key {
+                Some(ref key) => key.clone(),
+                None => {
+                    eprintln!("No key sent yet");
+                    continue;
+                }
+            },
+            _ => line
+        };
+        let response = connection.send_key(&key, deadline(args))?;
+        print_response(response, args, None);
+        last_key = Some(key);
+    }
+}
+
+/// Sends the key, or follows the job, on the socket, returning the response
+/// along with the failure standing for it if it is not a success
+fn request(args: &SendArgs, socket: Option<&Path>) -> Result<(Value, Option<Failure>), Failure> {
+    let mut connection = open(args, socket).map_err(Failure::socket)?;
+    let response = match (args.follow, &args.key) {
+        (Some(job_id), _) => connection.follow_job(job_id),
+        (None, Some(key)) => connection.send_key(key, deadline(args)),
+        (None, None) => unreachable!("Checked before sending")
+    }.map_err(Failure::protocol)?;
+    // A job left running is what --no-wait asks for
+    let is_left_running = args.no_wait && response["status"] == "detached";
+    let failure = exit_code(&response)
+        .filter(|_| !is_left_running)
+        .map(|code| Failure::response(code, client::describe(&response)));
+    Ok((response, failure))
+}
+
+pub fn run(args: SendArgs) -> Result<(), Failure> {
+    if args.no_wait && args.wait_timeout.is_some() {
+        return Err(Failure::usage("--no-wait and --wait-timeout cannot be given together".to_owned()));
+    }
+    let modes = [args.key.is_some(), args.interactive, args.follow.is_some()];
+    if modes.into_iter().filter(|is_given| *is_given).count() != 1 {
+        return Err(Failure::usage("Give exactly one of a key, --interactive, or --follow".to_owned()));
+    }
+    let sockets = client::expand_sockets(&args.socket).map_err(Failure::socket)?;
+    if sockets.len() > 1 && args.key.is_none() {
+        return Err(Failure::usage("Only one socket can be given with --interactive or --follow".to_owned()));
+    }
+    if args.interactive {
+        return interact(&args, sockets.first().map(PathBuf::as_path)).map_err(Failure::socket);
+    }
+    if sockets.len() <= 1 {
+        let (response, failure) = request(&args, sockets.first().map(PathBuf::as_path))?;
+        print_response(response, &args, None);
+        return failure.map_or(Ok(()), Err);
+    }
+    // Sent to every socket at once, with the results printed in the order of the sockets
+    let results: Vec<_> = std::thread::scope(|scope| {
+        let requests: Vec<_> = sockets.iter()
+            .map(|socket| scope.spawn(|| request(&args, Some(socket))))
+            .collect();
+        requests.into_iter().map(|request| request.join().expect("Request thread panicked")).collect()
+    });
+    let mut first_failure = None;
+    for (socket, result) in sockets.iter().zip(results) {
+        let failure = match result {
+            Ok((response, failure)) => {
+                print_response(response, &args, Some(socket));
+                failure
+            },
+            Err(failure) => {
+                match args.json {
+                    true => {
+                        let mut output = serde_json::Map::new();
+                        output.insert("socket".to_owned(), socket.to_string_lossy().into());
+                        output.insert("error".to_owned(), failure.message.clone().into());
+                        crate::print_json_output(output, false);
+                    },
+                    false => eprintln!("{}{}: {}", STDERR_PREFIX, socket.display(), failure)
+                }
+                // Already reported, so that it is not reported again on exit
+                Some(Failure::response(failure.kind.exit_code(), failure.message))
+            }
+        };
+        first_failure = first_failure.or(failure);
+    }
+    first_failure.map_or(Ok(()), Err)
+}
@@ -0,0 +1,232 @@
+//! Tracking of the commands started by detached keys
+//!
+//! Each detached key has at most one running command. The companion keys
+//! `<key>:stop` and `<key>:status` act on that command.
+
+use log::{error, info, warn};
+
+use nix::sys::signal::{kill, killpg, Signal};
+use nix::unistd::{getpgid, Pid};
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::watch;
+
+use crate::config::{KeyConfig, Rotation};
+use crate::output_file;
+use crate::protocol::Outcome;
+use crate::run_cmd;
+use crate::subreaper;
+
+/// An operation on the command of a detached key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompanionOp {
+    /// Send SIGTERM to the command's process group
+    Stop,
+    /// Report whether the command is running
+    Status
+}
+
+/// Splits a companion key into the detached key it refers to and the operation
+pub fn parse_companion(key: &str) -> Option<(&str, CompanionOp)> {
+    let (base, op) = key.rsplit_once(':')?;
+    match op {
+        "stop" => Some((base, CompanionOp::Stop)),
+        "status" => Some((base, CompanionOp::Status)),
+        _ => None
+    }
+}
+
+/// Opens the file a detached command's output stream is appended to
+fn detached_output(path: Option<&PathBuf>, rotation: Option<&Rotation>) -> std::io::Result<Stdio> {
+    match path {
+        Some(path) => output_file::open(path, rotation).map(Stdio::from),
+        None => Ok(Stdio::null())
+    }
+}
+
+/// Checks that the PID still belongs to the session leader we started
+///
+/// The reaper task removes exited commands from the table, so this only
+/// guards against the small window between reaping and removal.
+fn is_alive(pid: u32) -> bool {
+    let pid = Pid::from_raw(pid as i32);
+    kill(pid, None).is_ok() && getpgid(Some(pid)) == Ok(pid)
+}
+
+/// Removes a command that has exited, unless the key has started another one since
+fn untrack(table: &Mutex<HashMap<String, u32>>, key: &str, pid: u32) {
+    let mut pids = table.lock().unwrap();
+    if pids.get(key) == Some(&pid) {
+        pids.remove(key);
+    }
+}
+
+/// The running commands of detached keys, by key
+#[derive(Debug, Default)]
+pub struct ServiceTable {
+    // Shared with the tasks that reap the commands
+    pids: Arc<Mutex<HashMap<String, u32>>>
+}
+impl ServiceTable {
+    /// Whether a command is recorded for the key, even if the key is no longer configured
+    pub fn is_tracked(&self, key: &str) -> bool {
+        self.pids.lock().unwrap().contains_key(key)
+    }
+
+    /// Starts the command of a detached key unless it is already running
+    pub fn start(&self, key: &str, key_config: &KeyConfig) -> Outcome {
+        // Hold the lock while spawning so that concurrent requests start one command
+        let mut pids = self.pids.lock().unwrap();
+        if let Some(&pid) = pids.get(key) {
+            if is_alive(pid) {
+                info!("Detached key {} is already running with PID {}", key, pid);
+                return Outcome::Running(pid);
+            }
+        }
+        let cmd = &key_config.cmd;
+        let rotation = key_config.rotate.as_ref();
+        let child = detached_output(key_config.stdout.as_ref(), rotation)
+            .and_then(|stdout| Ok((stdout, detached_output(key_config.stderr.as_ref(), rotation)?)))
+            .and_then(|(stdout, stderr)| run_cmd::spawn_detached(key_config, stdout, stderr));
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                error!("Error starting detached command: {}", e);
+                return Outcome::SpawnFailed;
+            }
+        };
+        // The child has not been waited on yet, so its PID is still known
+        let pid = child.id().unwrap();
+        info!("Started detached command {:?} with PID {}", cmd, pid);
+        // The command leads a session of its own
+        subreaper::name_group(pid, key);
+        pids.insert(key.to_owned(), pid);
+
+        let table = self.pids.clone();
+        let key = key.to_owned();
+        let cmd = cmd.clone();
+        // Reap the child so that it does not linger as a zombie
+        tokio::spawn(async move {
+            match child.wait().await {
+                Ok(status) => info!("Detached command {:?} with PID {} exited with {}", cmd, pid, status),
+                Err(e) => error!("Error waiting for detached command {:?}: {}", cmd, e)
+            }
+            untrack(&table, &key, pid);
+        });
+        Outcome::Started(pid)
+    }
+
+    /// The PIDs of all tracked commands, for handing over to a new daemon process
+    pub fn export(&self) -> HashMap<String, u32> {
+        self.pids.lock().unwrap().clone()
+    }
+
+    /// Tracks a command that was started by a previous daemon process
+    pub fn adopt(&self, key: &str, pid: u32) {
+        self.pids.lock().unwrap().insert(key.to_owned(), pid);
+        let table = self.pids.clone();
+        let key = key.to_owned();
+        let exited = run_cmd::wait_adopted(pid);
+        tokio::spawn(async move {
+            match exited.await {
+                Ok(status) => info!("Detached command for key {} with PID {} finished: {:?}", key, pid, status),
+                Err(e) => warn!("Stopped tracking detached command for key {} with PID {}: {}", key, pid, e)
+            }
+            untrack(&table, &key, pid);
+        });
+    }
+
+    /// Runs a companion operation on the command of a detached key
+    pub fn companion(&self, key: &str, op: CompanionOp) -> Outcome {
+        let pid = match self.pids.lock().unwrap().get(key) {
+            Some(&pid) if is_alive(pid) => pid,
+            _ => return Outcome::NotRunning
+        };
+        match op {
+            CompanionOp::Status => Outcome::Running(pid),
+            CompanionOp::Stop => match killpg(Pid::from_raw(pid as i32), Signal::SIGTERM) {
+                Ok(()) => {
+                    info!("Sent SIGTERM to detached key {} with PID {}", key, pid);
+                    Outcome::Stopped(pid)
+                },
+                Err(e) => {
+                    warn!("Could not stop detached key {} with PID {}: {}", key, pid, e);
+                    Outcome::NotRunning
+                }
+            }
+        }
+    }
+}
+
+/// Keeps stopping the daemon waiting for a job until dropped
+#[derive(Debug)]
+pub struct Supervised(Arc<watch::Sender<usize>>);
+impl Drop for Supervised {
+    fn drop(&mut self) {
+        self.0.send_modify(|count| *count -= 1);
+    }
+}
+
+/// Commands that outlived a client deadline and continue in the background, by job id
+#[derive(Debug, Clone)]
+pub struct JobTable {
+    pids: Arc<Mutex<HashMap<u32, u32>>>,
+    // Number of jobs this process is still waiting on
+    supervised: Arc<watch::Sender<usize>>
+}
+impl Default for JobTable {
+    fn default() -> Self {
+        JobTable {
+            pids: Arc::default(),
+            supervised: Arc::new(watch::Sender::new(0))
+        }
+    }
+}
+impl JobTable {
+    /// Marks a job as waited on until the returned guard is dropped
+    pub fn supervise(&self) -> Supervised {
+        self.supervised.send_modify(|count| *count += 1);
+        Supervised(self.supervised.clone())
+    }
+
+    /// Waits until no job is being waited on
+    ///
+    /// Jobs adopted from a previous daemon process are not counted.
+    pub async fn drained(&self) {
+        let mut supervised = self.supervised.subscribe();
+        let _ = supervised.wait_for(|count| *count == 0).await;
+    }
+
+    pub fn insert(&self, job_id: u32, pid: u32) {
+        self.pids.lock().unwrap().insert(job_id, pid);
+    }
+
+    pub fn remove(&self, job_id: u32) {
+        self.pids.lock().unwrap().remove(&job_id);
+    }
+
+    /// The PIDs of all running jobs, for handing over to a new daemon process
+    pub fn export(&self) -> HashMap<u32, u32> {
+        self.pids.lock().unwrap().clone()
+    }
+
+    /// Tracks a job that was started by a previous daemon process
+    ///
+    /// Its output went to the previous process, so only its exit is logged.
+    pub fn adopt(&self, job_id: u32, pid: u32) {
+        self.insert(job_id, pid);
+        let table = self.clone();
+        let exited = run_cmd::wait_adopted(pid);
+        tokio::spawn(async move {
+            match exited.await {
+                Ok(status) => info!("Job {} with PID {} finished: {:?}", job_id, pid, status),
+                Err(e) => warn!("Stopped tracking job {} with PID {}: {}", job_id, pid, e)
+            }
+            table.remove(job_id);
+        });
+    }
+}
@@ -0,0 +1,48 @@
+//! Keys that are only run when requested as `<name>:<timestamp>:<hmac>`
+//!
+//! The HMAC is the hex HMAC-SHA256 of `<name>:<timestamp>` under the key's
+//! secret, and the timestamp is in seconds since the Unix epoch.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::sha256;
+
+/// Whether two strings are equal, taking the same time wherever they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Returns the name of the key that a request is for, checking its signature if the key needs one
+///
+/// Requests for keys that do not need a signature are returned unchanged.
+pub fn resolve<'a>(config: &Config, key: &'a [u8], now: SystemTime) -> Result<&'a [u8], String> {
+    let key_str = match std::str::from_utf8(key) {
+        Ok(key_str) => key_str,
+        Err(_) => return Ok(key)
+    };
+    if config.keys.get(key_str).is_some_and(|key_config| key_config.signing.is_some()) {
+        return Err(format!("Key {} needs a signature", key_str));
+    }
+    let mut parts = key_str.rsplitn(3, ':');
+    let (hmac, timestamp, name) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(hmac), Some(timestamp), Some(name)) => (hmac, timestamp, name),
+        _ => return Ok(key)
+    };
+    let signing = match config.keys.get(name).and_then(|key_config| key_config.signing.as_ref()) {
+        Some(signing) => signing,
+        None => return Ok(key)
+    };
+    let signed_at = timestamp.parse::<u64>().ok()
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+        .ok_or_else(|| format!("Key {} has an invalid timestamp {:?}", name, timestamp))?;
+    let skew = now.duration_since(signed_at).or_else(|_| signed_at.duration_since(now)).unwrap_or_default();
+    if skew > signing.window {
+        return Err(format!("Key {} was signed {}s away from now", name, skew.as_secs()));
+    }
+    let expected = sha256::to_hex(&sha256::hmac(&signing.secret, format!("{}:{}", name, timestamp).as_bytes()));
+    match constant_time_eq(expected.as_bytes(), hmac.to_ascii_lowercase().as_bytes()) {
+        true => Ok(name.as_bytes()),
+        false => Err(format!("Key {} has an invalid signature", name))
+    }
+}
@@ -0,0 +1,110 @@
+//! Creating the socket file that clients connect to
+
+use log::debug;
+
+use nix::sys::stat::{fchmodat, FchmodatFlags, Mode};
+use nix::unistd::chown;
+
+use std::fs;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+
+use crate::privilege::TargetIdentity;
+
+/// How missing parent directories of the socket are created
+#[derive(Debug, Clone)]
+pub struct ParentDirs {
+    pub mode: Mode,
+    /// Owner of the created directories, instead of the daemon's user
+    pub owner: Option<TargetIdentity>
+}
+
+/// Parses a directory mode given as an octal string, like `"750"`
+pub fn parse_dir_mode(value: &str) -> Result<Mode, String> {
+    u32::from_str_radix(value, 8).ok()
+        .filter(|mode| *mode <= 0o7777)
+        .and_then(Mode::from_bits)
+        .ok_or_else(|| format!("{} is not an octal mode", value))
+}
+
+/// Creates the missing ancestors of `path`, outermost first
+fn create_parents(path: &Path, parent_dirs: &ParentDirs) -> Result<(), String> {
+    let missing: Vec<&Path> = path.ancestors()
+        .skip(1)
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .take_while(|dir| !dir.exists())
+        .collect();
+    for dir in missing.into_iter().rev() {
+        debug!("Creating socket directory {}", dir.display());
+        // The mode is set explicitly as well, since the builder's is reduced by the umask
+        fs::DirBuilder::new().mode(parent_dirs.mode.bits()).create(dir)
+            .and_then(|()| fs::set_permissions(dir, fs::Permissions::from_mode(parent_dirs.mode.bits())))
+            .map_err(|e| format!("Could not create socket directory {}: {}", dir.display(), e))?;
+        if let Some(ref owner) = parent_dirs.owner {
+            chown(dir, Some(owner.uid), Some(owner.gid))
+                .map_err(|e| format!("Could not set ownership of {}: {}", dir.display(), e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes whatever is at `path` if it can be replaced by the socket
+fn clear_old(path: &Path) -> Result<(), String> {
+    let metadata = match path.metadata() {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(())
+    };
+    // Sockets and empty files are replaced by the rename
+    if metadata.file_type().is_socket() || (metadata.is_file() && metadata.len() == 0) {
+        return Ok(());
+    }
+    // A directory cannot be renamed over, so an empty one is removed first
+    if metadata.is_dir() && fs::remove_dir(path).is_ok() {
+        debug!("Removed empty directory at {}", path.display());
+        return Ok(());
+    }
+    Err(format!("{} already exists and cannot be removed", path.display()))
+}
+
+/// The path the socket is bound at before being moved into place
+fn temp_path(path: &Path) -> Result<PathBuf, String> {
+    let name = path.file_name()
+        .ok_or_else(|| format!("Socket location {} has no file name", path.display()))?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    Ok(path.with_file_name(temp_name))
+}
+
+fn prepare(temp: &Path, identity: Option<&TargetIdentity>) -> Result<(), String> {
+    fchmodat(None, temp, Mode::from_bits(0o660).unwrap(), FchmodatFlags::NoFollowSymlink)
+        .map_err(|e| format!("Could not set socket permissions: {}", e))?;
+    if let Some(identity) = identity {
+        chown(temp, Some(identity.uid), Some(identity.gid))
+            .map_err(|e| format!("Could not set socket ownership: {}", e))?;
+    }
+    Ok(())
+}
+
+/// Binds the socket at `path`, with its final mode and owner, replacing an old socket
+///
+/// The socket is set up under a temporary name and then renamed over the old
+/// one, so that clients never find the path missing or with the wrong permissions.
+pub fn bind(path: &Path, identity: Option<&TargetIdentity>, parent_dirs: &ParentDirs)
+        -> Result<UnixListener, String> {
+    create_parents(path, parent_dirs)?;
+    clear_old(path)?;
+    let temp = temp_path(path)?;
+    // Left behind if a previous process with the same PID was killed here
+    let _ = fs::remove_file(&temp);
+    let listener = UnixListener::bind(&temp)
+        .map_err(|e| format!("Could not open socket: {}", e))?;
+    let result = prepare(&temp, identity).and_then(|()| fs::rename(&temp, path)
+        .map_err(|e| format!("Could not move socket to {}: {}", path.display(), e)));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    Ok(listener)
+}
@@ -0,0 +1,643 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::io::Read;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use chrono::{Local, NaiveDate};
+
+use log::warn;
+
+use tokio::net::unix::UCred;
+use tokio::sync::{watch, Notify};
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use crate::config::{Approvals, Config, KeyConfig};
+#[cfg(feature = "email")]
+use crate::email;
+use crate::handover::Handover;
+use crate::journal;
+use crate::protocol::{self, Outcome};
+use crate::queue_file::{self, QueueWriter};
+use crate::rate_limit::RateLimiter;
+use crate::runner::{CommandRunner, ProcessRunner};
+use crate::services::{JobTable, ServiceTable};
+use crate::util::NonEmptyNoNullString;
+use crate::KeyResult;
+
+/// A loaded config together with the data derived from it
+///
+/// Snapshots are never modified; reloading the config replaces the whole
+/// snapshot, which also resets the rate limit buckets.
+#[derive(Debug)]
+pub struct ConfigSnapshot {
+    pub config: Config,
+    /// Length of the longest configured key
+    pub max_key_len: usize,
+    /// All configured keys in sorted order
+    pub sorted_keys: Vec<NonEmptyNoNullString>,
+    pub rate_limiter: RateLimiter
+}
+impl ConfigSnapshot {
+    pub fn new(config: Config) -> Self {
+        let mut sorted_keys: Vec<_> = config.keys.keys().cloned().collect();
+        sorted_keys.sort_unstable();
+        ConfigSnapshot {
+            max_key_len: sorted_keys.iter().map(|k| k.as_ref().len()).max().unwrap_or(0),
+            sorted_keys,
+            rate_limiter: RateLimiter::new(&config),
+            config
+        }
+    }
+}
+
+/// Triggers beyond this many are not queued during maintenance
+const MAX_DEFERRED: usize = 1024;
+
+/// A trigger received during maintenance, to be run once it ends
+#[derive(Debug)]
+pub struct DeferredTrigger {
+    pub key: Vec<u8>,
+    pub peer: Option<UCred>,
+    /// Whether a nonce or a token stood in for the key's confirmation
+    pub is_confirmed: bool,
+    /// Whether the key got through its rate limits, daily budget, and approvals,
+    /// which all triggers deferred by this daemon have
+    pub is_admitted: bool,
+    pub payload: Option<Vec<u8>>,
+    pub queued_at: SystemTime
+}
+
+#[derive(Debug, Default)]
+struct Maintenance {
+    is_active: bool,
+    deferred: Vec<DeferredTrigger>
+}
+
+#[derive(Debug)]
+struct RunningEntry {
+    key: String,
+    pid: Option<u32>,
+    started: Instant
+}
+
+/// The commands that are being waited on, including jobs that outlived a deadline
+#[derive(Debug, Default, Clone)]
+pub struct RunningTable {
+    next_id: Arc<AtomicU64>,
+    entries: Arc<Mutex<BTreeMap<u64, RunningEntry>>>
+}
+impl RunningTable {
+    /// Records a started command until the returned guard is dropped
+    pub fn insert(&self, key: &str, pid: Option<u32>) -> RunningGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let entry = RunningEntry {key: key.to_owned(), pid, started: Instant::now()};
+        journal::started(id, key, pid);
+        self.entries.lock().unwrap().insert(id, entry);
+        RunningGuard {table: self.clone(), id}
+    }
+
+    /// The key, PID, and elapsed time of every running command, oldest first
+    pub fn list(&self) -> Vec<(String, Option<u32>, Duration)> {
+        self.entries.lock().unwrap().values()
+            .map(|entry| (entry.key.clone(), entry.pid, entry.started.elapsed()))
+            .collect()
+    }
+}
+
+/// Removes a command from the running table when dropped
+#[derive(Debug)]
+pub struct RunningGuard {
+    table: RunningTable,
+    id: u64
+}
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        if let Some(entry) = self.table.entries.lock().unwrap().remove(&self.id) {
+            journal::ended(self.id, entry.started.elapsed());
+        }
+    }
+}
+
+#[cfg(feature = "email")]
+#[derive(Debug, Default)]
+struct Streak {
+    count: u32,
+    stderr: Vec<u8>
+}
+
+/// How many times in a row each key failed, with the end of the stderr of its last command
+#[cfg(feature = "email")]
+#[derive(Debug, Default)]
+pub struct FailureStreaks(Mutex<HashMap<String, Streak>>);
+#[cfg(feature = "email")]
+impl FailureStreaks {
+    /// Keeps the end of a command's stderr for the outcome recorded next
+    pub fn set_stderr(&self, key: &str, stderr: &[u8]) {
+        let excerpt = &stderr[stderr.len().saturating_sub(email::STDERR_EXCERPT_LEN)..];
+        let mut streaks = self.0.lock().unwrap();
+        streaks.entry(key.to_owned()).or_default().stderr = excerpt.to_vec();
+    }
+
+    /// Counts the outcome, returning the stderr of the last command once the key
+    /// has failed exactly the given number of times in a row
+    pub fn record(&self, key: &str, outcome: Outcome, alert_after: u32) -> Option<Vec<u8>> {
+        let mut streaks = self.0.lock().unwrap();
+        if outcome.is_success() {
+            streaks.remove(key);
+            return None;
+        }
+        let streak = streaks.entry(key.to_owned()).or_default();
+        streak.count += 1;
+        let stderr = std::mem::take(&mut streak.stderr);
+        (streak.count == alert_after).then_some(stderr)
+    }
+}
+
+/// The distinct peers that have requested each key needing approvals, and when the first of them did
+#[derive(Debug, Default)]
+pub struct ApprovalTable(Mutex<HashMap<String, (Instant, BTreeSet<u32>)>>);
+impl ApprovalTable {
+    /// Counts a request from the peer, returning how many approvals the key has
+    /// unless it now has enough of them, which starts the count over
+    pub fn approve(&self, key: &str, uid: u32, approvals: &Approvals) -> Option<u8> {
+        let now = Instant::now();
+        let mut pending = self.0.lock().unwrap();
+        let (armed_at, uids) = pending.entry(key.to_owned()).or_insert_with(|| (now, BTreeSet::new()));
+        if now.duration_since(*armed_at) > approvals.window {
+            *armed_at = now;
+            uids.clear();
+        }
+        uids.insert(uid);
+        if uids.len() < approvals.count.into() {
+            return Some(uids.len() as u8);
+        }
+        pending.remove(key);
+        None
+    }
+}
+
+/// How long each key with a daily budget has run for since the budgets last started over
+#[derive(Debug, Default)]
+pub struct RuntimeBudgets(Mutex<HashMap<String, (NaiveDate, Duration)>>);
+impl RuntimeBudgets {
+    /// The day that is counted against, which starts at the reset hour
+    fn today(reset_hour: u8) -> NaiveDate {
+        (Local::now() - chrono::Duration::hours(reset_hour.into())).date_naive()
+    }
+
+    /// Whether the key has run for less than its budget today
+    pub fn has_left(&self, key: &str, budget: Duration, reset_hour: u8) -> bool {
+        let today = Self::today(reset_hour);
+        match self.0.lock().unwrap().get(key) {
+            Some(&(day, used)) if day == today => used < budget,
+            _ => true
+        }
+    }
+
+    /// Counts a run of the key against today's budget
+    pub fn spend(&self, key: &str, duration: Duration, reset_hour: u8) {
+        let today = Self::today(reset_hour);
+        let mut budgets = self.0.lock().unwrap();
+        let (day, used) = budgets.entry(key.to_owned()).or_insert((today, Duration::ZERO));
+        if *day != today {
+            *day = today;
+            *used = Duration::ZERO;
+        }
+        *used += duration;
+    }
+}
+
+/// Single-use tokens, each for a key until it expires
+#[derive(Debug, Default)]
+pub struct TokenTable(Mutex<HashMap<u128, (String, Instant)>>);
+impl TokenTable {
+    /// Mints a random token for the key
+    pub fn mint(&self, key: &str, lifetime: Duration) -> std::io::Result<u128> {
+        let mut bytes = [0u8; 16];
+        std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+        let token = u128::from_be_bytes(bytes);
+        let now = Instant::now();
+        let mut tokens = self.0.lock().unwrap();
+        tokens.retain(|_, (_, expiry)| *expiry > now);
+        tokens.insert(token, (key.to_owned(), now + lifetime));
+        Ok(token)
+    }
+
+    /// Invalidates the token, given as 32 lowercase hex characters, returning its key if it had not expired
+    pub fn redeem(&self, token: &str) -> Option<String> {
+        if token.len() != 32 || !token.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f')) {
+            return None;
+        }
+        let token = u128::from_str_radix(token, 16).ok()?;
+        let (key, expiry) = self.0.lock().unwrap().remove(&token)?;
+        (expiry > Instant::now()).then_some(key)
+    }
+}
+
+/// Runs beyond this many are dropped from the history, oldest first
+const MAX_HISTORY: usize = 1000;
+
+/// A run of a key, as kept in the history
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub key: String,
+    pub start: SystemTime,
+    pub duration: Duration,
+    pub outcome: Outcome,
+    /// The uid of the peer that requested it, if known
+    pub uid: Option<u32>
+}
+
+/// The latest runs of keys, oldest first
+#[derive(Debug, Default)]
+pub struct History(Mutex<VecDeque<HistoryEntry>>);
+impl History {
+    pub fn record(&self, entry: HistoryEntry) {
+        let mut entries = self.0.lock().unwrap();
+        if entries.len() == MAX_HISTORY {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// The latest `count` runs
+    pub fn latest(&self, count: usize) -> Vec<HistoryEntry> {
+        let entries = self.0.lock().unwrap();
+        entries.iter().skip(entries.len().saturating_sub(count)).cloned().collect()
+    }
+
+    /// The runs of the key, or of every key, that started at or after `since`
+    pub fn query(&self, key: Option<&str>, since: SystemTime) -> Vec<HistoryEntry> {
+        self.0.lock().unwrap().iter()
+            .filter(|entry| key.is_none_or(|key| entry.key == key) && entry.start >= since)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Finished results beyond this many make room by dropping the oldest
+const MAX_RETAINED: usize = 1024;
+
+#[derive(Debug)]
+struct RetainedEntry {
+    key: Vec<u8>,
+    result: watch::Sender<Option<Arc<KeyResult>>>,
+    /// When the key's result was stored, or `None` while it still runs
+    finished: Option<Instant>
+}
+
+/// A result retained by a `RETAIN` frame, which may still be running
+#[derive(Debug)]
+pub struct Retained {
+    pub key: Vec<u8>,
+    result: watch::Receiver<Option<Arc<KeyResult>>>
+}
+impl Retained {
+    /// Waits for the result, returning `None` if it was acknowledged before it finished
+    pub(crate) async fn wait(mut self) -> Option<Arc<KeyResult>> {
+        self.result.wait_for(Option::is_some).await.ok().and_then(|result| result.clone())
+    }
+}
+
+/// The id that the result of a job is retained under, for `JOB` frames
+///
+/// It contains a space, so no `RETAIN` frame can use it or acknowledge it.
+pub fn job_result_id(job_id: u32) -> String {
+    format!("job {}", job_id)
+}
+
+/// Results kept by `RETAIN` frames until acknowledged, by the peer's uid and the client's id
+#[derive(Debug, Default)]
+pub struct ResultTable(Mutex<HashMap<(Option<u32>, String), RetainedEntry>>);
+impl ResultTable {
+    /// Starts retaining the result of the key under the id, returning `None` if
+    /// the caller is to run it, or the result already retained under the id
+    pub fn retain(&self, uid: Option<u32>, id: &str, key: &[u8]) -> Option<Retained> {
+        let now = Instant::now();
+        let mut entries = self.0.lock().unwrap();
+        entries.retain(|_, entry| entry.finished.is_none_or(|finished| now - finished < protocol::RETENTION));
+        if let Some(entry) = entries.get(&(uid, id.to_owned())) {
+            return Some(Retained {key: entry.key.clone(), result: entry.result.subscribe()});
+        }
+        if entries.len() >= MAX_RETAINED {
+            let oldest = entries.iter()
+                .filter_map(|(name, entry)| Some((entry.finished?, name)))
+                .min()
+                .map(|(_, name)| name.clone());
+            if let Some(oldest) = oldest {
+                warn!("Dropping the unacknowledged result of {} to retain more", oldest.1);
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert((uid, id.to_owned()), RetainedEntry {
+            key: key.to_owned(),
+            result: watch::Sender::new(None),
+            finished: None
+        });
+        None
+    }
+
+    /// Stores the result retained under the id, unless it has been acknowledged already
+    pub(crate) fn finish(&self, uid: Option<u32>, id: &str, result: Arc<KeyResult>) {
+        if let Some(entry) = self.0.lock().unwrap().get_mut(&(uid, id.to_owned())) {
+            entry.result.send_replace(Some(result));
+            entry.finished = Some(Instant::now());
+        }
+    }
+
+    /// The result retained under the id, if it is
+    pub fn fetch(&self, uid: Option<u32>, id: &str) -> Option<Retained> {
+        let entries = self.0.lock().unwrap();
+        let entry = entries.get(&(uid, id.to_owned()))
+            .filter(|entry| entry.finished.is_none_or(|finished| finished.elapsed() < protocol::RETENTION))?;
+        Some(Retained {key: entry.key.clone(), result: entry.result.subscribe()})
+    }
+
+    /// Stops retaining the result under the id, returning whether it was retained
+    pub fn acknowledge(&self, uid: Option<u32>, id: &str) -> bool {
+        self.0.lock().unwrap().remove(&(uid, id.to_owned())).is_some()
+    }
+}
+
+/// Counts a connection as open until dropped
+#[derive(Debug)]
+pub struct ConnectionGuard<'a>(&'a AtomicUsize);
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// State shared by all connection handlers
+#[derive(Debug)]
+pub struct ServerState {
+    // The lock is only held long enough to clone the Arc
+    snapshot: RwLock<Arc<ConfigSnapshot>>,
+    /// Starts the commands of keys that are waited on
+    pub runner: Arc<dyn CommandRunner>,
+    next_job_id: AtomicU32,
+    /// Commands started by detached keys, which outlive config reloads
+    pub services: ServiceTable,
+    /// Commands that outlived a client deadline
+    pub jobs: JobTable,
+    /// Commands that are being waited on
+    pub running: RunningTable,
+    /// Failures in a row of each key, for email alerts
+    #[cfg(feature = "email")]
+    pub failure_streaks: FailureStreaks,
+    /// Tokens minted by `MINT` frames that have not been presented yet
+    pub tokens: TokenTable,
+    /// Nonces that confirm a request for a key with `confirm` set
+    pub confirmations: TokenTable,
+    /// Keys with `approvals` set that are waiting for more peers to request them
+    pub approvals: ApprovalTable,
+    /// Time used today by keys with `daily_budget_ms` set
+    pub budgets: RuntimeBudgets,
+    /// Results of `RETAIN` frames that have not been acknowledged
+    pub retained: Arc<ResultTable>,
+    /// The latest runs, for `HISTORY` frames
+    pub history: History,
+    open_connections: AtomicUsize,
+    // Requests by outcome label since the daemon started
+    outcome_counts: Mutex<BTreeMap<&'static str, u64>>,
+    // Set by admin frames, and kept across reloads so that a fenced off key stays that way
+    enabled_overrides: Mutex<HashMap<String, bool>>,
+    // Shared with the queue file's writes, which snapshot it off the runtime's worker threads
+    maintenance: Arc<Mutex<Maintenance>>,
+    queue_writer: Arc<QueueWriter>,
+    // Looked up in every snapshot, so that a reload can change the profile's responses
+    response_profile: OnceLock<String>,
+    /// Notified when maintenance ends with triggers left to run
+    pub maintenance_ended: Notify,
+    is_halting: watch::Sender<bool>
+}
+impl ServerState {
+    pub fn new(snapshot: ConfigSnapshot) -> Self {
+        Self::with_runner(snapshot, Arc::new(ProcessRunner))
+    }
+
+    pub fn with_runner(snapshot: ConfigSnapshot, runner: Arc<dyn CommandRunner>) -> Self {
+        ServerState {
+            snapshot: RwLock::new(Arc::new(snapshot)),
+            runner,
+            next_job_id: AtomicU32::new(1),
+            services: ServiceTable::default(),
+            jobs: JobTable::default(),
+            running: RunningTable::default(),
+            #[cfg(feature = "email")]
+            failure_streaks: FailureStreaks::default(),
+            tokens: TokenTable::default(),
+            confirmations: TokenTable::default(),
+            approvals: ApprovalTable::default(),
+            budgets: RuntimeBudgets::default(),
+            retained: Arc::default(),
+            history: History::default(),
+            open_connections: AtomicUsize::new(0),
+            outcome_counts: Mutex::new(BTreeMap::new()),
+            enabled_overrides: Mutex::new(HashMap::new()),
+            maintenance: Arc::default(),
+            queue_writer: Arc::default(),
+            response_profile: OnceLock::new(),
+            maintenance_ended: Notify::new(),
+            is_halting: watch::Sender::new(false)
+        }
+    }
+
+    /// Returns the current config snapshot
+    pub fn snapshot(&self) -> Arc<ConfigSnapshot> {
+        self.snapshot.read().unwrap().clone()
+    }
+
+    /// Allocates an id for a command that continues in the background
+    pub fn next_job_id(&self) -> u32 {
+        self.next_job_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Counts a connection as open for as long as the returned guard lives
+    pub fn open_connection(&self) -> ConnectionGuard<'_> {
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(&self.open_connections)
+    }
+
+    pub fn open_connections(&self) -> usize {
+        self.open_connections.load(Ordering::Relaxed)
+    }
+
+    /// Makes responses use a profile from the config instead of the standard vocabulary
+    pub fn select_response_profile(&self, name: String) {
+        let _ = self.response_profile.set(name);
+    }
+
+    /// The bytes sent back to the client for the outcome
+    pub fn response(&self, outcome: Outcome) -> Vec<u8> {
+        let snapshot = self.snapshot();
+        match self.response_profile.get().and_then(|name| snapshot.config.response_profiles.get(name)) {
+            Some(profile) => profile.response(outcome),
+            None => outcome.response()
+        }
+    }
+
+    pub fn record_outcome(&self, outcome: Outcome) {
+        *self.outcome_counts.lock().unwrap().entry(outcome.label()).or_insert(0) += 1;
+    }
+
+    /// Requests handled since the daemon started, by outcome label
+    pub fn outcome_counts(&self) -> BTreeMap<&'static str, u64> {
+        self.outcome_counts.lock().unwrap().clone()
+    }
+
+    /// Whether the key can be triggered, taking runtime overrides into account
+    pub fn is_enabled(&self, key: &str, key_config: &KeyConfig) -> bool {
+        self.enabled_overrides.lock().unwrap().get(key).copied().unwrap_or(key_config.enabled)
+    }
+
+    /// Overrides whether the key is enabled until the daemon restarts
+    pub fn set_enabled(&self, key: &str, enabled: bool) {
+        self.enabled_overrides.lock().unwrap().insert(key.to_owned(), enabled);
+    }
+
+    pub fn is_in_maintenance(&self) -> bool {
+        self.maintenance.lock().unwrap().is_active
+    }
+
+    /// Makes connections close once their current request has been answered
+    pub fn halt(&self) {
+        self.is_halting.send_replace(true);
+    }
+
+    pub fn is_halting(&self) -> bool {
+        *self.is_halting.borrow()
+    }
+
+    /// Returns a future that resolves once the daemon starts halting
+    pub fn halted(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut is_halting = self.is_halting.subscribe();
+        async move {
+            // The sender lives as long as the state, which outlives every request
+            let _ = is_halting.wait_for(|is_halting| *is_halting).await;
+        }
+    }
+
+    /// Enters or leaves maintenance mode
+    pub fn set_maintenance(&self, is_active: bool) {
+        let mut maintenance = self.maintenance.lock().unwrap();
+        maintenance.is_active = is_active;
+        if !is_active && !maintenance.deferred.is_empty() {
+            self.maintenance_ended.notify_one();
+        }
+    }
+
+    /// Returns true if the trigger has to wait for maintenance to end, queueing it if asked to
+    pub fn defer(&self, key: &[u8], peer: Option<&UCred>, is_confirmed: bool, payload: Option<&[u8]>, queue: bool) -> bool {
+        let mut maintenance = self.maintenance.lock().unwrap();
+        if !maintenance.is_active {
+            return false;
+        }
+        if queue && maintenance.deferred.len() < MAX_DEFERRED {
+            maintenance.deferred.push(DeferredTrigger {
+                key: key.to_owned(),
+                peer: peer.copied(),
+                is_confirmed,
+                is_admitted: true,
+                payload: payload.map(<[u8]>::to_vec),
+                queued_at: SystemTime::now()
+            });
+            self.save_deferred();
+        }
+        true
+    }
+
+    /// Writes the queued triggers to the `queue_file` on a blocking thread, if one is configured
+    ///
+    /// The triggers are read once the thread gets to write them, so that the
+    /// lock is not held for the write and triggers queued meanwhile share it.
+    fn save_deferred(&self) {
+        let Some(path) = self.snapshot().config.queue_file.clone() else {
+            return;
+        };
+        let maintenance = Arc::clone(&self.maintenance);
+        self.queue_writer.save_later(path, move || queue_file::encode(&maintenance.lock().unwrap().deferred));
+    }
+
+    /// Writes the queued triggers to the `queue_file` after any save still waiting
+    /// for its turn, so that the file is up to date once the daemon stops
+    pub async fn flush_deferred(&self) {
+        let Some(path) = self.snapshot().config.queue_file.clone() else {
+            return;
+        };
+        let maintenance = Arc::clone(&self.maintenance);
+        let writer = Arc::clone(&self.queue_writer);
+        let _ = tokio::task::spawn_blocking(move || {
+            let contents = queue_file::encode(&maintenance.lock().unwrap().deferred);
+            writer.save_now(&path, &contents);
+        }).await;
+    }
+
+    /// Queues triggers read back from the `queue_file`, to run once maintenance is not active
+    pub fn requeue(&self, triggers: Vec<DeferredTrigger>) {
+        let mut maintenance = self.maintenance.lock().unwrap();
+        let room = MAX_DEFERRED.saturating_sub(maintenance.deferred.len());
+        if triggers.len() > room {
+            warn!("Dropping {} queued triggers beyond the {} that can be queued", triggers.len() - room, MAX_DEFERRED);
+        }
+        maintenance.deferred.extend(triggers.into_iter().take(room));
+        if !maintenance.is_active && !maintenance.deferred.is_empty() {
+            self.maintenance_ended.notify_one();
+        }
+    }
+
+    /// Number of triggers queued during maintenance
+    pub fn deferred_len(&self) -> usize {
+        self.maintenance.lock().unwrap().deferred.len()
+    }
+
+    /// Takes the triggers queued during maintenance, unless it has started again
+    pub fn take_deferred(&self) -> Vec<DeferredTrigger> {
+        let mut maintenance = self.maintenance.lock().unwrap();
+        if maintenance.is_active {
+            return Vec::new();
+        }
+        let deferred = std::mem::take(&mut maintenance.deferred);
+        self.save_deferred();
+        deferred
+    }
+
+    /// Collects the runtime state to hand over to a re-executed daemon
+    ///
+    /// Queued triggers are dropped, since the peers that sent them cannot be
+    /// passed on, unless they are kept in a `queue_file`.
+    pub fn handover(&self, listen_fd: i32, log_path: String) -> Handover {
+        let maintenance = self.maintenance.lock().unwrap();
+        if !maintenance.deferred.is_empty() && self.snapshot().config.queue_file.is_none() {
+            warn!("Dropping {} triggers queued during maintenance", maintenance.deferred.len());
+        }
+        Handover {
+            listen_fd,
+            log_path,
+            services: self.services.export(),
+            jobs: self.jobs.export(),
+            enabled_overrides: self.enabled_overrides.lock().unwrap().clone(),
+            is_in_maintenance: maintenance.is_active,
+            next_job_id: self.next_job_id.load(Ordering::Relaxed)
+        }
+    }
+
+    /// Restores the runtime state handed over by the previous daemon process
+    pub fn restore(&self, handover: &Handover) {
+        for (key, &pid) in &handover.services {
+            self.services.adopt(key, pid);
+        }
+        for (&job_id, &pid) in &handover.jobs {
+            self.jobs.adopt(job_id, pid);
+        }
+        *self.enabled_overrides.lock().unwrap() = handover.enabled_overrides.clone();
+        self.maintenance.lock().unwrap().is_active = handover.is_in_maintenance;
+        self.next_job_id.store(handover.next_job_id, Ordering::Relaxed);
+    }
+
+    /// Replaces the config snapshot used by subsequent requests
+    pub fn replace_snapshot(&self, snapshot: ConfigSnapshot) {
+        *self.snapshot.write().unwrap() = Arc::new(snapshot);
+    }
+}
@@ -0,0 +1,108 @@
+//! The status dump logged on SIGUSR1, and sent in response to a `STATUS` frame
+
+use log::info;
+
+use serde_json::{json, Value};
+
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+use crate::json_response;
+use crate::state::ServerState;
+use crate::subreaper;
+
+/// How many of the latest runs a `STATUS` frame gets
+const RECENT_RUNS: usize = 10;
+
+/// How far back the runs counted for each key's rate go
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Logs what the daemon is doing right now, without going through the socket
+pub fn log(state: &ServerState) {
+    let snapshot = state.snapshot();
+    info!("Status: {} keys configured, maintenance {}, {} open connections, {} queued triggers{}",
+        snapshot.sorted_keys.len(),
+        if state.is_in_maintenance() { "on" } else { "off" },
+        state.open_connections(),
+        state.deferred_len(),
+        if state.is_halting() { ", stopping" } else { "" });
+    let keys: Vec<String> = snapshot.sorted_keys.iter()
+        .map(|key| {
+            let key = key.as_ref();
+            let key_config = &snapshot.config.keys[key];
+            match state.is_enabled(key, key_config) {
+                true => key.to_owned(),
+                false => format!("{} (disabled)", key)
+            }
+        })
+        .collect();
+    info!("Keys: {}", keys.join(", "));
+    for (key, pid, elapsed) in state.running.list() {
+        match pid {
+            Some(pid) => info!("Running key {} with PID {} for {:.1}s", key, pid, elapsed.as_secs_f64()),
+            None => info!("Running key {} for {:.1}s", key, elapsed.as_secs_f64())
+        }
+    }
+    for (job_id, pid) in state.jobs.export().into_iter().collect::<BTreeMap<_, _>>() {
+        info!("Job {} has PID {}", job_id, pid);
+    }
+    for (key, pid) in state.services.export().into_iter().collect::<BTreeMap<_, _>>() {
+        info!("Detached key {} has PID {}", key, pid);
+    }
+    for (pid, name, key) in subreaper::orphans() {
+        match key {
+            Some(key) => info!("Orphaned process {} ({}) of key {} is running", pid, name, key),
+            None => info!("Orphaned process {} ({}) is running", pid, name)
+        }
+    }
+    for (key, count) in subreaper::reaped_orphans() {
+        match key {
+            Some(key) => info!("Reaped {} orphaned processes of key {}", count, key),
+            None => info!("Reaped {} orphaned processes of unknown keys", count)
+        }
+    }
+    let counts: Vec<String> = state.outcome_counts().into_iter()
+        .map(|(label, count)| format!("{} {}", count, label))
+        .collect();
+    match counts.is_empty() {
+        true => info!("No requests handled yet"),
+        false => info!("Requests handled: {}", counts.join(", "))
+    }
+}
+
+/// What the daemon is doing right now, for a `STATUS` frame
+pub fn json(state: &ServerState) -> Value {
+    let snapshot = state.snapshot();
+    let window_start = SystemTime::now() - RATE_WINDOW;
+    let mut runs_last_minute: BTreeMap<String, u64> = BTreeMap::new();
+    for entry in state.history.query(None, window_start) {
+        *runs_last_minute.entry(entry.key).or_default() += 1;
+    }
+    let keys: Vec<Value> = snapshot.sorted_keys.iter()
+        .map(|key| {
+            let key = key.as_ref();
+            json!({
+                "key": key,
+                "enabled": state.is_enabled(key, &snapshot.config.keys[key]),
+                "runs_last_minute": runs_last_minute.get(key).copied().unwrap_or(0)
+            })
+        })
+        .collect();
+    let mut running = state.running.list();
+    running.sort_unstable_by_key(|(_, _, elapsed)| std::cmp::Reverse(*elapsed));
+    let running: Vec<Value> = running.into_iter()
+        .map(|(key, pid, elapsed)| json!({"key": key, "pid": pid, "elapsed_ms": elapsed.as_millis() as u64}))
+        .collect();
+    json!({
+        "maintenance": state.is_in_maintenance(),
+        "stopping": state.is_halting(),
+        "open_connections": state.open_connections(),
+        "queued_triggers": state.deferred_len(),
+        "jobs": state.jobs.export().len(),
+        "detached": state.services.export().len(),
+        "keys": keys,
+        "running": running,
+        "recent": json_response::history_json(&state.history.latest(RECENT_RUNS)),
+        "outcomes": state.outcome_counts()
+    })
+}
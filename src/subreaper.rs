@@ -0,0 +1,193 @@
+//! Adopting the orphaned descendants of commands
+//!
+//! With `--subreaper`, a process whose parent exits before it is reparented
+//! to the daemon instead of init. Tokio only waits for the children it
+//! spawned, so every child the daemon starts is recorded here, and any other
+//! child that exits is an orphan that is reaped by `run_reaper()`.
+
+use log::{info, warn};
+
+use nix::sys::wait::{waitpid, WaitPidFlag};
+use nix::unistd::{getpid, Pid};
+
+use tokio::process::{Child, Command};
+use tokio::signal::unix::{signal, SignalKind};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+static IS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Start times of the children that are waited on elsewhere, by PID
+///
+/// The start time tells a recorded child apart from an orphan that was
+/// given its PID after the child was reaped.
+static CHILDREN: Mutex<BTreeMap<u32, u64>> = Mutex::new(BTreeMap::new());
+
+/// The keys of commands that lead a process group, by PID, for as long as the group has processes
+static GROUPS: Mutex<BTreeMap<u32, String>> = Mutex::new(BTreeMap::new());
+
+/// How many orphans have been reaped, by the key whose command left them behind if it is known
+static REAPED: Mutex<BTreeMap<Option<String>, u64>> = Mutex::new(BTreeMap::new());
+
+/// The fields of `/proc/<pid>/stat` used here
+struct ProcStat {
+    name: String,
+    state: char,
+    ppid: i32,
+    pgrp: u32,
+    start_time: u64
+}
+
+fn read_stat(pid: u32) -> Option<ProcStat> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The name is in parentheses and may itself contain spaces and parentheses
+    let (head, tail) = stat.rsplit_once(')')?;
+    let name = head.split_once('(')?.1.to_owned();
+    let fields: Vec<&str> = tail.split_whitespace().collect();
+    Some(ProcStat {
+        name,
+        state: fields.first()?.chars().next()?,
+        ppid: fields.get(1)?.parse().ok()?,
+        pgrp: fields.get(2)?.parse().ok()?,
+        // Field 22 of the file, counting the PID and name
+        start_time: fields.get(19)?.parse().ok()?
+    })
+}
+
+/// Makes orphaned descendants of commands reparent to the daemon
+#[cfg(target_os = "linux")]
+pub fn enable() -> Result<(), String> {
+    nix::sys::prctl::set_child_subreaper(true)
+        .map_err(|e| format!("Could not become a subreaper: {}", e))?;
+    IS_ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable() -> Result<(), String> {
+    Err("Subreaper mode is only supported on Linux".to_owned())
+}
+
+pub fn is_enabled() -> bool {
+    IS_ENABLED.load(Ordering::Relaxed)
+}
+
+fn record(children: &mut BTreeMap<u32, u64>, pid: u32) {
+    // A child that has exited keeps its stat file until it is reaped
+    if let Some(stat) = read_stat(pid) {
+        children.insert(pid, stat.start_time);
+    }
+}
+
+/// Records a child that is waited on elsewhere, so that it is not mistaken for an orphan
+pub fn track(pid: u32) {
+    if is_enabled() {
+        record(&mut CHILDREN.lock().unwrap(), pid);
+    }
+}
+
+/// Records the key of a command that leads a process group, so that orphans left in it
+/// are counted for that key
+pub fn name_group(pid: u32, key: &str) {
+    if is_enabled() {
+        GROUPS.lock().unwrap().insert(pid, key.to_owned());
+    }
+}
+
+/// Spawns the command and records the child
+pub fn spawn(cmd: &mut Command) -> std::io::Result<Child> {
+    if !is_enabled() {
+        return cmd.spawn();
+    }
+    // Hold the lock so that the reaper cannot see the child before it is recorded
+    let mut children = CHILDREN.lock().unwrap();
+    let child = cmd.spawn()?;
+    if let Some(pid) = child.id() {
+        record(&mut children, pid);
+    }
+    Ok(child)
+}
+
+/// Calls `f` with every child that was not started by the daemon, along with the key
+/// of the command whose process group it is in, forgetting recorded children that
+/// have since been reaped and groups that have no processes left
+fn for_each_orphan(mut f: impl FnMut(u32, &ProcStat, Option<&str>)) {
+    let own_pid = getpid().as_raw();
+    let mut children = CHILDREN.lock().unwrap();
+    let proc_entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Could not list processes to find orphans: {}", e);
+            return;
+        }
+    };
+    let mut live_children = BTreeMap::new();
+    let mut live_groups = BTreeSet::new();
+    let mut groups = GROUPS.lock().unwrap();
+    for pid in proc_entries.filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok()) {
+        let stat = match read_stat(pid) {
+            Some(stat) if stat.ppid == own_pid => stat,
+            _ => continue
+        };
+        live_groups.insert(stat.pgrp);
+        if children.get(&pid) == Some(&stat.start_time) {
+            live_children.insert(pid, stat.start_time);
+        } else {
+            f(pid, &stat, groups.get(&stat.pgrp).map(String::as_str));
+        }
+    }
+    *children = live_children;
+    groups.retain(|pgid, _| live_groups.contains(pgid));
+}
+
+/// Reaps the children that have exited and were not started by the daemon
+fn reap_orphans() {
+    for_each_orphan(|pid, stat, key| {
+        if stat.state != 'Z' {
+            return;
+        }
+        match waitpid(Pid::from_raw(pid as i32), Some(WaitPidFlag::WNOHANG)) {
+            Ok(status) => {
+                match key {
+                    Some(key) => info!("Reaped orphaned process {} ({}) of key {}: {:?}", pid, stat.name, key, status),
+                    None => info!("Reaped orphaned process {} ({}): {:?}", pid, stat.name, status)
+                }
+                *REAPED.lock().unwrap().entry(key.map(str::to_owned)).or_insert(0) += 1;
+            },
+            Err(e) => warn!("Could not reap orphaned process {} ({}): {}", pid, stat.name, e)
+        }
+    });
+}
+
+/// The PIDs, names, and keys of the orphans that are still running
+pub fn orphans() -> Vec<(u32, String, Option<String>)> {
+    let mut orphans = Vec::new();
+    if is_enabled() {
+        for_each_orphan(|pid, stat, key| if stat.state != 'Z' {
+            orphans.push((pid, stat.name.clone(), key.map(str::to_owned)));
+        });
+    }
+    orphans
+}
+
+/// How many orphans have been reaped, by the key whose command left them behind if it is known
+pub fn reaped_orphans() -> BTreeMap<Option<String>, u64> {
+    REAPED.lock().unwrap().clone()
+}
+
+/// Reaps orphans whenever a child exits, until the daemon stops
+pub async fn run_reaper() {
+    let mut sigchld = match signal(SignalKind::child()) {
+        Ok(sigchld) => sigchld,
+        Err(e) => {
+            warn!("Could not listen for exiting children, so orphans will not be reaped: {}", e);
+            return;
+        }
+    };
+    loop {
+        reap_orphans();
+        sigchld.recv().await;
+    }
+}
@@ -0,0 +1,103 @@
+//! Socket activation, readiness notification, and unit jobs under systemd
+
+use log::{debug, warn};
+
+use nix::sys::stat::{fstat, SFlag};
+use nix::unistd::getpid;
+
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+
+use crate::config::{UnitJob, UnitOperation};
+use crate::dbus::{self, Arg};
+
+/// The first descriptor passed by the service manager
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Takes the listening socket passed by systemd, if the daemon was socket activated
+///
+/// The variables are removed so that commands and re-executed daemons do not
+/// mistake the socket as theirs.
+pub fn take_listener() -> Result<Option<UnixListener>, String> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if pid.and_then(|pid| pid.parse::<i32>().ok()) != Some(getpid().as_raw()) {
+        return Ok(None);
+    }
+    match fds.as_deref() {
+        None | Some("0") => return Ok(None),
+        Some("1") => {},
+        Some(_) => return Err("Socket activation must pass exactly one socket".to_owned())
+    }
+    let is_socket = fstat(LISTEN_FDS_START)
+        .map(|stat| SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFSOCK)
+        .unwrap_or(false);
+    if !is_socket {
+        return Err("Socket activation passed a descriptor that is not a socket".to_owned());
+    }
+    // SAFETY: the service manager passed the descriptor to this process alone,
+    // and the variables naming it were removed above
+    #[allow(unsafe_code)]
+    let listener = unsafe { UnixListener::from_raw_fd(LISTEN_FDS_START) };
+    Ok(Some(listener))
+}
+
+/// Sends a state change such as `READY=1` to the service manager, if there is one
+pub fn notify(state: &str) {
+    let path = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return
+    };
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        let path = std::path::Path::new(&path);
+        #[cfg(target_os = "linux")]
+        if let Some(name) = path.to_str().and_then(|path| path.strip_prefix('@')) {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            return socket.send_to_addr(state.as_bytes(), &addr);
+        }
+        socket.send_to(state.as_bytes(), path)
+    });
+    if let Err(e) = result {
+        warn!("Could not notify service manager: {}", e);
+    }
+}
+
+const SYSTEMD_NAME: &str = "org.freedesktop.systemd1";
+const MANAGER_PATH: &str = "/org/freedesktop/systemd1";
+const MANAGER_INTERFACE: &str = "org.freedesktop.systemd1.Manager";
+
+/// Queues a job with the service manager over D-Bus and waits for it to finish
+///
+/// Returns the job result, such as `done` or `failed`.
+pub async fn run_unit_job(job: &UnitJob) -> Result<String, String> {
+    let mut bus = dbus::Connection::system().await?;
+    let match_rule = format!("type='signal',sender='{}',interface='{}',member='JobRemoved'",
+        SYSTEMD_NAME, MANAGER_INTERFACE);
+    bus.call("org.freedesktop.DBus", "/org/freedesktop/DBus", "org.freedesktop.DBus", "AddMatch",
+        &[Arg::Str(match_rule)]).await?;
+    // Job signals are only sent while some client is subscribed
+    bus.call(SYSTEMD_NAME, MANAGER_PATH, MANAGER_INTERFACE, "Subscribe", &[]).await?;
+    let method = match job.operation {
+        UnitOperation::Start => "StartUnit",
+        UnitOperation::Stop => "StopUnit",
+        UnitOperation::Restart => "RestartUnit"
+    };
+    let reply = bus.call(SYSTEMD_NAME, MANAGER_PATH, MANAGER_INTERFACE, method,
+        &[Arg::Str(job.unit.clone()), Arg::Str("replace".to_owned())]).await?;
+    let job_path = reply.first().and_then(Arg::as_str)
+        .ok_or_else(|| format!("{} did not return a job", method))?
+        .to_owned();
+    debug!("Queued job {} to {} {}", job_path, job.operation.as_str(), job.unit);
+    loop {
+        // The arguments are the job id, job path, unit name, and result
+        let args = bus.signal(MANAGER_INTERFACE, "JobRemoved").await?;
+        if args.get(1).and_then(Arg::as_str) == Some(&job_path) {
+            return args.get(3).and_then(Arg::as_str).map(str::to_owned)
+                .ok_or_else(|| "JobRemoved signal has no result".to_owned());
+        }
+    }
+}
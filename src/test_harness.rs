@@ -0,0 +1,107 @@
+//! Running the connection handler against in-memory streams, for tests
+//!
+//! Connections made with [`TestServer::connect`] have no peer credentials, so
+//! they are treated like clients of another user.
+
+use tokio::io::DuplexStream;
+use tokio::net::UnixStream;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config;
+use crate::runner::ProcessRunner;
+
+// For decoding responses
+pub use crate::protocol::{response_len, FRAME_MARKER};
+// For implementing fake runners
+pub use crate::config::KeyConfig;
+pub use crate::runner::{CommandOutput, CommandRunner, RunningCommand};
+use crate::state::{ConfigSnapshot, ServerState};
+use crate::transport::Connection;
+
+/// Bytes a connection buffers in each direction before writes wait for the other side
+const DUPLEX_CAPACITY: usize = 64*1024;
+
+/// A server without a socket, serving connections made with [`TestServer::connect`]
+#[derive(Debug)]
+pub struct TestServer {
+    state: Arc<ServerState>,
+    send: Sender<()>,
+    recv: Receiver<()>
+}
+impl TestServer {
+    /// Loads the config file or directory like the daemon would, without checking its permissions
+    pub fn new(config_path: &Path) -> Result<Self, String> {
+        Self::with_runner(config_path, Arc::new(ProcessRunner))
+    }
+
+    /// Like [`TestServer::new`], but starting commands with the given runner
+    pub fn with_runner(config_path: &Path, runner: Arc<dyn CommandRunner>) -> Result<Self, String> {
+        let config = config::load_config(config_path)?;
+        let state = Arc::new(ServerState::with_runner(ConfigSnapshot::new(config), runner));
+        crate::resume_queued(&state)?;
+        let (send, recv) = channel(1);
+        Ok(TestServer {state, send, recv})
+    }
+
+    /// Opens a connection and returns the client end of it
+    ///
+    /// This must be called from within a tokio runtime.
+    pub fn connect(&self) -> DuplexStream {
+        let (client, server) = tokio::io::duplex(DUPLEX_CAPACITY);
+        let connection = Connection {stream: server, peer: None};
+        tokio::spawn(crate::handle_connection(self.state.clone(), connection, self.send.clone()));
+        client
+    }
+
+    /// Like [`TestServer::connect`], but over a socket pair, so that the connection
+    /// has the credentials of this process and is treated as an admin
+    pub fn connect_unix(&self) -> std::io::Result<UnixStream> {
+        let (client, server) = UnixStream::pair()?;
+        let peer = Some(server.peer_cred()?);
+        let connection = Connection {stream: server, peer};
+        tokio::spawn(crate::handle_connection(self.state.clone(), connection, self.send.clone()));
+        Ok(client)
+    }
+
+    /// Answers with a response profile from the config, like `--response-profile`
+    pub fn select_response_profile(&self, name: &str) {
+        self.state.select_response_profile(name.to_owned());
+    }
+
+    /// Mints a token for the key like a `MINT` frame from an admin would, returning it
+    pub fn mint_token(&self, key: &str, lifetime: Duration) -> std::io::Result<String> {
+        self.state.tokens.mint(key, lifetime).map(|token| format!("{:032x}", token))
+    }
+
+    /// Approves the key like a request from a peer with the UID would, returning
+    /// how many approvals it has, or `None` if this one completed them
+    pub fn approve(&self, key: &str, uid: u32) -> Option<u8> {
+        let snapshot = self.state.snapshot();
+        let approvals = snapshot.config.keys[key].approvals.as_ref().expect("Key needs approvals");
+        self.state.approvals.approve(key, uid, approvals)
+    }
+
+    /// Runs the triggers queued during maintenance, or read back from the `queue_file`,
+    /// like the daemon does once maintenance ends
+    pub async fn run_queued(&self) {
+        crate::run_deferred(self.state.clone(), self.send.clone()).await;
+    }
+
+    /// Starts shutting down like on Ctrl-C, so that connections close after their next response
+    pub fn halt(&self) {
+        self.state.halt();
+    }
+
+    /// Shuts down and waits for every connection to close and every job to finish
+    pub async fn shutdown(mut self) {
+        self.state.halt();
+        drop(self.send);
+        let _ = self.recv.recv().await;
+        self.state.jobs.drained().await;
+        self.state.flush_deferred().await;
+    }
+}
@@ -0,0 +1,107 @@
+//! Windows of local time that a key may be run in, written like `Mon-Fri 09:00-17:00`
+
+use chrono::{Datelike, Local, Timelike};
+
+const DAY_NAMES: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+const MINUTES_PER_DAY: u16 = 24*60;
+
+/// Days of the week and a range of times on them
+///
+/// A range that ends before it starts continues past midnight into the next day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindow {
+    /// Bit `n` is set for the `n`th day from Monday
+    days: u8,
+    /// Minutes after midnight, with the end excluded
+    start: u16,
+    end: u16
+}
+
+fn parse_day(name: &str) -> Result<u8, String> {
+    DAY_NAMES.iter().position(|day| day.eq_ignore_ascii_case(name))
+        .map(|index| index as u8)
+        .ok_or_else(|| format!("Unknown day {:?}", name))
+}
+
+fn parse_days(days: &str) -> Result<u8, String> {
+    let mut mask = 0;
+    for part in days.split(',') {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (parse_day(first)?, parse_day(last)?),
+            None => (parse_day(part)?, parse_day(part)?)
+        };
+        // Ranges like Sat-Mon wrap around the end of the week
+        let mut day = first;
+        loop {
+            mask |= 1 << day;
+            if day == last {
+                break;
+            }
+            day = (day + 1) % 7;
+        }
+    }
+    Ok(mask)
+}
+
+fn parse_time(time: &str) -> Result<u16, String> {
+    let minutes = time.split_once(':')
+        .filter(|(hours, minutes)| hours.len() == 2 && minutes.len() == 2)
+        .and_then(|(hours, minutes)| Some((hours.parse::<u16>().ok()?, minutes.parse::<u16>().ok()?)))
+        .filter(|&(hours, minutes)| minutes < 60 && hours*60 + minutes <= MINUTES_PER_DAY)
+        .map(|(hours, minutes)| hours*60 + minutes);
+    minutes.ok_or_else(|| format!("Invalid time {:?}, which should be HH:MM", time))
+}
+
+impl TimeWindow {
+    /// Parses `<days> <times>`, `<days>`, or `<times>`
+    ///
+    /// Days are names like `Mon` or ranges of them like `Mon-Fri`, separated
+    /// by commas, and times are a range like `09:00-17:00`.
+    pub fn parse(window: &str) -> Result<Self, String> {
+        let (days, times) = match window.split_once(' ') {
+            Some((days, times)) => (Some(days), Some(times)),
+            None if window.contains(':') => (None, Some(window)),
+            None => (Some(window), None)
+        };
+        let days = days.map(parse_days).transpose()?.unwrap_or(0x7f);
+        let (start, end) = match times {
+            Some(times) => {
+                let (start, end) = times.split_once('-')
+                    .ok_or_else(|| format!("Invalid times {:?}, which should be HH:MM-HH:MM", times))?;
+                (parse_time(start)?, parse_time(end)?)
+            },
+            None => (0, MINUTES_PER_DAY)
+        };
+        if start == end {
+            return Err(format!("Window {:?} is empty", window));
+        }
+        Ok(TimeWindow {days, start: start % MINUTES_PER_DAY, end})
+    }
+
+    /// Whether the window covers the minute of the day on the day `weekday` days from Monday
+    fn contains(&self, weekday: u8, minute: u16) -> bool {
+        let on = |day: u8| self.days & (1 << day) != 0;
+        match self.start < self.end {
+            true => on(weekday) && (self.start..self.end).contains(&minute),
+            false => (on(weekday) && minute >= self.start) || (on((weekday + 6) % 7) && minute < self.end)
+        }
+    }
+}
+impl std::fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let days: Vec<&str> = (0..7).filter(|day| self.days & (1 << day) != 0)
+            .map(|day| DAY_NAMES[day])
+            .collect();
+        write!(f, "{} {:02}:{:02}-{:02}:{:02}", days.join(","),
+            self.start / 60, self.start % 60, self.end / 60, self.end % 60)
+    }
+}
+
+/// Whether the local time now is in any of the windows
+pub fn is_open(windows: &[TimeWindow]) -> bool {
+    let now = Local::now();
+    let weekday = now.weekday().num_days_from_monday() as u8;
+    let minute = (now.hour()*60 + now.minute()) as u16;
+    windows.iter().any(|window| window.contains(weekday, minute))
+}
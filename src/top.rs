@@ -0,0 +1,123 @@
+//! The `status` and `top` subcommands, which show what a running daemon is doing, once or refreshing until interrupted
+
+use argh::FromArgs;
+
+use serde_json::Value;
+
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::client;
+use crate::history;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(FromArgs)]
+#[argh(description = "Show the keys, running commands, and latest runs of a running daemon, refreshing until interrupted")]
+#[argh(example = "sock_trigger_cmd top --interval 5 /run/sock_trigger_cmd.sock")]
+pub struct TopArgs {
+    #[argh(option, default = "2")]
+    #[argh(description = "seconds between refreshes (default: 2)")]
+    interval: u64,
+    #[argh(positional)]
+    #[argh(description = "location of the daemon's socket")]
+    socket_location: Option<PathBuf>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(FromArgs)]
+#[argh(description = "Print the keys, running commands, and latest runs of a running daemon")]
+#[argh(example = "sock_trigger_cmd status --json /run/sock_trigger_cmd.sock")]
+pub struct StatusArgs {
+    #[argh(switch)]
+    #[argh(description = "print the status as JSON")]
+    json: bool,
+    #[argh(positional)]
+    #[argh(description = "location of the daemon's socket")]
+    socket_location: Option<PathBuf>
+}
+
+/// Clears the terminal and moves the cursor to the top left
+const CLEAR_SCREEN: &str = "\x1b[H\x1b[2J";
+
+fn total_requests(status: &Value) -> u64 {
+    status["outcomes"].as_object()
+        .map_or(0, |outcomes| outcomes.values().filter_map(Value::as_u64).sum())
+}
+
+/// The screen for the status, with the request rate since the previous one if there was one
+fn render(status: &Value, request_rate: Option<f64>) -> String {
+    let mut screen = String::new();
+    let flag = |name: &str| match status[name].as_bool() {
+        Some(true) => "on",
+        _ => "off"
+    };
+    write!(screen, "maintenance {}  {} open connections  {} queued triggers  {} jobs  {} detached",
+        flag("maintenance"), status["open_connections"], status["queued_triggers"], status["jobs"],
+        status["detached"]).unwrap();
+    if let Some(rate) = request_rate {
+        write!(screen, "  {:.1} requests/s", rate).unwrap();
+    }
+    if status["stopping"].as_bool() == Some(true) {
+        screen += "  stopping";
+    }
+    screen += "\n\nRunning:\n";
+    let running = status["running"].as_array().map_or(&[][..], Vec::as_slice);
+    if running.is_empty() {
+        screen += "  nothing\n";
+    }
+    for command in running {
+        let elapsed = command["elapsed_ms"].as_u64().unwrap_or(0) as f64 / 1000.0;
+        match command["pid"].as_u64() {
+            Some(pid) => writeln!(screen, "  {}  PID {}  {:.1}s", command["key"], pid, elapsed).unwrap(),
+            None => writeln!(screen, "  {}  {:.1}s", command["key"], elapsed).unwrap()
+        }
+    }
+    screen += "\nKeys (runs in the last minute):\n";
+    let keys = status["keys"].as_array().map_or(&[][..], Vec::as_slice);
+    let key_width = keys.iter().map(|key| key["key"].to_string().chars().count()).max().unwrap_or(0);
+    for key in keys {
+        write!(screen, "  {:width$}  {}", key["key"].to_string(), key["runs_last_minute"], width = key_width).unwrap();
+        if key["enabled"].as_bool() == Some(false) {
+            screen += "  (disabled)";
+        }
+        screen += "\n";
+    }
+    screen += "\nLatest runs:\n";
+    let recent = status["recent"].as_array().map_or(&[][..], Vec::as_slice);
+    if recent.is_empty() {
+        screen += "  none\n";
+    }
+    for line in history::run_lines(recent) {
+        writeln!(screen, "  {}", line).unwrap();
+    }
+    screen
+}
+
+pub fn run_once(args: StatusArgs) -> Result<(), String> {
+    let status = client::admin_request(args.socket_location.as_deref(), "STATUS")?;
+    match status {
+        Value::Object(status) if args.json => crate::print_json_output(status, true),
+        Value::Object(_) => print!("{}", render(&status, None)),
+        _ => return Err("The daemon sent an invalid status".to_owned())
+    }
+    Ok(())
+}
+
+pub fn run(args: TopArgs) -> Result<(), String> {
+    if args.interval == 0 {
+        return Err("--interval must be at least 1 second".to_owned());
+    }
+    let mut previous: Option<(Instant, u64)> = None;
+    loop {
+        let status = client::admin_request(args.socket_location.as_deref(), "STATUS")?;
+        let now = Instant::now();
+        let total = total_requests(&status);
+        let request_rate = previous.map(|(then, previous_total)|
+            total.saturating_sub(previous_total) as f64 / now.duration_since(then).as_secs_f64());
+        print!("{}{}", CLEAR_SCREEN, render(&status, request_rate));
+        std::io::Write::flush(&mut std::io::stdout()).map_err(|e| format!("Could not write to stdout: {}", e))?;
+        previous = Some((now, total));
+        std::thread::sleep(Duration::from_secs(args.interval));
+    }
+}
@@ -0,0 +1,36 @@
+//! The listeners that clients connect through
+//!
+//! Connection handling only sees a byte stream and the peer's credentials, so
+//! a new kind of listener only needs a [`TriggerTransport`] implementation.
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::net::unix::UCred;
+
+use std::future::Future;
+
+/// An accepted connection
+#[derive(Debug)]
+pub struct Connection<S> {
+    pub stream: S,
+    /// The credentials of the peer, if the transport can tell them
+    pub peer: Option<UCred>
+}
+
+/// A listener that client connections are accepted from
+pub trait TriggerTransport {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// Waits for the next client to connect
+    fn accept_connection(&self) -> impl Future<Output = std::io::Result<Connection<Self::Stream>>> + Send;
+}
+
+impl TriggerTransport for UnixListener {
+    type Stream = UnixStream;
+
+    async fn accept_connection(&self) -> std::io::Result<Connection<UnixStream>> {
+        let (stream, _) = self.accept().await?;
+        let peer = stream.peer_cred().ok();
+        Ok(Connection {stream, peer})
+    }
+}
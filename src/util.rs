@@ -1,17 +1,21 @@
 use serde::Deserialize;
 use std::error::Error;
 use std::borrow::Borrow;
+use std::sync::Arc;
 
 /// A string that is nonempty and has no null bytes
+///
+/// The string is shared between clones, since configured keys are copied into
+/// every table indexed by key.
 #[derive(Deserialize)]
 #[serde(try_from = "String")]
 #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct NonEmptyNoNullString {
-    inner: String
+    inner: Arc<str>
 }
 impl AsRef<str> for NonEmptyNoNullString {
     fn as_ref(&self) -> &str {
-        self.inner.as_ref()
+        &self.inner
     }
 }
 impl Borrow<str> for NonEmptyNoNullString {
@@ -21,12 +25,12 @@ impl Borrow<str> for NonEmptyNoNullString {
 }
 impl From<NonEmptyNoNullString> for String {
     fn from(nstr: NonEmptyNoNullString) -> Self {
-        nstr.inner
+        nstr.inner.as_ref().to_owned()
     }
 }
 impl PartialEq<str> for NonEmptyNoNullString {
     fn eq(&self, other: &str) -> bool {
-        self.inner == other
+        *self.inner == *other
     }
 }
 
@@ -42,12 +46,12 @@ impl TryFrom<String> for NonEmptyNoNullString {
     type Error = TryIntoNonEmptyNoNullStringErr;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
-        if value.len()==0 {
+        if value.is_empty() {
             Err(TryIntoNonEmptyNoNullStringErr::Empty)
         } else if let Some(index) = value.as_bytes().iter().position(|c| *c==b'\x00') {
             Err(TryIntoNonEmptyNoNullStringErr::HasNull(index))
         } else {
-            Ok(NonEmptyNoNullString {inner: value})
+            Ok(NonEmptyNoNullString {inner: value.into()})
         }
     }
 }
@@ -62,3 +66,39 @@ impl std::fmt::Display for TryIntoNonEmptyNoNullStringErr {
     }
 }
 impl Error for TryIntoNonEmptyNoNullStringErr {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arbitrary::{Rng, CASES};
+
+    #[test]
+    fn accepts_exactly_the_nonempty_strings_without_null() {
+        let mut rng = Rng::new(0x5eed_0001);
+        for _ in 0..CASES {
+            let input = rng.string(16);
+            match NonEmptyNoNullString::try_from(input.clone()) {
+                Ok(nstr) => {
+                    assert!(!input.is_empty() && !input.contains('\0'), "Accepted {:?}", input);
+                    assert_eq!(nstr.as_ref(), input);
+                    assert_eq!(String::from(nstr), input);
+                },
+                Err(TryIntoNonEmptyNoNullStringErr::Empty) => assert!(input.is_empty()),
+                Err(TryIntoNonEmptyNoNullStringErr::HasNull(index)) => {
+                    assert_eq!(input.find('\0'), Some(index), "Wrong index for {:?}", input);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn deserializes_like_try_from() {
+        let mut rng = Rng::new(0x5eed_0002);
+        for _ in 0..CASES {
+            let input = rng.string(16);
+            let json = serde_json::to_string(&input).unwrap();
+            let deserialized = serde_json::from_str::<NonEmptyNoNullString>(&json).ok();
+            assert_eq!(deserialized, NonEmptyNoNullString::try_from(input.clone()).ok(), "Differ on {:?}", input);
+        }
+    }
+}
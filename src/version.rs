@@ -0,0 +1,66 @@
+//! The `version` subcommand and `--version`, which print what the binary was built from
+
+use argh::FromArgs;
+
+use serde_json::Value;
+
+use crate::protocol;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(FromArgs)]
+#[argh(description = "Print the version, commit, build time, and features of this binary, and the protocols it speaks")]
+#[argh(example = "sock_trigger_cmd version --json")]
+pub struct VersionArgs {
+    #[argh(switch)]
+    #[argh(description = "print the build information as JSON")]
+    json: bool
+}
+
+/// The cargo features the binary was built with
+pub fn features() -> Vec<&'static str> {
+    let features = [
+        ("syslog", cfg!(feature = "syslog")),
+        ("http", cfg!(feature = "http")),
+        ("email", cfg!(feature = "email")),
+        ("event-bus", cfg!(feature = "event-bus")),
+        ("otlp", cfg!(feature = "otlp"))
+    ];
+    features.into_iter()
+        .filter(|(_, is_enabled)| *is_enabled)
+        .map(|(feature, _)| feature)
+        .collect()
+}
+
+/// When the binary was built, in RFC 3339 UTC
+fn build_time() -> String {
+    env!("SOCK_TRIGGER_CMD_BUILD_TIME").parse::<i64>().ok()
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+        .map_or_else(|| "unknown".to_owned(), |time| time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+}
+
+fn build_info() -> serde_json::Map<String, Value> {
+    let mut info = serde_json::Map::new();
+    info.insert("version".to_owned(), env!("CARGO_PKG_VERSION").into());
+    info.insert("commit".to_owned(), env!("SOCK_TRIGGER_CMD_GIT_COMMIT").into());
+    info.insert("build_time".to_owned(), build_time().into());
+    info.insert("features".to_owned(), features().into());
+    info.insert("protocol_version".to_owned(), protocol::PROTOCOL_VERSION.into());
+    info
+}
+
+pub fn run(args: VersionArgs) {
+    let info = build_info();
+    if args.json {
+        crate::print_json_output(info, true);
+        return;
+    }
+    println!("sock_trigger_cmd {}", info["version"].as_str().unwrap_or_default());
+    for field in ["commit", "build_time"] {
+        println!("{}: {}", field, info[field].as_str().unwrap_or_default());
+    }
+    let features = features();
+    println!("features: {}", if features.is_empty() { "none".to_owned() } else { features.join(", ") });
+    println!("protocol_version: {}", protocol::PROTOCOL_VERSION);
+    // Sent as schema_version along with the rest in JSON
+    println!("output_schema_version: {}", crate::OUTPUT_SCHEMA_VERSION);
+}
@@ -0,0 +1,143 @@
+//! Posting the result of every run of a key to a webhook, with a body rendered from a template
+//!
+//! Strings in the template may hold `{{variable}}` placeholders. A string that
+//! is only a placeholder becomes the variable's JSON value, so that numbers
+//! stay numbers; elsewhere the variable is written out as text.
+
+use serde_json::Value;
+#[cfg(any(feature = "http", feature = "event-bus"))]
+use serde_json::{json, Map};
+
+#[cfg(feature = "http")]
+use std::time::Duration;
+
+#[cfg(feature = "http")]
+use log::warn;
+
+#[cfg(feature = "http")]
+use crate::config::Webhook;
+#[cfg(feature = "http")]
+use crate::http_client;
+#[cfg(any(feature = "http", feature = "event-bus"))]
+use crate::protocol::Outcome;
+#[cfg(any(feature = "http", feature = "event-bus"))]
+use crate::KeyResult;
+
+/// Every variable a template may use
+pub const VARIABLES: [&str; 9] = ["key", "status", "success", "code", "signal", "duration_ms", "stdout", "stderr",
+    "host"];
+
+/// How much of each of stdout and stderr the `stdout` and `stderr` variables hold
+#[cfg(any(feature = "http", feature = "event-bus"))]
+const OUTPUT_EXCERPT_LEN: usize = 4096;
+
+/// How long each attempt at posting may take
+#[cfg(feature = "http")]
+const ATTEMPT_TIME_LIMIT: Duration = Duration::from_secs(30);
+
+/// Calls `f` with the name of every placeholder in the template
+pub fn placeholders(template: &Value, f: &mut impl FnMut(&str)) {
+    match template {
+        Value::String(s) => {
+            let mut rest = s.as_str();
+            while let Some((name, after)) = rest.split_once("{{").and_then(|(_, after)| after.split_once("}}")) {
+                f(name);
+                rest = after;
+            }
+        },
+        Value::Array(values) => values.iter().for_each(|value| placeholders(value, f)),
+        Value::Object(fields) => fields.values().for_each(|value| placeholders(value, f)),
+        _ => {}
+    }
+}
+
+/// The variables describing a run of the key, which are also the fields of event bus events
+#[cfg(any(feature = "http", feature = "event-bus"))]
+pub fn variables(key: &str, result: &KeyResult) -> Map<String, Value> {
+    let outcome = result.outcome;
+    let hostname = nix::unistd::gethostname()
+        .map(|hostname| hostname.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let excerpt = |data: &[u8]| String::from_utf8_lossy(&data[..data.len().min(OUTPUT_EXCERPT_LEN)]).into_owned();
+    let variables = json!({
+        "key": key,
+        "status": outcome.label(),
+        "success": outcome.is_success(),
+        "code": match outcome {
+            Outcome::Completed(code) => Some(code),
+            _ => None
+        },
+        "signal": match outcome {
+            Outcome::Signaled(sig) => Some(sig),
+            _ => None
+        },
+        "duration_ms": result.duration.map(|duration| duration.as_millis() as u64),
+        "stdout": result.output.as_ref().map(|output| excerpt(&output.output.stdout)).unwrap_or_default(),
+        "stderr": result.output.as_ref().map(|output| excerpt(&output.output.stderr)).unwrap_or_default(),
+        "host": hostname
+    });
+    match variables {
+        Value::Object(variables) => variables,
+        _ => unreachable!()
+    }
+}
+
+/// Fills in the placeholders of the template
+#[cfg(feature = "http")]
+pub fn render(template: &Value, variables: &Map<String, Value>) -> Value {
+    match template {
+        Value::String(s) => {
+            let whole = s.strip_prefix("{{").and_then(|s| s.strip_suffix("}}"))
+                .filter(|name| !name.contains("{{") && !name.contains("}}"));
+            if let Some(value) = whole.and_then(|name| variables.get(name)) {
+                return value.clone();
+            }
+            let mut rendered = String::with_capacity(s.len());
+            let mut rest = s.as_str();
+            while let Some((before, after)) = rest.split_once("{{") {
+                let Some((name, after)) = after.split_once("}}") else {
+                    break;
+                };
+                rendered.push_str(before);
+                match variables.get(name) {
+                    Some(Value::String(value)) => rendered.push_str(value),
+                    Some(Value::Null) | None => {},
+                    Some(value) => rendered.push_str(&value.to_string())
+                }
+                rest = after;
+            }
+            rendered.push_str(rest);
+            Value::String(rendered)
+        },
+        Value::Array(values) => Value::Array(values.iter().map(|value| render(value, variables)).collect()),
+        Value::Object(fields) => Value::Object(fields.iter()
+            .map(|(name, value)| (name.clone(), render(value, variables)))
+            .collect()),
+        value => value.clone()
+    }
+}
+
+/// Posts the body, retrying with a doubling delay until a 2xx response or the retries run out
+#[cfg(feature = "http")]
+pub async fn send(webhook: Webhook, key: String, body: Value) {
+    let body = body.to_string().into_bytes();
+    let mut headers = webhook.headers.clone();
+    if !headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("content-type")) {
+        headers.push(("Content-Type".to_owned(), "application/json".to_owned()));
+    }
+    let mut delay = webhook.retry_delay;
+    for attempt in 0..=webhook.retries {
+        let error = match http_client::request("POST", &webhook.url, &headers, &body, ATTEMPT_TIME_LIMIT).await {
+            Ok(response) if (200..300).contains(&response.status) => return,
+            Ok(response) => format!("{} answered with status {}", webhook.url, response.status),
+            Err(e) => e
+        };
+        if attempt == webhook.retries {
+            warn!("Gave up on the webhook for key {} after {} attempts: {}", key, attempt + 1, error);
+            return;
+        }
+        warn!("Webhook for key {} failed, retrying in {}ms: {}", key, delay.as_millis(), error);
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+}
@@ -0,0 +1,878 @@
+use sock_trigger_cmd::test_harness::{CommandRunner, KeyConfig, RunningCommand, TestServer};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::{ExitStatus, Output};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+// Commands are interpreted by FakeRunner rather than run
+const CONFIG: &str = r#"{
+    "ok": "exit 0",
+    "fail": "exit 3",
+    "with space": "exit 0",
+    "slow": {"cmd": "sleep 5000", "on_deadline": "kill"},
+    "capped": {"cmd": "sleep 5000", "timeout_ms": 50}
+}"#;
+
+/// Runs `exit <code>` and `sleep <ms>` without spawning anything, recording what it started
+#[derive(Debug, Default)]
+struct FakeRunner {
+    started: Mutex<Vec<Vec<String>>>
+}
+impl FakeRunner {
+    fn started(&self) -> Vec<String> {
+        self.started.lock().unwrap().iter().map(|cmd| cmd.join(" ")).collect()
+    }
+}
+impl CommandRunner for FakeRunner {
+    fn start(&self, key_config: &KeyConfig, _kill_on_drop: bool) -> std::io::Result<RunningCommand> {
+        self.started.lock().unwrap().push(key_config.cmd.clone());
+        let arg: u64 = key_config.cmd[1].parse().unwrap();
+        let output = match key_config.cmd[0].as_str() {
+            "exit" => Box::pin(async move {
+                Ok(Output {status: ExitStatus::from_raw((arg as i32) << 8), stdout: Vec::new(), stderr: Vec::new()}.into())
+            }) as _,
+            "sleep" => Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(arg)).await;
+                Ok(Output {status: ExitStatus::from_raw(0), stdout: Vec::new(), stderr: Vec::new()}.into())
+            }) as _,
+            other => panic!("FakeRunner cannot run {}", other)
+        };
+        Ok(RunningCommand {pid: None, output})
+    }
+}
+
+/// A path of its own in the temporary directory, since tests run in parallel
+fn temp_path(extension: &str) -> PathBuf {
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+    std::env::temp_dir().join(format!("sock_trigger_cmd_test_{}_{}.{}",
+        std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed), extension))
+}
+
+fn write_config(contents: &str) -> PathBuf {
+    let path = temp_path("json");
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+fn server_with_config(contents: &str) -> (TestServer, Arc<FakeRunner>) {
+    let path = write_config(contents);
+    let runner = Arc::new(FakeRunner::default());
+    let server = TestServer::with_runner(&path, runner.clone()).unwrap();
+    std::fs::remove_file(path).unwrap();
+    (server, runner)
+}
+
+fn server_with_runner() -> (TestServer, Arc<FakeRunner>) {
+    server_with_config(CONFIG)
+}
+
+fn server() -> TestServer {
+    server_with_runner().0
+}
+
+/// Sends the messages, closes the write side, and returns everything the server sent back
+async fn exchange(mut client: DuplexStream, messages: &[u8]) -> Vec<u8> {
+    client.write_all(messages).await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn runs_configured_keys() {
+    let server = server();
+    assert_eq!(exchange(server.connect(), b"ok\0").await, b"C\0");
+    assert_eq!(exchange(server.connect(), b"fail\0").await, b"C\x03");
+}
+
+#[tokio::test]
+async fn answers_every_message_on_a_connection_in_order() {
+    let server = server();
+    assert_eq!(exchange(server.connect(), b"fail\0ok\0fail\0").await, b"C\x03C\0C\x03");
+}
+
+#[tokio::test]
+async fn answers_pipelined_requests_without_waiting_for_more() {
+    let server = server();
+    let mut client = server.connect();
+    // Sent in one write and left open, so every request is already buffered when the first is answered
+    client.write_all(b"fail\0ok\0\x01JSON\0ok\0\x01PAYLOAD 2\0hiok\0").await.unwrap();
+    let mut response = [0; 4];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"C\x03C\0");
+    let mut lines = tokio::io::BufReader::new(client).lines();
+    assert_eq!(lines.next_line().await.unwrap().unwrap(), r#"{"status":"ack"}"#);
+    // The payload's bytes are taken from the buffer too, leaving the key after them
+    for status in ["succeeded", "payload_rejected"] {
+        let line: serde_json::Value = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(line["key"], "ok");
+        assert_eq!(line["status"], status);
+    }
+}
+
+#[tokio::test]
+async fn matches_keys_exactly() {
+    let server = server();
+    assert_eq!(exchange(server.connect(), b"with space\0").await, b"C\0");
+    assert_eq!(exchange(server.connect(), b"o\0ok \0OK\0with\0").await, b"XXXX");
+}
+
+#[tokio::test]
+async fn rejects_unknown_keys() {
+    let server = server();
+    assert_eq!(exchange(server.connect(), b"missing\0ok\0").await, b"XC\0");
+}
+
+#[tokio::test]
+async fn treats_invalid_utf8_as_unknown() {
+    let server = server();
+    assert_eq!(exchange(server.connect(), b"\xff\xfe\0ok\0").await, b"XC\0");
+}
+
+#[tokio::test]
+async fn answers_empty_keys_distinctly() {
+    let server = server();
+    assert_eq!(exchange(server.connect(), b"\0ok\0").await, b"ZC\0");
+}
+
+#[tokio::test]
+async fn trims_keys_if_configured() {
+    let (server, runner) = server_with_config(r#"{"keys": {"ok": "exit 0"}, "trim_keys": true}"#);
+    assert_eq!(exchange(server.connect(), b" ok\n\0\t\0").await, b"C\0Z");
+    assert_eq!(runner.started(), ["exit 0"]);
+}
+
+#[tokio::test]
+async fn answers_with_response_profiles() {
+    let (server, _) = server_with_config(r#"{"keys": {"ok": "exit 0", "fail": "exit 3"},
+        "response_profiles": {"legacy": {"succeeded": "0", "failed": "1\n"}}}"#);
+    assert_eq!(exchange(server.connect(), b"ok\0").await, b"C\0");
+    server.select_response_profile("legacy");
+    assert_eq!(exchange(server.connect(), b"ok\0fail\0missing\0").await, b"01\nX");
+}
+
+#[tokio::test]
+async fn refuses_keys_outside_their_windows() {
+    use chrono::Timelike;
+    // A window that starts two hours from now cannot be open yet
+    let start = (chrono::Local::now().hour() + 2) % 24;
+    let (server, runner) = server_with_config(&format!(r#"{{
+        "always": {{"cmd": "exit 0", "allowed_windows": ["Mon-Sun"]}},
+        "later": {{"cmd": "exit 1", "allowed_windows": ["{:02}:00-{:02}:00"]}}
+    }}"#, start, (start + 1) % 24));
+    assert_eq!(exchange(server.connect(), b"always\0later\0").await, b"C\0G");
+    assert_eq!(runner.started(), ["exit 0"]);
+}
+
+#[tokio::test]
+async fn refuses_keys_over_their_rate_limit() {
+    let (server, runner) = server_with_config(r#"{"keys": {"limited": {"cmd": "exit 0", "rate_limit": {"rate": 0.001, "burst": 2}}}}"#);
+    assert_eq!(exchange(server.connect(), b"limited\0limited\0limited\0").await, b"C\0C\0R");
+    assert_eq!(runner.started(), ["exit 0", "exit 0"]);
+}
+
+#[tokio::test]
+async fn refuses_keys_over_their_daily_budget() {
+    let (server, runner) = server_with_config(r#"{"keys": {"slow": {"cmd": "sleep 30", "daily_budget_ms": 20}}}"#);
+    assert_eq!(exchange(server.connect(), b"slow\0slow\0").await, b"C\0Y");
+    assert_eq!(runner.started(), ["sleep 30"]);
+}
+
+#[tokio::test]
+async fn hides_keys_in_namespaces_of_other_peers() {
+    let (server, runner) = server_with_config(&format!(r#"{{
+        "keys": {{"ops/ok": "exit 0", "other/ok": "exit 1", "ok": "exit 2"}},
+        "namespaces": {{"ops": {{"uids": [{}]}}, "other": {{}}}}
+    }}"#, nix::unistd::getuid()));
+    let mut client = server.connect_unix().unwrap();
+    client.write_all(b"ops/ok\0other/ok\0ok\0").await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"C\0XC\x02");
+    // Peers without credentials are allowed by no namespace
+    assert_eq!(exchange(server.connect(), b"ops/ok\0ok\0").await, b"XC\x02");
+    assert_eq!(runner.started(), ["exit 0", "exit 2", "exit 2"]);
+}
+
+#[tokio::test]
+async fn checks_free_space_before_running() {
+    let (server, runner) = server_with_config(r#"{
+        "roomy": {"cmd": "exit 0", "min_free_space": {"path": "/", "bytes": 1}},
+        "huge": {"cmd": "exit 1", "min_free_space": {"path": "/", "bytes": 18446744073709551615}}
+    }"#);
+    assert_eq!(exchange(server.connect(), b"roomy\0huge\0").await, b"C\0I");
+    assert_eq!(runner.started(), ["exit 0"]);
+}
+
+#[tokio::test]
+async fn checks_preconditions_before_running() {
+    let (server, runner) = server_with_config(r#"{
+        "needs_file": {"cmd": "exit 1", "preconditions": [{"file_exists": "/nonexistent/sock_trigger_cmd"}]},
+        "checked": {"cmd": "exit 0", "preconditions": [{"file_exists": "/"}, {"check": "true"}]},
+        "failing_check": {"cmd": "exit 2", "preconditions": [{"check": "false"}]},
+        "exclusive": {"cmd": "exit 3", "preconditions": [{"not_running": "slow"}]},
+        "slow": "sleep 200"
+    }"#);
+    assert_eq!(exchange(server.connect(), b"needs_file\0checked\0failing_check\0").await, b"IC\0I");
+    let mut client = server.connect();
+    client.write_all(b"slow\0").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(exchange(server.connect(), b"exclusive\0").await, b"I");
+    assert_eq!(exchange(client, b"").await, b"C\0");
+    assert_eq!(runner.started(), ["exit 0", "sleep 200"]);
+}
+
+#[tokio::test]
+async fn sends_collected_files_after_the_response() {
+    let report = temp_path("txt");
+    std::fs::write(&report, "all good\n").unwrap();
+    let missing = temp_path("txt");
+    let (server, _) = server_with_config(&format!(r#"{{
+        "report": {{"cmd": "exit 0", "collect": [{:?}, {:?}], "collect_max_bytes": 4}}
+    }}"#, report, missing));
+    assert_eq!(exchange(server.connect(), b"report\0").await, b"C\0");
+    assert_eq!(exchange(server.connect(), b"\x01COLLECT\0report\0").await, b"C\0\x02~\0\0\0\x04all -\0\0\0\0");
+    std::fs::remove_file(report).unwrap();
+}
+
+#[tokio::test]
+async fn resumes_queued_triggers_after_a_restart() {
+    let queue_path = temp_path("queue");
+    let config_path = write_config(&format!(r#"{{"keys": {{"ok": "exit 0", "fail": "exit 3"}},
+        "queue_during_maintenance": true, "queue_file": {:?}}}"#, queue_path));
+    let server = TestServer::with_runner(&config_path, Arc::new(FakeRunner::default())).unwrap();
+    let mut client = server.connect_unix().unwrap();
+    client.write_all(b"\x01MAINTENANCE on\0ok\0").await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"AW");
+    server.shutdown().await;
+    // Triggers queued longer ago than queue_max_age_ms are dropped
+    let mut queue = std::fs::read_to_string(&queue_path).unwrap();
+    queue.push_str("{\"key\": \"fail\", \"queued_at\": 0}\n");
+    std::fs::write(&queue_path, queue).unwrap();
+    let runner = Arc::new(FakeRunner::default());
+    let server = TestServer::with_runner(&config_path, runner.clone()).unwrap();
+    server.run_queued().await;
+    assert_eq!(runner.started(), ["exit 0"]);
+    // The file is written off the connection's thread, and is up to date once the server stops
+    server.shutdown().await;
+    assert_eq!(std::fs::read_to_string(&queue_path).unwrap(), "");
+    std::fs::remove_file(queue_path).unwrap();
+    std::fs::remove_file(config_path).unwrap();
+}
+
+#[tokio::test]
+async fn resumes_queued_triggers_without_checking_their_limits_and_approvals_again() {
+    let queue_path = temp_path("queue");
+    let config_path = write_config(&format!(r#"{{"keys": {{"failover": {{"cmd": "exit 0", "approvals": 2}},
+        "limited": {{"cmd": "exit 1", "rate_limit": {{"rate": 0.001, "burst": 1}}}}}},
+        "queue_during_maintenance": true, "queue_file": {:?}}}"#, queue_path));
+    let server = TestServer::with_runner(&config_path, Arc::new(FakeRunner::default())).unwrap();
+    assert_eq!(server.approve("failover", u32::MAX - 1), Some(1));
+    let mut client = server.connect_unix().unwrap();
+    client.write_all(b"\x01MAINTENANCE on\0failover\0limited\0").await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"AWW");
+    server.shutdown().await;
+    // Resumed triggers have no peer to approve them, and were approved already
+    let runner = Arc::new(FakeRunner::default());
+    let server = TestServer::with_runner(&config_path, runner.clone()).unwrap();
+    server.run_queued().await;
+    assert_eq!(runner.started(), ["exit 0", "exit 1"]);
+    // Nor was running the trigger charged to the rate limit
+    assert_eq!(exchange(server.connect(), b"limited\0limited\0").await, b"C\x01R");
+    std::fs::remove_file(queue_path).unwrap();
+    std::fs::remove_file(config_path).unwrap();
+}
+
+#[tokio::test]
+async fn retains_results_until_acknowledged() {
+    let (server, runner) = server_with_runner();
+    assert_eq!(exchange(server.connect(), b"\x01RETAIN job-1\0fail\0").await, b"C\x03");
+    // Another connection fetches it, and retrying RETAIN does not run the key again
+    assert_eq!(exchange(server.connect(), b"\x01FETCH job-1\0\x01RETAIN job-1\0fail\0").await, b"C\x03C\x03");
+    assert_eq!(exchange(server.connect(), b"\x01ACK job-1\0\x01FETCH job-1\0\x01ACK job-1\0").await, b"AXX");
+    assert_eq!(runner.started(), ["exit 3"]);
+}
+
+#[tokio::test]
+async fn exports_the_history_to_admins() {
+    let server = server();
+    assert_eq!(exchange(server.connect(), b"ok\0fail\0missing\0\x01HISTORY 0\0").await, b"C\0C\x03XP");
+    let mut client = server.connect_unix().unwrap();
+    client.write_all(b"\x01HISTORY 3600 fail\0").await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    let (header, runs) = response.split_at(5);
+    assert_eq!(header[0], b'A');
+    assert_eq!(u32::from_be_bytes(header[1..].try_into().unwrap()) as usize, runs.len());
+    let runs: Vec<serde_json::Value> = serde_json::from_slice(runs).unwrap();
+    assert_eq!(runs.len(), 1);
+    assert_eq!(runs[0]["key"], "fail");
+    assert_eq!(runs[0]["status"], "failed");
+    assert_eq!(runs[0]["code"], 3);
+}
+
+#[tokio::test]
+async fn sends_the_status_to_admins() {
+    let server = server();
+    assert_eq!(exchange(server.connect(), b"fail\0\x01STATUS\0").await, b"C\x03P");
+    let mut client = server.connect_unix().unwrap();
+    client.write_all(b"\x01JSON\0\x01STATUS\0").await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    let line: serde_json::Value = serde_json::from_str(response.lines().nth(1).unwrap()).unwrap();
+    assert_eq!(line["status"], "ack");
+    let status = &line["daemon"];
+    assert_eq!(status["maintenance"], false);
+    assert!(status["keys"].as_array().unwrap().contains(&serde_json::json!(
+        {"key": "fail", "enabled": true, "runs_last_minute": 1})));
+    assert_eq!(status["recent"][0]["key"], "fail");
+    assert_eq!(status["outcomes"]["failed"], 1);
+}
+
+#[tokio::test]
+async fn switches_to_json_responses() {
+    let server = server();
+    let response = exchange(server.connect(), b"ok\0\x01JSON\0fail\0missing\0\x01BATCH 2 stop\0fail\0ok\0\x01JSON x\0").await;
+    let response = String::from_utf8(response).unwrap();
+    let lines = response.strip_prefix("C\0").unwrap();
+    let lines: Vec<serde_json::Value> = lines.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(lines[0], serde_json::json!({"status": "ack"}));
+    assert_eq!(lines[1]["status"], "failed");
+    assert_eq!(lines[1]["code"], 3);
+    assert_eq!(lines[1]["truncated"], false);
+    assert!(lines[1]["duration_ms"].is_u64());
+    assert_eq!(lines[2], serde_json::json!({"key": "missing", "status": "unknown_key", "success": false}));
+    assert_eq!(lines[3]["key"], "fail");
+    assert_eq!(lines[4], serde_json::json!({"status": "skipped"}));
+    assert_eq!(lines[5], serde_json::json!({"status": "invalid_frame"}));
+    assert_eq!(lines.len(), 6);
+}
+
+#[tokio::test]
+async fn stops_reading_output_held_open_by_descendants() {
+    // A real process, since what matters is who holds the pipes
+    let path = write_config(r#"{"daemonize": {"cmd": "sh -c 'sleep 2 & echo started'", "output_grace_ms": 100}}"#);
+    let server = TestServer::new(&path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    let response = tokio::time::timeout(Duration::from_secs(1), exchange(server.connect(), b"\x01JSON\0daemonize\0"))
+        .await.unwrap();
+    let line: serde_json::Value = serde_json::from_slice(response.split(|b| *b == b'\n').nth(1).unwrap()).unwrap();
+    assert_eq!(line["code"], 0);
+    assert_eq!(line["stdout"], "started\n");
+    assert_eq!(line["descendants_running"], true);
+}
+
+#[tokio::test]
+async fn runs_length_prefixed_keys() {
+    let (server, _) = server_with_config(r#"{"ok": "exit 0", "base64:AP8A": "exit 4"}"#);
+    assert_eq!(exchange(server.connect(), b"\x01KEY 3\0\0\xff\0\x01KEY 2\0ok\x01KEY 1\0\0\x01KEY 0\0").await, b"C\x04C\0XE");
+    // The name of a binary key works as a plain key too
+    assert_eq!(exchange(server.connect(), b"base64:AP8A\0").await, b"C\x04");
+}
+
+#[tokio::test]
+async fn checks_signed_keys() {
+    // The window reaches back to the epoch, so that a fixed timestamp can be signed
+    let (server, runner) = server_with_config(r#"{"deploy": {"cmd": "exit 0", "hmac_secret": "s3cret",
+        "hmac_window_ms": 100000000000000}, "stale": {"cmd": "exit 0", "hmac_secret": "s3cret"}}"#);
+    let signed = b"deploy:0:7a6bb1adb5c4e5576ef3ca86d2eebb72100f52ea4f24e22cc9c72d990f5c53f9\0";
+    assert_eq!(exchange(server.connect(), signed).await, b"C\0");
+    assert_eq!(runner.started().len(), 1);
+    let invalid = b"deploy:0:0000000000000000000000000000000000000000000000000000000000000000\0";
+    let stale = b"stale:0:7a6bb1adb5c4e5576ef3ca86d2eebb72100f52ea4f24e22cc9c72d990f5c53f9\0";
+    let requests = [&invalid[..], stale, b"deploy\0", b"deploy:x:00\0"].concat();
+    assert_eq!(exchange(server.connect(), &requests).await, b"XXXX");
+    assert_eq!(runner.started().len(), 1);
+}
+
+#[tokio::test]
+async fn runs_signed_keys_deferred_during_maintenance() {
+    let (server, runner) = server_with_config(r#"{"keys": {"deploy": {"cmd": "exit 0", "hmac_secret": "s3cret",
+        "hmac_window_ms": 100000000000000}}, "queue_during_maintenance": true}"#);
+    let mut client = server.connect_unix().unwrap();
+    client.write_all(b"\x01MAINTENANCE on\0deploy:0:7a6bb1adb5c4e5576ef3ca86d2eebb72100f52ea4f24e22cc9c72d990f5c53f9\0").await.unwrap();
+    client.write_all(b"\x01MAINTENANCE off\0").await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"AWA");
+    assert!(runner.started().is_empty());
+    server.run_queued().await;
+    assert_eq!(runner.started(), ["exit 0"]);
+}
+
+#[tokio::test]
+async fn runs_final_message_without_terminator() {
+    let server = server();
+    assert_eq!(exchange(server.connect(), b"ok\0fail").await, b"C\0C\x03");
+}
+
+#[tokio::test]
+async fn runs_batches() {
+    let server = server();
+    assert_eq!(exchange(server.connect(), b"\x01BATCH 3\0ok\0fail\0ok\0").await, b"B\x03C\0C\x03C\0");
+}
+
+#[tokio::test]
+async fn skips_rest_of_batch_after_failure() {
+    let (server, runner) = server_with_runner();
+    assert_eq!(exchange(server.connect(), b"\x01BATCH 3 stop\0ok\0fail\0ok\0").await, b"B\x03C\0C\x03N");
+    assert_eq!(runner.started(), ["exit 0", "exit 3"]);
+}
+
+#[tokio::test]
+async fn does_not_start_commands_for_rejected_keys() {
+    let (server, runner) = server_with_runner();
+    assert_eq!(exchange(server.connect(), b"missing\0\x01NOPE\0ok\0").await, b"XEC\0");
+    assert_eq!(runner.started(), ["exit 0"]);
+}
+
+#[tokio::test]
+async fn rejects_invalid_frames() {
+    let server = server();
+    let frames: &[&[u8]] = &[
+        b"\x01BATCH 0\0",
+        b"\x01BATCH 1 sometimes\0",
+        b"\x01DEADLINE soon\0",
+        b"\x01PAYLOAD 65537\0",
+        b"\x01ROTATE-LOG now\0",
+        b"\x01NOPE\0",
+        b"\x01\xff\0"
+    ];
+    for frame in frames {
+        let mut messages = frame.to_vec();
+        messages.extend(b"ok\0");
+        assert_eq!(exchange(server.connect(), &messages).await, b"EC\0", "frame {:?}", frame);
+    }
+}
+
+#[tokio::test]
+async fn writes_files() {
+    let dir = temp_path("d");
+    std::fs::create_dir(&dir).unwrap();
+    let config = format!(r#"{{
+        "flag": {{"write_file": {{"path": "{0}/flag", "contents": "1\n", "mode": "600"}}}},
+        "state": {{"write_file": {{"path": "{0}/state", "max_bytes": 4}}}}
+    }}"#, dir.display());
+    let (server, runner) = server_with_config(&config);
+    assert_eq!(exchange(server.connect(), b"flag\0\x01PAYLOAD 3\0a\0cstate\0").await, b"C\0C\0");
+    assert_eq!(std::fs::read(dir.join("flag")).unwrap(), b"1\n");
+    assert_eq!(std::fs::metadata(dir.join("flag")).unwrap().permissions().mode() & 0o777, 0o600);
+    assert_eq!(std::fs::read(dir.join("state")).unwrap(), b"a\0c");
+    assert!(runner.started().is_empty());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn rejects_unexpected_and_oversized_payloads() {
+    let dir = temp_path("d");
+    std::fs::create_dir(&dir).unwrap();
+    let config = format!(r#"{{
+        "ok": "exit 0",
+        "state": {{"write_file": {{"path": "{}/state", "max_bytes": 4}}}}
+    }}"#, dir.display());
+    let (server, runner) = server_with_config(&config);
+    assert_eq!(exchange(server.connect(), b"state\0\x01PAYLOAD 5\0abcdestate\0\x01PAYLOAD 1\0aok\0").await, b"LLL");
+    assert!(!dir.join("state").exists());
+    assert!(runner.started().is_empty());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn sends_http_requests() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = format!(r#"{{"hook": {{"http": {{"url": "http://{}/hook", "max_bytes": 16}}}}}}"#,
+        listener.local_addr().unwrap());
+    let server_task = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        // The client keeps the connection open for the response, so read up to the known body
+        while !request.ends_with(b"payload") {
+            let mut buf = [0; 1024];
+            let len = stream.read(&mut buf).await.unwrap();
+            assert!(len > 0, "request ended early: {:?}", String::from_utf8_lossy(&request));
+            request.extend(&buf[..len]);
+        }
+        stream.write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+        String::from_utf8(request).unwrap()
+    });
+    let (server, _) = server_with_config(&config);
+    assert_eq!(exchange(server.connect(), b"\x01PAYLOAD 7\0payloadhook\0").await, b"C\x05");
+    let request = server_task.await.unwrap();
+    assert!(request.starts_with("POST /hook HTTP/1.1\r\n"), "{}", request);
+    assert!(request.contains("Content-Length: 7\r\n"), "{}", request);
+}
+
+#[tokio::test]
+async fn emails_after_repeated_failures() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = format!(r#"{{"keys": {{"ok": "exit 0", "fail": "exit 3"}}, "email_alert": {{"server": "{}",
+        "from": "daemon@example.com", "to": ["ops@example.com"], "after_failures": 2}}}}"#,
+        listener.local_addr().unwrap());
+    let relay_task = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (read, mut write) = stream.into_split();
+        let mut lines = tokio::io::BufReader::new(read).lines();
+        write.write_all(b"220 relay\r\n").await.unwrap();
+        let mut transcript = String::new();
+        let mut is_in_data = false;
+        while let Some(line) = lines.next_line().await.unwrap() {
+            transcript += &line;
+            transcript += "\n";
+            let reply: &[u8] = match (is_in_data, line.as_str()) {
+                (true, ".") => b"250 queued\r\n",
+                (true, _) => continue,
+                (false, "DATA") => b"354 go ahead\r\n",
+                (false, "QUIT") => b"221 bye\r\n",
+                (false, _) => b"250-relay\r\n250 ok\r\n"
+            };
+            is_in_data = line == "DATA";
+            write.write_all(reply).await.unwrap();
+        }
+        transcript
+    });
+    let (server, _) = server_with_config(&config);
+    assert_eq!(exchange(server.connect(), b"fail\0ok\0fail\0fail\0").await, b"C\x03C\0C\x03C\x03");
+    let transcript = tokio::time::timeout(Duration::from_secs(5), relay_task).await.unwrap().unwrap();
+    assert!(transcript.contains("RCPT TO:<ops@example.com>\n"), "{}", transcript);
+    assert!(transcript.contains("Subject: Key fail failed 2 times in a row"), "{}", transcript);
+}
+
+#[tokio::test]
+async fn posts_results_to_webhooks() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = format!(r#"{{"fail": {{"cmd": "exit 3", "webhook": {{"url": "http://{}/hook",
+        "body": {{"text": "{{{{key}}}} {{{{status}}}}", "code": "{{{{code}}}}"}}, "retry_delay_ms": 10}}}}}}"#,
+        listener.local_addr().unwrap());
+    let hook_task = tokio::spawn(async move {
+        // The first attempt is refused, so the body comes from the retry
+        let mut bodies = Vec::new();
+        for status in ["500 Internal Server Error", "204 No Content"] {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = tokio::io::BufReader::new(stream);
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                if let Some(len) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    content_length = len.trim().parse().unwrap();
+                }
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0; content_length];
+            stream.read_exact(&mut body).await.unwrap();
+            bodies.push(body);
+            stream.write_all(format!("HTTP/1.1 {}\r\n\r\n", status).as_bytes()).await.unwrap();
+        }
+        bodies
+    });
+    let (server, _) = server_with_config(&config);
+    assert_eq!(exchange(server.connect(), b"fail\0").await, b"C\x03");
+    let bodies = tokio::time::timeout(Duration::from_secs(5), hook_task).await.unwrap().unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&bodies[1]).unwrap();
+    assert_eq!(body, serde_json::json!({"text": "fail failed", "code": 3}));
+}
+
+#[tokio::test]
+async fn publishes_events_to_redis() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = format!(r#"{{"keys": {{"ok": "exit 0"}}, "event_bus": {{"redis": "{}", "channel": "runs"}}}}"#,
+        listener.local_addr().unwrap());
+    let redis_task = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = tokio::io::BufReader::new(stream);
+        // Each of the 3 bulk strings is a length line and a data line
+        let mut lines = Vec::new();
+        for _ in 0..7 {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            lines.push(line.trim_end().to_owned());
+        }
+        stream.write_all(b":1\r\n").await.unwrap();
+        lines
+    });
+    let (server, _) = server_with_config(&config);
+    assert_eq!(exchange(server.connect(), b"ok\0").await, b"C\0");
+    let lines = tokio::time::timeout(Duration::from_secs(5), redis_task).await.unwrap().unwrap();
+    assert_eq!(lines[..5], ["*3", "$7", "PUBLISH", "$4", "runs"]);
+    let event: serde_json::Value = serde_json::from_str(&lines[6]).unwrap();
+    assert_eq!(event["key"], "ok");
+    assert_eq!(event["status"], "succeeded");
+    assert_eq!(event["code"], 0);
+}
+
+#[tokio::test]
+async fn forwards_keys_to_peers() {
+    let peer_paths = [temp_path("sock"), temp_path("sock")];
+    let mut peer_tasks = Vec::new();
+    for (path, response) in peer_paths.iter().zip([b"C\0", b"C\x03"]) {
+        let listener = tokio::net::UnixListener::bind(path).unwrap();
+        peer_tasks.push(tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 7];
+            stream.read_exact(&mut request).await.unwrap();
+            stream.write_all(response).await.unwrap();
+            request
+        }));
+    }
+    let missing_path = temp_path("sock");
+    let config = format!(r#"{{"fleet": {{"forward": {{"sockets": [{:?}, {:?}], "key": "remote"}}}},
+        "missing": {{"forward": {{"sockets": [{:?}]}}}}}}"#, peer_paths[0], peer_paths[1], missing_path);
+    let (server, _) = server_with_config(&config);
+    // One peer failed and the socket of the other key does not exist
+    assert_eq!(exchange(server.connect(), b"fleet\0missing\0").await, b"C\x01C\x01");
+    for task in peer_tasks {
+        assert_eq!(&task.await.unwrap(), b"remote\0");
+    }
+    for path in peer_paths {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn kills_commands_past_the_deadline() {
+    let server = server();
+    assert_eq!(exchange(server.connect(), b"\x01DEADLINE 50\0slow\0ok\0").await, b"TC\0");
+}
+
+#[tokio::test]
+async fn waits_for_jobs_past_the_deadline() {
+    let (server, _) = server_with_config(r#"{"nap": "sleep 100"}"#);
+    assert_eq!(exchange(server.connect(), b"\x01DEADLINE 0\0nap\0").await, b"J\0\0\0\x01");
+    // Jobs can be waited for more than once, but only by the uid that started them
+    assert_eq!(exchange(server.connect(), b"\x01JOB 1\0\x01JOB 1\0\x01JOB 2\0").await, b"C\0C\0X");
+    let mut client = server.connect_unix().unwrap();
+    client.write_all(b"\x01JOB 1\0\x01JOB x\0").await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"XE");
+}
+
+#[tokio::test]
+async fn kills_commands_past_their_timeout() {
+    let server = server();
+    assert_eq!(exchange(server.connect(), b"capped\0ok\0").await, b"TC\0");
+}
+
+#[tokio::test]
+async fn runs_timeout_hooks() {
+    let marker = temp_path("txt");
+    let config = format!(r#"{{"capped": {{"cmd": "sleep 5000", "timeout_ms": 50,
+        "on_timeout": "sh -c 'echo $TRIGGER_KEY > {}'"}}}}"#, marker.display());
+    let (server, _) = server_with_config(&config);
+    assert_eq!(exchange(server.connect(), b"capped\0").await, b"T");
+    for _ in 0..100 {
+        if std::fs::read_to_string(&marker).is_ok_and(|contents| contents.ends_with('\n')) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(std::fs::read_to_string(&marker).unwrap(), "capped\n");
+    std::fs::remove_file(marker).unwrap();
+}
+
+#[tokio::test]
+async fn denies_admin_frames_without_credentials() {
+    let server = server();
+    assert_eq!(exchange(server.connect(), b"\x01DISABLE ok\0\x01MAINTENANCE on\0\x01ROTATE-LOG\0ok\0").await, b"PPPC\0");
+}
+
+#[tokio::test]
+async fn enables_and_disables_keys_for_admins() {
+    let (server, runner) = server_with_config(r#"{"ok": "exit 0", "off": {"cmd": "exit 1", "enabled": false}}"#);
+    let mut client = server.connect_unix().unwrap();
+    client.write_all(b"\x01DISABLE ok\0ok\0\x01ENABLE off\0off\0\x01DISABLE missing\0").await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"AUAC\x01X");
+    // The change applies to other connections too
+    assert_eq!(exchange(server.connect(), b"ok\0off\0").await, b"UC\x01");
+    assert_eq!(runner.started(), ["exit 1", "exit 1"]);
+}
+
+#[tokio::test]
+async fn runs_keys_once_for_tokens() {
+    let (server, runner) = server_with_runner();
+    let token = server.mint_token("ok", Duration::from_secs(60)).unwrap();
+    assert_eq!(token.len(), 32);
+    let request = format!("token:{}\0token:{}\0token:0123\0", token, token);
+    assert_eq!(exchange(server.connect(), request.as_bytes()).await, b"C\0XX");
+    assert_eq!(runner.started(), ["exit 0"]);
+    assert_eq!(exchange(server.connect(), b"\x01MINT 60 ok\0\x01MINT 0 ok\0").await, b"PE");
+}
+
+#[tokio::test]
+async fn runs_confirmed_keys_after_echoing_the_nonce() {
+    let (server, runner) = server_with_config(r#"{"danger": {"cmd": "exit 0", "confirm": true}}"#);
+    let mut client = server.connect();
+    client.write_all(b"danger\0").await.unwrap();
+    let mut response = [0u8; 33];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(response[0], b'Q');
+    assert!(runner.started().is_empty());
+    let nonce = std::str::from_utf8(&response[1..]).unwrap();
+    let request = format!("confirm:{}\0confirm:{}\0", nonce, nonce);
+    assert_eq!(exchange(client, request.as_bytes()).await, b"C\0X");
+    assert_eq!(runner.started(), ["exit 0"]);
+}
+
+#[tokio::test]
+async fn runs_keys_confirmed_during_maintenance_after_a_restart() {
+    let queue_path = temp_path("queue");
+    let config_path = write_config(&format!(r#"{{"keys": {{"danger": {{"cmd": "exit 0", "confirm": true}}}},
+        "queue_during_maintenance": true, "queue_file": {:?}}}"#, queue_path));
+    let server = TestServer::with_runner(&config_path, Arc::new(FakeRunner::default())).unwrap();
+    let mut client = server.connect_unix().unwrap();
+    client.write_all(b"\x01MAINTENANCE on\0danger\0").await.unwrap();
+    let mut response = [0u8; 34];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response[..2], b"AQ");
+    let request = format!("confirm:{}\0", std::str::from_utf8(&response[2..]).unwrap());
+    client.write_all(request.as_bytes()).await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"W");
+    server.shutdown().await;
+    let runner = Arc::new(FakeRunner::default());
+    let server = TestServer::with_runner(&config_path, runner.clone()).unwrap();
+    server.run_queued().await;
+    assert_eq!(runner.started(), ["exit 0"]);
+    std::fs::remove_file(queue_path).unwrap();
+    std::fs::remove_file(config_path).unwrap();
+}
+
+#[tokio::test]
+async fn waits_for_approvals_from_distinct_peers() {
+    let (server, runner) = server_with_config(r#"{"failover": {"cmd": "exit 0", "approvals": 2}}"#);
+    let mut client = server.connect_unix().unwrap();
+    client.write_all(b"failover\0failover\0").await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    // The same peer approving twice still counts once
+    assert_eq!(response, b"V\x01V\x01");
+    assert_eq!(exchange(server.connect(), b"failover\0").await, b"X");
+    assert!(runner.started().is_empty());
+}
+
+#[tokio::test]
+async fn runs_keys_approved_during_maintenance_once_it_ends() {
+    let (server, runner) = server_with_config(r#"{"keys": {"failover": {"cmd": "exit 0", "approvals": 2}},
+        "queue_during_maintenance": true}"#);
+    assert_eq!(server.approve("failover", u32::MAX - 1), Some(1));
+    let mut client = server.connect_unix().unwrap();
+    client.write_all(b"\x01MAINTENANCE on\0failover\0\x01MAINTENANCE off\0").await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"AWA");
+    assert!(runner.started().is_empty());
+    // The approval that completed them is not counted as the first of the next ones
+    server.run_queued().await;
+    assert_eq!(runner.started(), ["exit 0"]);
+}
+
+#[tokio::test]
+async fn closes_connections_after_halting() {
+    let server = server();
+    let mut client = server.connect();
+    client.write_all(b"ok\0").await.unwrap();
+    let mut response = [0u8; 2];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"C\0");
+
+    server.halt();
+    // The request in progress is still answered, and then the connection is closed
+    client.write_all(b"fail\0ok\0").await.unwrap();
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest).await.unwrap();
+    assert_eq!(rest, b"C\x03");
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn asks_clients_to_reconnect_after_max_requests() {
+    let (server, runner) = server_with_config(r#"{"keys": {"ok": "exit 0"}, "max_requests_per_connection": 2}"#);
+    // The connection is closed without reading the third key, even though the client has not closed its side
+    let mut client = server.connect();
+    client.write_all(b"ok\0ok\0ok\0").await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"C\0C\0r");
+    assert_eq!(runner.started().len(), 2);
+    let response = exchange(server.connect(), b"\x01JSON\0ok\0").await;
+    assert_eq!(String::from_utf8(response).unwrap().lines().last(), Some(r#"{"status":"reconnect"}"#));
+}
+
+#[tokio::test]
+async fn shutdown_waits_for_open_connections() {
+    let server = server();
+    let client = server.connect();
+    let shutdown = tokio::spawn(server.shutdown());
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert!(!shutdown.is_finished());
+    assert_eq!(exchange(client, b"ok\0").await, b"C\0");
+    shutdown.await.unwrap();
+}
+
+#[tokio::test]
+async fn kills_commands_past_the_shutdown_timeout() {
+    let (server, _) = server_with_config(r#"{
+        "keys": {"slow": {"cmd": "sleep 5000", "on_shutdown": "kill"}},
+        "shutdown_timeout_ms": 50
+    }"#);
+    let mut client = server.connect();
+    client.write_all(b"slow\0").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    server.halt();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"T");
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn leaves_commands_running_at_shutdown() {
+    let (server, _) = server_with_config(r#"{
+        "keys": {"slow": {"cmd": "sleep 5000", "on_shutdown": "detach"}}
+    }"#);
+    let mut client = server.connect();
+    client.write_all(b"slow\0").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    server.halt();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"J\0\0\0\x01");
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn closes_connections_when_responses_cannot_be_written() {
+    let (server, runner) = server_with_runner();
+    let mut client = server.connect();
+    client.write_all(b"ok\0ok\0ok\0").await.unwrap();
+    drop(client);
+    server.shutdown().await;
+    assert_eq!(runner.started(), ["exit 0"]);
+}